@@ -0,0 +1,6 @@
+#!/bin/sh
+# Cargo's runner for the kernel target. Builds the runner for the host, since the kernel's
+# target in .cargo/config.toml applies here as well, and hands it the kernel ELF.
+set -e
+host=$(rustc -vV | sed -n 's/^host: //p')
+exec cargo run --quiet --manifest-path "$(dirname "$0")/Cargo.toml" --target "$host" -- "$@"
@@ -0,0 +1,49 @@
+//! Boots the kernel in QEMU, as cargo's runner for `cargo run` and `cargo test`.
+//!
+//! Cargo passes the path of the kernel ELF. The runner wraps it in a BIOS disk image next to it
+//! and starts QEMU with COM1 on standard output and the `isa-debug-exit` device. Test kernels,
+//! which cargo builds into `deps`, run without a display and report through the device: QEMU
+//! exits with 33 when every test passed.
+
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+
+/// QEMU's exit status once the test kernel wrote `testing::ExitCode::Success`.
+const TESTS_PASSED: i32 = (0x10 << 1) | 1;
+
+fn main() {
+    let Some(kernel) = std::env::args_os().nth(1).map(PathBuf::from) else {
+        eprintln!("usage: runner <kernel>");
+        exit(2);
+    };
+    let image = kernel.with_extension("img");
+    if let Err(error) = bootloader::BiosBoot::new(&kernel).create_disk_image(&image) {
+        eprintln!("runner: cannot build the disk image: {error:#}");
+        exit(1);
+    }
+    let test = kernel
+        .parent()
+        .and_then(Path::file_name)
+        .is_some_and(|directory| directory == "deps");
+
+    let mut qemu = Command::new("qemu-system-x86_64");
+    qemu.arg("-drive")
+        .arg(format!("format=raw,file={}", image.display()))
+        .args(["-serial", "stdio"])
+        .args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]);
+    if test {
+        qemu.args(["-display", "none"]);
+    }
+    let status = match qemu.status() {
+        Ok(status) => status,
+        Err(error) => {
+            eprintln!("runner: cannot start QEMU: {error}");
+            exit(1);
+        }
+    };
+    exit(match (test, status.code()) {
+        (true, Some(TESTS_PASSED)) => 0,
+        (true, _) => 1,
+        (false, code) => code.unwrap_or(1),
+    });
+}
@@ -0,0 +1,557 @@
+//! ACPI table discovery.
+//!
+//! The RSDP, whose address the bootloader passes on, points to the XSDT, or to the RSDT on ACPI
+//! 1.0 firmware, which lists the physical addresses of all other tables. The tables are read
+//! through the bootloader's mapping of physical memory.
+//!
+//! Two tables are parsed here, on first use, since several parts of the kernel need them: the
+//! MADT, [`madt`], lists the processors, the IO APICs and how the ISA IRQs are wired to them, and
+//! the FADT, [`fadt`], describes the power management registers and how to reset the machine.
+//! Drivers parse the other tables they need themselves.
+
+use alloc::vec::Vec;
+use core::slice;
+use spin::Once;
+use x86_64::VirtAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Length of the ACPI 1.0 part of the RSDP, which its first checksum covers.
+const RSDP_V1_LENGTH: usize = 20;
+/// Length of the ACPI 2.0 RSDP, which adds the XSDT address.
+const RSDP_V2_LENGTH: usize = 36;
+const RSDT_SIGNATURE: &[u8; 4] = b"RSDT";
+const XSDT_SIGNATURE: &[u8; 4] = b"XSDT";
+/// Length of the header all system description tables start with.
+const HEADER_LENGTH: usize = 36;
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+
+/// MADT fields: the local APIC address, the flags and the first entry.
+const MADT_LOCAL_APIC_ADDRESS: usize = 36;
+const MADT_FLAGS: usize = 40;
+const MADT_ENTRIES: usize = 44;
+/// MADT flag set when the machine also has the 8259 PICs.
+const MADT_PC_AT_COMPATIBLE: u32 = 1 << 0;
+/// MADT entry types.
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+/// Local APIC entry flag set for processors that can be started.
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+
+/// FADT fields. The reset register and value came with ACPI 2.0 and the 64-bit DSDT address
+/// after them, so older tables end before those.
+const FADT_DSDT: usize = 40;
+const FADT_SCI_INTERRUPT: usize = 46;
+const FADT_SMI_COMMAND: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_ACPI_DISABLE: usize = 53;
+const FADT_PM1A_EVENT_BLOCK: usize = 56;
+const FADT_PM1B_EVENT_BLOCK: usize = 60;
+const FADT_PM1A_CONTROL_BLOCK: usize = 64;
+const FADT_PM1B_CONTROL_BLOCK: usize = 68;
+const FADT_PM_TIMER_BLOCK: usize = 76;
+const FADT_CENTURY: usize = 108;
+const FADT_BOOT_ARCHITECTURE: usize = 109;
+const FADT_FLAGS: usize = 112;
+const FADT_RESET_REGISTER: usize = 116;
+const FADT_RESET_VALUE: usize = 128;
+const FADT_X_DSDT: usize = 140;
+/// FADT flags: the power management timer counts with 32 bits instead of 24, and the reset
+/// register is there.
+const FADT_TIMER_32_BIT: u32 = 1 << 8;
+const FADT_RESET_SUPPORTED: u32 = 1 << 10;
+/// Boot architecture flag set when the machine has a PS/2 controller.
+const BOOT_8042: u16 = 1 << 1;
+/// Length of a generic address structure.
+const GENERIC_ADDRESS_LENGTH: usize = 12;
+
+/// Address spaces of a [`GenericAddress`].
+pub const SPACE_SYSTEM_MEMORY: u8 = 0;
+pub const SPACE_SYSTEM_IO: u8 = 1;
+pub const SPACE_PCI_CONFIG: u8 = 2;
+
+/// Errors that keep the ACPI tables from being used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The RSDP has a wrong signature or checksum.
+    InvalidRsdp,
+    /// The RSDT or XSDT has a wrong signature or checksum.
+    InvalidRootTable,
+}
+
+struct RootTable {
+    physical_memory_offset: VirtAddr,
+    /// Entries of the RSDT or XSDT, physical addresses of the other tables.
+    entries: &'static [u8],
+    /// Size of an entry: 4 bytes in the RSDT, 8 in the XSDT.
+    entry_size: usize,
+}
+
+static ROOT_TABLE: Once<RootTable> = Once::new();
+static MADT: Once<Option<Madt>> = Once::new();
+static FADT: Once<Option<Fadt>> = Once::new();
+
+/// The interrupt controllers and processors, from the MADT.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    /// Physical address of every local APIC's registers.
+    pub local_apic_address: u64,
+    /// Whether the 8259 PICs are there too, and should be masked when the APICs take over.
+    pub pc_at_compatible: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    /// ISA IRQs that are not wired to the IO APIC input of the same number, or not edge
+    /// triggered and active high.
+    pub overrides: Vec<InterruptOverride>,
+    /// Local APIC inputs wired to NMI.
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+/// A processor and its local APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Whether the processor can be started. A disabled one is not usable at all.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    /// Physical address of the registers.
+    pub address: u32,
+    /// Global system interrupt of the first input.
+    pub gsi_base: u32,
+}
+
+/// The global system interrupt an ISA IRQ arrives on, with its polarity and trigger mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0 and 1, trigger mode in bits 2 and 3, 0 meaning the
+    /// bus default.
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// A local APIC input wired to NMI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    /// ACPI processor id, or `0xFF` for every processor.
+    pub processor_id: u8,
+    pub flags: u16,
+    /// LINT0 or LINT1.
+    pub lint: u8,
+}
+
+impl Madt {
+    fn parse(table: &[u8]) -> Option<Madt> {
+        let flags = read_u32(table.get(..MADT_ENTRIES)?, MADT_FLAGS);
+        let mut madt = Madt {
+            local_apic_address: u64::from(read_u32(table, MADT_LOCAL_APIC_ADDRESS)),
+            pc_at_compatible: flags & MADT_PC_AT_COMPATIBLE != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+        let mut entries = &table[MADT_ENTRIES..];
+        while let [kind, length, ..] = *entries {
+            let length = usize::from(length);
+            if length < 2 || length > entries.len() {
+                break;
+            }
+            let entry = &entries[..length];
+            match (kind, length) {
+                (ENTRY_LOCAL_APIC, 8..) => madt.processors.push(Processor {
+                    processor_id: entry[2],
+                    apic_id: entry[3],
+                    enabled: read_u32(entry, 4) & PROCESSOR_ENABLED != 0,
+                }),
+                (ENTRY_IO_APIC, 12..) => madt.io_apics.push(IoApic {
+                    id: entry[2],
+                    address: read_u32(entry, 4),
+                    gsi_base: read_u32(entry, 8),
+                }),
+                (ENTRY_INTERRUPT_OVERRIDE, 10..) => madt.overrides.push(InterruptOverride {
+                    irq: entry[3],
+                    gsi: read_u32(entry, 4),
+                    flags: u16::from_le_bytes([entry[8], entry[9]]),
+                }),
+                (ENTRY_LOCAL_APIC_NMI, 6..) => madt.nmis.push(LocalApicNmi {
+                    processor_id: entry[2],
+                    flags: u16::from_le_bytes([entry[3], entry[4]]),
+                    lint: entry[5],
+                }),
+                (ENTRY_LOCAL_APIC_ADDRESS, 12..) => madt.local_apic_address = read_u64(entry, 4),
+                _ => {}
+            }
+            entries = &entries[length..];
+        }
+        Some(madt)
+    }
+
+    /// The IO APIC whose inputs include global system interrupt `gsi`, and the input.
+    pub fn io_apic_for(&self, gsi: u32) -> Option<(&IoApic, u32)> {
+        // The number of inputs is only known from the IO APIC itself, so the closest base wins.
+        self.io_apics
+            .iter()
+            .filter(|io| io.gsi_base <= gsi)
+            .max_by_key(|io| io.gsi_base)
+            .map(|io| (io, gsi - io.gsi_base))
+    }
+
+    /// The override of ISA IRQ `irq`, if it has one.
+    pub fn override_for(&self, irq: u8) -> Option<&InterruptOverride> {
+        self.overrides.iter().find(|entry| entry.irq == irq)
+    }
+}
+
+/// A register as ACPI describes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    /// [`SPACE_SYSTEM_MEMORY`], [`SPACE_SYSTEM_IO`], [`SPACE_PCI_CONFIG`] or another space.
+    pub space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    fn parse(bytes: &[u8]) -> GenericAddress {
+        GenericAddress {
+            space: bytes[0],
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read_u64(bytes, 4),
+        }
+    }
+}
+
+/// The fixed hardware power management registers and the reset register, from the FADT. Port
+/// addresses of 0 mean the block is not there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// Physical address of the DSDT, the 64-bit one if the table has it.
+    pub dsdt: u64,
+    /// The ISA IRQ of the system control interrupt, which signals power management events.
+    pub sci_interrupt: u16,
+    /// Port that [`Fadt::acpi_enable`] is written to to hand power management from the firmware
+    /// to the kernel, 0 on machines that are always in ACPI mode.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    /// Whether the power management timer counts with 32 bits rather than 24.
+    pub pm_timer_32_bit: bool,
+    /// CMOS RAM index of the century, or 0 if the RTC does not keep it.
+    pub century: u8,
+    /// IA-PC boot architecture flags, 0 in ACPI 1.0 tables.
+    pub boot_architecture: u16,
+    /// The register that resets the machine when [`Fadt::reset_value`] is written to it.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    fn parse(table: &[u8]) -> Option<Fadt> {
+        let field = table.get(..FADT_FLAGS + 4)?;
+        let flags = read_u32(field, FADT_FLAGS);
+        let reset = table.get(FADT_RESET_REGISTER..=FADT_RESET_VALUE);
+        let reset_register = reset
+            .filter(|_| flags & FADT_RESET_SUPPORTED != 0)
+            .map(|reset| GenericAddress::parse(&reset[..GENERIC_ADDRESS_LENGTH]))
+            .filter(|register| register.address != 0);
+        let x_dsdt = table
+            .get(FADT_X_DSDT..FADT_X_DSDT + 8)
+            .map_or(0, |bytes| read_u64(bytes, 0));
+        Some(Fadt {
+            dsdt: match x_dsdt {
+                0 => u64::from(read_u32(field, FADT_DSDT)),
+                address => address,
+            },
+            sci_interrupt: u16::from_le_bytes([
+                field[FADT_SCI_INTERRUPT],
+                field[FADT_SCI_INTERRUPT + 1],
+            ]),
+            smi_command: read_u32(field, FADT_SMI_COMMAND),
+            acpi_enable: field[FADT_ACPI_ENABLE],
+            acpi_disable: field[FADT_ACPI_DISABLE],
+            pm1a_event_block: read_u32(field, FADT_PM1A_EVENT_BLOCK),
+            pm1b_event_block: read_u32(field, FADT_PM1B_EVENT_BLOCK),
+            pm1a_control_block: read_u32(field, FADT_PM1A_CONTROL_BLOCK),
+            pm1b_control_block: read_u32(field, FADT_PM1B_CONTROL_BLOCK),
+            pm_timer_block: read_u32(field, FADT_PM_TIMER_BLOCK),
+            pm_timer_32_bit: flags & FADT_TIMER_32_BIT != 0,
+            century: field[FADT_CENTURY],
+            boot_architecture: u16::from_le_bytes([
+                field[FADT_BOOT_ARCHITECTURE],
+                field[FADT_BOOT_ARCHITECTURE + 1],
+            ]),
+            reset_register,
+            reset_value: reset.map_or(0, |reset| reset[FADT_RESET_VALUE - FADT_RESET_REGISTER]),
+        })
+    }
+
+    /// Whether the machine says it has a PS/2 controller. ACPI 1.0 tables do not say, so they
+    /// count as having one.
+    pub fn has_8042(&self) -> bool {
+        self.boot_architecture == 0 || self.boot_architecture & BOOT_8042 != 0
+    }
+}
+
+/// Returns whether the bytes sum up to zero, which is how ACPI checksums work.
+fn checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Maps the table at physical address `address` and checks its length and checksum. Returns the
+/// whole table, header included.
+fn table_at(physical_memory_offset: VirtAddr, address: u64) -> Option<&'static [u8]> {
+    let start = (physical_memory_offset + address).as_ptr::<u8>();
+    let header = unsafe { slice::from_raw_parts(start, HEADER_LENGTH) };
+    let length = read_u32(header, 4) as usize;
+    if length < HEADER_LENGTH {
+        return None;
+    }
+    let table = unsafe { slice::from_raw_parts(start, length) };
+    checksum_valid(table).then_some(table)
+}
+
+/// Validates the RSDP at physical address `rsdp_address` and the root table it points to.
+pub(crate) fn init(physical_memory_offset: VirtAddr, rsdp_address: u64) -> Result<(), AcpiError> {
+    let start = (physical_memory_offset + rsdp_address).as_ptr::<u8>();
+    let rsdp = unsafe { slice::from_raw_parts(start, RSDP_V1_LENGTH) };
+    if &rsdp[..8] != RSDP_SIGNATURE || !checksum_valid(rsdp) {
+        return Err(AcpiError::InvalidRsdp);
+    }
+    let revision = rsdp[15];
+    let (address, signature, entry_size) = if revision >= 2 {
+        let rsdp = unsafe { slice::from_raw_parts(start, RSDP_V2_LENGTH) };
+        if !checksum_valid(rsdp) {
+            return Err(AcpiError::InvalidRsdp);
+        }
+        (read_u64(rsdp, 24), XSDT_SIGNATURE, 8)
+    } else {
+        (u64::from(read_u32(rsdp, 16)), RSDT_SIGNATURE, 4)
+    };
+    let root = table_at(physical_memory_offset, address)
+        .filter(|table| &table[..4] == signature)
+        .ok_or(AcpiError::InvalidRootTable)?;
+    ROOT_TABLE.call_once(|| RootTable {
+        physical_memory_offset,
+        entries: &root[HEADER_LENGTH..],
+        entry_size,
+    });
+    Ok(())
+}
+
+/// Returns the first table with the given signature, such as `b"HPET"`, header included, or
+/// `None` if there is none with a valid checksum or the tables have not been found.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let root = ROOT_TABLE.get()?;
+    root.entries
+        .chunks_exact(root.entry_size)
+        .map(|entry| match root.entry_size {
+            8 => read_u64(entry, 0),
+            _ => u64::from(read_u32(entry, 0)),
+        })
+        .filter_map(|address| table_at(root.physical_memory_offset, address))
+        .find(|table| &table[..4] == signature)
+}
+
+/// The MADT, parsed on the first call, or `None` if there is none or the tables have not been
+/// found.
+pub fn madt() -> Option<&'static Madt> {
+    ROOT_TABLE.get()?;
+    MADT.call_once(|| find_table(MADT_SIGNATURE).and_then(Madt::parse))
+        .as_ref()
+}
+
+/// The FADT, parsed on the first call, or `None` if there is none or the tables have not been
+/// found.
+pub fn fadt() -> Option<&'static Fadt> {
+    ROOT_TABLE.get()?;
+    FADT.call_once(|| find_table(FADT_SIGNATURE).and_then(Fadt::parse))
+        .as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{put_u16, put_u32, put_u64};
+    use alloc::vec;
+
+    /// A MADT with the flags and local APIC address QEMU gives, followed by `entries`.
+    fn madt_table(entries: &[&[u8]]) -> Vec<u8> {
+        let mut table = vec![0; MADT_ENTRIES];
+        table[..4].copy_from_slice(MADT_SIGNATURE);
+        put_u32(&mut table, MADT_LOCAL_APIC_ADDRESS, 0xFEE0_0000);
+        put_u32(&mut table, MADT_FLAGS, MADT_PC_AT_COMPATIBLE);
+        for entry in entries {
+            table.extend_from_slice(entry);
+        }
+        let length = table.len() as u32;
+        put_u32(&mut table, 4, length);
+        table
+    }
+
+    #[test_case]
+    fn parses_madt_entries() {
+        let table = madt_table(&[
+            &[ENTRY_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0],
+            &[ENTRY_LOCAL_APIC, 8, 1, 2, 0, 0, 0, 0],
+            &[ENTRY_IO_APIC, 12, 3, 0, 0, 0, 0xC0, 0xFE, 0, 0, 0, 0],
+            &[ENTRY_IO_APIC, 12, 4, 0, 0, 0x10, 0xC0, 0xFE, 24, 0, 0, 0],
+            &[ENTRY_INTERRUPT_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0, 0],
+            &[ENTRY_INTERRUPT_OVERRIDE, 10, 0, 9, 9, 0, 0, 0, 0x0F, 0],
+            &[ENTRY_LOCAL_APIC_NMI, 6, 0xFF, 5, 0, 1],
+            // A processor-local x2APIC, which is skipped.
+            &[9, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            // An entry longer than what is left ends the list.
+            &[ENTRY_LOCAL_APIC, 40, 2, 3, 1, 0, 0, 0],
+        ]);
+        let madt = Madt::parse(&table).unwrap();
+        assert_eq!(madt.local_apic_address, 0xFEE0_0000);
+        assert!(madt.pc_at_compatible);
+        assert_eq!(
+            madt.processors,
+            [
+                Processor {
+                    processor_id: 0,
+                    apic_id: 0,
+                    enabled: true,
+                },
+                Processor {
+                    processor_id: 1,
+                    apic_id: 2,
+                    enabled: false,
+                },
+            ]
+        );
+        let (io_apic, input) = madt.io_apic_for(30).unwrap();
+        assert_eq!((io_apic.id, io_apic.address, input), (4, 0xFEC0_1000, 6));
+        assert_eq!(madt.io_apic_for(23).unwrap().0.id, 3);
+        let timer = madt.override_for(0).unwrap();
+        assert_eq!(timer.gsi, 2);
+        assert!(!timer.active_low() && !timer.level_triggered());
+        let sci = madt.override_for(9).unwrap();
+        assert!(sci.active_low() && sci.level_triggered());
+        assert!(madt.override_for(1).is_none());
+        assert_eq!(
+            madt.nmis,
+            [LocalApicNmi {
+                processor_id: 0xFF,
+                flags: 5,
+                lint: 1,
+            }]
+        );
+    }
+
+    #[test_case]
+    fn madt_entries_may_move_the_local_apic() {
+        let mut entry = [0; 12];
+        entry[..2].copy_from_slice(&[ENTRY_LOCAL_APIC_ADDRESS, 12]);
+        put_u64(&mut entry, 4, 0x1_FEE0_0000);
+        let madt = Madt::parse(&madt_table(&[&entry])).unwrap();
+        assert_eq!(madt.local_apic_address, 0x1_FEE0_0000);
+        assert!(Madt::parse(&madt_table(&[])[..MADT_ENTRIES - 1]).is_none());
+    }
+
+    /// An FADT `length` bytes long, with its fields filled in as far as it reaches.
+    fn fadt_table(length: usize, flags: u32) -> Vec<u8> {
+        let mut table = vec![0; FADT_X_DSDT + 8];
+        table[..4].copy_from_slice(FADT_SIGNATURE);
+        put_u32(&mut table, FADT_DSDT, 0x7FE0_0000);
+        put_u16(&mut table, FADT_SCI_INTERRUPT, 9);
+        put_u32(&mut table, FADT_SMI_COMMAND, 0xB2);
+        table[FADT_ACPI_ENABLE] = 0xF1;
+        table[FADT_ACPI_DISABLE] = 0xF0;
+        put_u32(&mut table, FADT_PM1A_EVENT_BLOCK, 0x600);
+        put_u32(&mut table, FADT_PM1A_CONTROL_BLOCK, 0x604);
+        put_u32(&mut table, FADT_PM_TIMER_BLOCK, 0x608);
+        table[FADT_CENTURY] = 0x32;
+        put_u32(&mut table, FADT_FLAGS, flags);
+        table[FADT_RESET_REGISTER] = SPACE_SYSTEM_IO;
+        table[FADT_RESET_REGISTER + 1] = 8;
+        put_u64(&mut table, FADT_RESET_REGISTER + 4, 0xCF9);
+        table[FADT_RESET_VALUE] = 0x06;
+        put_u64(&mut table, FADT_X_DSDT, 0x1_7FE0_0000);
+        table.truncate(length);
+        put_u32(&mut table, 4, length as u32);
+        table
+    }
+
+    #[test_case]
+    fn parses_the_fadt() {
+        let mut table = fadt_table(FADT_X_DSDT + 8, FADT_TIMER_32_BIT | FADT_RESET_SUPPORTED);
+        put_u16(&mut table, FADT_BOOT_ARCHITECTURE, 1);
+        let fadt = Fadt::parse(&table).unwrap();
+        assert_eq!(fadt.dsdt, 0x1_7FE0_0000);
+        assert_eq!(fadt.sci_interrupt, 9);
+        assert_eq!(
+            (fadt.smi_command, fadt.acpi_enable, fadt.acpi_disable),
+            (0xB2, 0xF1, 0xF0)
+        );
+        assert_eq!((fadt.pm1a_event_block, fadt.pm1b_event_block), (0x600, 0));
+        assert_eq!(
+            (fadt.pm1a_control_block, fadt.pm1b_control_block),
+            (0x604, 0)
+        );
+        assert_eq!(fadt.pm_timer_block, 0x608);
+        assert!(fadt.pm_timer_32_bit);
+        assert_eq!(fadt.century, 0x32);
+        assert!(!fadt.has_8042());
+        let reset = fadt.reset_register.unwrap();
+        assert_eq!(
+            (reset.space, reset.bit_width, reset.address),
+            (SPACE_SYSTEM_IO, 8, 0xCF9)
+        );
+        assert_eq!(fadt.reset_value, 0x06);
+    }
+
+    #[test_case]
+    fn reads_what_older_fadts_have() {
+        // ACPI 1.0 tables end before the reset register.
+        let table = fadt_table(FADT_RESET_REGISTER, FADT_RESET_SUPPORTED);
+        let fadt = Fadt::parse(&table).unwrap();
+        assert_eq!(fadt.dsdt, 0x7FE0_0000);
+        assert!(!fadt.pm_timer_32_bit);
+        assert!(fadt.has_8042());
+        assert_eq!((fadt.reset_register, fadt.reset_value), (None, 0));
+        // Without the flag, the reset register is not there even if the table has room for it.
+        let fadt = Fadt::parse(&fadt_table(FADT_X_DSDT, 0)).unwrap();
+        assert_eq!(fadt.reset_register, None);
+        assert_eq!(fadt.dsdt, 0x7FE0_0000);
+        assert!(Fadt::parse(&fadt_table(FADT_FLAGS + 3, 0)).is_none());
+    }
+}
@@ -0,0 +1,637 @@
+//! SATA disks behind an AHCI controller.
+//!
+//! [`init`] finds the first AHCI controller on the PCI bus, maps its registers and registers the
+//! disk on every port that has one as a [`BlockDevice`], `ahci0`, `ahci1` and so on. Each port
+//! gets one page of DMA memory holding its command list, the area the disk posts received FISes
+//! to and a single command table, so commands on a port run one at a time, and a bounce buffer
+//! of 64 KiB. Blocks move with READ DMA EXT and WRITE DMA EXT, addressed with 48-bit LBAs, up to
+//! a bounce buffer full in one command.
+//!
+//! Completion is signalled by an MSI or MSI-X vector where the controller has one, by its legacy
+//! interrupt line where the firmware routed one, and found by polling the port otherwise. With
+//! the interrupt, a transfer begun by [`BlockDevice::transfer`] is finished by the interrupt
+//! handler, which copies the data out and calls back the request queue.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::block::queue::BioOp;
+use crate::block::{self, BlockDevice, BlockError, Done, InFlight, BLOCK_SIZE};
+use crate::memory::dma::{self, DmaBuffer, DmaError};
+use crate::memory::vmm::VmmError;
+use crate::pci::PciDevice;
+use crate::sync::{Semaphore, WaitQueue};
+
+/// PCI class, subclass and programming interface of an AHCI controller.
+const CLASS_STORAGE: u8 = 0x01;
+const SUBCLASS_SATA: u8 = 0x06;
+const PROG_IF_AHCI: u8 = 0x01;
+
+/// The BAR holding the controller's registers.
+const ABAR: u8 = 5;
+
+/// Generic host control registers.
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0C;
+const GHC_INTERRUPT_ENABLE: u32 = 1 << 1;
+const GHC_AHCI_ENABLE: u32 = 1 << 31;
+
+/// Port registers, from the start of a port's block.
+const PORTS: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PORT_CLB: usize = 0x00;
+const PORT_CLBU: usize = 0x04;
+const PORT_FB: usize = 0x08;
+const PORT_FBU: usize = 0x0C;
+const PORT_IS: usize = 0x10;
+const PORT_IE: usize = 0x14;
+const PORT_CMD: usize = 0x18;
+const PORT_TFD: usize = 0x20;
+const PORT_SIG: usize = 0x24;
+const PORT_SSTS: usize = 0x28;
+const PORT_SERR: usize = 0x30;
+const PORT_CI: usize = 0x38;
+const MAX_PORTS: usize = 32;
+
+/// Size of the register block with every port.
+const REGISTERS_SIZE: usize = PORTS + MAX_PORTS * PORT_SIZE;
+
+/// Port command bits: start, FIS receive enable, and the running flags of both.
+const CMD_START: u32 = 1 << 0;
+const CMD_FIS_RECEIVE: u32 = 1 << 4;
+const CMD_FIS_RUNNING: u32 = 1 << 14;
+const CMD_LIST_RUNNING: u32 = 1 << 15;
+
+/// Port interrupt bits: a register FIS from the disk, which ends every command here, and a task
+/// file error.
+const IS_REGISTER_FIS: u32 = 1 << 0;
+const IS_TASK_FILE_ERROR: u32 = 1 << 30;
+
+/// Task file status bits.
+const TFD_ERROR: u32 = 1 << 0;
+const TFD_DATA_REQUEST: u32 = 1 << 3;
+const TFD_DEVICE_FAULT: u32 = 1 << 5;
+const TFD_BUSY: u32 = 1 << 7;
+
+/// Link status: a device is present and communicating, and the interface is active.
+const SSTS_DETECTION_MASK: u32 = 0xF;
+const SSTS_DEVICE_PRESENT: u32 = 3;
+const SSTS_POWER_MASK: u32 = 0xF00;
+const SSTS_ACTIVE: u32 = 0x100;
+
+/// Signature of a SATA disk, as opposed to ATAPI drives, port multipliers and bridges.
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+/// Layout of a port's DMA page.
+const COMMAND_LIST: usize = 0x000;
+const RECEIVED_FIS: usize = 0x400;
+const COMMAND_TABLE: usize = 0x500;
+
+/// Most blocks one command moves, a bounce buffer full.
+const MAX_TRANSFER_BLOCKS: usize = 128;
+
+/// Command header: the length of the command FIS in dwords, the write flag and where the
+/// number of PRDT entries goes.
+const HEADER_FIS_DWORDS: u32 = 5;
+const HEADER_WRITE: u32 = 1 << 6;
+const HEADER_PRDT_SHIFT: u32 = 16;
+
+/// Command table: the command FIS, then the PRDT.
+const TABLE_PRDT: usize = 0x80;
+/// PRDT entry bit asking for an interrupt once the region is transferred.
+const PRDT_INTERRUPT: u32 = 1 << 31;
+
+/// Register FIS from host to device, with the flag marking it a command.
+const FIS_REGISTER_H2D: u8 = 0x27;
+const FIS_COMMAND: u8 = 1 << 7;
+/// Device register bit for LBA addressing.
+const DEVICE_LBA: u8 = 1 << 6;
+
+const READ_DMA_EXT: u8 = 0x25;
+const WRITE_DMA_EXT: u8 = 0x35;
+const FLUSH_CACHE_EXT: u8 = 0xEA;
+const IDENTIFY: u8 = 0xEC;
+
+/// Words of IDENTIFY data holding the model name and the number of 48-bit addressable sectors.
+const IDENTIFY_MODEL: core::ops::Range<usize> = 27..47;
+const IDENTIFY_SECTORS_48: usize = 100;
+
+/// Register reads before a port that does not finish is given up on.
+const POLL_LIMIT: u32 = 10_000_000;
+
+/// Errors returned by [`init`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AhciError {
+    /// There is no AHCI controller on the PCI bus.
+    NoController,
+    /// The controller has no memory BAR for its registers.
+    NoRegisters,
+    /// The registers could not be mapped.
+    Mmio(VmmError),
+    /// No DMA memory for a port.
+    Dma(DmaError),
+}
+
+impl From<VmmError> for AhciError {
+    fn from(error: VmmError) -> Self {
+        AhciError::Mmio(error)
+    }
+}
+
+impl From<DmaError> for AhciError {
+    fn from(error: DmaError) -> Self {
+        AhciError::Dma(error)
+    }
+}
+
+/// The registers of the controller, once mapped.
+static HBA: Once<Registers> = Once::new();
+/// Set if the controller's interrupt is handled, and polling is not needed.
+static INTERRUPTS: AtomicBool = AtomicBool::new(false);
+/// Interrupt status of each port, collected by the interrupt handler until a command takes it.
+static PORT_STATUS: [AtomicU32; MAX_PORTS] = [const { AtomicU32::new(0) }; MAX_PORTS];
+static INTERRUPT: WaitQueue = WaitQueue::new();
+/// The disks, for the interrupt handler to finish their transfers. Locked with interrupts
+/// disabled.
+static DISKS: Mutex<Vec<Arc<AhciDisk>>> = Mutex::new(Vec::new());
+
+/// A block of memory-mapped registers.
+#[derive(Debug, Clone, Copy)]
+struct Registers {
+    base: VirtAddr,
+}
+
+impl Registers {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + register).as_ptr::<u32>()) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + register).as_mut_ptr::<u32>(), value) }
+    }
+
+    fn port(&self, index: usize) -> Registers {
+        Registers {
+            base: self.base + (PORTS + index * PORT_SIZE),
+        }
+    }
+
+    /// Polls until the bits in `mask` of `register` are clear.
+    fn poll_clear(&self, register: usize, mask: u32) -> Result<(), BlockError> {
+        for _ in 0..POLL_LIMIT {
+            if self.read(register) & mask == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+}
+
+/// What a command moves through the port's bounce buffer.
+enum Transfer<'a> {
+    None,
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+/// The memory of a port, shared with the interrupt handler.
+struct Memory {
+    /// The command list, received FISes and command table.
+    page: DmaBuffer,
+    bounce: DmaBuffer,
+    /// The transfer the interrupt handler finishes, if one is running.
+    in_flight: Option<InFlight>,
+}
+
+/// A SATA disk on a port of the AHCI controller.
+pub struct AhciDisk {
+    index: usize,
+    port: Registers,
+    sectors: u64,
+    model: String,
+    /// Taken for the whole of a command, and given back by the interrupt handler for a transfer
+    /// it finishes.
+    slot: Semaphore,
+    /// Locked with interrupts disabled.
+    memory: Mutex<Memory>,
+}
+
+impl AhciDisk {
+    /// The model name the disk reports.
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// The number of the controller port the disk is on.
+    pub fn port(&self) -> usize {
+        self.index
+    }
+
+    /// Checks the blocks `bytes` long from `index` on.
+    fn check(&self, index: u64, bytes: usize) -> Result<(), BlockError> {
+        let count = bytes.div_ceil(BLOCK_SIZE).max(1) as u64;
+        match index.checked_add(count) {
+            Some(end) if end <= self.sectors => Ok(()),
+            _ => Err(BlockError::OutOfRange(index)),
+        }
+    }
+
+    /// Issues `command` on the sectors from `lba` on, `count` of them moving through the bounce
+    /// buffer, in the direction `write` says. The caller holds the slot.
+    fn issue(&self, memory: &mut Memory, command: u8, lba: u64, count: usize, write: bool) {
+        let base = memory.page.phys.as_u64();
+        let page = memory.page.as_mut_slice();
+        let flags = if write { HEADER_WRITE } else { 0 };
+        let entries = u32::from(count != 0);
+        let table = base + COMMAND_TABLE as u64;
+        let header = [
+            HEADER_FIS_DWORDS | flags | (entries << HEADER_PRDT_SHIFT),
+            0,
+            table as u32,
+            (table >> 32) as u32,
+        ];
+        write_dwords(&mut page[COMMAND_LIST..], &header);
+        page[COMMAND_TABLE..COMMAND_TABLE + TABLE_PRDT + 16].fill(0);
+        let lba = lba.to_le_bytes();
+        let count_bytes = (count as u16).to_le_bytes();
+        let fis = [
+            FIS_REGISTER_H2D,
+            FIS_COMMAND,
+            command,
+            0,
+            lba[0],
+            lba[1],
+            lba[2],
+            DEVICE_LBA,
+            lba[3],
+            lba[4],
+            lba[5],
+            0,
+            count_bytes[0],
+            count_bytes[1],
+            0,
+            0,
+        ];
+        page[COMMAND_TABLE..COMMAND_TABLE + fis.len()].copy_from_slice(&fis);
+        if count != 0 {
+            let data = memory.bounce.phys.as_u64();
+            let entry = [
+                data as u32,
+                (data >> 32) as u32,
+                0,
+                (count * BLOCK_SIZE - 1) as u32 | PRDT_INTERRUPT,
+            ];
+            write_dwords(&mut page[COMMAND_TABLE + TABLE_PRDT..], &entry);
+        }
+        PORT_STATUS[self.index].store(0, Ordering::Relaxed);
+        self.port.write(PORT_IS, u32::MAX);
+        self.port.write(PORT_CI, 1);
+    }
+
+    /// Runs `command` on the sectors from `lba` on and waits for it, moving the blocks of
+    /// `transfer` through the bounce buffer.
+    fn run(&self, command: u8, lba: u64, transfer: Transfer) -> Result<(), BlockError> {
+        self.slot.acquire();
+        let result = self.execute(command, lba, transfer);
+        self.slot.release();
+        result
+    }
+
+    /// [`Self::run`] with the slot taken.
+    fn execute(&self, command: u8, lba: u64, transfer: Transfer) -> Result<(), BlockError> {
+        self.port
+            .poll_clear(PORT_TFD, TFD_BUSY | TFD_DATA_REQUEST)?;
+        interrupts::without_interrupts(|| {
+            let mut memory = self.memory.lock();
+            match &transfer {
+                Transfer::None => self.issue(&mut memory, command, lba, 0, false),
+                Transfer::Read(buffer) => {
+                    let count = buffer.len() / BLOCK_SIZE;
+                    self.issue(&mut memory, command, lba, count, false);
+                }
+                Transfer::Write(data) => {
+                    memory.bounce.as_mut_slice()[..data.len()].copy_from_slice(data);
+                    self.issue(&mut memory, command, lba, data.len() / BLOCK_SIZE, true);
+                }
+            }
+        });
+        if let Err(error) = self.wait() {
+            // The port stops processing commands after an error until it is restarted.
+            let _ = restart(self.port);
+            return Err(error);
+        }
+        self.outcome()?;
+        if let Transfer::Read(buffer) = transfer {
+            interrupts::without_interrupts(|| {
+                let memory = self.memory.lock();
+                buffer.copy_from_slice(&memory.bounce.as_slice()[..buffer.len()]);
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the command in slot 0 is done or has failed.
+    fn finished(&self) -> bool {
+        let status = self.port.read(PORT_IS) | PORT_STATUS[self.index].load(Ordering::Relaxed);
+        status & IS_TASK_FILE_ERROR != 0 || self.port.read(PORT_CI) & 1 == 0
+    }
+
+    /// Waits until the command in slot 0 is done or has failed.
+    fn wait(&self) -> Result<(), BlockError> {
+        if INTERRUPTS.load(Ordering::Relaxed) && interrupts::are_enabled() {
+            INTERRUPT.wait_until(|| self.finished());
+            return Ok(());
+        }
+        for _ in 0..POLL_LIMIT {
+            if self.finished() {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Whether the command that just finished succeeded, restarting the port if not.
+    fn outcome(&self) -> Result<(), BlockError> {
+        let status = self.port.read(PORT_IS) | PORT_STATUS[self.index].swap(0, Ordering::Relaxed);
+        if status & IS_TASK_FILE_ERROR != 0
+            || self.port.read(PORT_TFD) & (TFD_ERROR | TFD_DEVICE_FAULT) != 0
+        {
+            let _ = restart(self.port);
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    /// Finishes the transfer in flight if the disk is done with it. Called by the interrupt
+    /// handler.
+    fn complete(&self) {
+        let mut memory = self.memory.lock();
+        if memory.in_flight.is_none() || !self.finished() {
+            return;
+        }
+        let mut transfer = memory.in_flight.take().unwrap();
+        let result = self.outcome();
+        if result.is_ok() && transfer.op == BioOp::Read {
+            let length = transfer.buffer.len();
+            transfer
+                .buffer
+                .copy_from_slice(&memory.bounce.as_slice()[..length]);
+        }
+        drop(memory);
+        self.slot.release();
+        transfer.finish(result);
+    }
+}
+
+impl fmt::Debug for AhciDisk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AhciDisk")
+            .field("port", &self.index)
+            .field("sectors", &self.sectors)
+            .field("model", &self.model)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        self.read_blocks(index, buffer)
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        self.write_blocks(index, data)
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check(start, buffer.len())?;
+        let chunks = buffer.chunks_mut(MAX_TRANSFER_BLOCKS * BLOCK_SIZE);
+        for (index, chunk) in chunks.enumerate() {
+            let lba = start + (index * MAX_TRANSFER_BLOCKS) as u64;
+            self.run(READ_DMA_EXT, lba, Transfer::Read(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        self.check(start, data.len())?;
+        let chunks = data.chunks(MAX_TRANSFER_BLOCKS * BLOCK_SIZE);
+        for (index, chunk) in chunks.enumerate() {
+            let lba = start + (index * MAX_TRANSFER_BLOCKS) as u64;
+            self.run(WRITE_DMA_EXT, lba, Transfer::Write(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn transfer(&self, op: BioOp, start: u64, buffer: Vec<u8>, done: Done) {
+        let count = buffer.len() / BLOCK_SIZE;
+        // Without the interrupt nothing would finish the command.
+        if !INTERRUPTS.load(Ordering::Relaxed)
+            || !interrupts::are_enabled()
+            || count == 0
+            || count > MAX_TRANSFER_BLOCKS
+        {
+            return block::transfer_now(self, op, start, buffer, done);
+        }
+        if let Err(error) = self.check(start, buffer.len()) {
+            return done(Err(error));
+        }
+        self.slot.acquire();
+        if let Err(error) = self.port.poll_clear(PORT_TFD, TFD_BUSY | TFD_DATA_REQUEST) {
+            self.slot.release();
+            return done(Err(error));
+        }
+        interrupts::without_interrupts(|| {
+            let mut memory = self.memory.lock();
+            let command = match op {
+                BioOp::Read => READ_DMA_EXT,
+                BioOp::Write => {
+                    memory.bounce.as_mut_slice()[..buffer.len()].copy_from_slice(&buffer);
+                    WRITE_DMA_EXT
+                }
+            };
+            self.issue(&mut memory, command, start, count, op == BioOp::Write);
+            memory.in_flight = Some(InFlight { op, buffer, done });
+        });
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.run(FLUSH_CACHE_EXT, 0, Transfer::None)
+    }
+}
+
+fn write_dwords(bytes: &mut [u8], dwords: &[u32]) {
+    for (chunk, dword) in bytes.chunks_exact_mut(4).zip(dwords) {
+        chunk.copy_from_slice(&dword.to_le_bytes());
+    }
+}
+
+/// Stops the command list and FIS receive engines of a port.
+fn stop(port: Registers) -> Result<(), BlockError> {
+    port.write(PORT_CMD, port.read(PORT_CMD) & !CMD_START);
+    port.poll_clear(PORT_CMD, CMD_LIST_RUNNING)?;
+    port.write(PORT_CMD, port.read(PORT_CMD) & !CMD_FIS_RECEIVE);
+    port.poll_clear(PORT_CMD, CMD_FIS_RUNNING)
+}
+
+/// Clears the errors of a port and starts its engines.
+fn start(port: Registers) {
+    port.write(PORT_SERR, u32::MAX);
+    port.write(PORT_IS, u32::MAX);
+    port.write(PORT_CMD, port.read(PORT_CMD) | CMD_FIS_RECEIVE);
+    port.write(PORT_CMD, port.read(PORT_CMD) | CMD_START);
+}
+
+fn restart(port: Registers) -> Result<(), BlockError> {
+    stop(port)?;
+    start(port);
+    Ok(())
+}
+
+/// Points port `index` at its own DMA page, starts it and identifies the disk on it. Returns
+/// `None` if the disk does not answer.
+fn attach(hba: Registers, index: usize) -> Result<Option<AhciDisk>, AhciError> {
+    let port = hba.port(index);
+    if stop(port).is_err() {
+        return Ok(None);
+    }
+    let page = dma::alloc_coherent(4096)?;
+    let bounce = dma::alloc_coherent(MAX_TRANSFER_BLOCKS * BLOCK_SIZE)?;
+    let base = page.phys.as_u64();
+    let (list, fis) = (base + COMMAND_LIST as u64, base + RECEIVED_FIS as u64);
+    port.write(PORT_CLB, list as u32);
+    port.write(PORT_CLBU, (list >> 32) as u32);
+    port.write(PORT_FB, fis as u32);
+    port.write(PORT_FBU, (fis >> 32) as u32);
+    start(port);
+    port.write(PORT_IE, IS_REGISTER_FIS | IS_TASK_FILE_ERROR);
+    let mut disk = AhciDisk {
+        index,
+        port,
+        sectors: 0,
+        model: String::new(),
+        slot: Semaphore::new(1),
+        memory: Mutex::new(Memory {
+            page,
+            bounce,
+            in_flight: None,
+        }),
+    };
+    let mut identify = [0; BLOCK_SIZE];
+    if disk
+        .run(IDENTIFY, 0, Transfer::Read(&mut identify))
+        .is_err()
+    {
+        let _ = stop(port);
+        // The controller no longer touches the memory, which goes with the disk.
+        return Ok(None);
+    }
+    let words: Vec<u16> = identify
+        .chunks_exact(2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect();
+    disk.sectors = words[IDENTIFY_SECTORS_48..IDENTIFY_SECTORS_48 + 4]
+        .iter()
+        .rev()
+        .fold(0, |sectors, &word| (sectors << 16) | u64::from(word));
+    // Each word holds two characters, the first in the high byte.
+    let model: String = words[IDENTIFY_MODEL]
+        .iter()
+        .flat_map(|word| word.to_be_bytes())
+        .map(char::from)
+        .collect();
+    disk.model = String::from(model.trim());
+    Ok(Some(disk))
+}
+
+/// Finds the first AHCI controller, enables it and registers a block device for every disk on
+/// it. Returns how many there are.
+pub fn init() -> Result<usize, AhciError> {
+    let controller = crate::pci::devices()
+        .into_iter()
+        .find(|device| {
+            (device.class, device.subclass, device.prog_if)
+                == (CLASS_STORAGE, SUBCLASS_SATA, PROG_IF_AHCI)
+        })
+        .ok_or(AhciError::NoController)?;
+    let hba = map(&controller)?;
+    hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_AHCI_ENABLE);
+    let implemented = hba.read(HBA_PI);
+    let mut found = 0;
+    for index in (0..MAX_PORTS).filter(|&index| implemented & (1 << index) != 0) {
+        let port = hba.port(index);
+        let status = port.read(PORT_SSTS);
+        let linked = status & SSTS_DETECTION_MASK == SSTS_DEVICE_PRESENT
+            && status & SSTS_POWER_MASK == SSTS_ACTIVE;
+        if !linked || port.read(PORT_SIG) != SIGNATURE_ATA {
+            continue;
+        }
+        let Some(disk) = attach(hba, index)? else {
+            continue;
+        };
+        crate::serial_println!(
+            "ahci: port {} is {}, {} MiB",
+            index,
+            disk.model,
+            disk.sectors * BLOCK_SIZE as u64 / (1024 * 1024)
+        );
+        let disk = Arc::new(disk);
+        interrupts::without_interrupts(|| DISKS.lock().push(disk.clone()));
+        block::register(&format!("ahci{}", found), disk);
+        found += 1;
+    }
+    let interrupt = controller
+        .enable_interrupt("AHCI", interrupt_handler)
+        .is_ok()
+        || controller
+            .interrupt_line
+            .is_some_and(|line| crate::interruptsa::register_irq(line, interrupt_handler).is_ok());
+    if interrupt {
+        hba.write(HBA_IS, u32::MAX);
+        hba.write(HBA_GHC, hba.read(HBA_GHC) | GHC_INTERRUPT_ENABLE);
+        INTERRUPTS.store(true, Ordering::Relaxed);
+    }
+    Ok(found)
+}
+
+/// Maps the registers of `controller` and lets it access memory.
+fn map(controller: &PciDevice) -> Result<Registers, AhciError> {
+    let phys = controller.memory_bar(ABAR).ok_or(AhciError::NoRegisters)?;
+    controller.enable_bus_mastering();
+    let base = unsafe { crate::memory::map_mmio(phys, REGISTERS_SIZE)? };
+    Ok(*HBA.call_once(|| Registers { base }))
+}
+
+fn interrupt_handler() {
+    let Some(hba) = HBA.get() else {
+        return;
+    };
+    let pending = hba.read(HBA_IS);
+    for index in (0..MAX_PORTS).filter(|&index| pending & (1 << index) != 0) {
+        let port = hba.port(index);
+        let status = port.read(PORT_IS);
+        port.write(PORT_IS, status);
+        PORT_STATUS[index].fetch_or(status, Ordering::Relaxed);
+    }
+    hba.write(HBA_IS, pending);
+    for disk in DISKS.lock().iter() {
+        if pending & (1 << disk.index) != 0 {
+            disk.complete();
+        }
+    }
+    INTERRUPT.wake_all();
+}
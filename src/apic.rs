@@ -0,0 +1,476 @@
+//! Local APIC and IO APIC.
+//!
+//! The APICs replace the 8259 PICs: the IO APIC routes the legacy ISA IRQs to interrupt vectors
+//! and the local APIC of each CPU receives them and takes the end of interrupt. Both are
+//! programmed through memory-mapped registers, which are mapped uncached with
+//! [`map_mmio`](crate::memory::map_mmio).
+//!
+//! The local APIC is found through the `IA32_APIC_BASE` MSR, at the same address on every CPU.
+//! The ACPI MADT gives the IO APICs and the global system interrupts each one handles, the ones
+//! the ISA IRQs are wired to with their polarity and trigger mode, and the list of processors, which the other CPUs are started
+//! from with inter-processor interrupts. Without a MADT nothing says where the IO APIC is, so
+//! the PICs stay in use.
+
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use spin::{Mutex, Once};
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::vmm::VmmError;
+
+/// Model-specific register holding the local APIC base address and enable bit.
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// CPUID leaf 1 EDX bit reporting an on-chip APIC.
+const CPUID_APIC: u32 = 1 << 9;
+
+/// Local APIC registers, as byte offsets from the base.
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SPURIOUS: usize = 0xF0;
+/// Spurious interrupt vector register bit that enables the local APIC.
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+/// Interrupt command register, which sends inter-processor interrupts: the low half holds the
+/// command and sends it when written, the high half the destination.
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+/// Interrupt command bit set while the local APIC has not yet sent the last command.
+const ICR_SEND_PENDING: u32 = 1 << 12;
+/// Interrupt command delivery modes and the level bit, which INIT and startup IPIs need.
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_NMI: u32 = 0b100 << 8;
+const ICR_ASSERT: u32 = 1 << 14;
+const LAPIC_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+/// Local vector table bit that masks the interrupt.
+const LVT_MASKED: u32 = 1 << 16;
+/// Timer local vector table bit that reloads the count when it reaches zero.
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide configuration value that divides the bus clock by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// Length of the calibration run against the PIT.
+const CALIBRATION_MS: u32 = 10;
+
+/// Vector of spurious local APIC interrupts, which must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// IO APIC register select and data window, as byte offsets from the base.
+const IO_APIC_SELECT: usize = 0x00;
+const IO_APIC_WINDOW: usize = 0x10;
+/// IO APIC registers, selected through [`IO_APIC_SELECT`].
+const IO_APIC_VERSION: u32 = 0x01;
+const IO_APIC_REDIRECTION: u32 = 0x10;
+/// Redirection entry bit that masks the pin.
+const REDIRECTION_MASKED: u32 = 1 << 16;
+/// Redirection entry delivery mode that raises an NMI instead of the vector.
+const REDIRECTION_NMI: u32 = 0b100 << 8;
+/// Redirection entry bits for an active low and a level triggered input.
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+
+/// Number of legacy ISA IRQs routed through the IO APIC.
+const ISA_IRQS: u8 = 16;
+
+/// Errors that keep the APICs from being used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    /// The CPU has no local APIC.
+    Unsupported,
+    /// There is no MADT listing an IO APIC for each ISA IRQ.
+    NoIoApic,
+    /// The IO APIC has fewer pins than there are ISA IRQs.
+    TooFewPins(u8),
+    /// The registers could not be mapped.
+    Mmio(VmmError),
+}
+
+/// Size of the register blocks of each APIC.
+const REGISTERS_SIZE: usize = 4096;
+
+struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + register).as_ptr::<u32>()) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + register).as_mut_ptr::<u32>(), value) }
+    }
+
+    fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    /// Enables the local APIC of the calling CPU, with all interrupt priorities accepted.
+    fn enable(&self) {
+        let mut base_msr = Msr::new(IA32_APIC_BASE);
+        unsafe {
+            let base = base_msr.read();
+            base_msr.write(base | APIC_BASE_ENABLE);
+        }
+        self.write(LAPIC_TASK_PRIORITY, 0);
+        self.write(
+            LAPIC_SPURIOUS,
+            LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+    }
+
+    /// Sends an inter-processor interrupt and waits until the local APIC has sent it. Interrupts
+    /// stay disabled in between, so a handler sending one cannot change the destination.
+    fn send(&self, destination: u8, command: u32) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.write(LAPIC_ICR_HIGH, u32::from(destination) << 24);
+            self.write(LAPIC_ICR_LOW, command);
+            while self.read(LAPIC_ICR_LOW) & ICR_SEND_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        });
+    }
+
+    /// Programs the timer to interrupt periodically on the timer vector every `count` ticks.
+    fn start_timer(&self, count: u32) {
+        self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(
+            LAPIC_TIMER,
+            u32::from(TIMER_VECTOR.load(Ordering::Relaxed)) | TIMER_PERIODIC,
+        );
+        self.write(LAPIC_TIMER_INITIAL_COUNT, count);
+    }
+}
+
+struct IoApic {
+    base: VirtAddr,
+    /// IO APIC id the MADT lists it with.
+    id: u8,
+    pins: u8,
+}
+
+impl IoApic {
+    fn read(&mut self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IO_APIC_SELECT).as_mut_ptr::<u32>(), register);
+            ptr::read_volatile((self.base + IO_APIC_WINDOW).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IO_APIC_SELECT).as_mut_ptr::<u32>(), register);
+            ptr::write_volatile((self.base + IO_APIC_WINDOW).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    fn read_pins(&mut self) -> u8 {
+        (((self.read(IO_APIC_VERSION) >> 16) & 0xFF) + 1) as u8
+    }
+
+    /// Routes `pin` to `vector` on the local APIC `destination`, with the polarity and trigger
+    /// mode bits in `mode`, 0 for edge triggered and active high like the ISA IRQs.
+    fn route(&mut self, pin: u8, vector: u8, destination: u8, mode: u32, masked: bool) {
+        let register = IO_APIC_REDIRECTION + 2 * u32::from(pin);
+        let mask = if masked { REDIRECTION_MASKED } else { 0 };
+        self.write(register + 1, u32::from(destination) << 24);
+        self.write(register, u32::from(vector) | mode | mask);
+    }
+
+    /// Makes `pin` raise an NMI on the local APIC `destination`.
+    fn route_nmi(&mut self, pin: u8, destination: u8) {
+        let register = IO_APIC_REDIRECTION + 2 * u32::from(pin);
+        self.write(register + 1, u32::from(destination) << 24);
+        self.write(register, REDIRECTION_NMI);
+    }
+
+    fn set_masked(&mut self, pin: u8, masked: bool) {
+        let register = IO_APIC_REDIRECTION + 2 * u32::from(pin);
+        let entry = self.read(register);
+        let entry = if masked {
+            entry | REDIRECTION_MASKED
+        } else {
+            entry & !REDIRECTION_MASKED
+        };
+        self.write(register, entry);
+    }
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// Local APIC timer frequency in Hz after the divider, or 0 before calibration.
+static TIMER_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+/// Set once the local APIC timer has been started.
+static TIMER_RUNNING: AtomicBool = AtomicBool::new(false);
+/// Initial count the timer was last started with, for the timers of the other CPUs.
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
+/// Vector the local APIC timer raises, the one the legacy timer IRQ was routed to.
+static TIMER_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+/// IO APIC input of an ISA IRQ: the global system interrupt the MADT overrides it with, or
+/// else the one of the same number. See [`pin_for_gsi`].
+fn pin_for_irq(io_apics: &[IoApic], irq: u8) -> Result<(usize, u8), ApicError> {
+    let madt = crate::acpi::madt().ok_or(ApicError::NoIoApic)?;
+    let gsi = madt
+        .override_for(irq)
+        .map_or(u32::from(irq), |entry| entry.gsi);
+    pin_for_gsi(io_apics, gsi)
+}
+
+/// Finds the IO APIC that owns global system interrupt `gsi`. Returns its index in `io_apics`
+/// and the pin, the offset of `gsi` from the IO APIC's first global system interrupt.
+fn pin_for_gsi(io_apics: &[IoApic], gsi: u32) -> Result<(usize, u8), ApicError> {
+    let madt = crate::acpi::madt().ok_or(ApicError::NoIoApic)?;
+    let (owner, pin) = madt.io_apic_for(gsi).ok_or(ApicError::NoIoApic)?;
+    let index = io_apics
+        .iter()
+        .position(|io| io.id == owner.id)
+        .ok_or(ApicError::NoIoApic)?;
+    let pins = io_apics[index].pins;
+    match u8::try_from(pin) {
+        Ok(pin) if pin < pins => Ok((index, pin)),
+        _ => Err(ApicError::TooFewPins(pins)),
+    }
+}
+
+/// Redirection entry bits for the polarity and trigger mode the MADT gives an ISA IRQ.
+fn mode_for_irq(irq: u8) -> u32 {
+    let Some(entry) = crate::acpi::madt().and_then(|madt| madt.override_for(irq)) else {
+        return 0;
+    };
+    let mut mode = 0;
+    if entry.active_low() {
+        mode |= REDIRECTION_ACTIVE_LOW;
+    }
+    if entry.level_triggered() {
+        mode |= REDIRECTION_LEVEL;
+    }
+    mode
+}
+
+/// Enables the local APIC and routes the ISA IRQs through the IO APIC to vectors
+/// `vector_base..vector_base + 16`, all masked. The local APIC timer uses the vector of IRQ 0.
+/// Must run with interrupts disabled, after which the PICs should be masked and [`unmask_irq`]
+/// called for the IRQs in use.
+pub(crate) fn init(vector_base: u8) -> Result<(), ApicError> {
+    if __cpuid(1).edx & CPUID_APIC == 0 {
+        return Err(ApicError::Unsupported);
+    }
+    let base = unsafe { Msr::new(IA32_APIC_BASE).read() };
+    let map = |address| unsafe {
+        crate::memory::map_mmio(PhysAddr::new(address), REGISTERS_SIZE).map_err(ApicError::Mmio)
+    };
+    let local = LocalApic {
+        base: map(base & APIC_BASE_ADDRESS_MASK)?,
+    };
+    let madt = crate::acpi::madt().ok_or(ApicError::NoIoApic)?;
+    let mut io_apics = Vec::new();
+    for entry in &madt.io_apics {
+        let mut io = IoApic {
+            base: map(u64::from(entry.address))?,
+            id: entry.id,
+            pins: 0,
+        };
+        io.pins = io.read_pins();
+        io_apics.push(io);
+    }
+    // IRQ 2 is the PIC cascade and never raised; its pin carries the timer instead.
+    let irqs = (0..ISA_IRQS).filter(|&irq| irq != 2);
+    let pins = irqs
+        .map(|irq| Ok((irq, pin_for_irq(&io_apics, irq)?)))
+        .collect::<Result<Vec<_>, ApicError>>()?;
+
+    local.enable();
+    let destination = local.id();
+    for (irq, (index, pin)) in pins {
+        io_apics[index].route(pin, vector_base + irq, destination, mode_for_irq(irq), true);
+    }
+    TIMER_VECTOR.store(vector_base, Ordering::Relaxed);
+    LOCAL_APIC.call_once(|| local);
+    *IO_APICS.lock() = io_apics;
+    Ok(())
+}
+
+/// Measures the local APIC timer frequency against the PIT. Returns the frequency in Hz, or 0
+/// if the local APIC is not enabled.
+pub(crate) fn calibrate_timer() -> u32 {
+    let Some(local) = LOCAL_APIC.get() else {
+        return 0;
+    };
+    local.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    local.write(LAPIC_TIMER, LVT_MASKED);
+    local.write(LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+    crate::pit::wait_ms_polled(CALIBRATION_MS);
+    let elapsed = u32::MAX - local.read(LAPIC_TIMER_CURRENT_COUNT);
+    local.write(LAPIC_TIMER_INITIAL_COUNT, 0);
+    let frequency = elapsed.saturating_mul(1000 / CALIBRATION_MS);
+    TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
+    frequency
+}
+
+/// Starts or reprograms the local APIC timer to interrupt `hz` times per second on the timer
+/// vector. The timers of the other CPUs follow on their next tick, see [`follow_timer`]. Returns the actual interrupt period in nanoseconds, or `None` if the timer has not
+/// been calibrated.
+pub(crate) fn start_timer(hz: u32) -> Option<u64> {
+    let frequency = TIMER_FREQUENCY.load(Ordering::Relaxed);
+    let local = LOCAL_APIC.get()?;
+    if frequency == 0 || hz == 0 {
+        return None;
+    }
+    let count = (frequency / hz).max(1);
+    local.start_timer(count);
+    TIMER_COUNT.store(count, Ordering::Relaxed);
+    TIMER_RUNNING.store(true, Ordering::Relaxed);
+    Some(u64::from(count) * 1_000_000_000 / u64::from(frequency))
+}
+
+/// Restarts the calling CPU's timer with the count [`start_timer`] last used, if it runs with
+/// another one. Called on every tick.
+pub(crate) fn follow_timer() {
+    let Some(local) = LOCAL_APIC.get() else {
+        return;
+    };
+    let count = TIMER_COUNT.load(Ordering::Relaxed);
+    if TIMER_RUNNING.load(Ordering::Relaxed) && local.read(LAPIC_TIMER_INITIAL_COUNT) != count {
+        local.start_timer(count);
+    }
+}
+
+/// Returns whether the local APIC timer has been calibrated and can drive the timer interrupt.
+pub fn timer_calibrated() -> bool {
+    TIMER_FREQUENCY.load(Ordering::Relaxed) != 0
+}
+
+/// Returns whether the local APIC timer drives the timer interrupt.
+pub fn timer_running() -> bool {
+    TIMER_RUNNING.load(Ordering::Relaxed)
+}
+
+/// Returns whether interrupts are delivered through the APICs instead of the PICs.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.is_completed()
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+pub(crate) fn end_of_interrupt() {
+    if let Some(local) = LOCAL_APIC.get() {
+        local.write(LAPIC_EOI, 0);
+    }
+}
+
+/// Routes a global system interrupt above the ISA IRQs, such as one of the HPET's, to
+/// `vector`, unmasked. Returns `false` if the APICs are not in use or no IO APIC has it.
+pub(crate) fn route_pin(gsi: u8, vector: u8) -> bool {
+    let Some(local) = LOCAL_APIC.get() else {
+        return false;
+    };
+    if gsi < ISA_IRQS {
+        return false;
+    }
+    let mut io_apics = IO_APICS.lock();
+    let Ok((index, pin)) = pin_for_gsi(&io_apics, u32::from(gsi)) else {
+        return false;
+    };
+    io_apics[index].route(pin, vector, local.id(), 0, false);
+    true
+}
+
+/// Delivers an ISA IRQ as an NMI, which arrives even with interrupts disabled. Returns `false`
+/// if the APICs are not in use.
+pub(crate) fn route_irq_as_nmi(irq: u8) -> bool {
+    let Some(local) = LOCAL_APIC.get() else {
+        return false;
+    };
+    let mut io_apics = IO_APICS.lock();
+    let Ok((index, pin)) = pin_for_irq(&io_apics, irq) else {
+        return false;
+    };
+    io_apics[index].route_nmi(pin, local.id());
+    true
+}
+
+/// Masks the IO APIC pin of an ISA IRQ.
+pub(crate) fn mask_irq(irq: u8) {
+    let mut io_apics = IO_APICS.lock();
+    if let Ok((index, pin)) = pin_for_irq(&io_apics, irq) {
+        io_apics[index].set_masked(pin, true);
+    }
+}
+
+/// Unmasks the IO APIC pin of an ISA IRQ.
+pub(crate) fn unmask_irq(irq: u8) {
+    let mut io_apics = IO_APICS.lock();
+    if let Ok((index, pin)) = pin_for_irq(&io_apics, irq) {
+        io_apics[index].set_masked(pin, false);
+    }
+}
+
+/// Enables the local APIC of an application processor and starts its timer at the rate of the
+/// boot CPU's. Does nothing if the boot CPU does not use its local APIC.
+pub(crate) fn init_ap() {
+    let Some(local) = LOCAL_APIC.get() else {
+        return;
+    };
+    local.enable();
+    if TIMER_RUNNING.load(Ordering::Relaxed) {
+        local.start_timer(TIMER_COUNT.load(Ordering::Relaxed));
+    }
+}
+
+/// Local APIC id of the calling CPU, or `None` if the local APIC is not in use.
+pub fn local_id() -> Option<u8> {
+    LOCAL_APIC.get().map(LocalApic::id)
+}
+
+/// Local APIC ids of the processors the MADT lists as enabled, the calling one included, or
+/// `None` if there is no MADT.
+pub fn processors() -> Option<Vec<u8>> {
+    let madt = crate::acpi::madt()?;
+    Some(
+        madt.processors
+            .iter()
+            .filter(|processor| processor.enabled)
+            .map(|processor| processor.apic_id)
+            .collect(),
+    )
+}
+
+/// Sends an INIT IPI, which resets the processor to wait for a startup IPI.
+pub(crate) fn send_init(apic_id: u8) {
+    if let Some(local) = LOCAL_APIC.get() {
+        local.send(apic_id, ICR_INIT | ICR_ASSERT);
+    }
+}
+
+/// Sends a startup IPI, which starts a processor waiting after INIT in real mode at physical
+/// address `page * 4096`.
+pub(crate) fn send_startup(apic_id: u8, page: u8) {
+    if let Some(local) = LOCAL_APIC.get() {
+        local.send(apic_id, ICR_STARTUP | ICR_ASSERT | u32::from(page));
+    }
+}
+
+/// Sends an NMI, which arrives even with interrupts disabled, to the processor with local APIC id
+/// `apic_id`.
+pub(crate) fn send_nmi(apic_id: u8) {
+    if let Some(local) = LOCAL_APIC.get() {
+        local.send(apic_id, ICR_NMI);
+    }
+}
+
+/// Raises `vector` on the processor with local APIC id `apic_id`.
+pub(crate) fn send_ipi(apic_id: u8, vector: u8) {
+    if let Some(local) = LOCAL_APIC.get() {
+        local.send(apic_id, u32::from(vector));
+    }
+}
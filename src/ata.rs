@@ -0,0 +1,476 @@
+//! ATA disks on the primary IDE channel, driven with programmed I/O.
+//!
+//! [`init`] sends IDENTIFY to the master and the slave drive and registers every ATA disk that
+//! answers as a [`BlockDevice`], `ata0` for the master and `ata1` for the slave. Sectors are
+//! addressed with 28-bit LBAs, which reach the first 128 GiB of a disk, and up to 256 of them
+//! move through the data port per command. After issuing a command the thread sleeps until IRQ
+//! 14 reports that the drive has a sector ready or is done; with interrupts disabled the status
+//! register is polled instead. A transfer begun by [`BlockDevice::transfer`] is moved on by the
+//! interrupt handler instead, a sector per interrupt, and finished there.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+use crate::block::queue::BioOp;
+use crate::block::{self, BlockDevice, BlockError, Done, InFlight, BLOCK_SIZE};
+use crate::interruptsa::IrqError;
+use crate::sync::{Mutex, Semaphore, WaitQueue};
+
+/// Command block registers of the primary channel, from the data port on.
+const IO_BASE: u16 = 0x1F0;
+const SECTOR_COUNT: u16 = IO_BASE + 2;
+const LBA_LOW: u16 = IO_BASE + 3;
+const LBA_MID: u16 = IO_BASE + 4;
+const LBA_HIGH: u16 = IO_BASE + 5;
+const DRIVE: u16 = IO_BASE + 6;
+/// Status when read, command when written.
+const STATUS: u16 = IO_BASE + 7;
+/// Alternate status when read, device control when written.
+const CONTROL: u16 = 0x3F6;
+
+/// IRQ line of the primary channel.
+const ATA_IRQ: u8 = 14;
+
+/// Status register bits.
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DEVICE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+/// Device control bit that keeps the drives from raising interrupts.
+const CONTROL_NO_INTERRUPT: u8 = 1 << 1;
+
+/// Drive register bits: LBA addressing, the two bits that are always set, and the slave drive.
+const DRIVE_LBA: u8 = 0xE0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const READ_SECTORS: u8 = 0x20;
+const WRITE_SECTORS: u8 = 0x30;
+const FLUSH_CACHE: u8 = 0xE7;
+const IDENTIFY: u8 = 0xEC;
+
+/// Words of IDENTIFY data holding the model name and the number of 28-bit addressable sectors.
+const IDENTIFY_MODEL: core::ops::Range<usize> = 27..47;
+const IDENTIFY_SECTORS: usize = 60;
+
+/// Highest sector count 28-bit LBAs reach.
+const MAX_LBA28_SECTORS: u64 = 1 << 28;
+
+/// Most sectors one command moves, written to the sector count register as 0.
+const MAX_TRANSFER_SECTORS: usize = 256;
+
+/// Status reads before a drive that stays busy is given up on, some hundreds of milliseconds.
+const POLL_LIMIT: u32 = 1_000_000;
+
+/// Errors returned by [`init`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    /// Nothing answers on the channel's ports.
+    NoChannel,
+    /// IRQ 14 could not be registered.
+    Irq(IrqError),
+}
+
+impl From<IrqError> for AtaError {
+    fn from(error: IrqError) -> Self {
+        AtaError::Irq(error)
+    }
+}
+
+/// The ports of the channel. Both drives share them, so one command runs at a time.
+struct Channel {
+    data: Port<u16>,
+    sector_count: PortWriteOnly<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive: PortWriteOnly<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+    alternate_status: PortReadOnly<u8>,
+    control: PortWriteOnly<u8>,
+}
+
+static CHANNEL: Mutex<Channel> = Mutex::new(Channel {
+    data: Port::new(IO_BASE),
+    sector_count: PortWriteOnly::new(SECTOR_COUNT),
+    lba_low: Port::new(LBA_LOW),
+    lba_mid: Port::new(LBA_MID),
+    lba_high: Port::new(LBA_HIGH),
+    drive: PortWriteOnly::new(DRIVE),
+    status: PortReadOnly::new(STATUS),
+    command: PortWriteOnly::new(STATUS),
+    alternate_status: PortReadOnly::new(CONTROL),
+    control: PortWriteOnly::new(CONTROL),
+});
+
+/// The channel runs one command at a time. Taken for the whole of one, and given back by the
+/// interrupt handler for a transfer it finishes.
+static SLOT: Semaphore = Semaphore::new(1);
+
+/// Set by the interrupt handler, cleared before each command and each sector.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INTERRUPT: WaitQueue = WaitQueue::new();
+
+/// The transfer the interrupt handler moves on. Locked with interrupts disabled.
+static PIO: spin::Mutex<Option<Pio>> = spin::Mutex::new(None);
+
+/// A transfer moved a sector per interrupt.
+struct Pio {
+    transfer: InFlight,
+    /// Sectors moved through the data port so far.
+    moved: usize,
+}
+
+impl Pio {
+    /// Moves the next sector after the interrupt that reported `status`. Returns the result once
+    /// the transfer is over.
+    fn advance(&mut self, status: u8) -> Option<Result<(), BlockError>> {
+        if let Err(error) = check_status(status) {
+            return Some(Err(error));
+        }
+        let sectors = self.transfer.buffer.len() / BLOCK_SIZE;
+        // The last interrupt of a write says the drive has the last sector.
+        if self.moved == sectors {
+            return Some(Ok(()));
+        }
+        if status & STATUS_DATA_REQUEST == 0 {
+            return Some(Err(BlockError::Io));
+        }
+        let sector = &mut self.transfer.buffer[self.moved * BLOCK_SIZE..][..BLOCK_SIZE];
+        let mut data = Port::new(IO_BASE);
+        match self.transfer.op {
+            BioOp::Read => read_sector(&mut data, sector),
+            BioOp::Write => write_sector(&mut data, sector),
+        }
+        self.moved += 1;
+        (self.transfer.op == BioOp::Read && self.moved == sectors).then_some(Ok(()))
+    }
+}
+
+/// An ATA disk on the primary channel.
+#[derive(Debug)]
+pub struct AtaDisk {
+    slave: bool,
+    sectors: u64,
+    model: String,
+}
+
+impl AtaDisk {
+    /// The model name the drive reports.
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Whether the disk is the slave drive of the channel rather than the master.
+    pub fn is_slave(&self) -> bool {
+        self.slave
+    }
+
+    /// Checks the sectors `bytes` long from `index` on.
+    fn check(&self, index: u64, bytes: usize) -> Result<(), BlockError> {
+        let count = bytes.div_ceil(BLOCK_SIZE).max(1) as u64;
+        match index.checked_add(count) {
+            Some(end) if end <= self.sectors => Ok(()),
+            _ => Err(BlockError::OutOfRange(index)),
+        }
+    }
+}
+
+/// Runs `command` on the channel once no other command is running on it.
+fn command<T>(run: impl FnOnce(&mut Channel) -> T) -> T {
+    SLOT.acquire();
+    let result = run(&mut CHANNEL.lock());
+    SLOT.release();
+    result
+}
+
+impl BlockDevice for AtaDisk {
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        self.read_blocks(index, buffer)
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        self.write_blocks(index, data)
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check(start, buffer.len())?;
+        let chunks = buffer.chunks_mut(MAX_TRANSFER_SECTORS * BLOCK_SIZE);
+        for (index, chunk) in chunks.enumerate() {
+            let lba = start + (index * MAX_TRANSFER_SECTORS) as u64;
+            command(|channel| {
+                channel.start(self.slave, lba, chunk.len() / BLOCK_SIZE, READ_SECTORS);
+                for sector in chunk.chunks_exact_mut(BLOCK_SIZE) {
+                    channel.wait_for_data()?;
+                    read_sector(&mut channel.data, sector);
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        self.check(start, data.len())?;
+        let chunks = data.chunks(MAX_TRANSFER_SECTORS * BLOCK_SIZE);
+        for (index, chunk) in chunks.enumerate() {
+            let lba = start + (index * MAX_TRANSFER_SECTORS) as u64;
+            command(|channel| {
+                channel.start(self.slave, lba, chunk.len() / BLOCK_SIZE, WRITE_SECTORS);
+                // The drive asks for the first sector without an interrupt, and interrupts once
+                // it has each one.
+                channel.poll_data()?;
+                for (number, sector) in chunk.chunks_exact(BLOCK_SIZE).enumerate() {
+                    if number > 0 {
+                        channel.wait_for_data()?;
+                    }
+                    write_sector(&mut channel.data, sector);
+                }
+                channel.wait_until_done()
+            })?;
+        }
+        Ok(())
+    }
+
+    fn transfer(&self, op: BioOp, start: u64, buffer: Vec<u8>, done: Done) {
+        let sectors = buffer.len() / BLOCK_SIZE;
+        // With interrupts disabled the handler would not move the transfer on.
+        if !interrupts::are_enabled() || sectors == 0 || sectors > MAX_TRANSFER_SECTORS {
+            return block::transfer_now(self, op, start, buffer, done);
+        }
+        if let Err(error) = self.check(start, buffer.len()) {
+            return done(Err(error));
+        }
+        SLOT.acquire();
+        let mut channel = CHANNEL.lock();
+        let mut pio = Pio {
+            transfer: InFlight { op, buffer, done },
+            moved: 0,
+        };
+        // The transfer is published before the drive can interrupt for it, and the handler on
+        // any CPU waits for the lock until the command is issued.
+        if op == BioOp::Read {
+            interrupts::without_interrupts(|| {
+                let mut slot = PIO.lock();
+                *slot = Some(pio);
+                channel.start(self.slave, start, sectors, READ_SECTORS);
+            });
+            return;
+        }
+        channel.start(self.slave, start, sectors, WRITE_SECTORS);
+        if let Err(error) = channel.poll_data() {
+            drop(channel);
+            SLOT.release();
+            return pio.transfer.finish(Err(error));
+        }
+        interrupts::without_interrupts(|| {
+            let mut slot = PIO.lock();
+            let pio = slot.insert(pio);
+            pio.moved = 1;
+            write_sector(&mut channel.data, &pio.transfer.buffer[..BLOCK_SIZE]);
+        });
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        command(|channel| {
+            channel.start(self.slave, 0, 1, FLUSH_CACHE);
+            channel.wait_until_done()
+        })
+    }
+}
+
+fn read_sector(data: &mut Port<u16>, sector: &mut [u8]) {
+    for bytes in sector.chunks_exact_mut(2) {
+        let word = unsafe { data.read() };
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+}
+
+fn write_sector(data: &mut Port<u16>, sector: &[u8]) {
+    for bytes in sector.chunks_exact(2) {
+        unsafe { data.write(u16::from_le_bytes([bytes[0], bytes[1]])) };
+    }
+}
+
+impl Channel {
+    /// Selects a drive, with the top bits of `lba`, and gives it 400 ns to switch, the time
+    /// four status reads take.
+    fn select(&mut self, slave: bool, lba: u64) {
+        let drive = if slave { DRIVE_SLAVE } else { 0 };
+        unsafe {
+            self.drive
+                .write(DRIVE_LBA | drive | ((lba >> 24) as u8 & 0x0F));
+            for _ in 0..4 {
+                self.alternate_status.read();
+            }
+        }
+    }
+
+    /// Issues `command` for the `count` sectors from `lba` on of a drive.
+    fn start(&mut self, slave: bool, lba: u64, count: usize, command: u8) {
+        self.select(slave, lba);
+        INTERRUPTED.store(false, Ordering::Relaxed);
+        unsafe {
+            self.sector_count.write(count as u8);
+            self.lba_low.write(lba as u8);
+            self.lba_mid.write((lba >> 8) as u8);
+            self.lba_high.write((lba >> 16) as u8);
+            self.command.write(command);
+        }
+    }
+
+    /// Waits for the interrupt that ends the current step of a command, then for the drive to
+    /// stop being busy, and returns the status.
+    fn wait(&mut self) -> Result<u8, BlockError> {
+        if interrupts::are_enabled() {
+            INTERRUPT.wait_until(|| INTERRUPTED.swap(false, Ordering::Relaxed));
+        }
+        self.poll_not_busy()
+    }
+
+    /// Waits until the drive has the next sector ready to be read, or wants the next one
+    /// written.
+    fn wait_for_data(&mut self) -> Result<(), BlockError> {
+        let status = self.wait()?;
+        check_status(status)?;
+        if status & STATUS_DATA_REQUEST == 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    /// Waits until the drive has finished a command that transfers no more data.
+    fn wait_until_done(&mut self) -> Result<(), BlockError> {
+        check_status(self.wait()?)
+    }
+
+    /// Polls until the drive asks for data.
+    fn poll_data(&mut self) -> Result<(), BlockError> {
+        let status = self.poll_not_busy()?;
+        check_status(status)?;
+        if status & STATUS_DATA_REQUEST == 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    fn poll_not_busy(&mut self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = unsafe { self.alternate_status.read() };
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Sends IDENTIFY to a drive with interrupts off and returns the disk, if it is an ATA disk.
+    fn identify(&mut self, slave: bool) -> Option<AtaDisk> {
+        self.select(slave, 0);
+        unsafe {
+            self.sector_count.write(0);
+            self.lba_low.write(0);
+            self.lba_mid.write(0);
+            self.lba_high.write(0);
+            self.command.write(IDENTIFY);
+            if self.status.read() == 0 {
+                return None;
+            }
+        }
+        self.poll_not_busy().ok()?;
+        // ATAPI and SATA devices set these to their signature instead of answering.
+        if unsafe { self.lba_mid.read() != 0 || self.lba_high.read() != 0 } {
+            return None;
+        }
+        let status = self.poll_not_busy().ok()?;
+        if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 || status & STATUS_DATA_REQUEST == 0 {
+            return None;
+        }
+        let mut words = [0u16; 256];
+        for word in &mut words {
+            *word = unsafe { self.data.read() };
+        }
+        let sectors =
+            u64::from(words[IDENTIFY_SECTORS]) | (u64::from(words[IDENTIFY_SECTORS + 1]) << 16);
+        // Each word holds two characters, the first in the high byte.
+        let model = words[IDENTIFY_MODEL]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .map(char::from)
+            .collect::<String>();
+        Some(AtaDisk {
+            slave,
+            sectors: sectors.min(MAX_LBA28_SECTORS),
+            model: String::from(model.trim()),
+        })
+    }
+}
+
+/// Fails if `status` reports an error.
+fn check_status(status: u8) -> Result<(), BlockError> {
+    if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 {
+        return Err(BlockError::Io);
+    }
+    Ok(())
+}
+
+/// Finds the ATA disks on the primary channel, registers them as block devices and enables
+/// their interrupt. Returns how many there are.
+pub fn init() -> Result<usize, AtaError> {
+    let mut channel = CHANNEL.lock();
+    // Without a controller the bus floats and reads all ones.
+    if unsafe { channel.alternate_status.read() } == 0xFF {
+        return Err(AtaError::NoChannel);
+    }
+    unsafe { channel.control.write(CONTROL_NO_INTERRUPT) };
+    let disks = [channel.identify(false), channel.identify(true)];
+    if disks.iter().all(Option::is_none) {
+        return Ok(0);
+    }
+    crate::interruptsa::register_irq(ATA_IRQ, interrupt_handler)?;
+    unsafe { channel.control.write(0) };
+    let mut found = 0;
+    for (index, disk) in disks.into_iter().enumerate() {
+        let Some(disk) = disk else {
+            continue;
+        };
+        crate::serial_println!(
+            "ata: ata{} is {}, {} MiB",
+            index,
+            disk.model,
+            disk.sectors * BLOCK_SIZE as u64 / (1024 * 1024)
+        );
+        block::register(&format!("ata{}", index), Arc::new(disk));
+        found += 1;
+    }
+    Ok(found)
+}
+
+fn interrupt_handler() {
+    // Reading the status acknowledges the interrupt.
+    let status = unsafe { PortReadOnly::<u8>::new(STATUS).read() };
+    let mut pio = PIO.lock();
+    let Some(transfer) = pio.as_mut() else {
+        drop(pio);
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        INTERRUPT.wake_all();
+        return;
+    };
+    if let Some(result) = transfer.advance(status) {
+        let transfer = pio.take().unwrap().transfer;
+        drop(pio);
+        SLOT.release();
+        transfer.finish(result);
+    }
+}
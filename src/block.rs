@@ -0,0 +1,144 @@
+//! Block devices.
+//!
+//! A block device stores fixed-size blocks of [`BLOCK_SIZE`] bytes, addressed by index from 0 to
+//! [`BlockDevice::block_count`]. Disk drivers implement [`BlockDevice`] and [`register`] each disk
+//! under a name, like `ata0`, where partitions and file systems look them up. File systems read
+//! and write through a [`cache::BlockCache`] over the device's [`queue::RequestQueue`], which
+//! sorts and merges the requests of all its users. The queue hands each transfer to the driver
+//! with [`BlockDevice::transfer`], which drivers of disks that interrupt on completion finish
+//! from their interrupt handler.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use self::queue::BioOp;
+
+pub mod cache;
+pub mod queue;
+
+/// Size of a block in bytes, the sector size of practically every disk.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Errors returned by block devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The block is past the end of the device.
+    OutOfRange(u64),
+    /// The device reported an error.
+    Io,
+    /// The device did not respond in time.
+    Timeout,
+    /// The device cannot be written.
+    ReadOnly,
+}
+
+/// Called once a transfer begun with [`BlockDevice::transfer`] is over, with the buffer back.
+/// Drivers may call it from their interrupt handler.
+pub type Done = Box<dyn FnOnce(Result<Vec<u8>, BlockError>) + Send>;
+
+/// A device that stores blocks. Transfers may block the calling thread.
+pub trait BlockDevice: Send + Sync {
+    /// Number of blocks on the device.
+    fn block_count(&self) -> u64;
+
+    /// Reads block `index` into `buffer`.
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError>;
+
+    /// Writes `data` to block `index`. It may sit in the device's cache until [`Self::flush`].
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError>;
+
+    /// Reads the blocks from `start` on into `buffer`, whose length is a multiple of
+    /// [`BLOCK_SIZE`]. Drivers that can move several blocks in one command override this.
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        for (index, block) in buffer.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            self.read_block(start + index as u64, block.try_into().unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Writes `data`, whose length is a multiple of [`BLOCK_SIZE`], to the blocks from `start`
+    /// on.
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        for (index, block) in data.chunks_exact(BLOCK_SIZE).enumerate() {
+            self.write_block(start + index as u64, block.try_into().unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Begins reading into or writing `buffer`, whose length is a multiple of [`BLOCK_SIZE`], from
+    /// block `start` on, and calls `done` once it is over. Drivers of disks that interrupt when
+    /// a command ends override this to return as soon as the command is issued; this one
+    /// transfers with [`transfer_now`].
+    fn transfer(&self, op: BioOp, start: u64, buffer: Vec<u8>, done: Done) {
+        transfer_now(self, op, start, buffer, done);
+    }
+
+    /// Makes the blocks written so far permanent.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// The device this one is a range of and the block the range starts at, for a partition.
+    /// Its requests then go to the queue of that device.
+    fn parent(&self) -> Option<(Arc<dyn BlockDevice>, u64)> {
+        None
+    }
+}
+
+/// A transfer a driver issued from [`BlockDevice::transfer`], kept until the disk is done.
+pub struct InFlight {
+    pub op: BioOp,
+    pub buffer: Vec<u8>,
+    pub done: Done,
+}
+
+impl InFlight {
+    /// Hands the buffer back to whoever began the transfer.
+    pub fn finish(self, result: Result<(), BlockError>) {
+        (self.done)(result.map(|()| self.buffer));
+    }
+}
+
+/// Moves `buffer` with [`BlockDevice::read_blocks`] or [`BlockDevice::write_blocks`] and calls
+/// `done` before returning.
+pub fn transfer_now<D: BlockDevice + ?Sized>(
+    device: &D,
+    op: BioOp,
+    start: u64,
+    mut buffer: Vec<u8>,
+    done: Done,
+) {
+    let result = match op {
+        BioOp::Read => device.read_blocks(start, &mut buffer),
+        BioOp::Write => device.write_blocks(start, &buffer),
+    };
+    done(result.map(|()| buffer));
+}
+
+/// The registered devices and their names. Locked with interrupts disabled.
+static DEVICES: Mutex<Vec<(String, Arc<dyn BlockDevice>)>> = Mutex::new(Vec::new());
+
+/// Makes `device` known under `name`, which the driver picks to be unique.
+pub fn register(name: &str, device: Arc<dyn BlockDevice>) {
+    interrupts::without_interrupts(|| DEVICES.lock().push((String::from(name), device)));
+}
+
+/// The device registered as `name`.
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    interrupts::without_interrupts(|| {
+        let devices = DEVICES.lock();
+        devices
+            .iter()
+            .find(|(device_name, _)| device_name == name)
+            .map(|(_, device)| device.clone())
+    })
+}
+
+/// All registered devices with their names, in the order they were registered.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    interrupts::without_interrupts(|| DEVICES.lock().clone())
+}
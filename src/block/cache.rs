@@ -0,0 +1,235 @@
+//! A write-back cache in front of a block device.
+//!
+//! A [`BlockCache`] is itself a [`BlockDevice`]. It keeps the most recently used blocks of the
+//! device it wraps in memory, so a file system that reads the same metadata over and over, like
+//! the FAT, reads it from the disk once. Writes only change the cached copy and mark it dirty; a
+//! dirty block goes to the device when it is evicted, when the cache is flushed, or when the
+//! `bflush` thread started by [`init`] writes back every cache, every few seconds.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::time::Duration;
+use spin::Mutex as SpinMutex;
+use x86_64::instructions::interrupts;
+
+use super::{BlockDevice, BlockError, BLOCK_SIZE};
+use crate::sync::Mutex;
+use crate::thread::{self, ThreadError};
+
+/// Blocks a cache holds unless asked otherwise, 512 KiB of them.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// How often the `bflush` thread writes dirty blocks back.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Every cache made so far, for [`sync`]. Locked with interrupts disabled.
+static CACHES: SpinMutex<Vec<Weak<BlockCache>>> = SpinMutex::new(Vec::new());
+
+/// A cached block.
+struct Slot {
+    data: Box<[u8; BLOCK_SIZE]>,
+    dirty: bool,
+    /// When the block was last used, in the cache's own clock.
+    used: u64,
+}
+
+struct State {
+    slots: BTreeMap<u64, Slot>,
+    /// Block indices by when they were last used, least recently first.
+    order: BTreeMap<u64, u64>,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl State {
+    /// Marks `index`, which must be cached, as just used.
+    fn touch(&mut self, index: u64) {
+        self.clock += 1;
+        let slot = self.slots.get_mut(&index).unwrap();
+        self.order.remove(&slot.used);
+        slot.used = self.clock;
+        self.order.insert(self.clock, index);
+    }
+}
+
+/// How a cache has been doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Blocks held.
+    pub cached: usize,
+    /// Blocks held that the device does not have yet.
+    pub dirty: usize,
+    /// Reads and writes that found their block cached.
+    pub hits: u64,
+    /// Reads and writes that did not.
+    pub misses: u64,
+}
+
+/// A block device with the recently used blocks of another one in memory.
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+impl BlockCache {
+    /// A cache of up to `capacity` blocks of `device`, which [`sync`] and the `bflush` thread
+    /// write back for as long as it exists.
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Arc<BlockCache> {
+        let cache = Arc::new(BlockCache {
+            device,
+            capacity: capacity.max(1),
+            state: Mutex::new(State {
+                slots: BTreeMap::new(),
+                order: BTreeMap::new(),
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        });
+        interrupts::without_interrupts(|| {
+            let mut caches = CACHES.lock();
+            caches.retain(|cache| cache.strong_count() > 0);
+            caches.push(Arc::downgrade(&cache));
+        });
+        cache
+    }
+
+    /// The device behind the cache.
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock();
+        CacheStats {
+            cached: state.slots.len(),
+            dirty: state.slots.values().filter(|slot| slot.dirty).count(),
+            hits: state.hits,
+            misses: state.misses,
+        }
+    }
+
+    /// Writes every dirty block to the device, in block order, without flushing the device.
+    pub fn write_back(&self) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        for (&index, slot) in state.slots.iter_mut().filter(|(_, slot)| slot.dirty) {
+            self.device.write_block(index, &slot.data)?;
+            slot.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Makes room for one more block, writing back the least recently used one if it is dirty.
+    fn evict(&self, state: &mut State) -> Result<(), BlockError> {
+        while state.slots.len() >= self.capacity {
+            let (&used, &index) = state.order.first_key_value().unwrap();
+            let slot = &state.slots[&index];
+            if slot.dirty {
+                self.device.write_block(index, &slot.data)?;
+            }
+            state.order.remove(&used);
+            state.slots.remove(&index);
+        }
+        Ok(())
+    }
+
+    /// Caches block `index` with `data`, which is dirty if it is not what the device has.
+    fn insert(
+        &self,
+        state: &mut State,
+        index: u64,
+        data: Box<[u8; BLOCK_SIZE]>,
+        dirty: bool,
+    ) -> Result<(), BlockError> {
+        self.evict(state)?;
+        state.slots.insert(
+            index,
+            Slot {
+                data,
+                dirty,
+                used: 0,
+            },
+        );
+        state.touch(index);
+        Ok(())
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        let mut state = self.state.lock();
+        if let Some(slot) = state.slots.get(&index) {
+            buffer.copy_from_slice(&slot.data[..]);
+            state.hits += 1;
+            state.touch(index);
+            return Ok(());
+        }
+        state.misses += 1;
+        self.device.read_block(index, buffer)?;
+        self.insert(&mut state, index, Box::new(*buffer), false)
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        if index >= self.device.block_count() {
+            return Err(BlockError::OutOfRange(index));
+        }
+        let mut state = self.state.lock();
+        if let Some(slot) = state.slots.get_mut(&index) {
+            slot.data.copy_from_slice(data);
+            slot.dirty = true;
+            state.hits += 1;
+            state.touch(index);
+            return Ok(());
+        }
+        state.misses += 1;
+        self.insert(&mut state, index, Box::new(*data), true)
+    }
+
+    /// Writes back the dirty blocks, then flushes the device.
+    fn flush(&self) -> Result<(), BlockError> {
+        self.write_back()?;
+        self.device.flush()
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        if let Err(error) = self.write_back() {
+            crate::serial_println!("block cache: dirty blocks lost: {:?}", error);
+        }
+    }
+}
+
+/// Flushes every cache. Returns the first error, after trying all of them.
+pub fn sync() -> Result<(), BlockError> {
+    let caches: Vec<_> =
+        interrupts::without_interrupts(|| CACHES.lock().iter().filter_map(Weak::upgrade).collect());
+    let mut result = Ok(());
+    for cache in caches {
+        let flushed = cache.flush();
+        if result.is_ok() {
+            result = flushed;
+        }
+    }
+    result
+}
+
+/// Starts the `bflush` thread. Call after [`thread::init`]; until then caches are only written
+/// back when they are flushed or full.
+pub fn init() -> Result<(), ThreadError> {
+    thread::spawn("bflush", || loop {
+        thread::sleep(FLUSH_INTERVAL);
+        if let Err(error) = sync() {
+            crate::serial_println!("block cache: write-back failed: {:?}", error);
+        }
+    })?;
+    Ok(())
+}
@@ -0,0 +1,633 @@
+//! Queued block I/O.
+//!
+//! A [`RequestQueue`] stands in front of a device. [`RequestQueue::submit`] takes a
+//! [`BioRequest`] and returns at once with a [`Bio`], which a thread can [`Bio::wait`] on and a
+//! task can await; either is woken when the request completes. The queue's own thread hands the
+//! requests to the driver in elevator order: upwards from the last block it transferred, then
+//! back to the lowest, so the disk head sweeps instead of seeking back and forth. Requests of the
+//! same kind that continue one another are merged into one transfer. A request never overtakes
+//! an earlier one it overlaps unless both only read. The thread issues a transfer with
+//! [`BlockDevice::transfer`] and goes on once the driver calls back, from its interrupt handler
+//! where the disk has one, which completes the requests of the transfer right there.
+//!
+//! [`get`] returns the queue of a registered device, starting it on first use. Each disk has one
+//! queue: a partition gets a view of the queue of its disk, so requests for the partition and
+//! for the rest of the disk are sorted together. The queue is a [`BlockDevice`] too, whose calls
+//! submit a request and wait for it.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{BlockDevice, BlockError, BLOCK_SIZE};
+use crate::sync::WaitQueue;
+use crate::task::WakerSlot;
+use crate::thread::{self, ThreadError};
+
+/// Most blocks merged into one transfer, 64 KiB.
+const MAX_MERGED_BLOCKS: u64 = 128;
+
+/// Errors returned by [`get`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// No device is registered under the name.
+    NoDevice,
+    /// The queue's thread could not be started.
+    Thread(ThreadError),
+}
+
+impl From<ThreadError> for QueueError {
+    fn from(error: ThreadError) -> Self {
+        QueueError::Thread(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BioOp {
+    Read,
+    Write,
+}
+
+/// A transfer of whole blocks to or from a device.
+pub struct BioRequest {
+    op: BioOp,
+    start: u64,
+    buffer: Vec<u8>,
+}
+
+impl BioRequest {
+    /// Reads `count` blocks from block `start` on.
+    pub fn read(start: u64, count: usize) -> BioRequest {
+        BioRequest {
+            op: BioOp::Read,
+            start,
+            buffer: vec![0; count * BLOCK_SIZE],
+        }
+    }
+
+    /// Writes `data` to the blocks from `start` on. A partial last block is padded with zeros.
+    pub fn write(start: u64, mut data: Vec<u8>) -> BioRequest {
+        data.resize(data.len().next_multiple_of(BLOCK_SIZE), 0);
+        BioRequest {
+            op: BioOp::Write,
+            start,
+            buffer: data,
+        }
+    }
+
+    pub fn op(&self) -> BioOp {
+        self.op
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// The block after the last one transferred.
+    pub fn end(&self) -> u64 {
+        self.start + (self.buffer.len() / BLOCK_SIZE) as u64
+    }
+
+    fn overlaps(&self, other: &BioRequest) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+/// Where the result of a request is left for whoever waits for it.
+struct Completion {
+    /// The read data, or the written data back. Locked with interrupts disabled.
+    result: Mutex<Option<Result<Vec<u8>, BlockError>>>,
+    waiters: WaitQueue,
+    waker: WakerSlot,
+}
+
+impl Completion {
+    fn complete(&self, result: Result<Vec<u8>, BlockError>) {
+        interrupts::without_interrupts(|| *self.result.lock() = Some(result));
+        self.waiters.wake_all();
+        self.waker.wake();
+    }
+
+    fn take(&self) -> Option<Result<Vec<u8>, BlockError>> {
+        interrupts::without_interrupts(|| self.result.lock().take())
+    }
+}
+
+/// A submitted request. Resolves to the blocks read, or the data written.
+pub struct Bio {
+    completion: Arc<Completion>,
+}
+
+impl Bio {
+    pub fn is_done(&self) -> bool {
+        interrupts::without_interrupts(|| self.completion.result.lock().is_some())
+    }
+
+    /// Blocks the current thread until the request completes.
+    pub fn wait(self) -> Result<Vec<u8>, BlockError> {
+        let mut result = None;
+        self.completion.waiters.wait_until(|| {
+            result = self.completion.result.lock().take();
+            result.is_some()
+        });
+        result.unwrap()
+    }
+}
+
+impl Future for Bio {
+    type Output = Result<Vec<u8>, BlockError>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        // Registered before looking, so a completion in between still wakes the task.
+        self.completion.waker.register(context.waker());
+        match self.completion.take() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+struct Pending {
+    request: BioRequest,
+    completion: Arc<Completion>,
+}
+
+/// How a queue has been used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub submitted: u64,
+    /// Transfers handed to the driver.
+    pub dispatched: u64,
+    /// Requests that joined the transfer of another.
+    pub merged: u64,
+}
+
+struct State {
+    /// Requests waiting for the driver, by first block and then submission order.
+    pending: BTreeMap<(u64, u64), Pending>,
+    sequence: u64,
+    /// The block after the last transfer, where the elevator continues.
+    head: u64,
+    /// Whether the driver has a transfer of requests taken from `pending`.
+    busy: bool,
+    stats: QueueStats,
+}
+
+impl State {
+    /// An earlier request that overlaps the one at `key` and so must go first.
+    fn blocker(&self, key: (u64, u64)) -> Option<(u64, u64)> {
+        let request = &self.pending[&key].request;
+        self.pending
+            .iter()
+            .find(|(other, pending)| {
+                other.1 < key.1
+                    && pending.request.overlaps(request)
+                    && (pending.request.op == BioOp::Write || request.op == BioOp::Write)
+            })
+            .map(|(&other, _)| other)
+    }
+
+    /// The requests of the next transfer: the first one at or after the head, or the lowest if
+    /// there is none, followed by those that continue it.
+    fn next_batch(&mut self) -> Vec<Pending> {
+        let mut key = match self.pending.range((self.head, 0)..).next() {
+            Some((&key, _)) => key,
+            None => *self.pending.keys().next().unwrap(),
+        };
+        while let Some(earlier) = self.blocker(key) {
+            key = earlier;
+        }
+        let first = self.pending.remove(&key).unwrap();
+        let op = first.request.op;
+        let mut end = first.request.end();
+        let mut batch = vec![first];
+        loop {
+            let Some((&key, pending)) = self.pending.range((end, 0)..).next() else {
+                break;
+            };
+            let blocks = pending.request.end() - batch[0].request.start;
+            if key.0 != end || pending.request.op != op || blocks > MAX_MERGED_BLOCKS {
+                break;
+            }
+            if self.blocker(key).is_some() {
+                break;
+            }
+            end = pending.request.end();
+            batch.push(self.pending.remove(&key).unwrap());
+            self.stats.merged += 1;
+        }
+        self.stats.dispatched += 1;
+        self.head = end;
+        batch
+    }
+}
+
+/// The request queue of a device and the thread that works it off.
+pub struct RequestQueue {
+    device: Arc<dyn BlockDevice>,
+    state: Mutex<State>,
+    /// The queue's thread, while nothing is pending.
+    work: WaitQueue,
+    /// Threads waiting for the queue to run empty.
+    idle: WaitQueue,
+}
+
+impl RequestQueue {
+    /// A queue in front of `device`, with its thread started. The thread keeps the queue alive
+    /// for as long as the kernel runs.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<RequestQueue>, ThreadError> {
+        let queue = Arc::new(RequestQueue {
+            device,
+            state: Mutex::new(State {
+                pending: BTreeMap::new(),
+                sequence: 0,
+                head: 0,
+                busy: false,
+                stats: QueueStats::default(),
+            }),
+            work: WaitQueue::new(),
+            idle: WaitQueue::new(),
+        });
+        let worker = queue.clone();
+        thread::spawn("bio", move || worker.run())?;
+        Ok(queue)
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        interrupts::without_interrupts(|| self.state.lock().stats)
+    }
+
+    /// Queues `request`. Requests past the end of the device complete with
+    /// [`BlockError::OutOfRange`] right away.
+    pub fn submit(&self, request: BioRequest) -> Bio {
+        let completion = Arc::new(Completion {
+            result: Mutex::new(None),
+            waiters: WaitQueue::new(),
+            waker: WakerSlot::new(),
+        });
+        let bio = Bio {
+            completion: completion.clone(),
+        };
+        if request.end() > self.device.block_count() {
+            let last = request.end().max(1) - 1;
+            completion.complete(Err(BlockError::OutOfRange(last)));
+            return bio;
+        }
+        interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            let key = (request.start, state.sequence);
+            state.sequence += 1;
+            state.stats.submitted += 1;
+            state.pending.insert(
+                key,
+                Pending {
+                    request,
+                    completion,
+                },
+            );
+        });
+        self.work.wake_one();
+        bio
+    }
+
+    /// Blocks until every request submitted so far has completed.
+    pub fn drain(&self) {
+        self.idle.wait_until(|| {
+            let state = self.state.lock();
+            state.pending.is_empty() && !state.busy
+        });
+    }
+
+    fn run(self: Arc<Self>) {
+        loop {
+            let mut batch = Vec::new();
+            self.work.wait_until(|| {
+                let mut state = self.state.lock();
+                if !state.busy && !state.pending.is_empty() {
+                    batch = state.next_batch();
+                    state.busy = true;
+                }
+                !batch.is_empty()
+            });
+            self.dispatch(batch);
+        }
+    }
+
+    /// Hands the requests of `batch`, which continue one another, to the driver in one transfer.
+    fn dispatch(self: &Arc<Self>, mut batch: Vec<Pending>) {
+        let (start, op) = (batch[0].request.start, batch[0].request.op);
+        let buffer = match &mut batch[..] {
+            [single] => core::mem::take(&mut single.request.buffer),
+            batch => batch
+                .iter()
+                .flat_map(|pending| pending.request.buffer.iter().copied())
+                .collect(),
+        };
+        let queue = self.clone();
+        let done = move |result: Result<Vec<u8>, BlockError>| {
+            complete(batch, result);
+            queue.finished();
+        };
+        self.device.transfer(op, start, buffer, Box::new(done));
+    }
+
+    /// Lets the thread issue the next transfer. Called when the driver is done with one,
+    /// possibly from its interrupt handler.
+    fn finished(&self) {
+        let idle = interrupts::without_interrupts(|| {
+            let mut state = self.state.lock();
+            state.busy = false;
+            state.pending.is_empty()
+        });
+        if idle {
+            self.idle.wake_all();
+        }
+        self.work.wake_one();
+    }
+}
+
+/// Completes the requests of a transfer with their part of `result`.
+fn complete(batch: Vec<Pending>, result: Result<Vec<u8>, BlockError>) {
+    if let [single] = &batch[..] {
+        single.completion.complete(result);
+        return;
+    }
+    let mut offset = 0;
+    for pending in batch {
+        let length = pending.request.buffer.len();
+        let result = result
+            .as_ref()
+            .map(|buffer| buffer[offset..offset + length].to_vec())
+            .map_err(|&error| error);
+        pending.completion.complete(result);
+        offset += length;
+    }
+}
+
+impl BlockDevice for RequestQueue {
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        buffer.copy_from_slice(&self.submit(BioRequest::read(index, 1)).wait()?);
+        Ok(())
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        self.submit(BioRequest::write(index, data.to_vec()))
+            .wait()?;
+        Ok(())
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = buffer.len() / BLOCK_SIZE;
+        buffer.copy_from_slice(&self.submit(BioRequest::read(start, count)).wait()?);
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        self.submit(BioRequest::write(start, data.to_vec()))
+            .wait()?;
+        Ok(())
+    }
+
+    /// Waits for the queue to run empty, then flushes the device.
+    fn flush(&self) -> Result<(), BlockError> {
+        self.drain();
+        self.device.flush()
+    }
+}
+
+/// A range of the blocks of a disk, moved through the disk's queue.
+struct Window {
+    queue: Arc<RequestQueue>,
+    start: u64,
+    blocks: u64,
+}
+
+impl Window {
+    /// Translates the first of the blocks `bytes` long from `index` on, after checking the last.
+    fn translate(&self, index: u64, bytes: usize) -> Result<u64, BlockError> {
+        let count = bytes.div_ceil(BLOCK_SIZE).max(1) as u64;
+        match index.checked_add(count) {
+            Some(end) if end <= self.blocks => Ok(self.start + index),
+            _ => Err(BlockError::OutOfRange(index)),
+        }
+    }
+}
+
+impl BlockDevice for Window {
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        self.queue
+            .read_block(self.translate(index, BLOCK_SIZE)?, buffer)
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        self.queue
+            .write_block(self.translate(index, BLOCK_SIZE)?, data)
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let start = self.translate(start, buffer.len())?;
+        self.queue.read_blocks(start, buffer)
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        let start = self.translate(start, data.len())?;
+        self.queue.write_blocks(start, data)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.queue.flush()
+    }
+}
+
+/// The queues started so far, one for each disk. Locked with interrupts disabled.
+static QUEUES: Mutex<Vec<Arc<RequestQueue>>> = Mutex::new(Vec::new());
+
+/// The queue in front of `device`, started on the first call.
+fn queue_of(device: Arc<dyn BlockDevice>) -> Result<Arc<RequestQueue>, QueueError> {
+    // Compared by address alone, as the same device may come with different vtables.
+    let same = |queue: &&Arc<RequestQueue>| {
+        Arc::as_ptr(&queue.device).cast::<()>() == Arc::as_ptr(&device).cast::<()>()
+    };
+    let find = || QUEUES.lock().iter().find(same).cloned();
+    if let Some(queue) = interrupts::without_interrupts(find) {
+        return Ok(queue);
+    }
+    let queue = RequestQueue::new(device.clone())?;
+    // Another thread may have started one meanwhile; the first one stays.
+    Ok(interrupts::without_interrupts(|| {
+        let mut queues = QUEUES.lock();
+        match queues.iter().find(same) {
+            Some(existing) => existing.clone(),
+            None => {
+                queues.push(queue.clone());
+                queue
+            }
+        }
+    }))
+}
+
+/// The request queue of the device registered as `name`, started on the first call. For a
+/// partition it is a view of the queue of the disk it is on.
+pub fn get(name: &str) -> Result<Arc<dyn BlockDevice>, QueueError> {
+    let device = super::get(name).ok_or(QueueError::NoDevice)?;
+    let blocks = device.block_count();
+    let (mut disk, mut start) = (device, 0);
+    while let Some((parent, offset)) = disk.parent() {
+        (disk, start) = (parent, start + offset);
+    }
+    let queue = queue_of(disk)?;
+    if start == 0 && blocks == queue.block_count() {
+        return Ok(queue);
+    }
+    Ok(Arc::new(Window {
+        queue,
+        start,
+        blocks,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+
+    /// A queue state with `requests`, submitted in order, and the elevator at `head`.
+    fn state(head: u64, requests: &[(BioOp, u64, usize)]) -> State {
+        let mut state = State {
+            pending: BTreeMap::new(),
+            sequence: 0,
+            head,
+            busy: false,
+            stats: QueueStats::default(),
+        };
+        for &(op, start, count) in requests {
+            let request = match op {
+                BioOp::Read => BioRequest::read(start, count),
+                BioOp::Write => BioRequest::write(start, vec![0; count * BLOCK_SIZE]),
+            };
+            let completion = Arc::new(Completion {
+                result: Mutex::new(None),
+                waiters: WaitQueue::new(),
+                waker: WakerSlot::new(),
+            });
+            let pending = Pending {
+                request,
+                completion,
+            };
+            state.pending.insert((start, state.sequence), pending);
+            state.sequence += 1;
+        }
+        state
+    }
+
+    /// The blocks of each request of the next batch.
+    fn next(state: &mut State) -> Vec<(u64, u64)> {
+        state
+            .next_batch()
+            .iter()
+            .map(|pending| (pending.request.start(), pending.request.end()))
+            .collect()
+    }
+
+    #[test_case]
+    fn sweeps_upwards_from_the_head() {
+        let reads = [
+            (BioOp::Read, 50, 1),
+            (BioOp::Read, 10, 1),
+            (BioOp::Read, 30, 1),
+        ];
+        let mut state = state(20, &reads);
+        assert_eq!(next(&mut state), [(30, 31)]);
+        assert_eq!(next(&mut state), [(50, 51)]);
+        assert_eq!(state.head, 51);
+        assert_eq!(next(&mut state), [(10, 11)]);
+        assert!(state.pending.is_empty());
+        assert_eq!(state.stats.dispatched, 3);
+    }
+
+    #[test_case]
+    fn merges_requests_that_continue_one_another() {
+        let requests = [
+            (BioOp::Write, 12, 1),
+            (BioOp::Write, 10, 2),
+            (BioOp::Write, 13, 1),
+            (BioOp::Read, 14, 1),
+            (BioOp::Write, 16, 1),
+        ];
+        let mut state = state(0, &requests);
+        assert_eq!(next(&mut state), [(10, 12), (12, 13), (13, 14)]);
+        assert_eq!(next(&mut state), [(14, 15)]);
+        assert_eq!(next(&mut state), [(16, 17)]);
+        assert_eq!(state.stats.merged, 2);
+    }
+
+    #[test_case]
+    fn merges_no_more_than_the_limit() {
+        let requests = [
+            (BioOp::Read, 0, 100),
+            (BioOp::Read, 100, 28),
+            (BioOp::Read, 128, 1),
+        ];
+        let mut state = state(0, &requests);
+        assert_eq!(next(&mut state), [(0, 100), (100, 128)]);
+        assert_eq!(next(&mut state), [(128, 129)]);
+    }
+
+    #[test_case]
+    fn overlapping_requests_keep_their_order() {
+        let requests = [(BioOp::Write, 20, 1), (BioOp::Read, 10, 20)];
+        let mut state = state(0, &requests);
+        assert_eq!(next(&mut state), [(20, 21)]);
+        assert_eq!(next(&mut state), [(10, 30)]);
+        // Reads may pass one another.
+        let requests = [(BioOp::Read, 20, 1), (BioOp::Read, 10, 20)];
+        let mut state = state(0, &requests);
+        assert_eq!(next(&mut state), [(10, 30)]);
+        // Nor does a write join a transfer ahead of an earlier request it overlaps.
+        let requests = [
+            (BioOp::Write, 10, 1),
+            (BioOp::Read, 5, 10),
+            (BioOp::Write, 11, 1),
+        ];
+        let mut state = state(10, &requests);
+        assert_eq!(next(&mut state), [(10, 11)]);
+        assert_eq!(next(&mut state), [(5, 15)]);
+        assert_eq!(next(&mut state), [(11, 12)]);
+    }
+
+    #[test_case]
+    fn completes_requests_through_the_device() {
+        let queue = RequestQueue::new(Arc::new(RamDisk::new(64))).unwrap();
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|byte| byte as u8).collect();
+        let written = queue.submit(BioRequest::write(4, data.clone()));
+        let first = queue.submit(BioRequest::read(4, 1));
+        let second = queue.submit(BioRequest::read(5, 1));
+        assert_eq!(written.wait().as_deref(), Ok(&data[..]));
+        assert_eq!(first.wait().as_deref(), Ok(&data[..BLOCK_SIZE]));
+        assert_eq!(second.wait().as_deref(), Ok(&data[BLOCK_SIZE..]));
+        let past = queue.submit(BioRequest::read(63, 2));
+        assert!(past.is_done());
+        assert_eq!(past.wait(), Err(BlockError::OutOfRange(64)));
+        queue.drain();
+        assert_eq!(queue.stats().submitted, 3);
+    }
+}
@@ -0,0 +1,313 @@
+//! Console-level helpers built on top of the global framebuffer writer.
+
+use bootloader_api::info::FrameBuffer;
+use core::fmt::{self, Arguments, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::ring_buffer::RingBuffer;
+use crate::workqueue::Work;
+use crate::writer::FrameBufferWriter;
+use crate::FRAME_BUFFER_WRITER;
+
+pub use crate::writer::{Attributes, ConsoleError, WrapMode};
+
+/// Number of blink phases per second.
+const BLINK_PHASES_PER_SECOND: u32 = 2;
+
+/// Uptime second shown in the status bar.
+static STATUS_SECOND: AtomicU64 = AtomicU64::new(0);
+
+/// Set when the status bar shows outdated information and should be redrawn on the next tick.
+static STATUS_STALE: AtomicBool = AtomicBool::new(false);
+
+/// Set when the mouse moved while the writer was busy, so the pointer is redrawn on the next tick.
+static POINTER_STALE: AtomicBool = AtomicBool::new(false);
+
+/// Number of characters of output that can wait for a busy writer.
+const PENDING_OUTPUT_SIZE: usize = 1024;
+
+/// Output printed while the writer was busy, written out by the worker thread, or before the next
+/// output or on the next tick if that comes first.
+static PENDING_OUTPUT: Mutex<RingBuffer<char, PENDING_OUTPUT_SIZE>> = Mutex::new(RingBuffer::new());
+
+/// Set when [`PENDING_OUTPUT`] holds characters.
+static OUTPUT_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Number of characters lost because [`PENDING_OUTPUT`] was full or busy.
+static DROPPED_OUTPUT: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs a writer for `framebuffer` as the target of `print!` and draws the status bar.
+pub fn init(framebuffer: &'static mut FrameBuffer) {
+    let info = framebuffer.info();
+    let buffer = framebuffer.buffer_mut();
+    crate::panic_screen::register_framebuffer(buffer, info);
+    let writer = FrameBufferWriter::new(buffer, info);
+    *lock_writer() = Some(writer);
+    refresh_status();
+}
+
+/// Locks the writer, waiting for it if necessary. Interrupt handlers must use `try_lock`
+/// instead, since the code they interrupted may hold the writer.
+fn lock_writer() -> crate::sync::MutexGuard<'static, Option<FrameBufferWriter<'static>>> {
+    debug_assert!(
+        !crate::preempt::in_interrupt(),
+        "console writer locked from an interrupt handler"
+    );
+    FRAME_BUFFER_WRITER.lock()
+}
+
+/// Runs `f` with exclusive access to the console writer, for callers that need to issue several
+/// drawing operations without other output interleaving.
+pub fn with_writer<R>(
+    f: impl FnOnce(&mut FrameBufferWriter<'static>) -> R,
+) -> Result<R, ConsoleError> {
+    match &mut *lock_writer() {
+        Some(writer) => Ok(f(writer)),
+        None => Err(ConsoleError::Unavailable),
+    }
+}
+
+/// Like [`with_writer`], but fails with [`ConsoleError::Busy`] instead of waiting if the writer
+/// is in use, so it is safe to call from interrupt handlers.
+pub fn try_with_writer<R>(
+    f: impl FnOnce(&mut FrameBufferWriter<'static>) -> R,
+) -> Result<R, ConsoleError> {
+    match FRAME_BUFFER_WRITER.try_lock() {
+        Some(mut writer) => writer.as_mut().map(f).ok_or(ConsoleError::Unavailable),
+        None => Err(ConsoleError::Busy),
+    }
+}
+
+/// Moves the `print!` write position to the given text row and column.
+pub fn set_cursor(row: usize, column: usize) {
+    if let Some(writer) = &mut *lock_writer() {
+        writer.set_cursor(row, column);
+    }
+}
+
+/// Returns the current `print!` write position as `(row, column)`.
+pub fn get_cursor() -> Result<(usize, usize), ConsoleError> {
+    match &*lock_writer() {
+        Some(writer) => Ok(writer.get_cursor()),
+        None => Err(ConsoleError::Unavailable),
+    }
+}
+
+/// Moves the `print!` write position, failing instead of clamping if it is off screen.
+pub fn set_cursor_checked(row: usize, column: usize) -> Result<(), ConsoleError> {
+    match &mut *lock_writer() {
+        Some(writer) => writer.set_cursor_checked(row, column),
+        None => Err(ConsoleError::Unavailable),
+    }
+}
+
+/// Writes `s` at the given position without disturbing the `print!` write position.
+pub fn write_at(row: usize, column: usize, s: &str) -> Result<(), ConsoleError> {
+    match &mut *lock_writer() {
+        Some(writer) => writer.write_at(row, column, s),
+        None => Err(ConsoleError::Unavailable),
+    }
+}
+
+/// Sets the attributes used for subsequent `print!` output.
+pub fn set_attributes(attributes: Attributes) {
+    if let Some(writer) = &mut *lock_writer() {
+        writer.set_attributes(attributes);
+    }
+}
+
+/// Returns the attributes used for `print!` output.
+pub fn attributes() -> Attributes {
+    match &*lock_writer() {
+        Some(writer) => writer.attributes(),
+        None => Attributes::NONE,
+    }
+}
+
+/// Sets how `print!` output is handled at the right edge of the screen.
+pub fn set_wrap_mode(wrap_mode: WrapMode) {
+    if let Some(writer) = &mut *lock_writer() {
+        writer.set_wrap_mode(wrap_mode);
+    }
+}
+
+/// Advances console animations. Called from the timer interrupt, so it never waits for the
+/// writer: if the writer is busy the blink phase is simply skipped.
+pub fn timer_tick() {
+    let interval = (crate::time::tick_rate() / BLINK_PHASES_PER_SECOND).max(1);
+    let blink = crate::time::ticks().is_multiple_of(u64::from(interval));
+    let second = crate::time::uptime_ms() / 1000;
+    if STATUS_SECOND.swap(second, Ordering::Relaxed) != second {
+        request_status_refresh();
+    }
+    let stale = STATUS_STALE.load(Ordering::Relaxed)
+        || POINTER_STALE.load(Ordering::Relaxed)
+        || OUTPUT_PENDING.load(Ordering::Relaxed)
+        || crate::tui::menu_stale();
+    if !blink && !stale {
+        return;
+    }
+    if let Some(mut writer) = FRAME_BUFFER_WRITER.try_lock() {
+        if let Some(writer) = writer.as_mut() {
+            flush_pending_output(writer);
+            if blink {
+                writer.blink();
+            }
+            if STATUS_STALE.swap(false, Ordering::Relaxed) {
+                write_status(writer);
+            }
+            if POINTER_STALE.swap(false, Ordering::Relaxed) {
+                follow_mouse(writer);
+            }
+            crate::tui::redraw_stale_menu(writer);
+        }
+    }
+}
+
+/// Writes formatted output, or holds it back for the worker thread if the writer is busy. Never
+/// waits for the writer, so it is safe to call from interrupt handlers; `print!` goes through
+/// here.
+pub fn write_fmt(args: Arguments) {
+    match FRAME_BUFFER_WRITER.try_lock() {
+        Some(mut writer) => {
+            if let Some(writer) = writer.as_mut() {
+                flush_pending_output(writer);
+                writer.write_fmt(args).unwrap();
+            }
+        }
+        None => {
+            let _ = PendingOutput.write_fmt(args);
+            let _ = FLUSH_PENDING_OUTPUT.schedule();
+        }
+    }
+}
+
+/// Writes out held back output from the worker thread, which can wait for the writer.
+static FLUSH_PENDING_OUTPUT: Work = Work::new(|| {
+    if let Some(writer) = &mut *lock_writer() {
+        flush_pending_output(writer);
+    }
+});
+
+/// Number of characters of output lost because the writer was busy for too long.
+pub fn dropped_output() -> usize {
+    DROPPED_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Appends to [`PENDING_OUTPUT`]. Never waits for it either: an NMI may interrupt the code that
+/// holds it.
+struct PendingOutput;
+
+impl Write for PendingOutput {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let Some(mut pending) = PENDING_OUTPUT.try_lock() else {
+            DROPPED_OUTPUT.fetch_add(s.chars().count(), Ordering::Relaxed);
+            return Ok(());
+        };
+        for c in s.chars() {
+            if pending.push(c).is_err() {
+                DROPPED_OUTPUT.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        OUTPUT_PENDING.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Writes out the output held back while the writer was busy, keeping it ahead of newer output.
+fn flush_pending_output(writer: &mut FrameBufferWriter) {
+    if !OUTPUT_PENDING.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    match PENDING_OUTPUT.try_lock() {
+        Some(mut pending) => {
+            while let Some(c) = pending.pop() {
+                writer.write_char(c).unwrap();
+            }
+        }
+        None => OUTPUT_PENDING.store(true, Ordering::Relaxed),
+    }
+}
+
+/// Asks for the status bar to be redrawn from the next timer tick. Unlike [`refresh_status`],
+/// this is safe to call from interrupt handlers.
+pub fn request_status_refresh() {
+    STATUS_STALE.store(true, Ordering::Relaxed);
+}
+
+/// Shows the mouse pointer at the given pixel position, or moves it there if already shown.
+pub fn show_pointer(x: usize, y: usize) {
+    if let Some(writer) = &mut *lock_writer() {
+        writer.show_pointer(x, y);
+    }
+}
+
+/// Moves a shown pointer to the mouse position. Called from the mouse interrupt handler, so if
+/// the writer is busy the move is deferred to the next timer tick.
+pub fn pointer_moved() {
+    match FRAME_BUFFER_WRITER.try_lock() {
+        Some(mut writer) => {
+            if let Some(writer) = writer.as_mut() {
+                follow_mouse(writer);
+            }
+        }
+        None => POINTER_STALE.store(true, Ordering::Relaxed),
+    }
+}
+
+fn follow_mouse(writer: &mut FrameBufferWriter) {
+    if writer.pointer_position().is_some() {
+        let (x, y) = crate::mouse::position();
+        writer.move_pointer(x, y);
+    }
+}
+
+/// Removes the mouse pointer, restoring the text underneath it.
+pub fn hide_pointer() {
+    if let Some(writer) = &mut *lock_writer() {
+        writer.hide_pointer();
+    }
+}
+
+/// Sends the current screen contents over the serial port as a PPM image.
+pub fn dump_screenshot() -> Result<(), ConsoleError> {
+    with_writer(|writer| {
+        crate::serial::with_port(|port| writer.write_ppm(|byte| port.send_raw(byte)));
+    })
+}
+
+/// Replaces the text shown in the status bar at the top of the screen.
+///
+/// Normal `print!` output scrolls beneath the status bar and never overwrites it.
+pub fn set_status(args: Arguments) {
+    if let Some(writer) = &mut *lock_writer() {
+        writer.set_status(args);
+    }
+}
+
+/// Redraws the status bar with the current kernel state.
+pub fn refresh_status() {
+    if let Some(writer) = &mut *lock_writer() {
+        write_status(writer);
+    }
+}
+
+fn write_status(writer: &mut FrameBufferWriter) {
+    let seconds = crate::time::uptime_ms() / 1000;
+    let heap = crate::memory::heap_stats();
+    writer.set_status(format_args!(
+        " rustkernel | kbd: {} | up {}:{:02}:{:02} | heap {}/{} KiB",
+        crate::keyboard::layout().name(),
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        heap.used / 1024,
+        heap.size / 1024
+    ));
+}
+
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => ($crate::console::set_status(format_args!($($arg)*)));
+}
@@ -0,0 +1,216 @@
+//! Loading ELF64 executables.
+//!
+//! [`Elf::parse`] checks that an image is a static x86-64 executable and that its program
+//! headers lie within it. [`Elf::load`] then maps each `PT_LOAD` segment into the user part of an
+//! address space with the permissions the segment asks for, copies in its file contents and
+//! zeroes the rest. [`crate::process::spawn_elf`] runs the result.
+//!
+//! Segments are copied, not shared with the image, so the image may be anywhere: embedded in the
+//! kernel like [`crate::usermode::echo`] or read from a file. Segments must not share pages.
+
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::memory::{AddressSpace, PagingError};
+use crate::usermode::{PROGRAM_END, USER_START};
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const TYPE_SHARED: u16 = 3;
+const MACHINE_X86_64: u16 = 0x3e;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// Errors returned by [`Elf::parse`] and [`Elf::load`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The image does not start with the ELF magic number.
+    NotElf,
+    /// The image is not a little-endian 64-bit x86-64 executable.
+    Unsupported,
+    /// A header or a segment's contents reach past the end of the image.
+    Truncated,
+    /// A loadable segment is larger in the file than in memory, or lies outside the part of the
+    /// address space programs are loaded to.
+    BadSegment,
+    /// The entry point is outside the part of the address space programs are loaded to.
+    BadEntry,
+    /// A segment could not be mapped.
+    Paging(PagingError),
+}
+
+impl From<PagingError> for ElfError {
+    fn from(error: PagingError) -> Self {
+        ElfError::Paging(error)
+    }
+}
+
+/// A loadable segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub offset: u64,
+    pub address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+/// A checked ELF64 executable.
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    image: &'a [u8],
+    entry: u64,
+    program_headers: usize,
+    program_header_count: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the ELF header of `image` and the bounds of its program header table and segments.
+    pub fn parse(image: &'a [u8]) -> Result<Self, ElfError> {
+        let elf = Self::parse_header(image, &[TYPE_EXECUTABLE])?;
+        for segment in elf.segments() {
+            let memory_end = segment.address.checked_add(segment.memory_size);
+            if segment.address < USER_START || memory_end.is_none_or(|end| end > PROGRAM_END) {
+                return Err(ElfError::BadSegment);
+            }
+        }
+        if !(USER_START..PROGRAM_END).contains(&elf.entry) {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(elf)
+    }
+
+    /// Like [`Elf::parse`], but for the kernel's own image, which may be position-independent and
+    /// lies outside the user part of the address space. Its segment addresses are relative to
+    /// where the bootloader loaded it.
+    pub fn parse_kernel(image: &'a [u8]) -> Result<Self, ElfError> {
+        Self::parse_header(image, &[TYPE_EXECUTABLE, TYPE_SHARED])
+    }
+
+    /// Checks the ELF header, which must give one of `types`, and the bounds of the program
+    /// header table and of the segments' contents.
+    fn parse_header(image: &'a [u8], types: &[u16]) -> Result<Self, ElfError> {
+        if image.get(..4) != Some(&MAGIC[..]) {
+            return Err(ElfError::NotElf);
+        }
+        if image.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if image[4] != CLASS_64
+            || image[5] != LITTLE_ENDIAN
+            || !types.contains(&read_u16(image, 16))
+            || read_u16(image, 18) != MACHINE_X86_64
+            || usize::from(read_u16(image, 54)) != PROGRAM_HEADER_SIZE
+        {
+            return Err(ElfError::Unsupported);
+        }
+        let elf = Elf {
+            image,
+            entry: read_u64(image, 24),
+            program_headers: usize::try_from(read_u64(image, 32))
+                .map_err(|_| ElfError::Truncated)?,
+            program_header_count: usize::from(read_u16(image, 56)),
+        };
+        let table_end = elf
+            .program_header_count
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(elf.program_headers))
+            .ok_or(ElfError::Truncated)?;
+        if table_end > image.len() {
+            return Err(ElfError::Truncated);
+        }
+        for segment in elf.segments() {
+            let file_end = segment
+                .offset
+                .checked_add(segment.file_size)
+                .ok_or(ElfError::Truncated)?;
+            if file_end > image.len() as u64 {
+                return Err(ElfError::Truncated);
+            }
+            if segment.file_size > segment.memory_size {
+                return Err(ElfError::BadSegment);
+            }
+        }
+        Ok(elf)
+    }
+
+    /// Address of the first instruction.
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// The end of the highest segment in memory, past which the program's heap can go.
+    pub fn end(&self) -> u64 {
+        self.segments()
+            .map(|segment| segment.address + segment.memory_size)
+            .max()
+            .unwrap_or(USER_START)
+    }
+
+    /// The `PT_LOAD` segments, in the order of the program header table.
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + 'a {
+        let image = self.image;
+        let table = self.program_headers;
+        (0..self.program_header_count)
+            .map(move |index| table + index * PROGRAM_HEADER_SIZE)
+            .filter(move |&header| read_u32(image, header) == PT_LOAD)
+            .map(move |header| {
+                let flags = read_u32(image, header + 4);
+                Segment {
+                    offset: read_u64(image, header + 8),
+                    address: read_u64(image, header + 16),
+                    file_size: read_u64(image, header + 32),
+                    memory_size: read_u64(image, header + 40),
+                    writable: flags & PF_W != 0,
+                    executable: flags & PF_X != 0,
+                }
+            })
+    }
+
+    /// Maps the segments into `space` and returns the entry point.
+    pub fn load(&self, space: &mut AddressSpace) -> Result<u64, ElfError> {
+        for segment in self.segments().filter(|segment| segment.memory_size > 0) {
+            let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+            if segment.writable {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if !segment.executable {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+            let first = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.address));
+            let last =
+                Page::containing_address(VirtAddr::new(segment.address + segment.memory_size - 1));
+            for page in Page::range_inclusive(first, last) {
+                space.map_zeroed(page, flags)?;
+            }
+            let offset = segment.offset as usize;
+            let contents = &self.image[offset..offset + segment.file_size as usize];
+            space.write(VirtAddr::new(segment.address), contents)?;
+        }
+        Ok(self.entry)
+    }
+}
+
+fn read_u16(image: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([image[offset], image[offset + 1]])
+}
+
+fn read_u32(image: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&image[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(image: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&image[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,145 @@
+//! Global descriptor table and task state segment.
+//!
+//! The GDT set up by the bootloader has no TSS, so exception handlers cannot be given stacks of
+//! their own. With a kernel stack overflow the CPU then fails to push the double fault frame onto
+//! the overflowed stack and triple faults. The TSS here provides separate double fault and page
+//! fault stacks through the interrupt stack table, so running into a stack's guard page reaches
+//! the page fault handler, which reports the overflow.
+//!
+//! A TSS is marked busy when loaded and its stacks can only serve one CPU, so every application
+//! processor gets a GDT and TSS of its own from [`init_ap`].
+//!
+//! Every GDT also holds the user code and data segments, at the same indices, and the TSS names
+//! the kernel stack the CPU switches to on an interrupt in user mode, see [`set_kernel_stack`].
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+use crate::memory::vmm::{self, VmmError};
+use crate::per_cpu;
+
+/// Interrupt stack table index of the double fault stack.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+/// Interrupt stack table index of the page fault stack.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+/// Size of the double fault stack.
+const DOUBLE_FAULT_STACK_SIZE: usize = 5 * 4096;
+
+/// Size of the page fault stack. The page fault handler panics, so it must fit the panic screen.
+const PAGE_FAULT_STACK_SIZE: usize = 5 * 4096;
+
+/// The TSS of the boot CPU. Mutable, since the kernel stack for interrupts in user mode changes
+/// with the running thread; only its own CPU writes it.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+per_cpu! {
+    /// The TSS loaded on each CPU.
+    static CPU_TSS: AtomicPtr<TaskStateSegment> = AtomicPtr::new(core::ptr::null_mut());
+}
+
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    user_data: SegmentSelector,
+    user_code: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+impl Selectors {
+    /// Adds the segments to `gdt`. The user data segment comes right before the user code
+    /// segment, the order `sysret` expects.
+    fn add(gdt: &mut GlobalDescriptorTable, tss: &'static TaskStateSegment) -> Self {
+        Selectors {
+            code: gdt.add_entry(Descriptor::kernel_code_segment()),
+            data: gdt.add_entry(Descriptor::kernel_data_segment()),
+            user_data: gdt.add_entry(Descriptor::user_data_segment()),
+            user_code: gdt.add_entry(Descriptor::user_code_segment()),
+            tss: gdt.add_entry(Descriptor::tss_segment(tss)),
+        }
+    }
+}
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let selectors = Selectors::add(&mut gdt, unsafe { &*&raw const TSS });
+        (gdt, selectors)
+    };
+}
+
+/// Loads the GDT and the TSS. Must run before the IDT is loaded, since the double fault handler
+/// refers to the TSS stack.
+pub fn init() {
+    unsafe {
+        let tss = &mut *&raw mut TSS;
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+            let stack_start = VirtAddr::from_ptr(&raw const STACK);
+            // Stacks grow downwards, so the table holds the end address.
+            stack_start + DOUBLE_FAULT_STACK_SIZE
+        };
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+            static mut STACK: [u8; PAGE_FAULT_STACK_SIZE] = [0; PAGE_FAULT_STACK_SIZE];
+            VirtAddr::from_ptr(&raw const STACK) + PAGE_FAULT_STACK_SIZE
+        };
+    }
+    CPU_TSS.get().store(&raw mut TSS, Ordering::Relaxed);
+    load(&GDT.0, &GDT.1);
+}
+
+/// Builds and loads a GDT and TSS for the calling application processor, with exception stacks
+/// from the [`vmm`].
+pub fn init_ap() -> Result<(), VmmError> {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        vmm::alloc_stack(DOUBLE_FAULT_STACK_SIZE, "double fault stack")?;
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+        vmm::alloc_stack(PAGE_FAULT_STACK_SIZE, "page fault stack")?;
+    let tss: &'static mut TaskStateSegment = Box::leak(Box::new(tss));
+    CPU_TSS.get().store(tss, Ordering::Relaxed);
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let selectors = Selectors::add(gdt, tss);
+    load(gdt, &selectors);
+    Ok(())
+}
+
+/// Selectors of the kernel code and data segments, the same on every CPU.
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code, GDT.1.data)
+}
+
+/// Selectors of the user code and data segments, the same on every CPU.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code, GDT.1.user_data)
+}
+
+/// Sets the stack the calling CPU switches to on an interrupt, exception or system call in user
+/// mode. Called with interrupts disabled, whenever a thread that runs user code is switched to.
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    crate::percpu::set_kernel_stack(top);
+    let tss = CPU_TSS.get().load(Ordering::Relaxed);
+    if !tss.is_null() {
+        unsafe { (*tss).privilege_stack_table[0] = top };
+    }
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.code);
+        // The data segment registers still hold selectors into the GDT of the bootloader or of the
+        // AP startup code.
+        SS::set_reg(selectors.data);
+        DS::set_reg(selectors.data);
+        ES::set_reg(selectors.data);
+        load_tss(selectors.tss);
+    }
+}
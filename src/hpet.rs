@@ -0,0 +1,244 @@
+//! High Precision Event Timer.
+//!
+//! The HPET has a main counter running at a fixed frequency of at least 10 MHz, which the ACPI
+//! HPET table locates, and a set of comparators that raise an interrupt when the counter reaches
+//! them. The counter is a clock source that needs no calibration, unlike the TSC, and comparator
+//! 0 serves as a one-shot timer. Machines without an HPET keep using the PIT.
+//!
+//! The registers are mapped uncached with [`map_mmio`](crate::memory::map_mmio).
+
+use core::ptr;
+use core::time::Duration;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::vmm::VmmError;
+
+/// Offsets in the ACPI HPET table: the address space of the register block, where 0 is memory,
+/// and its address.
+const TABLE_ADDRESS_SPACE: usize = 40;
+const TABLE_ADDRESS: usize = 44;
+const TABLE_LENGTH: usize = 56;
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+/// Size of the register block.
+const REGISTERS_SIZE: usize = 0x400;
+
+/// Registers, as byte offsets from the base.
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+
+const fn timer_configuration(timer: usize) -> usize {
+    0x100 + 0x20 * timer
+}
+
+const fn timer_comparator(timer: usize) -> usize {
+    0x108 + 0x20 * timer
+}
+
+/// Capabilities bit reporting a 64-bit main counter.
+const CAPABILITY_COUNTER_64BIT: u64 = 1 << 13;
+/// Configuration bit that starts the main counter.
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+/// Timer configuration bits: interrupt enable, forced 32-bit comparator, and the IO APIC pin
+/// the interrupt is routed to. The upper half lists the pins the timer can be routed to.
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_CAPABILITY_SHIFT: u64 = 32;
+
+/// Longest counter period the specification allows, 100 ns, in femtoseconds.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+
+/// IO APIC pins below this one carry the ISA IRQs.
+const FIRST_FREE_PIN: u8 = 16;
+
+/// Comparator of the one-shot timer.
+const ONE_SHOT_TIMER: usize = 0;
+
+/// Errors that keep the HPET or its one-shot timer from being used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// There is no valid ACPI HPET table, or the HPET has not been initialized.
+    NotPresent,
+    /// The registers are not in memory space.
+    UnsupportedAddressSpace(u8),
+    /// The counter period in femtoseconds is outside what the specification allows.
+    InvalidPeriod(u64),
+    /// The one-shot timer could not be routed to a free IO APIC pin, for example because the
+    /// PICs are in use.
+    NoInterruptRoute,
+    /// The registers could not be mapped.
+    Mmio(VmmError),
+}
+
+struct Hpet {
+    base: VirtAddr,
+    /// Length of a counter tick in femtoseconds.
+    period_fs: u64,
+    /// Bits of the main counter, which may only be 32 wide.
+    counter_mask: u64,
+    /// IO APIC pin of the one-shot timer.
+    route: Option<u8>,
+    /// Uptime from the ticks when the counter was started at 0.
+    start_ns: u64,
+}
+
+impl Hpet {
+    fn read(&self, register: usize) -> u64 {
+        unsafe { ptr::read_volatile((self.base + register).as_ptr::<u64>()) }
+    }
+
+    fn write(&self, register: usize, value: u64) {
+        unsafe { ptr::write_volatile((self.base + register).as_mut_ptr::<u64>(), value) }
+    }
+
+    fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER) & self.counter_mask
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+/// A callback run when the one-shot timer expires. It runs in interrupt context, like an
+/// [`IrqHandler`](crate::interruptsa::IrqHandler).
+pub type TimerCallback = fn();
+
+static CALLBACK: Mutex<Option<TimerCallback>> = Mutex::new(None);
+
+/// Finds the HPET in the ACPI tables, resets and starts its counter, and, with the APICs in use,
+/// routes the one-shot timer to `vector`. Returns the counter frequency in Hz. Must run with
+/// interrupts disabled.
+pub(crate) fn init(vector: u8) -> Result<u64, HpetError> {
+    let table = crate::acpi::find_table(b"HPET")
+        .filter(|table| table.len() >= TABLE_LENGTH)
+        .ok_or(HpetError::NotPresent)?;
+    let address_space = table[TABLE_ADDRESS_SPACE];
+    if address_space != ADDRESS_SPACE_MEMORY {
+        return Err(HpetError::UnsupportedAddressSpace(address_space));
+    }
+    let address = u64::from_le_bytes(table[TABLE_ADDRESS..TABLE_ADDRESS + 8].try_into().unwrap());
+    let base = unsafe { crate::memory::map_mmio(PhysAddr::new(address), REGISTERS_SIZE) }
+        .map_err(HpetError::Mmio)?;
+    let mut hpet = Hpet {
+        base,
+        period_fs: 0,
+        counter_mask: u64::MAX,
+        route: None,
+        start_ns: 0,
+    };
+    let capabilities = hpet.read(CAPABILITIES);
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        return Err(HpetError::InvalidPeriod(period_fs));
+    }
+    hpet.period_fs = period_fs;
+    if capabilities & CAPABILITY_COUNTER_64BIT == 0 {
+        hpet.counter_mask = u64::from(u32::MAX);
+    }
+
+    // The counter can only be written while it is halted.
+    hpet.write(CONFIGURATION, 0);
+    let timers = ((capabilities >> 8) & 0x1F) as usize + 1;
+    for timer in 0..timers {
+        let configuration = hpet.read(timer_configuration(timer));
+        hpet.write(
+            timer_configuration(timer),
+            configuration & !TIMER_INTERRUPT_ENABLE,
+        );
+    }
+    hpet.write(MAIN_COUNTER, 0);
+    if crate::apic::is_enabled() {
+        let routes = hpet.read(timer_configuration(ONE_SHOT_TIMER)) >> TIMER_ROUTE_CAPABILITY_SHIFT;
+        hpet.route = (FIRST_FREE_PIN..32)
+            .find(|&pin| routes & (1 << pin) != 0 && crate::apic::route_pin(pin, vector));
+    }
+    // The ticks stand still with interrupts disabled, so the uptime is that of the counter start.
+    hpet.start_ns = crate::time::uptime().as_nanos() as u64;
+    hpet.write(CONFIGURATION, CONFIGURATION_ENABLE);
+    HPET.call_once(|| hpet);
+    Ok(FEMTOSECONDS_PER_SECOND / period_fs)
+}
+
+/// Returns whether the HPET has been found and started.
+pub fn is_available() -> bool {
+    HPET.is_completed()
+}
+
+/// Counter frequency in Hz, or `None` without an HPET.
+pub fn frequency() -> Option<u64> {
+    HPET.get()
+        .map(|hpet| FEMTOSECONDS_PER_SECOND / hpet.period_fs)
+}
+
+/// Reads the main counter, or returns `None` without an HPET.
+pub fn counter() -> Option<u64> {
+    HPET.get().map(Hpet::counter)
+}
+
+/// Nanoseconds since boot: the counter, plus the uptime the ticks had counted when it started.
+/// `None` without an HPET or with a 32-bit counter, which wraps around after a few minutes.
+pub fn nanos() -> Option<u64> {
+    let hpet = HPET.get().filter(|hpet| hpet.counter_mask == u64::MAX)?;
+    let femtoseconds = u128::from(hpet.counter()) * u128::from(hpet.period_fs);
+    let nanos = (femtoseconds / u128::from(FEMTOSECONDS_PER_NANOSECOND)) as u64;
+    Some(hpet.start_ns.saturating_add(nanos))
+}
+
+/// Runs `callback` once after `delay`, replacing a pending one-shot timer.
+pub fn start_one_shot(delay: Duration, callback: TimerCallback) -> Result<(), HpetError> {
+    let hpet = HPET.get().ok_or(HpetError::NotPresent)?;
+    let route = hpet.route.ok_or(HpetError::NoInterruptRoute)?;
+    let femtoseconds = delay.as_nanos() * u128::from(FEMTOSECONDS_PER_NANOSECOND);
+    let ticks =
+        (femtoseconds / u128::from(hpet.period_fs)).clamp(1, u128::from(hpet.counter_mask >> 1));
+    let mut ticks = ticks as u64;
+    interrupts::without_interrupts(|| {
+        *CALLBACK.lock() = Some(callback);
+        let mut configuration = TIMER_INTERRUPT_ENABLE | (u64::from(route) << TIMER_ROUTE_SHIFT);
+        if hpet.counter_mask != u64::MAX {
+            configuration |= TIMER_32BIT_MODE;
+        }
+        hpet.write(timer_configuration(ONE_SHOT_TIMER), configuration);
+        // The comparator only fires when the counter passes it, so a deadline that is already
+        // over by the time it is written would never fire. Retry with a longer delay then.
+        loop {
+            let now = hpet.counter();
+            hpet.write(
+                timer_comparator(ONE_SHOT_TIMER),
+                now.wrapping_add(ticks) & hpet.counter_mask,
+            );
+            if hpet.counter().wrapping_sub(now) & hpet.counter_mask < ticks {
+                break;
+            }
+            ticks = ticks.saturating_mul(2).min(hpet.counter_mask >> 1);
+        }
+    });
+    Ok(())
+}
+
+/// Cancels a pending one-shot timer.
+pub fn cancel_one_shot() {
+    let Some(hpet) = HPET.get() else {
+        return;
+    };
+    interrupts::without_interrupts(|| {
+        hpet.write(timer_configuration(ONE_SHOT_TIMER), 0);
+        *CALLBACK.lock() = None;
+    });
+}
+
+/// Handles the one-shot timer interrupt. The end of interrupt is left to the caller.
+pub(crate) fn interrupt() {
+    if let Some(hpet) = HPET.get() {
+        hpet.write(timer_configuration(ONE_SHOT_TIMER), 0);
+    }
+    let callback = CALLBACK.lock().take();
+    if let Some(callback) = callback {
+        callback();
+    }
+}
@@ -1,16 +1,22 @@
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::idt::InterruptDescriptorTable;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-use spin::Mutex;
-use x86_64::instructions::port::Port;
-use crate::print;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::idt::ExceptionVector;
+use core::sync::atomic::{AtomicU64, Ordering};
 use crate::println;//use your custom println macro.
 
 // /In this section we define handlers for interrupts/
+//every handler first switches to the kernel's GS base if it came from user mode, see KernelGs
 //1. breakpoint_handler - handles the invocation of INT3
 extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: InterruptStackFrame)
+    mut stack_frame: InterruptStackFrame)
 {
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::Breakpoint as u8);
+    //a kdebug breakpoint has already been reported, and may start single-stepping
+    if crate::kdebug::on_breakpoint(&mut stack_frame) {
+        return;
+    }
     println!("EXCEPTION: BREAKPOINT\n Stack Frame:\n {:#?}", stack_frame);
 }
 
@@ -18,23 +24,271 @@ extern "x86-interrupt" fn breakpoint_handler(
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
+    let _gs = crate::percpu::KernelGs::enter_paranoid();
+    count(ExceptionVector::Double as u8);
     panic!("EXCEPTION: DOUBLE FAULT\n Stack Frame:\n{:#?}", stack_frame);
 }
 
 //3. General protection handler
 extern "x86-interrupt" fn general_protection_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64)
+    mut stack_frame: InterruptStackFrame, _error_code: u64)
 {
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::GeneralProtection as u8);
+    if crate::usermode::on_exception(&mut stack_frame, ExceptionVector::GeneralProtection as u8) {
+        return;
+    }
     println!("EXCEPTION: GENERAL PROTECTION\n Error Code: {:#?}\n Stack Frame:\n{:#?}", _error_code, stack_frame);
 }
 
 //4. Invalid opcode handler
 extern "x86-interrupt" fn invalid_opcode_handler(
-    stack_frame: InterruptStackFrame)
+    mut stack_frame: InterruptStackFrame)
 {
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::InvalidOpcode as u8);
+    if crate::usermode::on_exception(&mut stack_frame, ExceptionVector::InvalidOpcode as u8) {
+        return;
+    }
     println!("EXCEPTION: INVALID OPCODE\n Stack Frame:\n {:#?}", stack_frame);
 }
 
+//5. Page fault handler. The faulting address is in CR2.
+extern "x86-interrupt" fn page_fault_handler(
+    mut stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode)
+{
+    use x86_64::registers::control::Cr2;
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::Page as u8);
+
+    let access = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+    let cause = if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        "reserved bit set in a page table entry"
+    } else if !error_code.contains(PageFaultErrorCode::USER_MODE)
+        && crate::usermode::uaccess::check_range(Cr2::read().as_u64(), 1).is_ok()
+    {
+        //SMEP and SMAP fault on every kernel access to user pages but the uaccess copies
+        "user memory accessed by the kernel outside the uaccess helpers"
+    } else if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+    let mode = if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        "user"
+    } else {
+        "kernel"
+    };
+    //a process created before the kernel added a top-level entry gets a copy of it
+    if crate::memory::address_space::sync_kernel_entry(Cr2::read()) {
+        return;
+    }
+    //a write to a copy-on-write page gets its own copy of the frame and is retried
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && (crate::memory::cow::handle_fault(Cr2::read())
+            || crate::process::handle_cow_fault(Cr2::read()))
+    {
+        return;
+    }
+    //a copy to or from user memory that faults gives up and reports it
+    if crate::usermode::uaccess::fixup(&mut stack_frame) {
+        return;
+    }
+    //any other fault in user mode sends the program SIGSEGV
+    if crate::usermode::on_exception(&mut stack_frame, ExceptionVector::Page as u8) {
+        return;
+    }
+    //a fault in a guard page means the stack above it ran out
+    if let Some(stack) = crate::memory::vmm::stack_guard(Cr2::read()) {
+        panic!(
+            "EXCEPTION: STACK OVERFLOW in {}\n Address: {:?}\n Stack Frame:\n{:#?}",
+            stack, Cr2::read(), stack_frame
+        );
+    }
+    //the mapping of the faulting page, if any, helps tell a missing mapping from wrong flags.
+    //the last page of the address space, or of its lower half, has no end address, so the range
+    //stops at its last byte instead
+    let page = Cr2::read().align_down(4096u64);
+    let end = page.as_u64().checked_add(4096);
+    let end = end.and_then(|end| x86_64::VirtAddr::try_new(end).ok());
+    let mapping = crate::memory::dump::Mappings(page..end.unwrap_or(page + 4095u64));
+    //the panic screen halts the machine after showing the report
+    panic!(
+        "EXCEPTION: PAGE FAULT\n Address: {:?}\n Access: {} {} ({})\n Error Code: {:?}\n Mapping:\n{} Stack Frame:\n{:#?}",
+        Cr2::read(), mode, access, cause, error_code, mapping, stack_frame
+    );
+}
+
+//6. Divide error handler - division by zero or a quotient that does not fit
+extern "x86-interrupt" fn divide_error_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::Division as u8);
+    if crate::usermode::on_exception(&mut stack_frame, ExceptionVector::Division as u8) {
+        return;
+    }
+    panic!("EXCEPTION: DIVIDE ERROR\n Stack Frame:\n{:#?}", stack_frame);
+}
+
+//7. Debug handler - single step and hardware breakpoints. A trap, so execution continues.
+extern "x86-interrupt" fn debug_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    let _gs = crate::percpu::KernelGs::enter_paranoid();
+    count(ExceptionVector::Debug as u8);
+    if crate::kdebug::on_debug(&mut stack_frame) {
+        return;
+    }
+    println!("EXCEPTION: DEBUG\n Stack Frame:\n {:#?}", stack_frame);
+}
+
+//8. Non-maskable interrupt handler - usually a hardware failure or a watchdog
+extern "x86-interrupt" fn nmi_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let _gs = crate::percpu::KernelGs::enter_paranoid();
+    count(ExceptionVector::NonMaskableInterrupt as u8);
+    //a CPU stopped for a panic or reboot halts here for good
+    crate::smp::on_nmi();
+    //the watchdog checks the timer heartbeat on every NMI
+    if crate::watchdog::on_nmi(&stack_frame) {
+        return;
+    }
+    println!("EXCEPTION: NON-MASKABLE INTERRUPT\n Stack Frame:\n {:#?}", stack_frame);
+}
+
+//9. Overflow handler - INTO with the overflow flag set. A trap, so execution continues.
+extern "x86-interrupt" fn overflow_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::Overflow as u8);
+    println!("EXCEPTION: OVERFLOW\n Stack Frame:\n {:#?}", stack_frame);
+}
+
+//10. Bound range exceeded handler - BOUND with an index outside the bounds
+extern "x86-interrupt" fn bound_range_exceeded_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::BoundRange as u8);
+    if crate::usermode::on_exception(&mut stack_frame, ExceptionVector::BoundRange as u8) {
+        return;
+    }
+    panic!("EXCEPTION: BOUND RANGE EXCEEDED\n Stack Frame:\n{:#?}", stack_frame);
+}
+
+//11. Device not available handler - FPU/SSE instruction while the FPU is disabled
+extern "x86-interrupt" fn device_not_available_handler(
+    stack_frame: InterruptStackFrame)
+{
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::DeviceNotAvailable as u8);
+    panic!("EXCEPTION: DEVICE NOT AVAILABLE\n Stack Frame:\n{:#?}", stack_frame);
+}
+
+/// Error code of the exceptions caused by a segment selector, which names the offending
+/// descriptor.
+struct SelectorErrorCode(u64);
+
+impl core::fmt::Debug for SelectorErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let table = match (self.0 >> 1) & 0b11 {
+            0 => "GDT",
+            1 | 3 => "IDT",
+            _ => "LDT",
+        };
+        write!(f, "{} index {}", table, (self.0 >> 3) & 0x1FFF)?;
+        if self.0 & 1 != 0 {
+            write!(f, " (external event)")?;
+        }
+        Ok(())
+    }
+}
+
+//12. Invalid TSS handler
+extern "x86-interrupt" fn invalid_tss_handler(
+    stack_frame: InterruptStackFrame, error_code: u64)
+{
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::InvalidTss as u8);
+    panic!("EXCEPTION: INVALID TSS\n Selector: {:?}\n Stack Frame:\n{:#?}", SelectorErrorCode(error_code), stack_frame);
+}
+
+//13. Segment not present handler
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame, error_code: u64)
+{
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::SegmentNotPresent as u8);
+    panic!("EXCEPTION: SEGMENT NOT PRESENT\n Selector: {:?}\n Stack Frame:\n{:#?}", SelectorErrorCode(error_code), stack_frame);
+}
+
+//14. Stack segment fault handler. An error code of 0 means a stack limit or canonical address
+//violation rather than a bad selector.
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    mut stack_frame: InterruptStackFrame, error_code: u64)
+{
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::Stack as u8);
+    if crate::usermode::on_exception(&mut stack_frame, ExceptionVector::Stack as u8) {
+        return;
+    }
+    panic!("EXCEPTION: STACK SEGMENT FAULT\n Selector: {:?}\n Stack Frame:\n{:#?}", SelectorErrorCode(error_code), stack_frame);
+}
+
+//15. x87 floating point handler - an unmasked x87 exception
+extern "x86-interrupt" fn x87_floating_point_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::X87FloatingPoint as u8);
+    if crate::usermode::on_exception(&mut stack_frame, ExceptionVector::X87FloatingPoint as u8) {
+        return;
+    }
+    panic!("EXCEPTION: x87 FLOATING POINT\n Stack Frame:\n{:#?}", stack_frame);
+}
+
+//16. SIMD floating point handler - an unmasked SSE exception, details are in MXCSR
+extern "x86-interrupt" fn simd_floating_point_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::SimdFloatingPoint as u8);
+    if crate::usermode::on_exception(&mut stack_frame, ExceptionVector::SimdFloatingPoint as u8) {
+        return;
+    }
+    panic!("EXCEPTION: SIMD FLOATING POINT\n Stack Frame:\n{:#?}", stack_frame);
+}
+
+//17. Alignment check handler - unaligned access with alignment checking enabled
+extern "x86-interrupt" fn alignment_check_handler(
+    mut stack_frame: InterruptStackFrame, error_code: u64)
+{
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(ExceptionVector::AlignmentCheck as u8);
+    if crate::usermode::on_exception(&mut stack_frame, ExceptionVector::AlignmentCheck as u8) {
+        return;
+    }
+    panic!("EXCEPTION: ALIGNMENT CHECK\n Error Code: {:#?}\n Stack Frame:\n{:#?}", error_code, stack_frame);
+}
+
+//18. Machine check handler - the CPU detected an internal or bus error and cannot continue
+extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: InterruptStackFrame) -> !
+{
+    let _gs = crate::percpu::KernelGs::enter_paranoid();
+    count(ExceptionVector::MachineCheck as u8);
+    panic!("EXCEPTION: MACHINE CHECK\n Stack Frame:\n{:#?}", stack_frame);
+}
+
 
 /*Here we setup our Programmable Interrupt Controller
 Ref: Class slides and https://os.phil-opp.com/hardware-interrupts*/
@@ -53,79 +307,465 @@ static PICS: spin::Mutex<ChainedPics> =
 fn init_pics(){
     unsafe { PICS.lock().initialize() };
 }
+/// IRQ line of the second PIC on the first one.
+const CASCADE_IRQ: u8 = 2;
+
+/// Command ports of the two PICs, and the OCW3 command that makes the next read of a command
+/// port return the in-service register.
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+const PIC_READ_ISR: u8 = 0x0B;
+
+/// Lowest priority line of each PIC, which a PIC raises when the interrupting line drops before
+/// the CPU acknowledges it.
+const SPURIOUS_IRQ_1: u8 = 7;
+const SPURIOUS_IRQ_2: u8 = 15;
+
+/// Returns whether an interrupt on IRQ 7 or 15 was spurious: the PIC raised the line but has no
+/// interrupt in service on it. A spurious IRQ must not be acknowledged with an EOI.
+fn is_spurious(irq: u8) -> bool {
+    //the IO APIC has no spurious IRQs, only its own spurious vector
+    if crate::apic::is_enabled() {
+        return false;
+    }
+    let port = match irq {
+        SPURIOUS_IRQ_1 => PIC_1_COMMAND,
+        SPURIOUS_IRQ_2 => PIC_2_COMMAND,
+        _ => return false,
+    };
+    //hold the PICs so nothing else talks to them between the command and the read
+    let _pics = PICS.lock();
+    let mut command = x86_64::instructions::port::Port::<u8>::new(port);
+    unsafe {
+        command.write(PIC_READ_ISR);
+        command.read() & (1 << (irq % 8)) == 0
+    }
+}
+
+/// Unmasks an IRQ line on the active interrupt controller. On the PICs the cascade line is
+/// unmasked as well for IRQs on the second PIC.
+fn unmask_irq(irq: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if crate::apic::is_enabled() {
+            //the local APIC timer replaces the PIT interrupt
+            if !(irq == TIMER_IRQ && crate::apic::timer_running()) {
+                crate::apic::unmask_irq(irq);
+            }
+            return;
+        }
+        let mut pics = PICS.lock();
+        unsafe {
+            let [mut primary, mut secondary] = pics.read_masks();
+            if irq < 8 {
+                primary &= !(1 << irq);
+            } else {
+                secondary &= !(1 << (irq - 8));
+                primary &= !(1 << CASCADE_IRQ);
+            }
+            pics.write_masks(primary, secondary);
+        }
+    });
+}
+
 //At this point, calling init_pics() from init() below 
 //will not yet lead to any interrupts because the interrupt
 //enable flag is unset by default.
 //To enable interrupt, add x86_64::instructions::interrupts::enable();
 // to the init below
 
-//Add enum for hardware interrupt offset index
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,//offset 0 is reserved for timer
-    Keyboard
+/// Number of IRQ lines on the two PICs.
+const IRQ_COUNT: usize = 16;
+
+/// IRQ line of the legacy timer (PIT channel 0).
+const TIMER_IRQ: u8 = 0;
+
+/// Errors returned by [`register_irq`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ number is not a PIC line.
+    InvalidIrq(u8),
+    /// Another handler is already registered for the IRQ.
+    AlreadyRegistered(u8),
+    /// Every vector of the kind asked for already has a handler.
+    NoFreeVector,
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
+/// A driver's interrupt handler. It runs in interrupt context, so it must not wait for locks
+/// that normal kernel code holds with interrupts enabled.
+pub type IrqHandler = fn();
+
+/// Handlers registered for each IRQ line, called by the dispatch stubs below.
+static IRQ_HANDLERS: spin::Mutex<[Option<IrqHandler>; IRQ_COUNT]> =
+    spin::Mutex::new([None; IRQ_COUNT]);
+
+/// Installs `handler` for an IRQ line and unmasks the line. The handler runs with interrupts
+/// disabled, and the end of interrupt is sent after it returns.
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if usize::from(irq) >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq(irq));
     }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = &mut handlers[usize::from(irq)];
+        if slot.is_some() {
+            return Err(IrqError::AlreadyRegistered(irq));
+        }
+        *slot = Some(handler);
+        Ok(())
+    })?;
+    unmask_irq(irq);
+    Ok(())
+}
+
+/// Removes the handler of an IRQ line. Interrupts on the line are still acknowledged.
+pub fn unregister_irq(irq: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(slot) = IRQ_HANDLERS.lock().get_mut(usize::from(irq)) {
+            *slot = None;
+        }
+    });
+}
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+//Common part of all IRQ stubs: run the registered handler, then send the EOI
+fn dispatch_irq(irq: u8) {
+    count(PIC_1_OFFSET + irq);
+    crate::preempt::irq_enter();
+    handle_irq(irq);
+    //the outermost exit is where the scheduler switches threads
+    if crate::preempt::irq_exit() {
+        crate::thread::preempt();
     }
 }
-//Add a handler for Timer
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
-{
-    //print!("."); //You can uncomment this to see that timer interrupt is on.
+
+fn handle_irq(irq: u8) {
+    if is_spurious(irq) {
+        SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+        //for a spurious IRQ 15 the first PIC did see the cascade line, so it still needs its EOI
+        if irq == SPURIOUS_IRQ_2 {
+            unsafe {
+                PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ);
+            }
+        }
+        return;
+    }
+    //copy the handler out so it can itself register or unregister handlers
+    let handler = IRQ_HANDLERS.lock()[usize::from(irq)];
+    if let Some(handler) = handler {
+        handler();
+    }
+    end_of_interrupt(irq);
+}
+
+//Acknowledge the IRQ on whichever interrupt controller delivered it
+fn end_of_interrupt(irq: u8) {
+    if crate::apic::is_enabled() {
+        crate::apic::end_of_interrupt();
+        return;
+    }
     unsafe {
         PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+            .notify_end_of_interrupt(PIC_1_OFFSET + irq);
     }
 }
 
-// Add a handler for keyboard
+/// Number of times each interrupt vector was raised, exceptions included.
+static VECTOR_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
 
-lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-        Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-    );
+/// Number of spurious interrupts from the PICs and the local APIC, which are also counted under
+/// their vectors.
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+//Called first thing in every handler, so even a fault that never returns is counted
+pub(crate) fn count(vector: u8) {
+    VECTOR_COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
 }
-extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
-    use crate::println;
-    use crate::FRAME_BUFFER_WRITER;
-    
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => {
-                    if character == '\u{8}' {
-                        // Backspace key
-                        if let Some(writer) = &mut *FRAME_BUFFER_WRITER.lock() {
-                            writer.backspace();
-                        }
-                    } else {
-                        print!("{}", character);
-                    }
-                }
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
+
+/// A snapshot of the per-vector interrupt counters, see [`stats`].
+#[derive(Debug, Clone)]
+pub struct InterruptStats {
+    counts: [u64; 256],
+    spurious: u64,
+}
+
+impl InterruptStats {
+    /// Number of times `vector` was raised.
+    pub fn vector(&self, vector: u8) -> u64 {
+        self.counts[usize::from(vector)]
+    }
+
+    /// Number of interrupts on an ISA IRQ line, whichever controller delivered them.
+    pub fn irq(&self, irq: u8) -> u64 {
+        self.counts
+            .get(usize::from(PIC_1_OFFSET) + usize::from(irq))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Number of spurious interrupts, which were counted under their vectors but not handled.
+    pub fn spurious(&self) -> u64 {
+        self.spurious
+    }
+
+    /// Total number of interrupts and exceptions.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The vectors raised at least once, with their counts, in vector order.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u64)> + '_ {
+        (0..=u8::MAX)
+            .map(|vector| (vector, self.vector(vector)))
+            .filter(|&(_, count)| count != 0)
+    }
+}
+
+/// Returns how often each interrupt vector has been raised since boot.
+pub fn stats() -> InterruptStats {
+    InterruptStats {
+        counts: core::array::from_fn(|vector| VECTOR_COUNTS[vector].load(Ordering::Relaxed)),
+        spurious: SPURIOUS_COUNT.load(Ordering::Relaxed),
+    }
+}
+
+/// Describes what raises `vector`, for listing [`stats`].
+pub fn vector_name(vector: u8) -> &'static str {
+    const LAST_IRQ_VECTOR: u8 = PIC_1_OFFSET + IRQ_COUNT as u8 - 1;
+    const IRQ_NAMES: [&str; IRQ_COUNT] = [
+        "timer", "keyboard", "cascade", "COM2", "COM1", "LPT2", "floppy", "LPT1",
+        "RTC", "IRQ 9", "IRQ 10", "IRQ 11", "mouse", "FPU", "primary ATA", "secondary ATA",
+    ];
+    match vector {
+        0x00 => "divide error",
+        0x01 => "debug",
+        0x02 => "NMI",
+        0x03 => "breakpoint",
+        0x04 => "overflow",
+        0x05 => "bound range exceeded",
+        0x06 => "invalid opcode",
+        0x07 => "device not available",
+        0x08 => "double fault",
+        0x0A => "invalid TSS",
+        0x0B => "segment not present",
+        0x0C => "stack segment fault",
+        0x0D => "general protection",
+        0x0E => "page fault",
+        0x10 => "x87 floating point",
+        0x11 => "alignment check",
+        0x12 => "machine check",
+        0x13 => "SIMD floating point",
+        PIC_1_OFFSET..=LAST_IRQ_VECTOR => IRQ_NAMES[usize::from(vector - PIC_1_OFFSET)],
+        HPET_VECTOR => "HPET timer",
+        IPI_VECTOR_BASE..=LAST_IPI_VECTOR => ipi_name(vector),
+        DEVICE_VECTOR_BASE..=LAST_DEVICE_VECTOR => device_vector_name(vector),
+        crate::apic::SPURIOUS_VECTOR => "APIC spurious",
+        crate::usermode::syscall::SYSCALL_VECTOR => "system call",
+        _ => "unknown",
+    }
+}
+
+/// Vector of the HPET one-shot timer, the first one after the ISA IRQs.
+const HPET_VECTOR: u8 = PIC_2_OFFSET + 8;
+
+//The HPET one-shot timer is routed through the IO APIC, so it only fires with the APICs in use
+extern "x86-interrupt" fn hpet_timer_handler(stack_frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(HPET_VECTOR);
+    crate::preempt::irq_enter();
+    crate::hpet::interrupt();
+    crate::apic::end_of_interrupt();
+    crate::preempt::irq_exit();
+}
+
+/// Number of vectors set aside for inter-processor interrupts, which follow the HPET timer.
+const IPI_COUNT: usize = 4;
+const IPI_VECTOR_BASE: u8 = HPET_VECTOR + 1;
+const LAST_IPI_VECTOR: u8 = IPI_VECTOR_BASE + IPI_COUNT as u8 - 1;
+
+/// Handlers of the IPI vectors handed out by [`register_ipi`], with a name for [`vector_name`].
+static IPI_HANDLERS: spin::Mutex<[Option<(&'static str, IrqHandler)>; IPI_COUNT]> =
+    spin::Mutex::new([None; IPI_COUNT]);
+
+/// Installs `handler` for a free IPI vector and returns the vector, which other CPUs can then
+/// send with [`crate::smp::send_ipi`]. The handler runs with interrupts disabled, and the end of
+/// interrupt is sent after it returns.
+pub fn register_ipi(name: &'static str, handler: IrqHandler) -> Result<u8, IrqError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = IPI_HANDLERS.lock();
+        let index = handlers
+            .iter()
+            .position(Option::is_none)
+            .ok_or(IrqError::NoFreeVector)?;
+        handlers[index] = Some((name, handler));
+        Ok(IPI_VECTOR_BASE + index as u8)
+    })
+}
+
+/// Frees an IPI vector. IPIs still arriving on it are acknowledged and otherwise ignored.
+pub fn unregister_ipi(vector: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let index = usize::from(vector.wrapping_sub(IPI_VECTOR_BASE));
+        if let Some(slot) = IPI_HANDLERS.lock().get_mut(index) {
+            *slot = None;
         }
+    });
+}
+
+fn ipi_name(vector: u8) -> &'static str {
+    let index = usize::from(vector - IPI_VECTOR_BASE);
+    x86_64::instructions::interrupts::without_interrupts(|| IPI_HANDLERS.lock()[index])
+        .map_or("IPI", |(name, _)| name)
+}
+
+//IPIs only ever come from local APICs, so they are acknowledged there
+fn dispatch_ipi(index: usize) {
+    count(IPI_VECTOR_BASE + index as u8);
+    crate::preempt::irq_enter();
+    let handler = IPI_HANDLERS.lock()[index];
+    if let Some((_, handler)) = handler {
+        handler();
+    }
+    crate::apic::end_of_interrupt();
+    if crate::preempt::irq_exit() {
+        crate::thread::preempt();
     }
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+macro_rules! ipi_stubs {
+    ($($index:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+                let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+                dispatch_ipi($index);
+            }
+        )*
+        const IPI_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IPI_COUNT] = [$($name),*];
+    };
+}
+
+ipi_stubs! {
+    0 => ipi0_stub, 1 => ipi1_stub, 2 => ipi2_stub, 3 => ipi3_stub,
+}
+
+/// Number of vectors handed out to devices that signal interrupts with messages (MSI and MSI-X)
+/// rather than on an IRQ line. They follow the IPIs.
+const DEVICE_VECTOR_COUNT: usize = 16;
+const DEVICE_VECTOR_BASE: u8 = LAST_IPI_VECTOR + 1;
+const LAST_DEVICE_VECTOR: u8 = DEVICE_VECTOR_BASE + DEVICE_VECTOR_COUNT as u8 - 1;
+
+/// Handlers of the vectors handed out by [`allocate_vector`], with a name for [`vector_name`].
+static DEVICE_HANDLERS: spin::Mutex<[Option<(&'static str, IrqHandler)>; DEVICE_VECTOR_COUNT]> =
+    spin::Mutex::new([None; DEVICE_VECTOR_COUNT]);
+
+/// Installs `handler` for a free device vector and returns the vector, for a device to be
+/// programmed with, see [`crate::pci::PciDevice::enable_interrupt`]. Only the local APIC
+/// receives such interrupts. The handler runs with interrupts disabled, and the end of interrupt
+/// is sent after it returns.
+pub fn allocate_vector(name: &'static str, handler: IrqHandler) -> Result<u8, IrqError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = DEVICE_HANDLERS.lock();
+        let index = handlers
+            .iter()
+            .position(Option::is_none)
+            .ok_or(IrqError::NoFreeVector)?;
+        handlers[index] = Some((name, handler));
+        Ok(DEVICE_VECTOR_BASE + index as u8)
+    })
+}
+
+/// Frees a vector from [`allocate_vector`]. Interrupts still arriving on it are acknowledged
+/// and otherwise ignored.
+pub fn free_vector(vector: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let index = usize::from(vector.wrapping_sub(DEVICE_VECTOR_BASE));
+        if let Some(slot) = DEVICE_HANDLERS.lock().get_mut(index) {
+            *slot = None;
+        }
+    });
+}
+
+fn device_vector_name(vector: u8) -> &'static str {
+    let index = usize::from(vector - DEVICE_VECTOR_BASE);
+    x86_64::instructions::interrupts::without_interrupts(|| DEVICE_HANDLERS.lock()[index])
+        .map_or("device", |(name, _)| name)
+}
+
+fn dispatch_device_vector(index: usize) {
+    count(DEVICE_VECTOR_BASE + index as u8);
+    crate::preempt::irq_enter();
+    let handler = DEVICE_HANDLERS.lock()[index];
+    if let Some((_, handler)) = handler {
+        handler();
+    }
+    crate::apic::end_of_interrupt();
+    if crate::preempt::irq_exit() {
+        crate::thread::preempt();
     }
 }
 
+macro_rules! device_vector_stubs {
+    ($($index:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame) {
+                let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+                dispatch_device_vector($index);
+                crate::usermode::signal::on_return(&mut stack_frame);
+            }
+        )*
+        const DEVICE_VECTOR_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); DEVICE_VECTOR_COUNT] =
+            [$($name),*];
+    };
+}
+
+device_vector_stubs! {
+    0 => device0_stub, 1 => device1_stub, 2 => device2_stub, 3 => device3_stub,
+    4 => device4_stub, 5 => device5_stub, 6 => device6_stub, 7 => device7_stub,
+    8 => device8_stub, 9 => device9_stub, 10 => device10_stub, 11 => device11_stub,
+    12 => device12_stub, 13 => device13_stub, 14 => device14_stub, 15 => device15_stub,
+}
+
+//Spurious interrupts of the local APIC are not acknowledged
+extern "x86-interrupt" fn apic_spurious_handler(stack_frame: InterruptStackFrame) {
+    let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+    count(crate::apic::SPURIOUS_VECTOR);
+    SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+//One stub per IRQ line, since the IDT entry is the only way to tell which line fired
+macro_rules! irq_stubs {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(mut stack_frame: InterruptStackFrame) {
+                let _gs = crate::percpu::KernelGs::enter(&stack_frame);
+                dispatch_irq($irq);
+                //a program interrupted by the IRQ takes its pending signals before it goes on
+                crate::usermode::signal::on_return(&mut stack_frame);
+            }
+        )*
+        const IRQ_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] = [$($name),*];
+    };
+}
+
+irq_stubs! {
+    0 => irq0_stub, 1 => irq1_stub, 2 => irq2_stub, 3 => irq3_stub,
+    4 => irq4_stub, 5 => irq5_stub, 6 => irq6_stub, 7 => irq7_stub,
+    8 => irq8_stub, 9 => irq9_stub, 10 => irq10_stub, 11 => irq11_stub,
+    12 => irq12_stub, 13 => irq13_stub, 14 => irq14_stub, 15 => irq15_stub,
+}
+
+//Handler for Timer. Every CPU's local APIC timer raises it, but only the boot CPU keeps the time
+fn timer_interrupt_handler() {
+    //print!("."); //You can uncomment this to see that timer interrupt is on.
+    let boot_cpu = crate::percpu::cpu_id() == 0;
+    if boot_cpu {
+        crate::time::tick();
+    }
+    //a tick rate changed on another CPU is picked up here
+    crate::apic::follow_timer();
+    crate::thread::timer_tick();
+    if boot_cpu {
+        crate::console::timer_tick();
+    }
+}
 
 //setup the IDT and make entries of all the handlers
 use lazy_static::lazy_static;
@@ -134,12 +774,50 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
+        //the double fault handler runs on its own stack, so it also works after a stack overflow
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
         idt.general_protection_fault.set_handler_fn(general_protection_handler);
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler); 
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        //the page fault handler too, so running into a guard page is reported as a stack overflow
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(crate::gdt::PAGE_FAULT_IST_INDEX);
+        }
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        for (irq, stub) in IRQ_STUBS.into_iter().enumerate() {
+            idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(stub);
+        }
+        idt[usize::from(HPET_VECTOR)].set_handler_fn(hpet_timer_handler);
+        for (index, stub) in IPI_STUBS.into_iter().enumerate() {
+            idt[usize::from(IPI_VECTOR_BASE) + index].set_handler_fn(stub);
+        }
+        for (index, stub) in DEVICE_VECTOR_STUBS.into_iter().enumerate() {
+            idt[usize::from(DEVICE_VECTOR_BASE) + index].set_handler_fn(stub);
+        }
+        idt[usize::from(crate::apic::SPURIOUS_VECTOR)].set_handler_fn(apic_spurious_handler);
+        //the only gate user mode may use directly
+        unsafe {
+            idt[usize::from(crate::usermode::syscall::SYSCALL_VECTOR)]
+                .set_handler_addr(crate::usermode::syscall::interrupt_entry())
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+        }
         idt
     };
 }
@@ -150,9 +828,48 @@ fn init_idt(){
     IDT.load();
 }
 
+//Application processors share the IDT, so they only need to load it
+pub(crate) fn init_ap() {
+    init_idt();
+}
+
 //init all interrupts
 pub fn init() {
     init_idt(); //IDT
     init_pics(); //PICS
+    let _ = crate::time::set_tick_rate(crate::time::DEFAULT_TICK_RATE);
+    register_irq(TIMER_IRQ, timer_interrupt_handler).expect("timer IRQ already registered");
     x86_64::instructions::interrupts::enable();//enable hardware interrupts. Without handler for timer interrupt, which is on by default, there will be a double fault
-}
\ No newline at end of file
+}
+
+/// Switches interrupt delivery from the 8259 PICs to the local APIC and IO APIC. The PICs are
+/// masked, and the IRQs with registered handlers are unmasked on the IO APIC instead.
+///
+/// On failure the PICs stay in use.
+pub fn enable_apic() -> Result<(), crate::apic::ApicError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        //the PICs stay remapped above the exceptions, so their spurious IRQs cannot be
+        //mistaken for exceptions
+        crate::apic::init(PIC_1_OFFSET)?;
+        unsafe { PICS.lock().disable() };
+        //the local APIC timer takes over the timer interrupt if it can be calibrated
+        if crate::apic::calibrate_timer() != 0 {
+            let _ = crate::time::set_tick_rate(crate::time::DEFAULT_TICK_RATE);
+        }
+        let handlers = *IRQ_HANDLERS.lock();
+        for (irq, handler) in handlers.iter().enumerate() {
+            if handler.is_some() {
+                unmask_irq(irq as u8);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Starts the HPET and, with the APICs in use, routes its one-shot timer to its own vector.
+/// Returns the counter frequency in Hz. Call after [`enable_apic`].
+pub fn enable_hpet() -> Result<u64, crate::hpet::HpetError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        crate::hpet::init(HPET_VECTOR)
+    })
+}
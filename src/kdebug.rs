@@ -0,0 +1,181 @@
+//! In-kernel debugging with software breakpoints.
+//!
+//! [`breakpoint!`](crate::breakpoint) prints where it was hit along with a snapshot of the
+//! general purpose registers, then raises a breakpoint exception. It can also single-step the
+//! instructions after it: the breakpoint handler sets the trap flag, and the debug exception
+//! that follows every instruction prints the instruction address until the requested number of
+//! steps has run.
+
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+
+pub use crate::breakpoint;
+#[doc(hidden)]
+pub use x86_64::instructions::interrupts::int3;
+
+/// RFLAGS trap flag, which raises a debug exception after every instruction.
+const TRAP_FLAG: u64 = 1 << 8;
+
+/// Set by [`breakpoint!`](crate::breakpoint) for the breakpoint exception that follows it, so
+/// the handler can tell it from a stray `int3`.
+static ARMED: AtomicBool = AtomicBool::new(false);
+/// Steps requested by the last breakpoint, started by the breakpoint handler.
+static REQUESTED_STEPS: AtomicUsize = AtomicUsize::new(0);
+/// Steps left to single-step.
+static STEPS_REMAINING: AtomicUsize = AtomicUsize::new(0);
+
+/// General purpose registers, the instruction pointer and the flags at a breakpoint.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+impl Registers {
+    /// Captures the registers at the call site. The register that holds the snapshot's address
+    /// shows that address instead of its own value.
+    #[inline(always)]
+    pub fn capture() -> Registers {
+        let mut registers = Registers::default();
+        unsafe {
+            asm!(
+                "mov [{0} + 0x00], rax",
+                "mov [{0} + 0x08], rbx",
+                "mov [{0} + 0x10], rcx",
+                "mov [{0} + 0x18], rdx",
+                "mov [{0} + 0x20], rsi",
+                "mov [{0} + 0x28], rdi",
+                "mov [{0} + 0x30], rbp",
+                "mov [{0} + 0x38], rsp",
+                "mov [{0} + 0x40], r8",
+                "mov [{0} + 0x48], r9",
+                "mov [{0} + 0x50], r10",
+                "mov [{0} + 0x58], r11",
+                "mov [{0} + 0x60], r12",
+                "mov [{0} + 0x68], r13",
+                "mov [{0} + 0x70], r14",
+                "mov [{0} + 0x78], r15",
+                "lea {1}, [rip]",
+                "mov [{0} + 0x80], {1}",
+                "pushfq",
+                "pop {1}",
+                "mov [{0} + 0x88], {1}",
+                in(reg) &mut registers as *mut Registers,
+                out(reg) _,
+            );
+        }
+        registers
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = [
+            [("rax", self.rax), ("rbx", self.rbx), ("rcx", self.rcx)],
+            [("rdx", self.rdx), ("rsi", self.rsi), ("rdi", self.rdi)],
+            [("rbp", self.rbp), ("rsp", self.rsp), ("r8", self.r8)],
+            [("r9", self.r9), ("r10", self.r10), ("r11", self.r11)],
+            [("r12", self.r12), ("r13", self.r13), ("r14", self.r14)],
+            [
+                ("r15", self.r15),
+                ("rip", self.rip),
+                ("rflags", self.rflags),
+            ],
+        ];
+        for row in rows {
+            for (name, value) in row {
+                write!(f, " {:>6} {:#018x}", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reports a breakpoint and arms the breakpoint handler. Called by
+/// [`breakpoint!`](crate::breakpoint) right before its `int3`.
+#[doc(hidden)]
+pub fn hit(file: &str, line: u32, registers: &Registers, steps: usize) {
+    crate::println!("BREAKPOINT at {}:{}\n{}", file, line, registers);
+    REQUESTED_STEPS.store(steps, Ordering::Relaxed);
+    ARMED.store(true, Ordering::Relaxed);
+}
+
+/// Handles a breakpoint exception. Returns whether it was raised by
+/// [`breakpoint!`](crate::breakpoint), in which case it has already been reported.
+pub(crate) fn on_breakpoint(stack_frame: &mut InterruptStackFrame) -> bool {
+    if !ARMED.swap(false, Ordering::Relaxed) {
+        return false;
+    }
+    let steps = REQUESTED_STEPS.swap(0, Ordering::Relaxed);
+    if steps > 0 {
+        STEPS_REMAINING.store(steps, Ordering::Relaxed);
+        set_trap_flag(stack_frame, true);
+    }
+    true
+}
+
+/// Handles a debug exception. Returns whether it was a single step requested by a breakpoint.
+pub(crate) fn on_debug(stack_frame: &mut InterruptStackFrame) -> bool {
+    let remaining = STEPS_REMAINING.load(Ordering::Relaxed);
+    if remaining == 0 {
+        return false;
+    }
+    crate::println!("  step: rip {:?}", stack_frame.instruction_pointer);
+    STEPS_REMAINING.store(remaining - 1, Ordering::Relaxed);
+    if remaining == 1 {
+        set_trap_flag(stack_frame, false);
+        crate::println!("  continuing");
+    }
+    true
+}
+
+fn set_trap_flag(stack_frame: &mut InterruptStackFrame, enabled: bool) {
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            if enabled {
+                frame.cpu_flags |= TRAP_FLAG;
+            } else {
+                frame.cpu_flags &= !TRAP_FLAG;
+            }
+        });
+    }
+}
+
+/// Prints the location and registers and raises a breakpoint exception. With `steps = n`, the
+/// next `n` instructions are then single-stepped, printing each instruction address.
+///
+/// ```ignore
+/// kdebug::breakpoint!();
+/// kdebug::breakpoint!(steps = 8);
+/// ```
+#[macro_export]
+macro_rules! breakpoint {
+    () => {
+        $crate::breakpoint!(steps = 0)
+    };
+    (steps = $steps:expr) => {{
+        let registers = $crate::kdebug::Registers::capture();
+        $crate::kdebug::hit(file!(), line!(), &registers, $steps);
+        $crate::kdebug::int3();
+    }};
+}
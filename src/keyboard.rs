@@ -0,0 +1,704 @@
+//! PS/2 keyboard input.
+//!
+//! The keyboard interrupt handler decodes scancodes and pushes the resulting keys into a queue,
+//! from which any part of the kernel can read them with [`read_key`] or [`try_read_key`], or
+//! await them from a [`KeyStream`]. Typed keys are echoed by a task rather than the handler.
+//! The raw scancodes are queued as well, for code that wants to do its own decoding, and can be
+//! awaited from a [`ScancodeStream`]. Components that react to specific keys, such as hotkeys,
+//! subscribe to them with [`on_key`].
+
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{Stream, StreamExt};
+use lazy_static::lazy_static;
+use pc_keyboard::ScancodeSet as _;
+use pc_keyboard::{
+    layouts, DecodeState, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard,
+    ScancodeSet1, ScancodeSet2,
+};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+use crate::print;
+use crate::ps2::{self, Ps2Error};
+use crate::ring_buffer::RingBuffer;
+use crate::task::WakerSlot;
+use crate::workqueue::Work;
+
+mod subscriptions;
+
+pub use subscriptions::{on_key, unsubscribe, KeyFilter, KeyHandler, KeyPress, Subscription};
+
+/// IRQ line of the PS/2 keyboard.
+const KEYBOARD_IRQ: u8 = 1;
+
+/// Number of keys buffered before further keys are dropped.
+const QUEUE_SIZE: usize = 128;
+
+/// Keyboard layouts that can be selected at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    Dvorak,
+    Azerty,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 4] = [Layout::Us, Layout::Uk, Layout::Dvorak, Layout::Azerty];
+
+    /// Short name shown in the status bar.
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "US",
+            Layout::Uk => "UK",
+            Layout::Dvorak => "Dvorak",
+            Layout::Azerty => "AZERTY",
+        }
+    }
+
+    /// The layout selected by the layout-cycling hotkey after this one.
+    pub fn next(self) -> Layout {
+        let index = Layout::ALL.iter().position(|&l| l == self).unwrap_or(0);
+        Layout::ALL[(index + 1) % Layout::ALL.len()]
+    }
+}
+
+/// Scancode sets the keyboard handler can decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+/// Turns scancode bytes into key events for the scancode set detected at boot.
+struct ScancodeDecoder {
+    set: ScancodeSet,
+    state: DecodeState,
+}
+
+impl ScancodeDecoder {
+    fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            state: DecodeState::Start,
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Result<Option<KeyEvent>, pc_keyboard::Error> {
+        match self.set {
+            ScancodeSet::Set1 => ScancodeSet1::advance_state(&mut self.state, byte),
+            ScancodeSet::Set2 => ScancodeSet2::advance_state(&mut self.state, byte),
+        }
+    }
+}
+
+/// Turns key events into keys for one of the supported layouts. The scancode set type parameter
+/// is unused, since scancodes are decoded by [`ScancodeDecoder`].
+enum LayoutKeyboard {
+    Us(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Uk(Keyboard<layouts::Uk105Key, ScancodeSet1>),
+    Dvorak(Keyboard<layouts::Dvorak104Key, ScancodeSet1>),
+    Azerty(Keyboard<layouts::Azerty, ScancodeSet1>),
+}
+
+/// Runs `$body` with `$keyboard` bound to the decoder, whatever its layout.
+macro_rules! dispatch {
+    ($self:expr, $keyboard:ident => $body:expr) => {
+        match $self {
+            LayoutKeyboard::Us($keyboard) => $body,
+            LayoutKeyboard::Uk($keyboard) => $body,
+            LayoutKeyboard::Dvorak($keyboard) => $body,
+            LayoutKeyboard::Azerty($keyboard) => $body,
+        }
+    };
+}
+
+impl LayoutKeyboard {
+    fn new(layout: Layout) -> Self {
+        // Ctrl+letter decodes to the matching control character, which the line editor uses.
+        let ctrl = HandleControl::MapLettersToUnicode;
+        match layout {
+            Layout::Us => Self::Us(Keyboard::new(layouts::Us104Key, ScancodeSet1, ctrl)),
+            Layout::Uk => Self::Uk(Keyboard::new(layouts::Uk105Key, ScancodeSet1, ctrl)),
+            Layout::Dvorak => {
+                Self::Dvorak(Keyboard::new(layouts::Dvorak104Key, ScancodeSet1, ctrl))
+            }
+            Layout::Azerty => Self::Azerty(Keyboard::new(layouts::Azerty, ScancodeSet1, ctrl)),
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        match self {
+            LayoutKeyboard::Us(_) => Layout::Us,
+            LayoutKeyboard::Uk(_) => Layout::Uk,
+            LayoutKeyboard::Dvorak(_) => Layout::Dvorak,
+            LayoutKeyboard::Azerty(_) => Layout::Azerty,
+        }
+    }
+
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        dispatch!(self, keyboard => keyboard.process_keyevent(event))
+    }
+}
+
+/// State of the modifier and lock keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    const fn new() -> Self {
+        Self {
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            left_alt: false,
+            right_alt: false,
+            caps_lock: false,
+            // Matches the initial state of the `pc_keyboard` decoder.
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+
+    /// Argument byte of the "set LEDs" command for the current lock state.
+    fn led_mask(&self) -> u8 {
+        (self.scroll_lock as u8) | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+/// Keyboard command that sets the lock LEDs from the byte that follows it.
+const SET_LEDS: u8 = 0xED;
+/// Number of times a byte of the LED command is sent before giving up on the update.
+const LED_ATTEMPTS: usize = 3;
+
+/// Progress of the "set LEDs" command. The keyboard acknowledges each byte with a reply that
+/// arrives as an interrupt of its own, so the interrupt handler sends the next byte on it rather
+/// than waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedUpdate {
+    Idle,
+    /// [`SET_LEDS`] was sent; the mask follows once it is acknowledged.
+    Command {
+        attempts: usize,
+    },
+    /// The mask was sent.
+    Mask {
+        mask: u8,
+        attempts: usize,
+    },
+}
+
+/// Bitmap of the keys that are currently held down, indexed by [`KeyCode`].
+#[derive(Debug, Clone, Copy)]
+struct PressedKeys([u64; 4]);
+
+impl PressedKeys {
+    fn set(&mut self, code: KeyCode, pressed: bool) {
+        let index = code as usize;
+        let mask = 1 << (index % 64);
+        if pressed {
+            self.0[index / 64] |= mask;
+        } else {
+            self.0[index / 64] &= !mask;
+        }
+    }
+
+    fn contains(&self, code: KeyCode) -> bool {
+        let index = code as usize;
+        self.0[index / 64] & (1 << (index % 64)) != 0
+    }
+}
+
+/// Decoder state owned by the interrupt handler.
+struct KeyboardState {
+    scancodes: ScancodeDecoder,
+    decoder: LayoutKeyboard,
+    modifiers: Modifiers,
+    pressed: PressedKeys,
+    leds: LedUpdate,
+}
+
+impl KeyboardState {
+    /// Updates the modifier and pressed-key state for `event`. Returns whether `event` was a
+    /// kernel hotkey that must not be passed on.
+    fn track(&mut self, event: &KeyEvent) -> bool {
+        let down = event.state == KeyState::Down;
+        // Typematic repeat sends further key-down events, which must not toggle the locks again.
+        let newly_down = down && !self.pressed.contains(event.code);
+        self.pressed.set(event.code, down);
+
+        let modifiers = &mut self.modifiers;
+        match event.code {
+            KeyCode::ShiftLeft => modifiers.left_shift = down,
+            KeyCode::ShiftRight => modifiers.right_shift = down,
+            KeyCode::ControlLeft => modifiers.left_ctrl = down,
+            KeyCode::ControlRight => modifiers.right_ctrl = down,
+            KeyCode::AltLeft => modifiers.left_alt = down,
+            KeyCode::AltRight => modifiers.right_alt = down,
+            KeyCode::CapsLock if newly_down => modifiers.caps_lock = !modifiers.caps_lock,
+            KeyCode::NumpadLock if newly_down => modifiers.num_lock = !modifiers.num_lock,
+            KeyCode::ScrollLock if newly_down => modifiers.scroll_lock = !modifiers.scroll_lock,
+            // Ctrl+Alt+L cycles through the keyboard layouts.
+            KeyCode::L if down && modifiers.ctrl() && modifiers.alt() => {
+                self.decoder = LayoutKeyboard::new(self.decoder.layout().next());
+                crate::console::request_status_refresh();
+                return true;
+            }
+            // Ctrl+Alt+Del reboots, once the file systems are written back.
+            KeyCode::Delete if down && modifiers.ctrl() && modifiers.alt() => {
+                if let Err(error) = REBOOT.schedule() {
+                    crate::serial_println!("keyboard: reboot not queued, rebooting: {:?}", error);
+                    crate::power::reboot()
+                }
+                return true;
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// Starts setting the LEDs to the lock state, unless an update is under way already, which
+    /// picks up the new state when it is done.
+    fn update_leds(&mut self) -> Result<(), Ps2Error> {
+        if self.leds != LedUpdate::Idle {
+            return Ok(());
+        }
+        self.send_led_byte(LedUpdate::Command { attempts: 1 })
+    }
+
+    /// Moves the LED update on after the keyboard replied to its last byte.
+    fn led_reply(&mut self, reply: u8) -> Result<(), Ps2Error> {
+        let next = match (self.leds, reply) {
+            (LedUpdate::Idle, _) => return Ok(()),
+            (LedUpdate::Command { .. }, ps2::ACK) => LedUpdate::Mask {
+                mask: self.modifiers.led_mask(),
+                attempts: 1,
+            },
+            (LedUpdate::Mask { mask, .. }, ps2::ACK) => {
+                self.leds = LedUpdate::Idle;
+                if mask == self.modifiers.led_mask() {
+                    return Ok(());
+                }
+                return self.update_leds();
+            }
+            (LedUpdate::Command { attempts }, ps2::RESEND) if attempts < LED_ATTEMPTS => {
+                LedUpdate::Command {
+                    attempts: attempts + 1,
+                }
+            }
+            (LedUpdate::Mask { mask, attempts }, ps2::RESEND) if attempts < LED_ATTEMPTS => {
+                LedUpdate::Mask {
+                    mask,
+                    attempts: attempts + 1,
+                }
+            }
+            (_, ps2::RESEND) => {
+                self.leds = LedUpdate::Idle;
+                return Err(Ps2Error::Resend);
+            }
+            (_, reply) => {
+                self.leds = LedUpdate::Idle;
+                return Err(Ps2Error::UnexpectedReply(reply));
+            }
+        };
+        self.send_led_byte(next)
+    }
+
+    fn send_led_byte(&mut self, update: LedUpdate) -> Result<(), Ps2Error> {
+        let byte = match update {
+            LedUpdate::Idle => return Ok(()),
+            LedUpdate::Command { .. } => SET_LEDS,
+            LedUpdate::Mask { mask, .. } => mask,
+        };
+        // The reply cannot be handled before the state is updated, since the handler waits for
+        // the lock.
+        if let Err(error) = ps2::write_data(byte) {
+            self.leds = LedUpdate::Idle;
+            return Err(error);
+        }
+        self.leds = update;
+        Ok(())
+    }
+}
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<KeyboardState> = Mutex::new(KeyboardState {
+        scancodes: ScancodeDecoder::new(ScancodeSet::Set1),
+        decoder: LayoutKeyboard::new(Layout::Us),
+        modifiers: Modifiers::new(),
+        pressed: PressedKeys([0; 4]),
+        leds: LedUpdate::Idle,
+    });
+}
+
+/// Keys decoded by the interrupt handler and not yet read.
+///
+/// Only locked with interrupts disabled outside of the interrupt handler, so the handler can
+/// never spin on a lock held by the code it interrupted.
+static QUEUE: Mutex<RingBuffer<DecodedKey, QUEUE_SIZE>> = Mutex::new(RingBuffer::new());
+
+/// Woken when a key is pushed to [`QUEUE`].
+static KEY_WAKER: WakerSlot = WakerSlot::new();
+
+/// Keys waiting to be echoed by [`echo_keys`]. Locked like [`QUEUE`].
+static ECHO_QUEUE: Mutex<RingBuffer<DecodedKey, QUEUE_SIZE>> = Mutex::new(RingBuffer::new());
+
+static ECHO_WAKER: WakerSlot = WakerSlot::new();
+
+/// Raw scancodes received by the interrupt handler and not yet polled. Lock-free, so the
+/// handler and the readers never wait for each other. Allocated by [`init`].
+///
+/// Most of the time nobody polls them, so once the queue is full each new scancode silently
+/// replaces the oldest one.
+static SCANCODES: Once<ArrayQueue<u8>> = Once::new();
+
+/// Woken when a scancode is pushed to [`SCANCODES`].
+static SCANCODE_WAKER: WakerSlot = WakerSlot::new();
+
+/// Number of keys dropped because the queue was full.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether typed characters are echoed to the console.
+static ECHO: AtomicBool = AtomicBool::new(true);
+
+fn interrupt_handler() {
+    // A byte from the mouse is left for the mouse interrupt handler.
+    if ps2::aux_data_pending() {
+        return;
+    }
+    if let Some(scancode) = ps2::read_pending() {
+        handle_scancode(scancode);
+    }
+}
+
+/// Feeds a scancode read from the PS/2 data port into the decoder.
+fn handle_scancode(scancode: u8) {
+    // Replies to commands sent to the keyboard are not keys.
+    if scancode == ps2::ACK || scancode == ps2::RESEND {
+        if let Err(error) = KEYBOARD.lock().led_reply(scancode) {
+            crate::serial_println!("keyboard: failed to set LEDs: {:?}", error);
+        }
+        return;
+    }
+    if let Some(scancodes) = SCANCODES.get() {
+        scancodes.force_push(scancode);
+        SCANCODE_WAKER.wake();
+    }
+    let mut keyboard = KEYBOARD.lock();
+    let Ok(Some(key_event)) = keyboard.scancodes.add_byte(scancode) else {
+        return;
+    };
+    let leds = keyboard.modifiers.led_mask();
+    let hotkey = keyboard.track(&key_event);
+    let new_leds = keyboard.modifiers.led_mask();
+    if new_leds != leds {
+        if let Err(error) = keyboard.update_leds() {
+            crate::serial_println!("keyboard: failed to set LEDs: {:?}", error);
+        }
+    }
+    if hotkey {
+        return;
+    }
+    let down = key_event.state == KeyState::Down;
+    let code = key_event.code;
+    let key = keyboard.decoder.process_keyevent(key_event);
+    let press = KeyPress {
+        code,
+        key,
+        modifiers: keyboard.modifiers,
+    };
+    drop(keyboard);
+
+    if down && subscriptions::notify(press) {
+        return;
+    }
+    let Some(key) = key else {
+        return;
+    };
+
+    // Keys consumed by an open menu are neither echoed nor queued.
+    if crate::tui::handle_key(key) {
+        return;
+    }
+    // Ctrl+C interrupts the foreground program. The key is still queued, so that a program
+    // waiting to read the console gets it and takes the signal on the way back.
+    if key == DecodedKey::Unicode('\u{3}') {
+        crate::process::interrupt_foreground();
+    }
+    if QUEUE.lock().push(key).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    KEY_WAKER.wake();
+    if ECHO.load(Ordering::Relaxed) {
+        if ECHO_QUEUE.lock().push(key).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        ECHO_WAKER.wake();
+    }
+}
+
+/// Echoes typed keys as the interrupt handler queues them. Spawned by [`init`].
+async fn echo_keys() {
+    let mut keys = KeyStream {
+        queue: &ECHO_QUEUE,
+        waker: &ECHO_WAKER,
+    };
+    while let Some(key) = keys.next().await {
+        echo(key);
+    }
+}
+
+fn echo(key: DecodedKey) {
+    match key {
+        // Backspace key, which the writer turns into erasing the previous character.
+        DecodedKey::Unicode('\u{8}') => print!("\u{8}"),
+        // Other control characters (Ctrl+letter, Escape, Delete) have no glyph.
+        DecodedKey::Unicode(character)
+            if character.is_control() && !matches!(character, '\n' | '\t') => {}
+        DecodedKey::Unicode(character) => print!("{}", character),
+        DecodedKey::RawKey(key) => print!("{:?}", key),
+    }
+}
+
+/// Keyboard command that reads or selects the scancode set, depending on the byte that follows.
+const SCANCODE_SET: u8 = 0xF0;
+/// Argument of [`SCANCODE_SET`] that asks for the current set instead of selecting one.
+const GET_SCANCODE_SET: u8 = 0;
+
+/// Detects the scancode set the keyboard sends and sets the LEDs to the initial lock state.
+///
+/// Controller translation is turned off so the keyboard can be asked for its scancode set and
+/// its scancodes decoded as sent. Sets other than 1 and 2 are switched to set 2. If the keyboard
+/// cannot report its set, translation is turned back on and set 1 is decoded.
+pub fn init() -> Result<ScancodeSet, Ps2Error> {
+    SCANCODES.call_once(|| ArrayQueue::new(QUEUE_SIZE));
+    crate::interruptsa::register_irq(KEYBOARD_IRQ, interrupt_handler)
+        .expect("keyboard IRQ already registered");
+    crate::task::spawn(echo_keys());
+    // With interrupts disabled the interrupt handler cannot consume the replies.
+    let set = interrupts::without_interrupts(|| {
+        ps2::flush_output();
+        let config = ps2::read_config()?;
+        ps2::write_config(config & !ps2::CONFIG_TRANSLATION)?;
+        let set = match query_scancode_set() {
+            Ok(1) => ScancodeSet::Set1,
+            Ok(2) => ScancodeSet::Set2,
+            Ok(_) => {
+                ps2::send_keyboard_byte(SCANCODE_SET)?;
+                ps2::send_keyboard_byte(2)?;
+                ScancodeSet::Set2
+            }
+            Err(_) => {
+                ps2::write_config(config | ps2::CONFIG_TRANSLATION)?;
+                ScancodeSet::Set1
+            }
+        };
+        KEYBOARD.lock().scancodes = ScancodeDecoder::new(set);
+        Ok::<ScancodeSet, Ps2Error>(set)
+    })?;
+    sync_leds()?;
+    Ok(set)
+}
+
+fn query_scancode_set() -> Result<u8, Ps2Error> {
+    ps2::send_keyboard_byte(SCANCODE_SET)?;
+    ps2::send_keyboard_byte(GET_SCANCODE_SET)?;
+    ps2::read_data()
+}
+
+/// Returns the scancode set being decoded.
+pub fn scancode_set() -> ScancodeSet {
+    interrupts::without_interrupts(|| KEYBOARD.lock().scancodes.set)
+}
+
+/// Starts setting the keyboard LEDs to match the current lock state. The interrupt handler
+/// sends the rest of the command as the keyboard acknowledges it, on whichever CPU it runs, and
+/// starts it by itself whenever a lock key changes the state. Returns an error if the controller
+/// did not take the first byte.
+pub fn sync_leds() -> Result<(), Ps2Error> {
+    interrupts::without_interrupts(|| KEYBOARD.lock().update_leds())
+}
+
+/// Writes back the file systems and the block caches, which the interrupt handler cannot, and
+/// reboots, for Ctrl+Alt+Del.
+static REBOOT: Work = Work::new(|| {
+    if let Err(error) = crate::vfs::sync() {
+        crate::serial_println!("keyboard: sync before reboot failed: {:?}", error);
+    }
+    if let Err(error) = crate::block::cache::sync() {
+        crate::serial_println!("keyboard: sync before reboot failed: {:?}", error);
+    }
+    crate::power::reboot();
+});
+
+/// Returns a snapshot of the modifier and lock key state.
+pub fn modifiers() -> Modifiers {
+    interrupts::without_interrupts(|| KEYBOARD.lock().modifiers)
+}
+
+/// Returns whether the key is currently held down.
+pub fn is_pressed(code: KeyCode) -> bool {
+    interrupts::without_interrupts(|| KEYBOARD.lock().pressed.contains(code))
+}
+
+/// Returns the active keyboard layout.
+pub fn layout() -> Layout {
+    interrupts::without_interrupts(|| KEYBOARD.lock().decoder.layout())
+}
+
+/// Switches to another keyboard layout. Keys that are held down while switching are forgotten.
+pub fn set_layout(layout: Layout) {
+    interrupts::without_interrupts(|| {
+        KEYBOARD.lock().decoder = LayoutKeyboard::new(layout);
+    });
+    crate::console::request_status_refresh();
+}
+
+/// Enables or disables echoing typed keys to the console. A shell that draws its own input line
+/// turns this off.
+pub fn set_echo(enabled: bool) {
+    ECHO.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns whether typed keys are echoed to the console.
+pub fn echo_enabled() -> bool {
+    ECHO.load(Ordering::Relaxed)
+}
+
+/// Returns the oldest unread key, if any.
+pub fn try_read_key() -> Option<DecodedKey> {
+    interrupts::without_interrupts(|| QUEUE.lock().pop())
+}
+
+/// Returns the oldest raw scancode that has not been polled yet, in the set returned by
+/// [`scancode_set`].
+///
+/// Scancodes are queued independently of decoded keys, so polling them does not consume keys
+/// from [`try_read_key`] and vice versa.
+pub fn poll_scancode() -> Option<u8> {
+    SCANCODES.get()?.pop()
+}
+
+/// The raw scancodes received, read asynchronously. Like [`poll_scancode`], reading consumes
+/// them, so only one reader should be active at a time. Never ends.
+pub struct ScancodeStream(());
+
+impl ScancodeStream {
+    pub fn new() -> ScancodeStream {
+        ScancodeStream(())
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        if let Some(scancode) = poll_scancode() {
+            return Poll::Ready(Some(scancode));
+        }
+        SCANCODE_WAKER.register(context.waker());
+        // A scancode queued before the waker was registered would not wake us.
+        match poll_scancode() {
+            Some(scancode) => Poll::Ready(Some(scancode)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Waits for the next key, running tasks and halting the CPU until one arrives.
+pub fn read_key() -> DecodedKey {
+    crate::task::block_on(keys().next()).expect("key stream ended")
+}
+
+/// The keys typed, read asynchronously. Reading from a stream consumes the keys like
+/// [`try_read_key`] does, so only one reader should be active at a time. Never ends.
+pub struct KeyStream {
+    queue: &'static Mutex<RingBuffer<DecodedKey, QUEUE_SIZE>>,
+    waker: &'static WakerSlot,
+}
+
+impl KeyStream {
+    fn pop(&self) -> Option<DecodedKey> {
+        interrupts::without_interrupts(|| self.queue.lock().pop())
+    }
+}
+
+/// Returns a stream of the typed keys.
+pub fn keys() -> KeyStream {
+    KeyStream {
+        queue: &QUEUE,
+        waker: &KEY_WAKER,
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<DecodedKey>> {
+        if let Some(key) = self.pop() {
+            return Poll::Ready(Some(key));
+        }
+        self.waker.register(context.waker());
+        // A key queued before the waker was registered would not wake us.
+        match self.pop() {
+            Some(key) => Poll::Ready(Some(key)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Returns the oldest unread character, discarding any non-character keys before it.
+pub fn try_read_char() -> Option<char> {
+    while let Some(key) = try_read_key() {
+        if let DecodedKey::Unicode(c) = key {
+            return Some(c);
+        }
+    }
+    None
+}
+
+/// Waits for the next typed character, discarding non-character keys.
+pub fn read_char() -> char {
+    loop {
+        if let DecodedKey::Unicode(c) = read_key() {
+            return c;
+        }
+    }
+}
+
+/// Number of keys dropped so far because nobody read them in time.
+pub fn dropped_keys() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,104 @@
+//! Key event subscriptions.
+//!
+//! Kernel components register a handler for the keys they care about instead of patching the
+//! interrupt handler. Handlers run in the keyboard interrupt handler, in registration order,
+//! before the key reaches menus, the queue and the echo.
+
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::Modifiers;
+
+/// Maximum number of subscriptions at the same time.
+const MAX_SUBSCRIPTIONS: usize = 16;
+
+/// A key press delivered to subscribers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPress {
+    pub code: KeyCode,
+    /// The key decoded with the active layout, if the key produces one. Modifier keys do not.
+    pub key: Option<DecodedKey>,
+    /// The modifier state, including this key if it is a modifier.
+    pub modifiers: Modifiers,
+}
+
+/// Selects the key presses a subscription receives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFilter {
+    /// Every key press.
+    Any,
+    /// Presses of the key, whatever modifiers are held.
+    Key(KeyCode),
+    /// Presses of the key with exactly these modifiers held, such as Ctrl+Alt+F1.
+    Chord {
+        code: KeyCode,
+        ctrl: bool,
+        alt: bool,
+        shift: bool,
+    },
+}
+
+impl KeyFilter {
+    fn matches(&self, press: &KeyPress) -> bool {
+        match *self {
+            KeyFilter::Any => true,
+            KeyFilter::Key(code) => press.code == code,
+            KeyFilter::Chord {
+                code,
+                ctrl,
+                alt,
+                shift,
+            } => {
+                let modifiers = &press.modifiers;
+                press.code == code
+                    && modifiers.ctrl() == ctrl
+                    && modifiers.alt() == alt
+                    && modifiers.shift() == shift
+            }
+        }
+    }
+}
+
+/// Called with each matching key press. Returns whether the key was consumed, in which case it
+/// is not passed to later subscribers, menus or the queue.
+///
+/// Runs in interrupt context: it must not wait for locks held by normal kernel code, such as the
+/// console writer, and must not subscribe or unsubscribe.
+pub type KeyHandler = fn(KeyPress) -> bool;
+
+/// Identifies a subscription for [`unsubscribe`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subscription(usize);
+
+static SUBSCRIPTIONS: Mutex<[Option<(KeyFilter, KeyHandler)>; MAX_SUBSCRIPTIONS]> =
+    Mutex::new([None; MAX_SUBSCRIPTIONS]);
+
+/// Calls `handler` for every key press matching `filter`. Returns `None` if all subscription
+/// slots are taken.
+pub fn on_key(filter: KeyFilter, handler: KeyHandler) -> Option<Subscription> {
+    interrupts::without_interrupts(|| {
+        let mut subscriptions = SUBSCRIPTIONS.lock();
+        let index = subscriptions.iter().position(Option::is_none)?;
+        subscriptions[index] = Some((filter, handler));
+        Some(Subscription(index))
+    })
+}
+
+/// Removes a subscription made with [`on_key`].
+pub fn unsubscribe(subscription: Subscription) {
+    interrupts::without_interrupts(|| {
+        SUBSCRIPTIONS.lock()[subscription.0] = None;
+    });
+}
+
+/// Offers a key press to the subscribers. Returns whether one of them consumed it.
+pub(super) fn notify(press: KeyPress) -> bool {
+    // Copy the table so handlers run without the lock held.
+    let subscriptions = *SUBSCRIPTIONS.lock();
+    subscriptions
+        .iter()
+        .flatten()
+        .filter(|(filter, _)| filter.matches(&press))
+        .any(|(_, handler)| handler(press))
+}
@@ -0,0 +1,321 @@
+#![no_std]
+#![cfg_attr(test, no_main)]
+#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::testing::run)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::config::Mapping;
+use bootloader_api::{BootInfo, BootloaderConfig};
+use core::fmt::Arguments;
+use core::ops::Range;
+use writer::FrameBufferWriter;
+
+pub mod acpi;
+pub mod ahci;
+pub mod apic;
+pub mod ata;
+pub mod block;
+pub mod console;
+pub mod elf;
+pub mod gdt;
+pub mod hpet;
+pub mod interruptsa;
+pub mod kdebug;
+pub mod keyboard;
+pub mod memory;
+pub mod mouse;
+pub mod panic_screen;
+pub mod partition;
+pub mod pci;
+pub mod percpu;
+pub mod pit;
+pub mod power;
+pub mod preempt;
+pub mod process;
+pub mod ps2;
+pub mod ramdisk;
+pub mod random;
+pub mod readline;
+pub mod ring_buffer;
+pub mod rtc;
+pub mod serial;
+pub mod shell;
+pub mod smp;
+pub mod sync;
+pub mod task;
+pub mod thread;
+pub mod time;
+pub mod tui;
+pub mod usermode;
+pub mod vfs;
+pub mod virtio;
+pub mod watchdog;
+pub mod workqueue;
+pub mod writer;
+
+#[cfg(test)]
+pub mod testing;
+
+/// What the kernel asks of the bootloader: all of physical memory mapped, and a 100 KiB stack.
+pub const BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config.kernel_stack_size = 100 * 1024;
+    config
+};
+
+#[cfg(test)]
+bootloader_api::entry_point!(test_entry_point, config = &BOOTLOADER_CONFIG);
+
+/// Entry point of `cargo test`: brings the kernel up and runs the tests.
+#[cfg(test)]
+fn test_entry_point(boot_info: &'static mut BootInfo) -> ! {
+    init(boot_info);
+    test_main();
+    testing::exit_qemu(testing::ExitCode::Success)
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    testing::panic(info)
+}
+
+/// The writer behind `print!`. `None` until [`init`] has found a framebuffer. A blocking lock, so
+/// threads contending for the screen sleep rather than spin.
+pub static FRAME_BUFFER_WRITER: sync::Mutex<Option<FrameBufferWriter>> = sync::Mutex::new(None);
+
+/// Brings up the kernel components: the serial port, the per-CPU area and the framebuffer console
+/// first, so later steps can print, then the GDT, the system call entry, the interrupt handlers,
+/// the APIC and the HPET, the scheduler and the worker thread, the other processors, and finally
+/// the PS/2 devices, the initial ramdisk, the PCI bus, the disks, their partitions and the file
+/// systems on them. The TSC is calibrated right after the serial port, before interrupts can
+/// disturb the measurement.
+pub fn init(boot_info: &'static mut BootInfo) {
+    serial::init();
+    percpu::init();
+    let tsc_frequency = time::init();
+    serial_println!("rtc: booted at {}", rtc::now());
+    serial_println!(
+        "time: TSC at {} MHz ({})",
+        tsc_frequency / 1_000_000,
+        if time::tsc::is_invariant() {
+            "invariant"
+        } else {
+            "not invariant"
+        }
+    );
+    let framebuffer_range = boot_info
+        .framebuffer
+        .as_ref()
+        .map(|framebuffer| (framebuffer.buffer().as_ptr(), framebuffer.info().byte_len));
+    if let Some(framebuffer) = boot_info.framebuffer.as_mut() {
+        console::init(framebuffer);
+    }
+    gdt::init();
+    usermode::syscall::init_cpu();
+    usermode::uaccess::init_cpu();
+    interruptsa::init();
+    if let Some(offset) = boot_info.physical_memory_offset.into_option() {
+        let offset = x86_64::VirtAddr::new(offset);
+        match unsafe { memory::init(&boot_info.memory_regions, offset) } {
+            Ok(frames) => {
+                serial_println!(
+                    "memory: {} MiB usable, {} frames free",
+                    frames.usable * 4096 / (1024 * 1024),
+                    frames.free
+                );
+                serial_println!("memory: layout randomized using {:?}", random::source());
+                let code = kernel_code(
+                    offset,
+                    boot_info.kernel_addr,
+                    boot_info.kernel_len,
+                    boot_info.kernel_image_offset,
+                );
+                if code.is_empty() {
+                    serial_println!("memory: W^X not enforced: kernel code not found");
+                } else {
+                    match memory::enforce_wx(&code) {
+                        Ok(changed) => serial_println!("memory: made {} mappings NX", changed),
+                        Err(error) => serial_println!("memory: W^X not enforced: {:?}", error),
+                    }
+                }
+                if let Some((start, len)) = framebuffer_range {
+                    let start = x86_64::VirtAddr::from_ptr(start);
+                    match unsafe { memory::promote_to_huge_pages(start, len as u64) } {
+                        Ok(pages) => {
+                            serial_println!("memory: framebuffer in {} 2 MiB pages", pages)
+                        }
+                        Err(error) => {
+                            serial_println!("memory: framebuffer not remapped: {:?}", error)
+                        }
+                    }
+                }
+                if memory::vmm::register_current_stack("boot stack").is_none() {
+                    serial_println!("memory: no guard page found beneath the boot stack");
+                }
+            }
+            Err(error) => serial_println!("memory: not available: {:?}", error),
+        }
+        if let Some(rsdp) = boot_info.rsdp_addr.into_option() {
+            match acpi::init(offset, rsdp) {
+                Ok(()) => {
+                    if let Some(madt) = acpi::madt() {
+                        serial_println!(
+                            "acpi: {} processors, {} IO APICs, {} interrupt overrides",
+                            madt.processors.len(),
+                            madt.io_apics.len(),
+                            madt.overrides.len()
+                        );
+                    }
+                    if let Some(fadt) = acpi::fadt() {
+                        serial_println!("acpi: SCI on IRQ {}", fadt.sci_interrupt);
+                    }
+                }
+                Err(error) => serial_println!("acpi: tables not usable: {:?}", error),
+            }
+        }
+        if let Err(error) = interruptsa::enable_apic() {
+            serial_println!("apic: not available, using the 8259 PIC: {:?}", error);
+        }
+        if let Err(error) = watchdog::enable(watchdog::DEFAULT_TIMEOUT_MS) {
+            serial_println!("watchdog: not available: {:?}", error);
+        }
+        match interruptsa::enable_hpet() {
+            Ok(frequency) => serial_println!("hpet: counter at {} kHz", frequency / 1000),
+            Err(error) => serial_println!("hpet: not available, using the PIT: {:?}", error),
+        }
+    }
+    if let Err(error) = thread::init() {
+        serial_println!("thread: scheduler not started: {:?}", error);
+    }
+    if let Err(error) = workqueue::init() {
+        serial_println!("workqueue: worker not started: {:?}", error);
+    }
+    if let Err(error) = block::cache::init() {
+        serial_println!("block: cache flush thread not started: {:?}", error);
+    }
+    match smp::init() {
+        Ok(cpus) => serial_println!("smp: {} CPUs online", cpus),
+        Err(error) => serial_println!("smp: running on the boot CPU only: {:?}", error),
+    }
+    match keyboard::init() {
+        Ok(set) => serial_println!("keyboard: decoding scancode {:?}", set),
+        Err(error) => serial_println!("keyboard: initialization failed: {:?}", error),
+    }
+    if let Err(error) = mouse::init() {
+        serial_println!("mouse: not available: {:?}", error);
+    }
+    if let Some(start) = boot_info.ramdisk_addr.into_option() {
+        let len = boot_info.ramdisk_len;
+        unsafe { ramdisk::init(x86_64::VirtAddr::new(start), len) };
+        serial_println!("ramdisk: {} KiB at {:#x}", len / 1024, start);
+    }
+    let (functions, ecam) = pci::init();
+    let access = if ecam { "ECAM" } else { "I/O ports" };
+    serial_println!(
+        "pci: {} functions, configured through {}",
+        functions,
+        access
+    );
+    match ata::init() {
+        Ok(disks) => serial_println!("ata: {} disks on the primary channel", disks),
+        Err(error) => serial_println!("ata: not available: {:?}", error),
+    }
+    match ahci::init() {
+        Ok(disks) => serial_println!("ahci: {} disks", disks),
+        Err(error) => serial_println!("ahci: not available: {:?}", error),
+    }
+    serial_println!("virtio: {} disks", virtio::block::init());
+    serial_println!("partition: {} partitions", partition::init());
+    if let Some(image) = ramdisk::image() {
+        let mounted = vfs::tar::Tar::new(image).and_then(|archive| {
+            let members = archive.members();
+            vfs::mount("/", alloc::sync::Arc::new(archive)).map(|()| members)
+        });
+        match mounted {
+            Ok(members) => serial_println!("vfs: initrd with {} members mounted at /", members),
+            Err(error) => serial_println!("vfs: initrd not mounted: {:?}", error),
+        }
+    }
+    match vfs::devfs::init() {
+        Ok(devices) => serial_println!("vfs: devfs with {} devices mounted at /dev", devices),
+        Err(error) => serial_println!("vfs: devfs not mounted: {:?}", error),
+    }
+    if let Err(error) = vfs::procfs::init() {
+        serial_println!("vfs: procfs not mounted: {:?}", error);
+    }
+    if let Err(error) = vfs::tmpfs::init() {
+        serial_println!("vfs: tmpfs not mounted: {:?}", error);
+    }
+    match vfs::mount_first("/boot", vfs::fat32::Fat32::new) {
+        Some(device) => serial_println!("vfs: {} mounted at /boot", device),
+        None => serial_println!("vfs: no FAT32 volume for /boot"),
+    }
+    match vfs::mount_first("/mnt", vfs::ext2::Ext2::new) {
+        Some(device) => serial_println!("vfs: {} mounted at /mnt", device),
+        None => serial_println!("vfs: no ext2 volume for /mnt"),
+    }
+}
+
+/// The ranges the kernel's executable segments are loaded at, read from the program headers of
+/// the kernel's ELF image, which the bootloader leaves in physical memory at `kernel_addr`. Empty
+/// if the image cannot be parsed.
+fn kernel_code(
+    physical_memory_offset: x86_64::VirtAddr,
+    kernel_addr: u64,
+    kernel_len: u64,
+    image_offset: u64,
+) -> Vec<Range<x86_64::VirtAddr>> {
+    let image = unsafe {
+        core::slice::from_raw_parts(
+            (physical_memory_offset + kernel_addr).as_ptr::<u8>(),
+            kernel_len as usize,
+        )
+    };
+    let Ok(elf) = elf::Elf::parse_kernel(image) else {
+        return Vec::new();
+    };
+    elf.segments()
+        .filter(|segment| segment.executable && segment.memory_size > 0)
+        .map(|segment| {
+            let start = x86_64::VirtAddr::new(image_offset + segment.address);
+            start..start + segment.memory_size
+        })
+        .collect()
+}
+
+/// Backs `print!`. Interrupts stay disabled while the writer is locked, so an interrupt handler
+/// that prints can never find it locked by the code it interrupted and wait forever. A handler
+/// that interrupts other writer users, such as [`console::set_cursor`], has its output held back
+/// until the writer is free.
+#[doc(hidden)]
+pub fn printx(args: Arguments) {
+    x86_64::instructions::interrupts::without_interrupts(|| console::write_fmt(args));
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::printx(format_args!($($arg)*)));
+}
+
+/// Returns the oldest unread scancode, or 0 if there is none.
+#[deprecated(note = "use `keyboard::poll_scancode` or `keyboard::read_key` instead")]
+#[macro_export]
+macro_rules! input_char {
+    () => {
+        $crate::keyboard::poll_scancode().unwrap_or(0)
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ({
+        $crate::print!("{}\n", core::format_args!($($arg)*));
+    })
+}
@@ -0,0 +1,656 @@
+//! Physical memory and page table management.
+//!
+//! The frame allocator hands out the 4 KiB frames the bootloader's memory map reports as usable.
+//! The mapper edits the active page tables, which it reaches through the bootloader's mapping of
+//! all physical memory at a fixed offset. Both live in globals so every subsystem that needs
+//! memory shares them; when both are locked, the mapper is locked first. On top of them, the
+//! kernel heap backs the `alloc` types, with slab caches for the small allocations, and the
+//! [`vmm`] hands out ranges of virtual memory mapped on demand.
+//!
+//! Mappings are writable or executable, never both: the mapping functions refuse writable pages
+//! without `NO_EXECUTE` outside [`allow_wx`], and [`enforce_wx`] fixes up the mappings the
+//! bootloader made, leaving only the kernel's code executable.
+//!
+//! All CPUs share the page tables. The functions that change or remove a mapping flush it from
+//! the TLB of every CPU through [`crate::smp::flush_tlb`] before they return. User processes each
+//! get an [`AddressSpace`] of their own, which shares the kernel's mappings; the functions here
+//! only edit the kernel's page tables.
+
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+    PageTableEntry, PageTableFlags, PhysFrame, Size2MiB, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub mod address_space;
+pub mod cow;
+pub mod dma;
+pub mod dump;
+pub mod early;
+pub mod frame_allocator;
+pub mod heap;
+pub mod slab;
+pub mod vmm;
+
+pub use address_space::AddressSpace;
+pub use dump::{dump_mappings, Mapping};
+pub use frame_allocator::{BuddyFrameAllocator, FrameLatency, FrameStats};
+pub use heap::{stats as heap_stats, HeapStats};
+pub use slab::SlabStats;
+
+/// Errors that keep memory management from starting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// No usable region is large enough for the frame allocator's order bytes.
+    NoSpaceForMetadata,
+    /// The heap could not be mapped.
+    Heap(PagingError),
+}
+
+/// Errors returned by the page mapping functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// [`init`] has not run.
+    NotInitialized,
+    /// A frame for a new page table could not be allocated.
+    OutOfFrames,
+    /// The page is already mapped, to the given frame.
+    AlreadyMapped(PhysFrame),
+    /// The page is not mapped.
+    NotMapped,
+    /// The address is mapped with pages of another size than the function handles.
+    HugePage,
+    /// The flags make the page both writable and executable, outside [`allow_wx`].
+    WritableExecutable,
+    /// The flags make a kernel page user-accessible, or leave a user page supervisor-only.
+    WrongPrivilege,
+}
+
+impl<S: PageSize> From<MapToError<S>> for PagingError {
+    fn from(error: MapToError<S>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => PagingError::OutOfFrames,
+            MapToError::ParentEntryHugePage => PagingError::HugePage,
+            MapToError::PageAlreadyMapped(frame) => {
+                PagingError::AlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+            }
+        }
+    }
+}
+
+impl From<UnmapError> for PagingError {
+    fn from(error: UnmapError) -> Self {
+        match error {
+            UnmapError::ParentEntryHugePage => PagingError::HugePage,
+            UnmapError::PageNotMapped | UnmapError::InvalidFrameAddress(_) => {
+                PagingError::NotMapped
+            }
+        }
+    }
+}
+
+impl From<FlagUpdateError> for PagingError {
+    fn from(error: FlagUpdateError) -> Self {
+        match error {
+            FlagUpdateError::PageNotMapped => PagingError::NotMapped,
+            FlagUpdateError::ParentEntryHugePage => PagingError::HugePage,
+        }
+    }
+}
+
+/// The frame allocator. `None` until [`init`] has run.
+pub static FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+/// The active page tables. `None` until [`init`] has run.
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// The top-level page table the bootloader left in CR3, which kernel threads run with.
+static KERNEL_PAGE_TABLE: Once<PhysFrame> = Once::new();
+
+/// Bytes in the memory map in total and in usable regions, recorded by [`init`].
+static PHYSICAL_TOTALS: Once<(u64, u64)> = Once::new();
+
+/// A summary of physical memory and heap usage, see [`stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Bytes of physical memory the bootloader's memory map describes, of any kind.
+    pub total: u64,
+    /// Bytes the memory map reports as usable.
+    pub usable: u64,
+    /// Bytes held by firmware, devices, the kernel image and the bootloader.
+    pub reserved: u64,
+    pub frames: FrameStats,
+    pub heap: HeapStats,
+}
+
+/// Whether [`allow_wx`] currently lifts the W^X check.
+static ALLOW_WX: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the frame allocator from the bootloader's memory map, the mapper for the active page
+/// tables, and the heap, after turning on the no-execute bit. Returns the frame counts after the
+/// heap is mapped.
+///
+/// # Safety
+///
+/// The memory map must be the one passed by the bootloader, and the complete physical memory
+/// must be mapped at `physical_memory_offset`. Must only be called once.
+pub unsafe fn init(
+    memory_regions: &[MemoryRegion],
+    physical_memory_offset: VirtAddr,
+) -> Result<FrameStats, MemoryError> {
+    enable_no_execute();
+    let allocator = BuddyFrameAllocator::new(memory_regions, physical_memory_offset)?;
+    let stats = allocator.stats();
+    let (level_4_frame, _) = Cr3::read();
+    let level_4_table = physical_memory_offset + level_4_frame.start_address().as_u64();
+    let mapper = OffsetPageTable::new(
+        &mut *level_4_table.as_mut_ptr::<PageTable>(),
+        physical_memory_offset,
+    );
+    let physical_end = memory_regions
+        .iter()
+        .map(|region| region.end)
+        .max()
+        .unwrap_or(0);
+    PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+    KERNEL_PAGE_TABLE.call_once(|| level_4_frame);
+    PHYSICAL_TOTALS.call_once(|| {
+        let size = |region: &MemoryRegion| region.end - region.start;
+        let total = memory_regions.iter().map(size).sum();
+        let usable = memory_regions
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(size)
+            .sum();
+        (total, usable)
+    });
+    interrupts::without_interrupts(|| {
+        *FRAME_ALLOCATOR.lock() = Some(allocator);
+        *MAPPER.lock() = Some(mapper);
+    });
+    // Fails only without a mapper, which is in place by now.
+    let _ = promote_to_huge_pages(physical_memory_offset, physical_end);
+    vmm::init();
+    heap::init().map_err(MemoryError::Heap)?;
+    Ok(frame_stats().unwrap_or(stats))
+}
+
+/// Maps `len` bytes of device memory at `phys` into a new [`vmm`] region with caching disabled,
+/// and returns the virtual address of `phys`. Drivers reach their registers through this rather
+/// than the physical memory mapping, which is cached.
+///
+/// # Safety
+///
+/// The range must be device memory, not memory the frame allocator hands out.
+pub unsafe fn map_mmio(phys: PhysAddr, len: usize) -> Result<VirtAddr, vmm::VmmError> {
+    let flags = PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    vmm::map_physical(phys, len, flags)
+}
+
+/// Sets `EFER.NXE`, without which the `NO_EXECUTE` bit of page table entries is reserved and
+/// faults. The bootloader normally sets it already.
+fn enable_no_execute() {
+    unsafe { Efer::update(|flags| *flags |= EferFlags::NO_EXECUTE_ENABLE) };
+}
+
+/// Fails with [`PagingError::WritableExecutable`] if `flags` map a page both writable and
+/// executable outside [`allow_wx`].
+fn check_wx(flags: PageTableFlags) -> Result<(), PagingError> {
+    let wx =
+        flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE);
+    if wx && !ALLOW_WX.load(Ordering::Relaxed) {
+        return Err(PagingError::WritableExecutable);
+    }
+    Ok(())
+}
+
+/// Fails with [`PagingError::WrongPrivilege`] unless `flags` make the page user-accessible
+/// exactly if it is a `user` page. Kernel pages stay out of reach of programs, and user pages out
+/// of reach of the kernel but for the copies SMAP lets through.
+fn check_privilege(flags: PageTableFlags, user: bool) -> Result<(), PagingError> {
+    if flags.contains(PageTableFlags::USER_ACCESSIBLE) != user {
+        return Err(PagingError::WrongPrivilege);
+    }
+    Ok(())
+}
+
+/// Runs `f` with the W^X check of the mapping functions lifted, for the rare page that must be
+/// writable and executable, such as code generated at run time. Interrupts are disabled
+/// meanwhile, so nothing else gets to map such pages.
+pub fn allow_wx<R>(f: impl FnOnce() -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let previous = ALLOW_WX.swap(true, Ordering::Relaxed);
+        let result = f();
+        ALLOW_WX.store(previous, Ordering::Relaxed);
+        result
+    })
+}
+
+/// Sets `NO_EXECUTE` on every mapping in the active page tables that lacks it and does not
+/// overlap `code`, the kernel's executable segments, and returns how many entries were changed.
+/// Besides writable mappings such as the bootloader's mapping of physical memory, this covers
+/// read-only data. Mappings of the kernel's code are left alone unless they are writable.
+pub fn enforce_wx(code: &[Range<VirtAddr>]) -> Result<usize, PagingError> {
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+        let offset = mapper.phys_offset();
+        let table = mapper.level_4_table();
+        let changed = unsafe { set_no_execute_outside(table, 4, 0, code, offset) };
+        x86_64::instructions::tlb::flush_all();
+        Ok(changed)
+    })
+}
+
+/// Sets `NO_EXECUTE` on the mappings under `table`, which is at page table `level` and maps the
+/// memory from `base` on, that are writable or lie outside `code`.
+unsafe fn set_no_execute_outside(
+    table: &mut PageTable,
+    level: u8,
+    base: u64,
+    code: &[Range<VirtAddr>],
+    physical_memory_offset: VirtAddr,
+) -> usize {
+    let size = 1u64 << (12 + 9 * (u32::from(level) - 1));
+    let mut changed = 0;
+    for (index, entry) in table.iter_mut().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let start = base + index as u64 * size;
+        if level > 1 && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            let next = physical_memory_offset + entry.addr().as_u64();
+            changed += set_no_execute_outside(
+                &mut *next.as_mut_ptr::<PageTable>(),
+                level - 1,
+                start,
+                code,
+                physical_memory_offset,
+            );
+            continue;
+        }
+        if flags.contains(PageTableFlags::NO_EXECUTE) {
+            continue;
+        }
+        // The upper half of the address space starts at a sign-extended address.
+        let start = VirtAddr::new_truncate(start).as_u64();
+        let end = start.saturating_add(size);
+        let is_code = code
+            .iter()
+            .any(|range| range.start.as_u64() < end && start < range.end.as_u64());
+        if flags.contains(PageTableFlags::WRITABLE) || !is_code {
+            entry.set_flags(flags | PageTableFlags::NO_EXECUTE);
+            changed += 1;
+        }
+    }
+    changed
+}
+
+/// Virtual address at which the bootloader mapped all physical memory, or `None` before [`init`].
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    PHYSICAL_MEMORY_OFFSET.get().copied()
+}
+
+/// The frame of the kernel's top-level page table, or `None` before [`init`].
+pub fn kernel_page_table() -> Option<PhysFrame> {
+    KERNEL_PAGE_TABLE.get().copied()
+}
+
+/// Virtual address of a physical address in the bootloader's mapping of physical memory.
+pub fn phys_to_virt(address: PhysAddr) -> Option<VirtAddr> {
+    physical_memory_offset().map(|offset| offset + address.as_u64())
+}
+
+/// Maps `page` to `frame` with `flags` in the active page tables and flushes it from the TLB.
+/// Page tables that are missing are allocated from the frame allocator.
+///
+/// # Safety
+///
+/// Mapping a frame that is in use elsewhere, or remapping memory the kernel relies on, breaks
+/// memory safety.
+pub unsafe fn map_page(
+    page: Page<Size4KiB>,
+    frame: PhysFrame<Size4KiB>,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    check_wx(flags)?;
+    check_privilege(flags, false)?;
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().ok_or(PagingError::NotInitialized)?;
+        // Intermediate tables must allow whatever the final entry allows.
+        let parent_flags = flags
+            & (PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE);
+        mapper
+            .map_to_with_table_flags(page, frame, flags, parent_flags, allocator)?
+            .flush();
+        Ok(())
+    })
+}
+
+/// Changes the flags of the mapped `page` and flushes it from the TLB.
+///
+/// # Safety
+///
+/// Taking away permissions the kernel relies on breaks memory safety.
+pub unsafe fn set_flags(page: Page<Size4KiB>, flags: PageTableFlags) -> Result<(), PagingError> {
+    check_wx(flags)?;
+    check_privilege(flags, false)?;
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+        mapper.update_flags(page, flags)?.flush();
+        Ok(())
+    })?;
+    crate::smp::flush_tlb(page.start_address(), 1);
+    Ok(())
+}
+
+/// Points the mapped `page` at `frame` with `flags` instead and flushes it from the TLB. Returns
+/// the frame it was mapped to.
+///
+/// # Safety
+///
+/// As for [`map_page`]; the contents of the page change to those of `frame`.
+pub unsafe fn replace_page(
+    page: Page<Size4KiB>,
+    frame: PhysFrame<Size4KiB>,
+    flags: PageTableFlags,
+) -> Result<PhysFrame<Size4KiB>, PagingError> {
+    check_wx(flags)?;
+    check_privilege(flags, false)?;
+    let old_frame = interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().ok_or(PagingError::NotInitialized)?;
+        // Interrupts are off, so nothing on this CPU sees the page between the two steps.
+        let (old_frame, flush) = mapper.unmap(page)?;
+        flush.ignore();
+        mapper.map_to(page, frame, flags, allocator)?.flush();
+        Ok(old_frame)
+    })?;
+    crate::smp::flush_tlb(page.start_address(), 1);
+    Ok(old_frame)
+}
+
+/// Removes the mapping of `page` from the active page tables and flushes it from the TLB.
+/// Returns the frame it was mapped to, which the caller may deallocate.
+///
+/// # Safety
+///
+/// Nothing may use the page any more.
+pub unsafe fn unmap_page(page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, PagingError> {
+    let frame = interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })?;
+    crate::smp::flush_tlb(page.start_address(), 1);
+    Ok(frame)
+}
+
+/// Maps the 2 MiB `page` to `frame` with `flags` in the active page tables and flushes it from
+/// the TLB. Page tables that are missing are allocated from the frame allocator.
+///
+/// # Safety
+///
+/// As for [`map_page`].
+pub unsafe fn map_huge_page(
+    page: Page<Size2MiB>,
+    frame: PhysFrame<Size2MiB>,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    check_wx(flags)?;
+    check_privilege(flags, false)?;
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().ok_or(PagingError::NotInitialized)?;
+        let parent_flags = flags
+            & (PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE);
+        mapper
+            .map_to_with_table_flags(page, frame, flags, parent_flags, allocator)?
+            .flush();
+        Ok(())
+    })
+}
+
+/// Removes the mapping of the 2 MiB `page` and flushes it from the TLB. Returns the frame it was
+/// mapped to.
+///
+/// # Safety
+///
+/// Nothing may use the page any more.
+pub unsafe fn unmap_huge_page(page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, PagingError> {
+    let frame = interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        Ok(frame)
+    })?;
+    // Flushing any address in the page drops the whole 2 MiB entry.
+    crate::smp::flush_tlb(page.start_address(), 1);
+    Ok(frame)
+}
+
+/// Replaces the mapping of the 2 MiB `page` with 512 4 KiB pages mapping the same memory with
+/// the same flags, so that parts of it can be remapped or unmapped. The new page table is
+/// allocated from the frame allocator.
+///
+/// The entry is swapped in one write, so the memory stays mapped throughout, even if it holds the
+/// code or the stack doing the split.
+///
+/// # Safety
+///
+/// Nothing may hold references into the page tables.
+pub unsafe fn split_huge_page(page: Page<Size2MiB>) -> Result<(), PagingError> {
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+        let offset = mapper.phys_offset();
+        let level_3 = next_table(&mut mapper.level_4_table()[page.p4_index()], offset)?;
+        let level_2 = next_table(&mut level_3[page.p3_index()], offset)?;
+        let entry = &mut level_2[page.p2_index()];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return Err(PagingError::NotMapped);
+        }
+        if !flags.contains(PageTableFlags::HUGE_PAGE) {
+            return Err(PagingError::HugePage);
+        }
+        let table_frame = FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .ok_or(PagingError::NotInitialized)?
+            .allocate_frame()
+            .ok_or(PagingError::OutOfFrames)?;
+        let table = &mut *(offset + table_frame.start_address().as_u64()).as_mut_ptr::<PageTable>();
+        // In a 4 KiB entry the huge page bit selects the memory type instead.
+        let page_flags = flags - PageTableFlags::HUGE_PAGE;
+        for (index, page_entry) in table.iter_mut().enumerate() {
+            page_entry.set_addr(entry.addr() + index as u64 * Size4KiB::SIZE, page_flags);
+        }
+        let parent_flags = flags
+            & (PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE);
+        entry.set_addr(table_frame.start_address(), parent_flags);
+        x86_64::instructions::tlb::flush(page.start_address());
+        Ok(())
+    })?;
+    crate::smp::flush_tlb(page.start_address(), 1);
+    Ok(())
+}
+
+/// Replaces the 4 KiB mappings in the `len` bytes from `start` with 2 MiB pages wherever an
+/// aligned 2 MiB of it maps aligned, contiguous physical memory with the same flags, undoing
+/// [`split_huge_page`]. Returns the number of 2 MiB pages made. Large windows such as the
+/// physical memory mapping and the framebuffer take far fewer TLB entries this way.
+///
+/// The page tables left unused belong to the bootloader, not the frame allocator, so they are not
+/// freed.
+///
+/// # Safety
+///
+/// Nothing may hold references into the page tables.
+pub unsafe fn promote_to_huge_pages(start: VirtAddr, len: u64) -> Result<usize, PagingError> {
+    let end = start.as_u64().saturating_add(len);
+    let mut address = start.align_up(Size2MiB::SIZE).as_u64();
+    let mut promoted = 0;
+    while end.saturating_sub(address) >= Size2MiB::SIZE {
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(address));
+        let changed = interrupts::without_interrupts(|| {
+            let mut mapper = MAPPER.lock();
+            let mapper = mapper.as_mut().ok_or(PagingError::NotInitialized)?;
+            let offset = mapper.phys_offset();
+            let tables = next_table(&mut mapper.level_4_table()[page.p4_index()], offset)
+                .and_then(|level_3| next_table(&mut level_3[page.p3_index()], offset));
+            let Ok(level_2) = tables else {
+                return Ok(false);
+            };
+            let entry = &mut level_2[page.p2_index()];
+            let Ok(level_1) = next_table(entry, offset) else {
+                return Ok(false);
+            };
+            let Some(flags) = huge_page_flags(level_1) else {
+                return Ok(false);
+            };
+            entry.set_addr(level_1[0].addr(), flags | PageTableFlags::HUGE_PAGE);
+            Ok::<_, PagingError>(true)
+        })?;
+        if changed {
+            crate::smp::flush_tlb(page.start_address(), Size2MiB::SIZE / Size4KiB::SIZE);
+            promoted += 1;
+        }
+        address += Size2MiB::SIZE;
+    }
+    Ok(promoted)
+}
+
+/// The flags for a 2 MiB entry standing in for the level 1 `table`, or `None` unless its entries
+/// map 2 MiB of aligned, contiguous physical memory with the same flags.
+fn huge_page_flags(table: &PageTable) -> Option<PageTableFlags> {
+    let ignored = PageTableFlags::ACCESSED | PageTableFlags::DIRTY;
+    let base = table[0].addr();
+    let flags = table[0].flags() - ignored;
+    // In a 4 KiB entry the huge page bit selects the memory type, which a 2 MiB entry keeps in
+    // another bit, so such tables are left alone.
+    if !flags.contains(PageTableFlags::PRESENT)
+        || flags.contains(PageTableFlags::HUGE_PAGE)
+        || !base.is_aligned(Size2MiB::SIZE)
+    {
+        return None;
+    }
+    let contiguous = table.iter().enumerate().all(|(index, entry)| {
+        entry.addr() == base + index as u64 * Size4KiB::SIZE && entry.flags() - ignored == flags
+    });
+    contiguous.then_some(flags)
+}
+
+/// Returns the table `entry` points to, reached through the physical memory mapping.
+unsafe fn next_table(
+    entry: &mut PageTableEntry,
+    physical_memory_offset: VirtAddr,
+) -> Result<&'static mut PageTable, PagingError> {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return Err(PagingError::NotMapped);
+    }
+    if flags.contains(PageTableFlags::HUGE_PAGE) {
+        return Err(PagingError::HugePage);
+    }
+    let table = physical_memory_offset + entry.addr().as_u64();
+    Ok(&mut *table.as_mut_ptr::<PageTable>())
+}
+
+/// Translates a virtual address through the active page tables, or returns `None` if it is not
+/// mapped or [`init`] has not run. Works for huge pages too.
+pub fn translate_addr(address: VirtAddr) -> Option<PhysAddr> {
+    interrupts::without_interrupts(|| MAPPER.lock().as_ref()?.translate_addr(address))
+}
+
+/// Translates a virtual address and also returns the flags of the mapping.
+pub fn translate(address: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    interrupts::without_interrupts(|| match MAPPER.lock().as_ref()?.translate(address) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } => Some((frame.start_address() + offset, flags)),
+        TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+    })
+}
+
+/// Allocates a physical frame, or returns `None` if memory is exhausted or [`init`] has not run.
+pub fn allocate_frame() -> Option<PhysFrame<Size4KiB>> {
+    interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame())
+}
+
+/// Returns a frame obtained from [`allocate_frame`].
+///
+/// # Safety
+///
+/// The frame must no longer be mapped or otherwise in use.
+pub unsafe fn deallocate_frame(frame: PhysFrame<Size4KiB>) {
+    interrupts::without_interrupts(|| {
+        if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            allocator.deallocate_frame(frame);
+        }
+    });
+}
+
+/// Allocates `count` physically contiguous frames below `limit` and returns the first, or `None`
+/// if there is no such run or [`init`] has not run.
+pub fn allocate_contiguous_frames(count: usize, limit: PhysAddr) -> Option<PhysFrame<Size4KiB>> {
+    interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR
+            .lock()
+            .as_mut()?
+            .allocate_contiguous(count, limit)
+    })
+}
+
+/// Returns how much physical memory there is, how much of it is allocated, and the heap usage,
+/// or `None` if [`init`] has not run.
+pub fn stats() -> Option<MemoryStats> {
+    let &(total, usable) = PHYSICAL_TOTALS.get()?;
+    Some(MemoryStats {
+        total,
+        usable,
+        reserved: total - usable,
+        frames: frame_stats()?,
+        heap: heap::stats(),
+    })
+}
+
+/// Returns the current frame counts, or `None` if [`init`] has not run.
+pub fn frame_stats() -> Option<FrameStats> {
+    interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().as_ref().map(|a| a.stats()))
+}
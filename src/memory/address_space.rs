@@ -0,0 +1,356 @@
+//! Address spaces of user processes.
+//!
+//! An [`AddressSpace`] has a top-level page table of its own. The entries covering the user part
+//! of the address space, from [`USER_START`] to [`USER_END`], are private to it. All other entries
+//! are copied from the kernel's top-level table when the address space is created, so the tables
+//! below them are shared and the kernel is mapped the same everywhere. The bootloader places the
+//! kernel's mappings wherever there is room rather than in the higher half, so the kernel's half
+//! is simply every entry but the user ones.
+//!
+//! [`AddressSpace::fork`] duplicates the user part copy-on-write (see [`super::cow`]): both
+//! address spaces map the same frames, writable pages turn read-only until the first write to
+//! them, and a frame is freed with the last address space that maps it.
+//!
+//! A top-level entry the kernel adds later is missing from the address spaces created before.
+//! The first kernel access through it faults, and [`sync_kernel_entry`] copies it over from the
+//! page fault handler.
+
+use core::ops::Range;
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageTable, PageTableEntry, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{check_privilege, check_wx, cow, PagingError, FRAME_ALLOCATOR};
+use crate::usermode::{USER_END, USER_START};
+
+/// The top-level entries private to each address space.
+const USER_ENTRIES: Range<usize> = p4_index(USER_START)..p4_index(USER_END - 1) + 1;
+
+const fn p4_index(address: u64) -> usize {
+    (address >> 39) as usize & 0x1ff
+}
+
+/// The page tables of a user process, freed along with the user memory mapped in them when
+/// dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with the kernel mapped and nothing in the user part.
+    pub fn new() -> Result<Self, PagingError> {
+        let kernel = super::kernel_page_table().ok_or(PagingError::NotInitialized)?;
+        let level_4 = super::allocate_frame().ok_or(PagingError::OutOfFrames)?;
+        unsafe {
+            let kernel = &*table(kernel);
+            for (index, entry) in (*table(level_4)).iter_mut().enumerate() {
+                if USER_ENTRIES.contains(&index) {
+                    entry.set_unused();
+                } else {
+                    *entry = kernel[index].clone();
+                }
+            }
+        }
+        Ok(AddressSpace { level_4 })
+    }
+
+    /// The frame of the top-level page table, for CR3.
+    pub fn page_table(&self) -> PhysFrame {
+        self.level_4
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'_> {
+        let offset = super::physical_memory_offset().expect("memory initialized");
+        unsafe { OffsetPageTable::new(&mut *table(self.level_4), offset) }
+    }
+
+    /// Maps the user `page` to `frame` with `flags`, which must include `USER_ACCESSIBLE`. The
+    /// frame then belongs to the address space.
+    ///
+    /// # Safety
+    ///
+    /// The frame must not be in use elsewhere.
+    pub unsafe fn map_page(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        debug_assert!(USER_ENTRIES.contains(&usize::from(page.p4_index())));
+        check_wx(flags)?;
+        check_privilege(flags, true)?;
+        let mut mapper = self.mapper();
+        interrupts::without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let allocator = allocator.as_mut().ok_or(PagingError::NotInitialized)?;
+            let parent_flags = flags
+                & (PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE);
+            // The page was not mapped, so no TLB holds it.
+            mapper
+                .map_to_with_table_flags(page, frame, flags, parent_flags, allocator)?
+                .ignore();
+            Ok(())
+        })
+    }
+
+    /// Maps the user `page` to a new zeroed frame with `flags`.
+    pub fn map_zeroed(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        let frame = super::allocate_frame().ok_or(PagingError::OutOfFrames)?;
+        let virt = super::phys_to_virt(frame.start_address()).expect("memory initialized");
+        unsafe {
+            virt.as_mut_ptr::<u8>().write_bytes(0, 4096);
+            if let Err(error) = self.map_page(page, frame, flags) {
+                super::deallocate_frame(frame);
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps the user `page` and frees its frame unless another address space maps it too. The
+    /// page tables stay, empty or not, until the address space is cleared.
+    pub fn unmap(&mut self, page: Page<Size4KiB>) -> Result<(), PagingError> {
+        let active = self.is_active();
+        let entry = self.entry(page).ok_or(PagingError::NotMapped)?;
+        let frame = PhysFrame::containing_address(entry.addr());
+        entry.set_unused();
+        if active {
+            tlb::flush(page.start_address());
+        }
+        if cow::release(frame) {
+            unsafe { super::deallocate_frame(frame) };
+        }
+        Ok(())
+    }
+
+    /// Translates a virtual address and also returns the flags of the mapping.
+    pub fn translate(&self, address: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.mapper().translate(address) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((frame.start_address() + offset, flags)),
+            TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+        }
+    }
+
+    /// Copies `bytes` to `address`, which must be mapped, whatever the flags of the mapping.
+    /// Copy-on-write pages get a frame of their own first.
+    pub fn write(&mut self, address: VirtAddr, bytes: &[u8]) -> Result<(), PagingError> {
+        let mut address = address;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let (_, flags) = self.translate(address).ok_or(PagingError::NotMapped)?;
+            if flags.contains(cow::COW) && !self.break_cow(address) {
+                return Err(PagingError::OutOfFrames);
+            }
+            let (phys, _) = self.translate(address).ok_or(PagingError::NotMapped)?;
+            let length = bytes.len().min(4096 - address.page_offset() as usize);
+            let target = super::phys_to_virt(phys).expect("memory initialized");
+            unsafe {
+                core::ptr::copy_nonoverlapping(bytes.as_ptr(), target.as_mut_ptr(), length);
+            }
+            address += length as u64;
+            bytes = &bytes[length..];
+        }
+        Ok(())
+    }
+
+    /// Duplicates the address space. The copy maps the same frames as this one, with writable
+    /// pages made copy-on-write in both.
+    pub fn fork(&mut self) -> Result<AddressSpace, PagingError> {
+        let mut copy = AddressSpace::new()?;
+        let result = self.for_each_page(|page, entry| {
+            let frame = PhysFrame::containing_address(entry.addr());
+            let mut flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | cow::COW;
+                entry.set_flags(flags);
+            }
+            // The frame is shared, and counted as such once mapped.
+            unsafe { copy.map_page(page, frame, flags)? };
+            cow::share(frame);
+            Ok(())
+        });
+        // Pages that were writable are not any more.
+        self.flush();
+        result.map(|()| copy)
+    }
+
+    /// Unmaps everything in the user part and frees the page tables and the frames no other
+    /// address space maps.
+    pub fn clear(&mut self) {
+        unsafe {
+            let level_4 = &mut *table(self.level_4);
+            for index in USER_ENTRIES {
+                free_table(&mut level_4[index], 3);
+            }
+        }
+        self.flush();
+    }
+
+    /// Gives the copy-on-write page at `address` a frame of its own and makes it writable, as
+    /// a write to it would. Returns `false` if the page is not copy-on-write or memory runs out.
+    ///
+    /// Called from the page fault handler for the running process, so it does not block.
+    pub fn break_cow(&mut self, address: VirtAddr) -> bool {
+        let page = Page::<Size4KiB>::containing_address(address);
+        let active = self.is_active();
+        let Some(entry) = self.entry(page) else {
+            return false;
+        };
+        let flags = entry.flags();
+        if !flags.contains(cow::COW) {
+            return false;
+        }
+        let frame = PhysFrame::containing_address(entry.addr());
+        let writable = (flags - cow::COW) | PageTableFlags::WRITABLE;
+        cow::resolve(frame, |target| {
+            entry.set_addr(target.start_address(), writable);
+            if active {
+                tlb::flush(page.start_address());
+            }
+            true
+        })
+    }
+
+    fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4
+    }
+
+    /// Flushes the user part from the TLB if the address space is loaded. Other CPUs do not
+    /// hold it: a process has a single thread, and switching away from it reloads CR3.
+    fn flush(&self) {
+        let (frame, flags) = Cr3::read();
+        if frame == self.level_4 {
+            unsafe { Cr3::write(frame, flags) };
+        }
+    }
+
+    /// The entry mapping the user `page`, if it is mapped.
+    fn entry(&mut self, page: Page<Size4KiB>) -> Option<&mut PageTableEntry> {
+        let mut level = unsafe { &mut *table(self.level_4) };
+        for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+            level = unsafe { next_table(&level[index])? };
+        }
+        let entry = &mut level[page.p1_index()];
+        entry
+            .flags()
+            .contains(PageTableFlags::PRESENT)
+            .then_some(entry)
+    }
+
+    /// Calls `f` with every mapped user page and its entry, stopping at the first error.
+    fn for_each_page(
+        &mut self,
+        mut f: impl FnMut(Page<Size4KiB>, &mut PageTableEntry) -> Result<(), PagingError>,
+    ) -> Result<(), PagingError> {
+        let level_4 = unsafe { &mut *table(self.level_4) };
+        for i4 in USER_ENTRIES {
+            let Some(level_3) = (unsafe { next_table(&level_4[i4]) }) else {
+                continue;
+            };
+            for i3 in 0..512 {
+                let Some(level_2) = (unsafe { next_table(&level_3[i3]) }) else {
+                    continue;
+                };
+                for i2 in 0..512 {
+                    let Some(level_1) = (unsafe { next_table(&level_2[i2]) }) else {
+                        continue;
+                    };
+                    for (i1, entry) in level_1.iter_mut().enumerate() {
+                        if !entry.flags().contains(PageTableFlags::PRESENT) {
+                            continue;
+                        }
+                        let address = (i4 << 39) | (i3 << 30) | (i2 << 21) | (i1 << 12);
+                        f(
+                            Page::containing_address(VirtAddr::new(address as u64)),
+                            entry,
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        debug_assert!(!self.is_active(), "active address space dropped");
+        self.clear();
+        unsafe { super::deallocate_frame(self.level_4) };
+    }
+}
+
+/// Frees the table `entry` points to at page table `level`, the tables below it, and the frames
+/// they map that are not shared copy-on-write.
+unsafe fn free_table(entry: &mut PageTableEntry, level: u8) {
+    if entry.is_unused() {
+        return;
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+    let next = &mut *table(frame);
+    for child in next.iter_mut().filter(|child| !child.is_unused()) {
+        if level > 1 {
+            free_table(child, level - 1);
+        } else {
+            let frame = PhysFrame::containing_address(child.addr());
+            if cow::release(frame) {
+                super::deallocate_frame(frame);
+            }
+        }
+    }
+    entry.set_unused();
+    super::deallocate_frame(frame);
+}
+
+/// The table below `entry`, if it points to one.
+unsafe fn next_table<'a>(entry: &PageTableEntry) -> Option<&'a mut PageTable> {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+        return None;
+    }
+    Some(&mut *table(PhysFrame::containing_address(entry.addr())))
+}
+
+/// The page table in `frame`, through the physical memory mapping.
+unsafe fn table(frame: PhysFrame) -> *mut PageTable {
+    let offset = super::physical_memory_offset().expect("memory initialized");
+    (offset + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+/// Copies the kernel's top-level entry covering `address` into the active top-level table if it
+/// is missing there. Returns whether it was, so that the faulting access can be retried.
+pub(crate) fn sync_kernel_entry(address: VirtAddr) -> bool {
+    let index = usize::from(address.p4_index());
+    let Some(kernel) = super::kernel_page_table() else {
+        return false;
+    };
+    let (active, _) = Cr3::read();
+    if active == kernel || USER_ENTRIES.contains(&index) {
+        return false;
+    }
+    unsafe {
+        let source = &(*table(kernel))[index];
+        let entry = &mut (*table(active))[index];
+        if source.is_unused() || !entry.is_unused() {
+            return false;
+        }
+        *entry = source.clone();
+    }
+    true
+}
@@ -0,0 +1,131 @@
+//! Copy-on-write frames.
+//!
+//! A frame shared copy-on-write is mapped read-only in every page that refers to it, with the
+//! [`COW`] bit set in the entries, and its references are counted here. The first write through
+//! any of those pages faults, and [`handle_fault`] gives that page a private copy of the frame,
+//! or, for the last reference, just makes the page writable again. Frames that are not shared
+//! are not in the table; they have one reference.
+
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Page table entry bit, free for the OS to use, marking a page that becomes writable by copying.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Reference counts of the frames mapped by more than one page, by physical address.
+static REFERENCES: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+
+/// Adds a reference to `frame`, which is about to be mapped by one more page.
+pub fn share(frame: PhysFrame<Size4KiB>) {
+    interrupts::without_interrupts(|| {
+        let mut references = REFERENCES.lock();
+        *references
+            .entry(frame.start_address().as_u64())
+            .or_insert(1) += 1;
+    });
+}
+
+/// Drops a reference to `frame`, whose page was unmapped. Returns whether it was the last one, in
+/// which case the frame may be deallocated.
+pub fn release(frame: PhysFrame<Size4KiB>) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut references = REFERENCES.lock();
+        let address = frame.start_address().as_u64();
+        match references.get_mut(&address) {
+            None => true,
+            Some(count) => {
+                *count -= 1;
+                if *count == 1 {
+                    references.remove(&address);
+                }
+                false
+            }
+        }
+    })
+}
+
+/// Returns how many pages map `frame`, as far as copy-on-write sharing is concerned.
+pub fn references(frame: PhysFrame<Size4KiB>) -> usize {
+    interrupts::without_interrupts(|| {
+        let references = REFERENCES.lock();
+        references
+            .get(&frame.start_address().as_u64())
+            .copied()
+            .unwrap_or(1)
+    })
+}
+
+/// Resolves a write fault at `address` if it hit a copy-on-write page in the kernel's page
+/// tables. Returns `false` if the page is not copy-on-write or the fault could not be resolved,
+/// which leaves it to the caller to report. Pages of user processes are resolved by
+/// [`super::AddressSpace::break_cow`].
+pub fn handle_fault(address: VirtAddr) -> bool {
+    let Some((physical, flags)) = super::translate(address) else {
+        return false;
+    };
+    if !flags.contains(COW) || flags.contains(PageTableFlags::HUGE_PAGE) {
+        return false;
+    }
+    let page = Page::<Size4KiB>::containing_address(address);
+    let frame = PhysFrame::<Size4KiB>::containing_address(physical);
+    let writable = (flags - COW) | PageTableFlags::WRITABLE;
+    resolve(frame, |target| unsafe {
+        if target == frame {
+            super::set_flags(page, writable).is_ok()
+        } else {
+            super::replace_page(page, target, writable).is_ok()
+        }
+    })
+}
+
+/// Gives a page that maps the copy-on-write `frame` a frame of its own: `frame` itself if no
+/// other page shares it any more, otherwise a copy. `remap` points the page at that frame and
+/// makes it writable, and returns whether it could. Returns `false`, with nothing changed, if
+/// memory runs out.
+///
+/// The reference table is only locked with interrupts disabled, so the page fault handler can
+/// wait for it.
+pub(crate) fn resolve(
+    frame: PhysFrame<Size4KiB>,
+    remap: impl FnOnce(PhysFrame<Size4KiB>) -> bool,
+) -> bool {
+    interrupts::without_interrupts(|| resolve_locked(frame, remap))
+}
+
+fn resolve_locked(
+    frame: PhysFrame<Size4KiB>,
+    remap: impl FnOnce(PhysFrame<Size4KiB>) -> bool,
+) -> bool {
+    let mut references = REFERENCES.lock();
+    let key = frame.start_address().as_u64();
+    let Some(count) = references.get_mut(&key) else {
+        // The other pages are gone, so this one can have the frame to itself.
+        return remap(frame);
+    };
+    let Some(copy) = super::allocate_frame() else {
+        return false;
+    };
+    let (Some(from), Some(to)) = (
+        super::phys_to_virt(frame.start_address()),
+        super::phys_to_virt(copy.start_address()),
+    ) else {
+        return false;
+    };
+    unsafe {
+        core::ptr::copy_nonoverlapping(from.as_ptr::<u8>(), to.as_mut_ptr::<u8>(), PAGE_SIZE);
+        if !remap(copy) {
+            super::deallocate_frame(copy);
+            return false;
+        }
+    }
+    *count -= 1;
+    if *count == 1 {
+        references.remove(&key);
+    }
+    true
+}
@@ -0,0 +1,85 @@
+//! Buffers for device DMA.
+//!
+//! Devices address memory physically, so a buffer they read or write must be one physically
+//! contiguous run of frames. Legacy devices with 32-bit address registers also need it below
+//! 4 GiB, so every buffer is placed there. x86 keeps DMA coherent with the caches, so the CPU
+//! reaches the buffers through the cached physical memory mapping.
+//!
+//! A [`DmaBuffer`] owns its frames and returns them to the frame allocator when dropped, so its
+//! owner must stop the device from accessing it first.
+
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Buffers lie entirely below this address.
+const DMA_LIMIT: u64 = 1 << 32;
+
+/// Errors returned by [`alloc_coherent`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaError {
+    /// A buffer of zero bytes was requested.
+    ZeroSize,
+    /// There is no free contiguous run of frames large enough below 4 GiB, or memory management
+    /// is not set up.
+    OutOfMemory,
+}
+
+/// A physically contiguous, zeroed buffer below 4 GiB. Its frames are freed when it is dropped.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    /// Address through which the CPU accesses the buffer.
+    pub virt: VirtAddr,
+    /// Address to program into the device.
+    pub phys: PhysAddr,
+    /// Size of the buffer in bytes, rounded up to whole frames.
+    pub len: usize,
+}
+
+impl DmaBuffer {
+    /// The buffer contents.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.len) }
+    }
+
+    /// The buffer contents, for filling in what the device should read.
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.len) }
+    }
+
+    fn frames(&self) -> impl Iterator<Item = PhysFrame> {
+        let first = PhysFrame::containing_address(self.phys);
+        (0..self.len as u64 / FRAME_SIZE).map(move |index| first + index)
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        for frame in self.frames() {
+            // The buffer is only reachable through the physical memory mapping, and its owner has
+            // stopped the device.
+            unsafe { super::deallocate_frame(frame) };
+        }
+    }
+}
+
+/// Allocates a zeroed buffer of at least `len` bytes that is physically contiguous and lies below
+/// 4 GiB.
+pub fn alloc_coherent(len: usize) -> Result<DmaBuffer, DmaError> {
+    if len == 0 {
+        return Err(DmaError::ZeroSize);
+    }
+    let frames = (len as u64).div_ceil(FRAME_SIZE);
+    let first = super::allocate_contiguous_frames(frames as usize, PhysAddr::new(DMA_LIMIT))
+        .ok_or(DmaError::OutOfMemory)?;
+    let phys = first.start_address();
+    let virt = super::phys_to_virt(phys).expect("frames allocated before memory is set up");
+    let mut buffer = DmaBuffer {
+        virt,
+        phys,
+        len: (frames * FRAME_SIZE) as usize,
+    };
+    buffer.as_mut_slice().fill(0);
+    Ok(buffer)
+}
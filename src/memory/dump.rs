@@ -0,0 +1,162 @@
+//! Listing the live page table mappings, to diagnose mapping bugs.
+//!
+//! The walk starts from CR3 and reads the tables through the physical memory mapping without
+//! taking the mapper's lock, so it also works from the page fault handler. It only reads, but
+//! does not stop the tables from changing under it.
+
+use core::fmt;
+use core::ops::Range;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Flags that change without the mapping changing, and are left out when comparing mappings.
+const VOLATILE_FLAGS: PageTableFlags = PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+
+/// A run of virtual memory mapped to contiguous physical memory with the same flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub phys: PhysAddr,
+    pub flags: PageTableFlags,
+}
+
+impl Mapping {
+    /// Returns whether `next` continues this mapping.
+    fn extends_to(&self, next: &Mapping) -> bool {
+        self.start.as_u64().wrapping_add(self.size) == next.start.as_u64()
+            && self.phys + self.size == next.phys
+            && self.flags - VOLATILE_FLAGS == next.flags - VOLATILE_FLAGS
+    }
+}
+
+impl fmt::Display for Mapping {
+    /// Formats as `start-end -> physical flags`, with the flags as present, writable, user,
+    /// executable and huge, e.g. `PW-X-`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |flag, c| if self.flags.contains(flag) { c } else { '-' };
+        let executable = if self.flags.contains(PageTableFlags::NO_EXECUTE) {
+            '-'
+        } else {
+            'X'
+        };
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {}{}{}{}{}",
+            self.start.as_u64(),
+            self.start.as_u64().wrapping_add(self.size - 1),
+            self.phys.as_u64(),
+            flag(PageTableFlags::PRESENT, 'P'),
+            flag(PageTableFlags::WRITABLE, 'W'),
+            flag(PageTableFlags::USER_ACCESSIBLE, 'U'),
+            executable,
+            flag(PageTableFlags::HUGE_PAGE, 'H'),
+        )
+    }
+}
+
+/// Calls `f` with every mapping that overlaps `range`, in address order, merging adjacent pages
+/// that continue each other. Does nothing before [`init`](super::init).
+pub fn for_each_mapping(range: Range<VirtAddr>, mut f: impl FnMut(Mapping)) {
+    let Some(offset) = super::physical_memory_offset() else {
+        return;
+    };
+    let (level_4_frame, _) = Cr3::read();
+    let level_4 = offset + level_4_frame.start_address().as_u64();
+    let range = range.start.as_u64()..range.end.as_u64();
+    let mut pending: Option<Mapping> = None;
+    let mut emit = |mapping: Mapping| {
+        if let Some(run) = &mut pending {
+            if run.extends_to(&mapping) {
+                run.size += mapping.size;
+                return;
+            }
+        }
+        if let Some(run) = pending.replace(mapping) {
+            f(run);
+        }
+    };
+    unsafe {
+        walk(
+            &*level_4.as_ptr::<PageTable>(),
+            4,
+            0,
+            &range,
+            offset,
+            &mut emit,
+        )
+    };
+    if let Some(run) = pending {
+        f(run);
+    }
+}
+
+/// Visits the leaf entries under `table`, which is at page table `level` and maps the virtual
+/// addresses from `base` on.
+unsafe fn walk(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    range: &Range<u64>,
+    offset: VirtAddr,
+    emit: &mut impl FnMut(Mapping),
+) {
+    let entry_size = 4096u64 << (9 * (level - 1));
+    for (index, entry) in table.iter().enumerate() {
+        // Addresses are sign extended from bit 47.
+        let start = ((base + index as u64 * entry_size) << 16) as i64 >> 16;
+        let start = start as u64;
+        if start.saturating_add(entry_size) <= range.start || start >= range.end {
+            continue;
+        }
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            emit(Mapping {
+                start: VirtAddr::new(start),
+                size: entry_size,
+                phys: entry.addr(),
+                flags,
+            });
+        } else {
+            let next = offset + entry.addr().as_u64();
+            walk(
+                &*next.as_ptr::<PageTable>(),
+                level - 1,
+                start,
+                range,
+                offset,
+                emit,
+            );
+        }
+    }
+}
+
+/// The mappings overlapping a range, displayed as by [`dump_mappings`].
+pub struct Mappings(pub Range<VirtAddr>);
+
+impl fmt::Display for Mappings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        dump_mappings(self.0.clone(), f)
+    }
+}
+
+/// Writes the mappings overlapping `range` to `out`, one per line, or a note that there are
+/// none.
+pub fn dump_mappings(range: Range<VirtAddr>, out: &mut impl fmt::Write) -> fmt::Result {
+    let mut result = Ok(());
+    let mut any = false;
+    for_each_mapping(range, |mapping| {
+        any = true;
+        if result.is_ok() {
+            result = writeln!(out, "{}", mapping);
+        }
+    });
+    if !any {
+        result = writeln!(out, "(nothing mapped)");
+    }
+    result
+}
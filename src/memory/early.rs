@@ -0,0 +1,95 @@
+//! Allocations before the heap exists.
+//!
+//! Until the heap is mapped, the global allocator hands out memory from a small static arena by
+//! bumping a pointer, so `alloc` types work from the first instruction on. Once the heap is up
+//! the arena is frozen: what was allocated from it stays valid, freeing it is a no-op (except
+//! for the most recent allocation, which is cheap to take back), and new allocations go to the
+//! heap.
+
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Size of the early arena.
+pub const ARENA_SIZE: usize = 64 * 1024;
+
+/// A fixed block of memory allocated front to back.
+pub struct BumpArena<const N: usize> {
+    memory: UnsafeCell<[u8; N]>,
+    /// Offset of the first free byte.
+    next: AtomicUsize,
+    frozen: AtomicBool,
+}
+
+// The memory is only reached through the disjoint ranges `alloc` hands out.
+unsafe impl<const N: usize> Sync for BumpArena<N> {}
+
+impl<const N: usize> BumpArena<N> {
+    pub const fn new() -> Self {
+        Self {
+            memory: UnsafeCell::new([0; N]),
+            next: AtomicUsize::new(0),
+            frozen: AtomicBool::new(false),
+        }
+    }
+
+    fn base(&self) -> usize {
+        self.memory.get() as usize
+    }
+
+    /// Allocates `layout`, or returns a null pointer if the arena is frozen or full.
+    pub fn alloc(&self, layout: Layout) -> *mut u8 {
+        if self.frozen.load(Ordering::Acquire) {
+            return ptr::null_mut();
+        }
+        let base = self.base();
+        let mut start = 0;
+        let reserved = self
+            .next
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |next| {
+                start = (base + next).next_multiple_of(layout.align()) - base;
+                let end = start.checked_add(layout.size())?;
+                (end <= N).then_some(end)
+            });
+        match reserved {
+            Ok(_) => (base + start) as *mut u8,
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    /// Takes back `ptr` if it is the most recent allocation; otherwise its memory stays used.
+    pub fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let start = ptr as usize - self.base();
+        let _ = self.next.compare_exchange(
+            start + layout.size(),
+            start,
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Returns whether `ptr` points into the arena.
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        (self.base()..self.base() + N).contains(&(ptr as usize))
+    }
+
+    /// Stops handing out memory. What was allocated stays valid.
+    pub fn freeze(&self) {
+        self.frozen.store(true, Ordering::Release);
+    }
+
+    /// Bytes in use, including alignment padding.
+    pub fn used(&self) -> usize {
+        self.next.load(Ordering::Relaxed)
+    }
+}
+
+impl<const N: usize> Default for BumpArena<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The arena the global allocator uses before the heap is mapped.
+pub static ARENA: BumpArena<ARENA_SIZE> = BumpArena::new();
@@ -0,0 +1,386 @@
+//! Physical frame allocator.
+//!
+//! A buddy allocator: free memory is kept as blocks of 2^order frames, aligned to their size, on
+//! one list per order. An allocation takes a block from the smallest order that has one and
+//! splits off the halves it does not need, and a freed block merges with its buddy, the other
+//! half of the block one order up, for as long as that is free too. Both take at most
+//! [`MAX_ORDER`] steps, however fragmented memory gets.
+//!
+//! The lists are threaded through the free blocks themselves, reached through the physical
+//! memory mapping. One byte per frame up to the end of the highest usable region records the
+//! order of every free block at its first frame. There is no heap to hold it yet, so it is carved
+//! out of the first usable region large enough, and its own frames are never freed.
+
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::MemoryError;
+use crate::time::tsc;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// Order of the largest blocks, 4 MiB.
+pub const MAX_ORDER: usize = 10;
+
+/// Set in the order byte of the first frame of a free block.
+const FREE: u8 = 0x80;
+
+/// Ends a free list.
+const NIL: usize = usize::MAX;
+
+/// Frame counts and latency counters of a [`BuddyFrameAllocator`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Frames the bootloader reported as usable, including those holding the order bytes.
+    pub usable: usize,
+    /// Frames currently free.
+    pub free: usize,
+    pub latency: FrameLatency,
+}
+
+impl FrameStats {
+    /// Frames allocated from the usable ones.
+    pub fn used(&self) -> usize {
+        self.usable - self.free
+    }
+}
+
+/// TSC cycles spent allocating and freeing single frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameLatency {
+    pub allocations: u64,
+    pub allocation_cycles: u64,
+    pub max_allocation_cycles: u64,
+    pub frees: u64,
+    pub free_cycles: u64,
+    pub max_free_cycles: u64,
+}
+
+impl FrameLatency {
+    pub fn average_allocation_cycles(&self) -> u64 {
+        self.allocation_cycles
+            .checked_div(self.allocations)
+            .unwrap_or(0)
+    }
+
+    pub fn average_free_cycles(&self) -> u64 {
+        self.free_cycles.checked_div(self.frees).unwrap_or(0)
+    }
+}
+
+/// Links of a free block, stored in its first frame.
+struct Link {
+    next: usize,
+    prev: usize,
+}
+
+/// A buddy frame allocator over the usable regions of the bootloader's memory map.
+pub struct BuddyFrameAllocator {
+    /// `FREE | order` at the first frame of each free block, 0 everywhere else.
+    orders: &'static mut [u8],
+    /// First block of the free list of each order, or [`NIL`].
+    heads: [usize; MAX_ORDER + 1],
+    physical_memory_offset: VirtAddr,
+    usable: usize,
+    free: usize,
+    latency: FrameLatency,
+}
+
+fn frame_range(region: &MemoryRegion) -> core::ops::Range<usize> {
+    let start = region.start.div_ceil(FRAME_SIZE) as usize;
+    let end = (region.end / FRAME_SIZE) as usize;
+    start..end.max(start)
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+fn index_of(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+impl BuddyFrameAllocator {
+    /// Builds the allocator from the bootloader's memory map.
+    ///
+    /// # Safety
+    ///
+    /// The usable regions must really be unused, and the complete physical memory must be mapped
+    /// at `physical_memory_offset`.
+    pub unsafe fn new(
+        memory_regions: &[MemoryRegion],
+        physical_memory_offset: VirtAddr,
+    ) -> Result<Self, MemoryError> {
+        let usable_regions = || {
+            memory_regions
+                .iter()
+                .filter(|region| region.kind == MemoryRegionKind::Usable)
+                .map(frame_range)
+        };
+        let frames = usable_regions().map(|range| range.end).max().unwrap_or(0);
+        let metadata_frames = frames.div_ceil(FRAME_SIZE as usize);
+        // Frame 0 is never handed out, so a null physical address can never be valid.
+        let metadata_start = usable_regions()
+            .map(|range| range.start.max(1)..range.end)
+            .find(|range| range.len() >= metadata_frames)
+            .ok_or(MemoryError::NoSpaceForMetadata)?
+            .start;
+
+        let address = physical_memory_offset + metadata_start as u64 * FRAME_SIZE;
+        let orders = core::slice::from_raw_parts_mut(address.as_mut_ptr::<u8>(), frames);
+        orders.fill(0);
+        let mut allocator = BuddyFrameAllocator {
+            orders,
+            heads: [NIL; MAX_ORDER + 1],
+            physical_memory_offset,
+            usable: 0,
+            free: 0,
+            latency: FrameLatency::default(),
+        };
+        for range in usable_regions() {
+            allocator.usable += range.len();
+            let start = if range.contains(&metadata_start) {
+                metadata_start + metadata_frames
+            } else {
+                range.start.max(1)
+            };
+            allocator.free_range(start, range.end);
+        }
+        Ok(allocator)
+    }
+
+    fn link(&self, frame: usize) -> *mut Link {
+        (self.physical_memory_offset + frame as u64 * FRAME_SIZE).as_mut_ptr()
+    }
+
+    fn push(&mut self, frame: usize, order: usize) {
+        let head = self.heads[order];
+        unsafe {
+            self.link(frame).write(Link {
+                next: head,
+                prev: NIL,
+            });
+            if head != NIL {
+                (*self.link(head)).prev = frame;
+            }
+        }
+        self.heads[order] = frame;
+        self.orders[frame] = FREE | order as u8;
+    }
+
+    fn remove(&mut self, frame: usize, order: usize) {
+        let Link { next, prev } = unsafe { self.link(frame).read() };
+        if prev == NIL {
+            self.heads[order] = next;
+        } else {
+            unsafe { (*self.link(prev)).next = next };
+        }
+        if next != NIL {
+            unsafe { (*self.link(next)).prev = prev };
+        }
+        self.orders[frame] = 0;
+    }
+
+    /// Frees the block of `2^order` frames at `frame`, merging it with its buddies.
+    fn release(&mut self, mut frame: usize, mut order: usize) {
+        self.free += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if self.orders.get(buddy) != Some(&(FREE | order as u8)) {
+                break;
+            }
+            self.remove(buddy, order);
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    /// Frees the frames `start..end` as the largest aligned blocks that fit.
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let order = (start.trailing_zeros() as usize)
+                .min((end - start).ilog2() as usize)
+                .min(MAX_ORDER);
+            self.release(start, order);
+            start += 1 << order;
+        }
+    }
+
+    /// Takes a block of `2^order` frames whose first frame satisfies `fits`, splitting a larger
+    /// block if there is no free one of that order.
+    fn take(&mut self, order: usize, fits: impl Fn(usize) -> bool) -> Option<usize> {
+        let (mut current, frame) = (order..=MAX_ORDER).find_map(|current| {
+            let mut frame = self.heads[current];
+            while frame != NIL && !fits(frame) {
+                frame = unsafe { (*self.link(frame)).next };
+            }
+            (frame != NIL).then_some((current, frame))
+        })?;
+        self.remove(frame, current);
+        while current > order {
+            current -= 1;
+            self.push(frame + (1 << current), current);
+        }
+        self.free -= 1 << order;
+        Some(frame)
+    }
+
+    /// Returns the current frame counts and latency counters.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            usable: self.usable,
+            free: self.free,
+            latency: self.latency,
+        }
+    }
+
+    /// Allocates `count` physically contiguous frames that all lie below `limit`, and returns the
+    /// first one. The run comes from a block of the next power of two, so at most
+    /// `2^MAX_ORDER` frames can be allocated at once; the rest of the block is freed again.
+    pub fn allocate_contiguous(&mut self, count: usize, limit: PhysAddr) -> Option<PhysFrame> {
+        if count == 0 {
+            return None;
+        }
+        let order = count.next_power_of_two().trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return None;
+        }
+        let end = (limit.as_u64() / FRAME_SIZE) as usize;
+        let frame = self.take(order, |frame| frame + (1 << order) <= end)?;
+        self.free_range(frame + count, frame + (1 << order));
+        Some(frame_at(frame))
+    }
+
+    /// Returns whether `frame` is free. Frames outside the usable regions are never free.
+    pub fn is_free(&self, frame: PhysFrame) -> bool {
+        let index = index_of(frame);
+        (0..=MAX_ORDER).any(|order| {
+            let head = index & !((1 << order) - 1);
+            self.orders.get(head) == Some(&(FREE | order as u8))
+        })
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let start = tsc::rdtsc_ordered();
+        let frame = self.take(0, |_| true);
+        let cycles = tsc::rdtsc_ordered() - start;
+        let latency = &mut self.latency;
+        latency.allocations += 1;
+        latency.allocation_cycles += cycles;
+        latency.max_allocation_cycles = latency.max_allocation_cycles.max(cycles);
+        frame.map(frame_at)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    /// Frees `frame`. Freeing a frame that is already free is a bug in the caller and is caught
+    /// by an assertion.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let start = tsc::rdtsc_ordered();
+        let index = index_of(frame);
+        assert!(
+            index != 0 && index < self.orders.len() && !self.is_free(frame),
+            "deallocating frame {:?} that is not allocated",
+            frame
+        );
+        self.release(index, 0);
+        let cycles = tsc::rdtsc_ordered() - start;
+        let latency = &mut self.latency;
+        latency.frees += 1;
+        latency.free_cycles += cycles;
+        latency.max_free_cycles = latency.max_free_cycles.max(cycles);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use core::ops::Range;
+
+    /// Frames of the region a test allocator manages, one block of the kernel's allocator.
+    const TEST_FRAMES: usize = 256;
+
+    /// Runs `test` on an allocator of its own over frames taken from the kernel's, which get them
+    /// back afterwards. The test allocator sees them as frames `0..TEST_FRAMES`, so its order
+    /// bytes stay small: frame 0 is never handed out, frame 1 holds the order bytes and the upper
+    /// half is one free block.
+    fn with_allocator(test: impl FnOnce(&mut BuddyFrameAllocator, Range<usize>)) {
+        let first = crate::memory::allocate_contiguous_frames(TEST_FRAMES, PhysAddr::new(u64::MAX))
+            .expect("no frames for the test allocator");
+        let regions = [MemoryRegion {
+            start: 0,
+            end: TEST_FRAMES as u64 * FRAME_SIZE,
+            kind: MemoryRegionKind::Usable,
+        }];
+        let offset =
+            crate::memory::physical_memory_offset().unwrap() + first.start_address().as_u64();
+        let mut allocator = unsafe { BuddyFrameAllocator::new(&regions, offset) }.unwrap();
+        test(&mut allocator, 0..TEST_FRAMES);
+        for index in 0..TEST_FRAMES {
+            unsafe { crate::memory::deallocate_frame(frame_at(index_of(first) + index)) };
+        }
+    }
+
+    #[test_case]
+    fn allocates_every_free_frame_once() {
+        with_allocator(|allocator, frames| {
+            let free = allocator.stats().free;
+            let mut taken = Vec::new();
+            while let Some(frame) = allocator.allocate_frame() {
+                assert!(frames.contains(&index_of(frame)));
+                assert!(!taken.contains(&frame));
+                taken.push(frame);
+            }
+            assert_eq!(taken.len(), free);
+            assert_eq!(allocator.stats().free, 0);
+            for frame in taken {
+                unsafe { allocator.deallocate_frame(frame) };
+            }
+            assert_eq!(allocator.stats().free, free);
+        });
+    }
+
+    #[test_case]
+    fn freed_buddies_merge() {
+        with_allocator(|allocator, frames| {
+            let upper = frames.start + TEST_FRAMES / 2;
+            let limit = PhysAddr::new(u64::MAX);
+            let block = allocator.allocate_contiguous(TEST_FRAMES / 2, limit);
+            assert_eq!(block.map(index_of), Some(upper));
+            assert!(!allocator.is_free(frame_at(upper)));
+            for index in upper..frames.end {
+                unsafe { allocator.deallocate_frame(frame_at(index)) };
+            }
+            assert!(allocator.is_free(frame_at(upper)));
+            assert_eq!(allocator.orders[upper], FREE | 7);
+            let block = allocator.allocate_contiguous(TEST_FRAMES / 2, limit);
+            assert_eq!(block.map(index_of), Some(upper));
+        });
+    }
+
+    #[test_case]
+    fn contiguous_runs_respect_the_limit_and_their_length() {
+        with_allocator(|allocator, frames| {
+            let upper = frames.start + TEST_FRAMES / 2;
+            let limit = PhysAddr::new(upper as u64 * FRAME_SIZE);
+            // Below the upper half the order bytes are in the way of a whole half.
+            assert!(allocator
+                .allocate_contiguous(TEST_FRAMES / 2, limit)
+                .is_none());
+            let free = allocator.stats().free;
+            let run = allocator.allocate_contiguous(3, limit).unwrap();
+            assert!(index_of(run) + 3 <= upper);
+            assert_eq!(allocator.stats().free, free - 3);
+            assert!(allocator.is_free(frame_at(index_of(run) + 3)));
+            assert!(allocator.allocate_contiguous(0, limit).is_none());
+            assert!(allocator
+                .allocate_contiguous((1 << MAX_ORDER) + 1, limit)
+                .is_none());
+        });
+    }
+}
@@ -0,0 +1,189 @@
+//! Kernel heap.
+//!
+//! A virtual range, placed at a random offset in a fixed area at boot, is backed with frames from
+//! the frame allocator and handed to a dlmalloc-style linked list allocator, which serves as the
+//! global allocator for `alloc`.
+//! Allocations of up to 256 bytes are served by the slab caches instead, which take their slabs
+//! from the same heap. With the `heap-debug` feature, every allocation is checked for overruns
+//! and double frees when it is freed. Allocations made before the heap is set up come from the
+//! [`early`](super::early) arena.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use good_memory_allocator::SpinLockedAllocator;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::early;
+use super::slab::{self, SlabCache, SlabStats, SIZE_CLASSES};
+use super::PagingError;
+
+#[cfg(feature = "heap-debug")]
+mod debug;
+
+/// Start of the area the heap is placed in, in a part of the address space the bootloader leaves
+/// free.
+pub const HEAP_AREA_START: u64 = 0x4444_0000_0000;
+/// Size of the area the heap is placed in.
+pub const HEAP_AREA_SIZE: u64 = 1024 * 1024 * 1024;
+/// Size of the heap.
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024;
+
+/// Byte counts of the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    /// Bytes requested by live allocations, not counting the allocator's overhead.
+    pub used: usize,
+    /// Counters of the slab caches, one per size class.
+    pub slabs: [SlabStats; SIZE_CLASSES.len()],
+    /// Bytes allocated from the early arena before the heap was set up.
+    pub early: usize,
+}
+
+/// The global allocator, counting the bytes in use.
+///
+/// The inner allocator and the slab caches are locked with spinlocks, so interrupts are disabled
+/// around every call: a handler that allocates must not find a lock held by the code it
+/// interrupted. A slab cache is always locked before the inner allocator.
+struct KernelAllocator {
+    inner: SpinLockedAllocator,
+    slabs: [SlabCache; SIZE_CLASSES.len()],
+    used: AtomicUsize,
+}
+
+// These run with interrupts disabled.
+impl KernelAllocator {
+    /// Takes a block from a slab cache or the inner allocator.
+    unsafe fn alloc_block(&self, layout: Layout) -> *mut u8 {
+        match slab::class_of(layout) {
+            Some(class) => self.slabs[class].alloc(|slab| self.inner.alloc(slab)),
+            None => self.inner.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc_block(&self, ptr: *mut u8, layout: Layout) {
+        match slab::class_of(layout) {
+            Some(class) => self.slabs[class].dealloc(ptr),
+            None => self.inner.dealloc(ptr, layout),
+        }
+    }
+
+    unsafe fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "heap-debug")]
+        return debug::alloc(layout, |block| self.alloc_block(block));
+        #[cfg(not(feature = "heap-debug"))]
+        self.alloc_block(layout)
+    }
+
+    unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "heap-debug")]
+        let (ptr, layout) = debug::dealloc(ptr, layout);
+        self.dealloc_block(ptr, layout)
+    }
+
+    unsafe fn realloc_raw(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        // The debugging header and canaries must move with the allocation, so with them every
+        // reallocation copies.
+        let debug = cfg!(feature = "heap-debug");
+        match (slab::class_of(layout), slab::class_of(new_layout)) {
+            (None, None) if !debug => self.inner.realloc(ptr, layout, new_size),
+            (Some(old), Some(new)) if old == new && !debug => ptr,
+            _ => {
+                let new_ptr = self.alloc_raw(new_layout);
+                if !new_ptr.is_null() {
+                    core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                    self.dealloc_raw(ptr, layout);
+                }
+                new_ptr
+            }
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if SIZE.load(Ordering::Acquire) == 0 {
+            return early::ARENA.alloc(layout);
+        }
+        let ptr = interrupts::without_interrupts(|| self.alloc_raw(layout));
+        if !ptr.is_null() {
+            self.used.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if early::ARENA.contains(ptr) {
+            early::ARENA.dealloc(ptr, layout);
+            return;
+        }
+        interrupts::without_interrupts(|| self.dealloc_raw(ptr, layout));
+        self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if early::ARENA.contains(ptr) {
+            // Arena memory cannot grow in place, and once the heap is up it moves there.
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            let new_ptr = self.alloc(new_layout);
+            if !new_ptr.is_null() {
+                core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                early::ARENA.dealloc(ptr, layout);
+            }
+            return new_ptr;
+        }
+        let new_ptr =
+            interrupts::without_interrupts(|| self.realloc_raw(ptr, layout, new_size));
+        if !new_ptr.is_null() {
+            self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+            self.used.fetch_add(new_size, Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator {
+    inner: SpinLockedAllocator::empty(),
+    slabs: [
+        SlabCache::new(SIZE_CLASSES[0]),
+        SlabCache::new(SIZE_CLASSES[1]),
+        SlabCache::new(SIZE_CLASSES[2]),
+        SlabCache::new(SIZE_CLASSES[3]),
+    ],
+    used: AtomicUsize::new(0),
+};
+
+static SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Maps the heap range and hands it to the allocator, which then freezes the early arena. Must
+/// run once, after the frame allocator and the mapper are set up.
+pub(super) fn init() -> Result<(), PagingError> {
+    let slots = (HEAP_AREA_SIZE - HEAP_SIZE as u64) / Size4KiB::SIZE + 1;
+    let start = VirtAddr::new(HEAP_AREA_START + crate::random::below(slots) * Size4KiB::SIZE);
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::containing_address(start + HEAP_SIZE - 1u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for page in Page::range_inclusive(first, last) {
+        let frame = super::allocate_frame().ok_or(PagingError::OutOfFrames)?;
+        unsafe { super::map_page(page, frame, flags)? };
+    }
+    unsafe { ALLOCATOR.inner.init(start.as_u64() as usize, HEAP_SIZE) };
+    SIZE.store(HEAP_SIZE, Ordering::Release);
+    early::ARENA.freeze();
+    Ok(())
+}
+
+/// Returns the heap size and usage, and the slab cache counters. The size is 0 before the heap
+/// is set up.
+pub fn stats() -> HeapStats {
+    HeapStats {
+        size: SIZE.load(Ordering::Relaxed),
+        used: ALLOCATOR.used.load(Ordering::Relaxed),
+        slabs: core::array::from_fn(|class| ALLOCATOR.slabs[class].stats()),
+        early: early::ARENA.used(),
+    }
+}
@@ -0,0 +1,135 @@
+//! Heap debugging, enabled with the `heap-debug` feature.
+//!
+//! Every allocation gets a header in front of it, recording its size, its state and the return
+//! addresses of the frames that requested it, and a canary behind it. Freeing checks both
+//! canaries and the state, so a double free or a write past either end of a buffer panics with
+//! what was known about the allocation. Freed memory is filled with `0xDEADBEEF`, which makes use
+//! after free stand out in a dump or a register.
+//!
+//! The return addresses come from walking the frame pointer chain, so they are only meaningful
+//! when the kernel is built with `-C force-frame-pointers=yes`.
+
+use core::alloc::Layout;
+use core::arch::asm;
+use core::mem::{align_of, size_of};
+use core::ptr;
+
+/// Return addresses recorded per allocation.
+const CALLER_DEPTH: usize = 4;
+/// Farthest a frame pointer may point above the previous one before the walk gives up, as without
+/// frame pointers `rbp` holds arbitrary data.
+const MAX_FRAME_SIZE: u64 = 16 * 1024;
+
+const ALLOCATED: u64 = 0xA110_CA7E_DA11_0CA7;
+const FREED: u64 = 0xF4EE_DF4E_EDF4_EEDF;
+const CANARY: u64 = 0xCA4A_4D1E_CA4A_4D1E;
+const POISON: u32 = 0xDEAD_BEEF;
+
+/// Written directly in front of every allocation. The state comes late, since a freed block
+/// starts with the allocator's free list pointers.
+#[repr(C)]
+struct Header {
+    callers: [u64; CALLER_DEPTH],
+    size: usize,
+    state: u64,
+    canary: u64,
+}
+
+/// Space in front of an allocation with `align`, so the header fits and the allocation stays
+/// aligned.
+fn front(align: usize) -> usize {
+    size_of::<Header>().next_multiple_of(align)
+}
+
+/// Layout of the block holding an allocation of `layout` with its header and trailing canary.
+fn block_layout(layout: Layout) -> Option<Layout> {
+    let size = front(layout.align())
+        .checked_add(layout.size())?
+        .checked_add(size_of::<u64>())?;
+    Layout::from_size_align(size, layout.align().max(align_of::<Header>())).ok()
+}
+
+/// Return addresses of the innermost frames, found through the frame pointers.
+#[inline(always)]
+fn callers() -> [u64; CALLER_DEPTH] {
+    let (mut rbp, rsp): (u64, u64);
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+    }
+    let mut callers = [0; CALLER_DEPTH];
+    let mut lowest = rsp;
+    for caller in &mut callers {
+        if rbp <= lowest || rbp - lowest > MAX_FRAME_SIZE || rbp % 8 != 0 {
+            break;
+        }
+        let frame = rbp as *const u64;
+        *caller = unsafe { frame.add(1).read() };
+        lowest = rbp;
+        rbp = unsafe { frame.read() };
+    }
+    callers
+}
+
+/// Allocates `layout` with a header and canaries, taking the block from `alloc_block`.
+pub(super) unsafe fn alloc(layout: Layout, alloc_block: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+    let Some(block_layout) = block_layout(layout) else {
+        return ptr::null_mut();
+    };
+    let block = alloc_block(block_layout);
+    if block.is_null() {
+        return block;
+    }
+    let ptr = block.add(front(layout.align()));
+    ptr.cast::<Header>().sub(1).write(Header {
+        callers: callers(),
+        size: layout.size(),
+        state: ALLOCATED,
+        canary: CANARY,
+    });
+    ptr.add(layout.size()).cast::<u64>().write_unaligned(CANARY);
+    ptr
+}
+
+/// Checks the allocation at `ptr` and poisons it. Returns the block and its layout, for the
+/// caller to free. Panics on a double free or a corrupted canary.
+pub(super) unsafe fn dealloc(ptr: *mut u8, layout: Layout) -> (*mut u8, Layout) {
+    let header = &mut *ptr.cast::<Header>().sub(1);
+    match header.state {
+        ALLOCATED => {}
+        FREED => panic!(
+            "heap: double free of {} bytes at {:p}, allocated from {:#x?}",
+            header.size, ptr, header.callers
+        ),
+        _ => panic!(
+            "heap: freeing {} bytes at {:p}, which is not allocated or whose header was overwritten",
+            layout.size(),
+            ptr
+        ),
+    }
+    if header.canary != CANARY || header.size != layout.size() {
+        panic!(
+            "heap: memory in front of {} bytes at {:p} was overwritten, allocated from {:#x?}",
+            layout.size(),
+            ptr,
+            header.callers
+        );
+    }
+    if ptr.add(layout.size()).cast::<u64>().read_unaligned() != CANARY {
+        panic!(
+            "heap: memory behind {} bytes at {:p} was overwritten, allocated from {:#x?}",
+            layout.size(),
+            ptr,
+            header.callers
+        );
+    }
+    for (index, byte) in core::slice::from_raw_parts_mut(ptr, layout.size())
+        .iter_mut()
+        .enumerate()
+    {
+        *byte = POISON.to_ne_bytes()[index % 4];
+    }
+    header.state = FREED;
+    let block = ptr.sub(front(layout.align()));
+    (block, block_layout(layout).unwrap())
+}
@@ -0,0 +1,199 @@
+//! Slab caches for small allocations.
+//!
+//! Each cache hands out objects of one size class. It takes whole slabs from the heap, splits
+//! them into objects, and keeps the free objects on a list threaded through the objects
+//! themselves, so an allocation is a list pop and nothing fragments the heap between classes.
+//! Slabs are never given back to the heap.
+
+use core::alloc::Layout;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Object sizes of the caches, in increasing order.
+pub const SIZE_CLASSES: [usize; 4] = [32, 64, 128, 256];
+
+/// Bytes taken from the heap whenever a cache runs empty.
+const SLAB_SIZE: usize = 4096;
+
+/// Returns the index of the smallest size class that fits `layout`, or `None` if it is too big
+/// for the caches.
+///
+/// Objects are aligned to their size, so the class must also cover the alignment.
+pub fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| size <= class)
+}
+
+/// A free object, holding the address of the next one.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// The head of a free list. Only reachable through the cache's lock.
+struct FreeList(*mut FreeObject);
+
+unsafe impl Send for FreeList {}
+
+/// Counters of a [`SlabCache`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabStats {
+    pub object_size: usize,
+    /// Allocations served from the free list.
+    pub hits: usize,
+    /// Allocations that had to take a new slab from the heap first.
+    pub misses: usize,
+    /// Slabs taken from the heap.
+    pub slabs: usize,
+    /// Objects on the free list.
+    pub free: usize,
+}
+
+impl SlabStats {
+    /// Objects handed out and not yet freed.
+    pub fn in_use(&self) -> usize {
+        self.slabs * (SLAB_SIZE / self.object_size) - self.free
+    }
+}
+
+/// A cache of objects of one size.
+pub struct SlabCache {
+    object_size: usize,
+    free_list: Mutex<FreeList>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    slabs: AtomicUsize,
+    free: AtomicUsize,
+}
+
+impl SlabCache {
+    /// Creates an empty cache. `object_size` must be a power of two of at least the size of a
+    /// pointer and at most the 4 KiB of a slab.
+    pub const fn new(object_size: usize) -> Self {
+        assert!(object_size.is_power_of_two());
+        assert!(object_size >= core::mem::size_of::<FreeObject>() && object_size <= SLAB_SIZE);
+        Self {
+            object_size,
+            free_list: Mutex::new(FreeList(ptr::null_mut())),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            slabs: AtomicUsize::new(0),
+            free: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes an object, calling `grow` for a new slab if the cache is empty. Returns a null
+    /// pointer if `grow` does.
+    pub fn alloc(&self, grow: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+        let mut free_list = self.free_list.lock();
+        if free_list.0.is_null() {
+            // Aligning the slab to the object size aligns every object in it as well.
+            let layout = Layout::from_size_align(SLAB_SIZE, self.object_size).unwrap();
+            let slab = grow(layout);
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            self.slabs.fetch_add(1, Ordering::Relaxed);
+            for offset in (0..SLAB_SIZE).step_by(self.object_size).rev() {
+                let object = unsafe { slab.add(offset) }.cast::<FreeObject>();
+                unsafe { object.write(FreeObject { next: free_list.0 }) };
+                free_list.0 = object;
+            }
+            self.free.fetch_add(SLAB_SIZE / self.object_size, Ordering::Relaxed);
+        } else {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        let object = free_list.0;
+        free_list.0 = unsafe { (*object).next };
+        self.free.fetch_sub(1, Ordering::Relaxed);
+        object.cast()
+    }
+
+    /// Returns an object to the cache.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have come from [`SlabCache::alloc`] on this cache and must not be used again.
+    pub unsafe fn dealloc(&self, ptr: *mut u8) {
+        let mut free_list = self.free_list.lock();
+        let object = ptr.cast::<FreeObject>();
+        object.write(FreeObject { next: free_list.0 });
+        free_list.0 = object;
+        self.free.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the cache's counters.
+    pub fn stats(&self) -> SlabStats {
+        SlabStats {
+            object_size: self.object_size,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            slabs: self.slabs.load(Ordering::Relaxed),
+            free: self.free.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Slabs for the test caches, from the heap. They are never freed, like those of the
+    /// kernel's caches.
+    fn grow(layout: Layout) -> *mut u8 {
+        unsafe { alloc::alloc::alloc(layout) }
+    }
+
+    #[test_case]
+    fn classes_cover_size_and_alignment() {
+        let class = |size, align| class_of(Layout::from_size_align(size, align).unwrap());
+        assert_eq!(class(1, 1), Some(0));
+        assert_eq!(class(32, 8), Some(0));
+        assert_eq!(class(33, 8), Some(1));
+        assert_eq!(class(8, 128), Some(2));
+        assert_eq!(class(256, 256), Some(3));
+        assert_eq!(class(257, 8), None);
+        assert_eq!(class(8, 512), None);
+    }
+
+    #[test_case]
+    fn objects_are_aligned_and_distinct() {
+        let cache = SlabCache::new(64);
+        let objects: Vec<*mut u8> = (0..SLAB_SIZE / 64 + 1).map(|_| cache.alloc(grow)).collect();
+        for (index, &object) in objects.iter().enumerate() {
+            assert!(!object.is_null());
+            assert_eq!(object as usize % 64, 0);
+            assert!(!objects[..index].contains(&object));
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.slabs, stats.misses), (2, 2));
+        assert_eq!(stats.hits, SLAB_SIZE / 64 - 1);
+        assert_eq!(stats.in_use(), objects.len());
+    }
+
+    #[test_case]
+    fn freed_objects_are_reused_first() {
+        let cache = SlabCache::new(32);
+        let first = cache.alloc(grow);
+        let second = cache.alloc(grow);
+        unsafe { cache.dealloc(first) };
+        assert_eq!(cache.stats().in_use(), 1);
+        assert_eq!(cache.alloc(grow), first);
+        unsafe {
+            cache.dealloc(second);
+            cache.dealloc(first);
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.slabs, stats.in_use()), (1, 0));
+        assert_eq!(stats.free, SLAB_SIZE / 32);
+    }
+
+    #[test_case]
+    fn failed_growth_returns_null() {
+        let cache = SlabCache::new(128);
+        assert!(cache.alloc(|_| ptr::null_mut()).is_null());
+        assert_eq!(cache.stats().slabs, 0);
+    }
+}
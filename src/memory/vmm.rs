@@ -0,0 +1,403 @@
+//! Kernel virtual memory areas.
+//!
+//! A fixed window of the kernel address space is handed out in page-granular regions, so callers
+//! that need memory mapped somewhere (stacks, buffers, MMIO windows) do not have to pick the
+//! addresses themselves. Every region is preceded by an unmapped guard page, which turns an
+//! overrun, such as a stack growing into its neighbour, into a page fault.
+//!
+//! Where regions go is randomized: the window starts at a random offset at boot, and every region
+//! is preceded by a random number of guard pages. This keeps stacks and MMIO windows at
+//! unpredictable addresses, and flushes out code that assumes where they are.
+//!
+//! Physical ranges of 2 MiB or more are placed so that they can be mapped with 2 MiB pages,
+//! which saves TLB entries for large windows such as the framebuffer.
+//!
+//! The regions are tracked in a [`BTreeMap`] keyed by start address and placed first fit. The
+//! guard pages beneath stacks are recorded with the stack's name, so the page fault handler can
+//! tell a stack overflow from any other fault.
+//!
+//! An anonymous region can be duplicated copy-on-write with [`share_cow`]; see [`super::cow`].
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::arch::asm;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{cow, PagingError};
+
+/// Start of the area the window regions are placed in is taken from.
+pub const VMA_START: u64 = 0x5000_0000_0000;
+/// Size of the area.
+pub const VMA_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+/// The window starts at a random 2 MiB boundary in the first half of the area.
+const MAX_WINDOW_OFFSET: u64 = VMA_SIZE / 2;
+/// Most guard pages added below a region on top of the one every region gets.
+const MAX_EXTRA_GUARD_PAGES: u64 = 255;
+
+const PAGE_SIZE: u64 = 4096;
+const HUGE_PAGE_SIZE: u64 = Size2MiB::SIZE;
+const PAGES_PER_HUGE_PAGE: u64 = HUGE_PAGE_SIZE / PAGE_SIZE;
+
+/// Errors returned by the region functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    /// A region of zero bytes was requested.
+    ZeroSize,
+    /// No free range in the window is large enough.
+    OutOfVirtualSpace,
+    /// No region contains the given address.
+    NotAllocated,
+    /// The region is not backed by frames of its own.
+    NotAnonymous,
+    /// Mapping the region failed; nothing of it stays mapped.
+    Paging(PagingError),
+}
+
+impl From<PagingError> for VmmError {
+    fn from(error: PagingError) -> Self {
+        VmmError::Paging(error)
+    }
+}
+
+/// What a region is mapped to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed frames from the frame allocator, returned when the region is freed.
+    Anonymous,
+    /// A physically contiguous range starting at the given address, such as device registers.
+    Physical(PhysAddr),
+}
+
+/// An allocated range of virtual memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub pages: u64,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl Region {
+    /// Size of the region in bytes.
+    pub fn size(&self) -> u64 {
+        self.pages * PAGE_SIZE
+    }
+
+    /// Address just past the end of the region.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size()
+    }
+}
+
+/// The regions allocated in a window of virtual memory.
+struct AddressSpace {
+    start: u64,
+    end: u64,
+    regions: BTreeMap<u64, Region>,
+}
+
+impl AddressSpace {
+    const fn new(start: u64, size: u64) -> Self {
+        Self {
+            start,
+            end: start + size,
+            regions: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the lowest start address with room for `pages` pages and `1 + extra_guard_pages`
+    /// guard pages below them that is congruent to `residue` modulo `alignment`.
+    fn find_free(
+        &self,
+        pages: u64,
+        extra_guard_pages: u64,
+        alignment: u64,
+        residue: u64,
+    ) -> Option<u64> {
+        let fits = |gap_start: u64, gap_end: u64| {
+            let lowest = gap_start + (1 + extra_guard_pages) * PAGE_SIZE;
+            let start = lowest + (residue.wrapping_sub(lowest) % alignment);
+            (gap_end.saturating_sub(start) >= pages * PAGE_SIZE).then_some(start)
+        };
+        let mut gap_start = self.start;
+        for region in self.regions.values() {
+            if let Some(start) = fits(gap_start, region.start.as_u64()) {
+                return Some(start);
+            }
+            gap_start = region.end().as_u64();
+        }
+        fits(gap_start, self.end)
+    }
+}
+
+static KERNEL_SPACE: Mutex<AddressSpace> = Mutex::new(AddressSpace::new(VMA_START, VMA_SIZE));
+
+/// Moves the window to a random offset in the area. Must run before the first region is
+/// allocated.
+pub(super) fn init() {
+    let offset = crate::random::below(MAX_WINDOW_OFFSET / HUGE_PAGE_SIZE) * HUGE_PAGE_SIZE;
+    interrupts::without_interrupts(|| KERNEL_SPACE.lock().start = VMA_START + offset);
+}
+
+/// Guard pages beneath stacks, by address, with the name of the stack.
+static STACK_GUARDS: Mutex<BTreeMap<u64, &'static str>> = Mutex::new(BTreeMap::new());
+
+/// Most pages [`register_current_stack`] searches below the stack pointer for the guard page.
+const MAX_STACK_PAGES: u64 = 1024;
+
+/// Reserves a range for `size` bytes, rounded up to whole pages, and records it.
+fn reserve(size: usize, flags: PageTableFlags, backing: Backing) -> Result<Region, VmmError> {
+    if size == 0 {
+        return Err(VmmError::ZeroSize);
+    }
+    let pages = (size as u64).div_ceil(PAGE_SIZE);
+    // Matching the physical address modulo 2 MiB lets the aligned middle use huge pages.
+    let (alignment, residue) = match backing {
+        Backing::Physical(address) if pages >= PAGES_PER_HUGE_PAGE => {
+            (HUGE_PAGE_SIZE, address.as_u64() % HUGE_PAGE_SIZE)
+        }
+        _ => (PAGE_SIZE, 0),
+    };
+    let extra_guard_pages = crate::random::below(MAX_EXTRA_GUARD_PAGES + 1);
+    interrupts::without_interrupts(|| {
+        let mut space = KERNEL_SPACE.lock();
+        let start = space
+            .find_free(pages, extra_guard_pages, alignment, residue)
+            .ok_or(VmmError::OutOfVirtualSpace)?;
+        let region = Region {
+            start: VirtAddr::new(start),
+            pages,
+            flags: flags | PageTableFlags::PRESENT,
+            backing,
+        };
+        space.regions.insert(start, region);
+        Ok(region)
+    })
+}
+
+fn release(start: VirtAddr) -> Option<Region> {
+    interrupts::without_interrupts(|| KERNEL_SPACE.lock().regions.remove(&start.as_u64()))
+}
+
+/// Returns the region containing `address`.
+fn find(address: VirtAddr) -> Option<Region> {
+    interrupts::without_interrupts(|| {
+        let space = KERNEL_SPACE.lock();
+        let (_, region) = space.regions.range(..=address.as_u64()).next_back()?;
+        (address < region.end()).then_some(*region)
+    })
+}
+
+/// Unmaps the first `mapped` pages of `region`, and returns their frames if the region owns them
+/// and no copy-on-write page still shares them.
+unsafe fn unmap_pages(region: &Region, mapped: u64) {
+    let mut index = 0;
+    while index < mapped {
+        let page = Page::<Size4KiB>::containing_address(region.start) + index;
+        match super::unmap_page(page) {
+            Ok(frame) if region.backing == Backing::Anonymous => {
+                if cow::release(frame) {
+                    super::deallocate_frame(frame);
+                }
+            }
+            Err(PagingError::HugePage) => {
+                let _ = super::unmap_huge_page(Page::containing_address(page.start_address()));
+                index += PAGES_PER_HUGE_PAGE;
+                continue;
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+}
+
+/// Maps page `index` of a region backed by physical memory, with a 2 MiB page if one fits
+/// there. Returns the number of 4 KiB pages mapped.
+unsafe fn map_physical_pages(
+    region: &Region,
+    address: PhysAddr,
+    index: u64,
+) -> Result<u64, PagingError> {
+    let virt = region.start + index * PAGE_SIZE;
+    let phys = address + index * PAGE_SIZE;
+    let huge = virt.is_aligned(HUGE_PAGE_SIZE)
+        && phys.is_aligned(HUGE_PAGE_SIZE)
+        && region.pages - index >= PAGES_PER_HUGE_PAGE;
+    if huge {
+        let page = Page::<Size2MiB>::containing_address(virt);
+        super::map_huge_page(page, PhysFrame::containing_address(phys), region.flags)?;
+        Ok(PAGES_PER_HUGE_PAGE)
+    } else {
+        let page = Page::<Size4KiB>::containing_address(virt);
+        super::map_page(page, PhysFrame::containing_address(phys), region.flags)?;
+        Ok(1)
+    }
+}
+
+/// Maps the pages of a reserved region. On failure, undoes the part that was mapped and releases
+/// the reservation.
+fn map_region(region: Region) -> Result<VirtAddr, VmmError> {
+    let mut mapped = 0;
+    while mapped < region.pages {
+        let result = match region.backing {
+            Backing::Anonymous => map_anonymous_page(&region, mapped),
+            Backing::Physical(address) => unsafe { map_physical_pages(&region, address, mapped) },
+        };
+        match result {
+            Ok(pages) => mapped += pages,
+            Err(error) => {
+                unsafe { unmap_pages(&region, mapped) };
+                release(region.start);
+                return Err(error.into());
+            }
+        }
+    }
+    Ok(region.start)
+}
+
+/// Maps page `index` of an anonymous region to a new zeroed frame. Returns the number of pages
+/// mapped.
+fn map_anonymous_page(region: &Region, index: u64) -> Result<u64, PagingError> {
+    let page = Page::<Size4KiB>::containing_address(region.start) + index;
+    let frame = super::allocate_frame().ok_or(PagingError::OutOfFrames)?;
+    let result =
+        zero_frame(frame).and_then(|()| unsafe { super::map_page(page, frame, region.flags) });
+    if result.is_err() {
+        unsafe { super::deallocate_frame(frame) };
+    }
+    result.map(|()| 1)
+}
+
+fn zero_frame(frame: PhysFrame<Size4KiB>) -> Result<(), PagingError> {
+    let address = super::phys_to_virt(frame.start_address()).ok_or(PagingError::NotInitialized)?;
+    unsafe { core::ptr::write_bytes(address.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+    Ok(())
+}
+
+/// Allocates a region of at least `size` bytes backed by zeroed frames and mapped with `flags`,
+/// and returns its start address. `PRESENT` is added to the flags.
+pub fn alloc_region(size: usize, flags: PageTableFlags) -> Result<VirtAddr, VmmError> {
+    map_region(reserve(size, flags, Backing::Anonymous)?)
+}
+
+/// Maps `size` bytes of physical memory starting at `address`, which need not be page aligned,
+/// into a new region, and returns the virtual address of `address`. For device registers, pass
+/// `NO_CACHE` in `flags`.
+///
+/// # Safety
+///
+/// The physical range must not be memory the frame allocator hands out, or writes through the
+/// region could corrupt whatever uses it.
+pub unsafe fn map_physical(
+    address: PhysAddr,
+    size: usize,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, VmmError> {
+    let offset = address.as_u64() % PAGE_SIZE;
+    let backing = Backing::Physical(address.align_down(PAGE_SIZE));
+    let start = map_region(reserve(size + offset as usize, flags, backing)?)?;
+    Ok(start + offset)
+}
+
+/// Unmaps the region containing `address` and returns its frames to the frame allocator if it
+/// owns them.
+///
+/// # Safety
+///
+/// Nothing may use the region any more.
+pub unsafe fn free_region(address: VirtAddr) -> Result<(), VmmError> {
+    let start = find(address).ok_or(VmmError::NotAllocated)?.start;
+    let region = release(start).ok_or(VmmError::NotAllocated)?;
+    interrupts::without_interrupts(|| {
+        STACK_GUARDS.lock().remove(&(region.start.as_u64() - PAGE_SIZE));
+    });
+    unmap_pages(&region, region.pages);
+    Ok(())
+}
+
+/// Duplicates the anonymous region containing `address` into a new region that shares its frames
+/// copy-on-write, and returns the start of the copy. Writable pages become read-only in both
+/// regions until the first write to each, which copies the frame; read-only pages stay shared.
+pub fn share_cow(address: VirtAddr) -> Result<VirtAddr, VmmError> {
+    let source = find(address).ok_or(VmmError::NotAllocated)?;
+    if source.backing != Backing::Anonymous {
+        return Err(VmmError::NotAnonymous);
+    }
+    let copy = reserve(source.size() as usize, source.flags, Backing::Anonymous)?;
+    let mut mapped = 0;
+    while mapped < copy.pages {
+        if let Err(error) = share_page(&source, &copy, mapped) {
+            unsafe { unmap_pages(&copy, mapped) };
+            release(copy.start);
+            return Err(error.into());
+        }
+        mapped += 1;
+    }
+    Ok(copy.start)
+}
+
+/// Maps page `index` of `copy` to the frame of the same page of `source`, marking both
+/// copy-on-write if the page is writable.
+fn share_page(source: &Region, copy: &Region, index: u64) -> Result<(), PagingError> {
+    let from = Page::<Size4KiB>::containing_address(source.start) + index;
+    let to = Page::<Size4KiB>::containing_address(copy.start) + index;
+    let (physical, flags) = super::translate(from.start_address()).ok_or(PagingError::NotMapped)?;
+    let frame = PhysFrame::containing_address(physical);
+    let flags = if flags.contains(PageTableFlags::WRITABLE) {
+        let flags = (flags - PageTableFlags::WRITABLE) | cow::COW;
+        unsafe { super::set_flags(from, flags)? };
+        flags
+    } else {
+        flags
+    };
+    unsafe { super::map_page(to, frame, flags)? };
+    cow::share(frame);
+    Ok(())
+}
+
+/// Allocates a stack of at least `size` bytes and returns its top, the address to load into RSP.
+/// Running into the guard page beneath it is reported as a stack overflow in `name`. Free the
+/// stack with [`free_region`] on an address inside it, such as the top minus one.
+pub fn alloc_stack(size: usize, name: &'static str) -> Result<VirtAddr, VmmError> {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = alloc_region(size, flags)?;
+    register_stack_guard(Page::containing_address(start) - 1, name);
+    Ok(start + (size as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE)
+}
+
+/// Records `guard` as the unmapped page beneath the stack called `name`.
+pub fn register_stack_guard(guard: Page<Size4KiB>, name: &'static str) {
+    interrupts::without_interrupts(|| {
+        STACK_GUARDS.lock().insert(guard.start_address().as_u64(), name);
+    });
+}
+
+/// Records the guard page beneath the stack in use, such as the boot stack the bootloader set up,
+/// under `name`. The guard is taken to be the first unmapped page below the stack pointer; if
+/// there is none close enough, nothing is recorded and `None` returned.
+pub fn register_current_stack(name: &'static str) -> Option<Page<Size4KiB>> {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    let top = Page::<Size4KiB>::containing_address(VirtAddr::new(rsp));
+    let guard = (1..=MAX_STACK_PAGES)
+        .map(|below| top - below)
+        .find(|page| super::translate_addr(page.start_address()).is_none())?;
+    register_stack_guard(guard, name);
+    Some(guard)
+}
+
+/// Returns the name of the stack whose guard page contains `address`. Meant for the page fault
+/// handler, so it gives up instead of waiting if the guards are locked.
+pub fn stack_guard(address: VirtAddr) -> Option<&'static str> {
+    let page = address.align_down(PAGE_SIZE).as_u64();
+    STACK_GUARDS.try_lock()?.get(&page).copied()
+}
+
+/// Returns the allocated regions in address order.
+pub fn regions() -> Vec<Region> {
+    interrupts::without_interrupts(|| KERNEL_SPACE.lock().regions.values().copied().collect())
+}
@@ -0,0 +1,192 @@
+//! PS/2 mouse input.
+//!
+//! The mouse sends a 3-byte packet for every movement or button change. The interrupt handler
+//! reassembles the packets and keeps track of the pointer position, which [`state`] reports
+//! together with the movement since the previous call. When the console pointer is shown it
+//! follows the mouse.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::ps2::{self, Ps2Error};
+
+/// IRQ line of the PS/2 auxiliary device.
+const MOUSE_IRQ: u8 = 12;
+
+/// Controller command that enables the auxiliary device.
+const ENABLE_AUX: u8 = 0xA8;
+
+/// Mouse commands.
+const SET_DEFAULTS: u8 = 0xF6;
+const SET_SAMPLE_RATE: u8 = 0xF3;
+const ENABLE_REPORTING: u8 = 0xF4;
+
+/// Packets per second the mouse is asked to send.
+const SAMPLE_RATE: u8 = 100;
+
+/// Bits of the first packet byte.
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+/// State of the mouse buttons.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Pointer position and button state, with the movement since the previous [`state`] call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseState {
+    /// Pointer position in pixels, clamped to the screen.
+    pub x: usize,
+    pub y: usize,
+    /// Movement in mouse units, with `dy` growing downwards like `y`.
+    pub dx: i32,
+    pub dy: i32,
+    pub buttons: Buttons,
+}
+
+struct Mouse {
+    packet: [u8; 3],
+    received: usize,
+    state: MouseState,
+    width: usize,
+    height: usize,
+}
+
+impl Mouse {
+    /// Adds a byte to the current packet. Returns whether a complete packet was processed.
+    fn add_byte(&mut self, byte: u8) -> bool {
+        // The first byte always has bit 3 set; skipping bytes until one does resynchronizes the
+        // stream after a lost byte.
+        if self.received == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            return false;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet.len() {
+            return false;
+        }
+        self.received = 0;
+        self.apply_packet();
+        true
+    }
+
+    fn apply_packet(&mut self) {
+        let [flags, x, y] = self.packet;
+        let state = &mut self.state;
+        state.buttons = Buttons {
+            left: flags & PACKET_LEFT != 0,
+            right: flags & PACKET_RIGHT != 0,
+            middle: flags & PACKET_MIDDLE != 0,
+        };
+        // Movement is a 9-bit two's complement value; overflowed values are meaningless.
+        if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0 {
+            return;
+        }
+        let dx = delta(x, flags & PACKET_X_SIGN != 0);
+        // The mouse reports upwards movement as positive.
+        let dy = -delta(y, flags & PACKET_Y_SIGN != 0);
+        state.dx += dx;
+        state.dy += dy;
+        state.x = offset(state.x, dx, self.width);
+        state.y = offset(state.y, dy, self.height);
+    }
+}
+
+fn delta(value: u8, negative: bool) -> i32 {
+    if negative {
+        value as i32 - 256
+    } else {
+        value as i32
+    }
+}
+
+/// Moves `position` by `delta`, keeping it below `limit`.
+fn offset(position: usize, delta: i32, limit: usize) -> usize {
+    let moved = position as isize + delta as isize;
+    moved.clamp(0, limit.saturating_sub(1) as isize) as usize
+}
+
+static MOUSE: Mutex<Mouse> = Mutex::new(Mouse {
+    packet: [0; 3],
+    received: 0,
+    state: MouseState {
+        x: 0,
+        y: 0,
+        dx: 0,
+        dy: 0,
+        buttons: Buttons {
+            left: false,
+            right: false,
+            middle: false,
+        },
+    },
+    width: 0,
+    height: 0,
+});
+
+/// Enables the mouse and its interrupt. The pointer starts in the middle of the screen.
+pub fn init() -> Result<(), Ps2Error> {
+    let (width, height) =
+        crate::console::with_writer(|writer| (writer.width(), writer.height())).unwrap_or((0, 0));
+    // The replies are polled, so the keyboard interrupt handler must not consume them.
+    interrupts::without_interrupts(|| {
+        ps2::flush_output();
+        ps2::write_command(ENABLE_AUX)?;
+        let config = ps2::read_config()?;
+        ps2::write_config((config | ps2::CONFIG_AUX_INTERRUPT) & !ps2::CONFIG_AUX_CLOCK_DISABLED)?;
+
+        ps2::send_mouse_byte(SET_DEFAULTS)?;
+        ps2::send_mouse_byte(SET_SAMPLE_RATE)?;
+        ps2::send_mouse_byte(SAMPLE_RATE)?;
+        ps2::send_mouse_byte(ENABLE_REPORTING)?;
+
+        let mut mouse = MOUSE.lock();
+        mouse.width = width;
+        mouse.height = height;
+        mouse.state.x = width / 2;
+        mouse.state.y = height / 2;
+        Ok::<(), Ps2Error>(())
+    })?;
+    crate::interruptsa::register_irq(MOUSE_IRQ, interrupt_handler)
+        .expect("mouse IRQ already registered");
+    Ok(())
+}
+
+fn interrupt_handler() {
+    let Some(byte) = ps2::read_pending() else {
+        return;
+    };
+    if MOUSE.lock().add_byte(byte) {
+        crate::console::pointer_moved();
+    }
+}
+
+/// Returns the mouse state. The movement is reset, so the next call reports only the movement
+/// since this one.
+pub fn state() -> MouseState {
+    interrupts::without_interrupts(|| {
+        let state = &mut MOUSE.lock().state;
+        let snapshot = *state;
+        state.dx = 0;
+        state.dy = 0;
+        snapshot
+    })
+}
+
+/// Returns the pointer position in pixels without resetting the reported movement.
+pub fn position() -> (usize, usize) {
+    interrupts::without_interrupts(|| {
+        let state = &MOUSE.lock().state;
+        (state.x, state.y)
+    })
+}
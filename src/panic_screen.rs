@@ -0,0 +1,129 @@
+//! The screen shown when the kernel panics.
+//!
+//! The panic may have happened while `FRAME_BUFFER_WRITER` was locked, so the panic screen has
+//! its own writer on top of the raw framebuffer instead of going through the mutex. It is built
+//! when the console is, since it is too large for the exception stack a panic may run on.
+
+use bootloader_api::info::FrameBufferInfo;
+use core::arch::asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::{Mutex, Once};
+use uart_16550::SerialPort;
+use x86_64::instructions::{hlt, interrupts};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::model_specific::Efer;
+use x86_64::registers::rflags;
+
+use crate::writer::{Attributes, Color, FrameBufferWriter};
+
+/// The writer [`show`] draws with.
+static WRITER: Once<Mutex<FrameBufferWriter<'static>>> = Once::new();
+
+/// Set by the first panic, so that a panic while drawing the panic screen only halts.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Builds the writer [`show`] draws to `buffer` with. Called before the console's writer is
+/// built, which clears the screen again.
+pub(crate) fn register_framebuffer(buffer: &mut [u8], info: FrameBufferInfo) {
+    // Safety: the framebuffer is mapped for the whole lifetime of the kernel, and the panic
+    // screen only draws to it once nothing else does.
+    let buffer = unsafe { slice::from_raw_parts_mut(buffer.as_mut_ptr(), buffer.len()) };
+    WRITER.call_once(|| Mutex::new(FrameBufferWriter::new(buffer, info)));
+}
+
+/// Control and stack registers captured on panic.
+struct Registers {
+    rsp: u64,
+    rbp: u64,
+    rflags: u64,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+}
+
+impl Registers {
+    fn capture() -> Self {
+        let (rsp, rbp): (u64, u64);
+        unsafe {
+            asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+            asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+        }
+        Self {
+            rsp,
+            rbp,
+            rflags: rflags::read_raw(),
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: Cr3::read_raw().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+            efer: Efer::read_raw(),
+        }
+    }
+
+    fn print(&self, out: &mut impl Write) -> core::fmt::Result {
+        writeln!(
+            out,
+            "  RSP={:#018x} RBP={:#018x} RFLAGS={:#018x}",
+            self.rsp, self.rbp, self.rflags
+        )?;
+        writeln!(
+            out,
+            "  CR0={:#018x} CR2={:#018x} CR3={:#018x}",
+            self.cr0, self.cr2, self.cr3
+        )?;
+        writeln!(out, "  CR4={:#018x} EFER={:#018x}", self.cr4, self.efer)
+    }
+}
+
+/// Writes the panic report to `out`.
+fn report(out: &mut impl Write, info: &PanicInfo, registers: &Registers) -> core::fmt::Result {
+    writeln!(out, "{}", info.message())?;
+    if let Some(location) = info.location() {
+        writeln!(
+            out,
+            "at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )?;
+    }
+    writeln!(out)?;
+    writeln!(out, "Registers:")?;
+    registers.print(out)
+}
+
+/// Paints the panic screen, mirrors the report to the serial port, and halts forever.
+pub fn show(info: &PanicInfo) -> ! {
+    interrupts::disable();
+    if !PANICKING.swap(true, Ordering::SeqCst) {
+        let registers = Registers::capture();
+        // The other CPUs must not run on, nor draw over the report.
+        crate::smp::stop_others();
+
+        // The serial lock may be held as well, so talk to the port directly.
+        let mut serial = unsafe { SerialPort::new(0x3F8) };
+        let _ = writeln!(serial, "KERNEL PANIC");
+        let _ = report(&mut serial, info, &registers);
+
+        if let Some(writer) = WRITER.get() {
+            // Only the first panic gets here, so the lock is free.
+            let mut writer = writer.lock();
+            writer.set_colors(Color::WHITE, Color::RED);
+            writer.fill_screen();
+            writer.set_status(format_args!(" KERNEL PANIC "));
+            writer.set_attributes(Attributes::BOLD);
+            let _ = writeln!(writer, "The kernel has stopped.");
+            writer.set_attributes(Attributes::NONE);
+            let _ = writeln!(writer);
+            let _ = report(&mut *writer, info, &registers);
+        }
+    }
+    loop {
+        hlt();
+    }
+}
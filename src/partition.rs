@@ -0,0 +1,459 @@
+//! Partition tables.
+//!
+//! [`scan`] reads the partition table of a block device, a GUID partition table or else a master
+//! boot record, and returns each partition as a [`Partition`], a block device of its own that
+//! translates block indices by the partition's start and names the disk as its parent, so its
+//! requests join the disk's queue. Extended MBR partitions are skipped along with the logical
+//! partitions inside them. [`init`] registers the partitions of every disk,
+//! `ata0p1` for the first one on `ata0`.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::block::queue::BioOp;
+use crate::block::{self, BlockDevice, BlockError, Done, BLOCK_SIZE};
+
+/// Boot signature at the end of the MBR.
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// Offset of the four MBR partition entries, each 16 bytes.
+const MBR_ENTRIES: usize = 446;
+/// MBR partition types: unused, the extended containers and the protective entry of a GPT disk.
+const MBR_EMPTY: u8 = 0x00;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+const MBR_PROTECTIVE: u8 = 0xEE;
+/// Status bytes an MBR entry may have, inactive and bootable.
+const MBR_STATUS: [u8; 2] = [0x00, 0x80];
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Size of the GPT header fields this module knows, and the smallest valid entry.
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_ENTRY_SIZE: usize = 128;
+/// Largest entry array read, 128 entries of 128 bytes being the usual.
+const GPT_MAX_ENTRIES_SIZE: usize = 1024 * 1024;
+
+/// Errors returned by [`scan`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    /// Reading the table failed.
+    Block(BlockError),
+    /// The device has no partition table.
+    NoTable,
+    /// Neither GPT header is valid.
+    BadHeader,
+    /// The partition entries do not match their checksum.
+    BadChecksum,
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(error: BlockError) -> Self {
+        PartitionError::Block(error)
+    }
+}
+
+/// What the partition table says about a partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// An MBR partition with its type byte.
+    Mbr(u8),
+    /// A GPT partition with its type GUID, its own GUID and its name, in on-disk byte order.
+    Gpt {
+        type_guid: [u8; 16],
+        guid: [u8; 16],
+        name: String,
+    },
+}
+
+/// A range of blocks of a device.
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    start: u64,
+    blocks: u64,
+    kind: PartitionKind,
+}
+
+impl Partition {
+    /// The first block on the device.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn kind(&self) -> &PartitionKind {
+        &self.kind
+    }
+
+    fn translate(&self, index: u64) -> Result<u64, BlockError> {
+        if index >= self.blocks {
+            return Err(BlockError::OutOfRange(index));
+        }
+        Ok(self.start + index)
+    }
+
+    /// Translates the first of the blocks `bytes` long from `index` on, after checking the last.
+    fn translate_range(&self, index: u64, bytes: usize) -> Result<u64, BlockError> {
+        let count = (bytes / BLOCK_SIZE) as u64;
+        let last = index
+            .checked_add(count.saturating_sub(1))
+            .ok_or(BlockError::OutOfRange(index))?;
+        self.translate(last)?;
+        self.translate(index)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        self.device.read_block(self.translate(index)?, buffer)
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        self.device.write_block(self.translate(index)?, data)
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let start = self.translate_range(start, buffer.len())?;
+        self.device.read_blocks(start, buffer)
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        let start = self.translate_range(start, data.len())?;
+        self.device.write_blocks(start, data)
+    }
+
+    fn transfer(&self, op: BioOp, start: u64, buffer: Vec<u8>, done: Done) {
+        match self.translate_range(start, buffer.len()) {
+            Ok(start) => self.device.transfer(op, start, buffer, done),
+            Err(error) => done(Err(error)),
+        }
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+
+    fn parent(&self) -> Option<(Arc<dyn BlockDevice>, u64)> {
+        Some((self.device.clone(), self.start))
+    }
+}
+
+/// Reads the partition table of `device`.
+pub fn scan(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, PartitionError> {
+    let mut mbr = [0; BLOCK_SIZE];
+    device.read_block(0, &mut mbr)?;
+    if mbr[BLOCK_SIZE - 2..] != MBR_SIGNATURE {
+        return Err(PartitionError::NoTable);
+    }
+    let entries = mbr[MBR_ENTRIES..MBR_ENTRIES + 64].chunks_exact(16);
+    // A file system's boot sector has the signature too, but no entries.
+    if !entries.clone().all(|entry| MBR_STATUS.contains(&entry[0])) {
+        return Err(PartitionError::NoTable);
+    }
+    if entries.clone().any(|entry| entry[4] == MBR_PROTECTIVE) {
+        return scan_gpt(device);
+    }
+    let mut partitions = Vec::new();
+    for entry in entries {
+        let kind = entry[4];
+        if kind == MBR_EMPTY || MBR_EXTENDED.contains(&kind) {
+            continue;
+        }
+        let start = u64::from(u32_at(entry, 8));
+        let blocks = u64::from(u32_at(entry, 12));
+        if blocks == 0 || start + blocks > device.block_count() {
+            continue;
+        }
+        partitions.push(Partition {
+            device: device.clone(),
+            start,
+            blocks,
+            kind: PartitionKind::Mbr(kind),
+        });
+    }
+    Ok(partitions)
+}
+
+/// Reads a GUID partition table, from the backup header at the end of the disk if the primary
+/// one is damaged.
+fn scan_gpt(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, PartitionError> {
+    let last = device.block_count().saturating_sub(1);
+    let header = match read_gpt_header(device.as_ref(), 1)? {
+        Some(header) => header,
+        None => read_gpt_header(device.as_ref(), last)?.ok_or(PartitionError::BadHeader)?,
+    };
+    let entries_lba = u64_at(&header, 72);
+    let count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    let size = count.saturating_mul(entry_size);
+    if entry_size < GPT_ENTRY_SIZE || entry_size % 8 != 0 || size > GPT_MAX_ENTRIES_SIZE {
+        return Err(PartitionError::BadHeader);
+    }
+    let mut entries = Vec::with_capacity(size.next_multiple_of(BLOCK_SIZE));
+    let mut block = [0; BLOCK_SIZE];
+    for index in 0..size.div_ceil(BLOCK_SIZE) as u64 {
+        device.read_block(entries_lba + index, &mut block)?;
+        entries.extend_from_slice(&block);
+    }
+    if crc32(&entries[..size]) != u32_at(&header, 88) {
+        return Err(PartitionError::BadChecksum);
+    }
+    let mut partitions = Vec::new();
+    for entry in entries[..size].chunks_exact(entry_size) {
+        let type_guid: [u8; 16] = entry[0..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let (first, last) = (u64_at(entry, 32), u64_at(entry, 40));
+        if first > last || last >= device.block_count() {
+            continue;
+        }
+        // The name is UTF-16, padded with zeros.
+        let name = char::decode_utf16(
+            entry[56..128]
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take_while(|&unit| unit != 0),
+        )
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+        partitions.push(Partition {
+            device: device.clone(),
+            start: first,
+            blocks: last - first + 1,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                guid: entry[16..32].try_into().unwrap(),
+                name,
+            },
+        });
+    }
+    Ok(partitions)
+}
+
+/// Reads the GPT header at `lba`, or `None` if it is not a valid one.
+fn read_gpt_header(
+    device: &dyn BlockDevice,
+    lba: u64,
+) -> Result<Option<[u8; BLOCK_SIZE]>, BlockError> {
+    let mut header = [0; BLOCK_SIZE];
+    device.read_block(lba, &mut header)?;
+    let size = u32_at(&header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE || !(GPT_HEADER_SIZE..=BLOCK_SIZE).contains(&size) {
+        return Ok(None);
+    }
+    // The checksum covers the header with the checksum field zeroed.
+    let checksum = u32_at(&header, 16);
+    let mut copy = header;
+    copy[16..20].fill(0);
+    Ok((crc32(&copy[..size]) == checksum && u64_at(&header, 24) == lba).then_some(header))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// The CRC-32 used by GPT, the one of Ethernet and zlib.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Registers the partitions of every block device registered so far, numbered from 1 after the
+/// device's name. Returns how many there are.
+pub fn init() -> usize {
+    let mut found = 0;
+    for (name, device) in block::devices() {
+        let partitions = match scan(&device) {
+            Ok(partitions) => partitions,
+            Err(PartitionError::NoTable) => continue,
+            Err(error) => {
+                crate::serial_println!("partition: {} unreadable: {:?}", name, error);
+                continue;
+            }
+        };
+        for (index, partition) in partitions.into_iter().enumerate() {
+            let description = match &partition.kind {
+                PartitionKind::Mbr(kind) => format!("type {:#04x}", kind),
+                PartitionKind::Gpt { name, .. } => format!("\"{}\"", name),
+            };
+            crate::serial_println!(
+                "partition: {}p{} is {}, {} blocks from {}",
+                name,
+                index + 1,
+                description,
+                partition.blocks,
+                partition.start
+            );
+            block::register(&format!("{}p{}", name, index + 1), Arc::new(partition));
+            found += 1;
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+    use crate::testing::{put_u32, put_u64};
+
+    const DISK_BLOCKS: u64 = 64;
+    /// The one partition of the test disks.
+    const FIRST: u64 = 10;
+    const LAST: u64 = 29;
+
+    /// A GPT header at `lba` for four entries at block 2.
+    fn gpt_header(lba: u64, entries: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+        let mut header = [0; BLOCK_SIZE];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        put_u32(&mut header, 12, GPT_HEADER_SIZE as u32);
+        put_u64(&mut header, 24, lba);
+        put_u64(&mut header, 72, 2);
+        put_u32(&mut header, 80, 4);
+        put_u32(&mut header, 84, GPT_ENTRY_SIZE as u32);
+        put_u32(&mut header, 88, crc32(entries));
+        let checksum = crc32(&header[..GPT_HEADER_SIZE]);
+        put_u32(&mut header, 16, checksum);
+        header
+    }
+
+    /// A disk with a protective MBR, both GPT headers and one partition called "data".
+    fn gpt_disk() -> Arc<dyn BlockDevice> {
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(DISK_BLOCKS as usize));
+        let mut mbr = [0; BLOCK_SIZE];
+        mbr[MBR_ENTRIES + 4] = MBR_PROTECTIVE;
+        mbr[BLOCK_SIZE - 2..].copy_from_slice(&MBR_SIGNATURE);
+        let mut entries = [0; BLOCK_SIZE];
+        entries[0..16].fill(0xAB);
+        entries[16..32].fill(0xCD);
+        put_u64(&mut entries, 32, FIRST);
+        put_u64(&mut entries, 40, LAST);
+        for (index, unit) in "data".encode_utf16().enumerate() {
+            entries[56 + 2 * index..58 + 2 * index].copy_from_slice(&unit.to_le_bytes());
+        }
+        disk.write_block(0, &mbr).unwrap();
+        disk.write_block(1, &gpt_header(1, &entries)).unwrap();
+        disk.write_block(2, &entries).unwrap();
+        let backup = DISK_BLOCKS - 1;
+        disk.write_block(backup, &gpt_header(backup, &entries))
+            .unwrap();
+        disk
+    }
+
+    fn only_partition(disk: &Arc<dyn BlockDevice>) -> Partition {
+        let mut partitions = scan(disk).unwrap();
+        assert_eq!(partitions.len(), 1);
+        partitions.pop().unwrap()
+    }
+
+    #[test_case]
+    fn crc32_matches_the_reference() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test_case]
+    fn reads_gpt_partitions() {
+        let partition = only_partition(&gpt_disk());
+        assert_eq!(partition.start(), FIRST);
+        assert_eq!(partition.block_count(), LAST - FIRST + 1);
+        match partition.kind() {
+            PartitionKind::Gpt {
+                type_guid,
+                guid,
+                name,
+            } => {
+                assert_eq!((type_guid, guid), (&[0xAB; 16], &[0xCD; 16]));
+                assert_eq!(name, "data");
+            }
+            kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test_case]
+    fn falls_back_to_the_backup_header() {
+        let disk = gpt_disk();
+        disk.write_block(1, &[0; BLOCK_SIZE]).unwrap();
+        assert_eq!(only_partition(&disk).start(), FIRST);
+    }
+
+    #[test_case]
+    fn rejects_damaged_entries() {
+        let disk = gpt_disk();
+        let mut entries = [0; BLOCK_SIZE];
+        disk.read_block(2, &mut entries).unwrap();
+        entries[56] = b'x';
+        disk.write_block(2, &entries).unwrap();
+        assert!(matches!(scan(&disk), Err(PartitionError::BadChecksum)));
+    }
+
+    #[test_case]
+    fn reads_mbr_partitions_without_extended_ones() {
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(DISK_BLOCKS as usize));
+        let mut mbr = [0; BLOCK_SIZE];
+        let entry = &mut mbr[MBR_ENTRIES..MBR_ENTRIES + 16];
+        entry[0] = 0x80;
+        entry[4] = 0x0C;
+        put_u32(entry, 8, 4);
+        put_u32(entry, 12, 16);
+        let entry = &mut mbr[MBR_ENTRIES + 16..MBR_ENTRIES + 32];
+        entry[4] = MBR_EXTENDED[0];
+        put_u32(entry, 8, 20);
+        put_u32(entry, 12, 8);
+        mbr[BLOCK_SIZE - 2..].copy_from_slice(&MBR_SIGNATURE);
+        disk.write_block(0, &mbr).unwrap();
+        let partition = only_partition(&disk);
+        assert_eq!((partition.start(), partition.block_count()), (4, 16));
+        assert_eq!(partition.kind(), &PartitionKind::Mbr(0x0C));
+    }
+
+    #[test_case]
+    fn translates_blocks_into_the_partition() {
+        let disk = gpt_disk();
+        let partition = only_partition(&disk);
+        partition.write_block(0, &[1; BLOCK_SIZE]).unwrap();
+        let mut block = [0; BLOCK_SIZE];
+        disk.read_block(FIRST, &mut block).unwrap();
+        assert_eq!(block, [1; BLOCK_SIZE]);
+        let data = [2; 2 * BLOCK_SIZE];
+        partition.write_blocks(LAST - FIRST - 1, &data).unwrap();
+        disk.read_block(LAST, &mut block).unwrap();
+        assert_eq!(block, [2; BLOCK_SIZE]);
+        let (parent, start) = partition.parent().unwrap();
+        assert!(Arc::ptr_eq(&parent, &disk));
+        assert_eq!(start, FIRST);
+    }
+
+    #[test_case]
+    fn rejects_blocks_outside_the_partition() {
+        let partition = only_partition(&gpt_disk());
+        let blocks = LAST - FIRST + 1;
+        let mut block = [0; BLOCK_SIZE];
+        let mut two = [0; 2 * BLOCK_SIZE];
+        assert_eq!(
+            partition.read_block(blocks, &mut block),
+            Err(BlockError::OutOfRange(blocks))
+        );
+        assert!(partition.read_blocks(blocks - 1, &mut two).is_err());
+        assert!(partition.write_blocks(u64::MAX, &two).is_err());
+        assert!(partition.write_blocks(u64::MAX - 1, &two).is_err());
+        assert_eq!(
+            partition.write_block(u64::MAX, &block),
+            Err(BlockError::OutOfRange(u64::MAX))
+        );
+    }
+}
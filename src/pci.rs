@@ -0,0 +1,672 @@
+//! The PCI bus.
+//!
+//! Every PCI function has 256 bytes of configuration space, read and written here through the
+//! memory-mapped ECAM window the ACPI MCFG table describes, or else through the legacy address
+//! and data ports. [`init`] tries every bus, device and function number once and keeps the
+//! functions that answer, with the size of each of their base address registers; [`iter`] and
+//! [`devices`] list them, and drivers pick theirs by class code with [`find_device`].
+//! [`class_name`] and [`vendor_name`] name the common ones.
+//!
+//! A driver can give its function an interrupt vector of its own with
+//! [`PciDevice::enable_interrupt`], which walks the capability list for MSI-X or MSI and points
+//! the messages at the boot CPU's local APIC, instead of sharing a legacy IRQ line.
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::interruptsa::{IrqError, IrqHandler};
+use crate::memory::vmm::VmmError;
+
+/// Configuration address and data ports.
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+/// Address bit that turns the access into a configuration cycle.
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+/// Configuration space registers.
+const VENDOR_ID: u8 = 0x00;
+const COMMAND: u8 = 0x04;
+const STATUS: u8 = 0x06;
+const CLASS: u8 = 0x08;
+const HEADER_TYPE: u8 = 0x0E;
+const BAR_0: u8 = 0x10;
+const CAPABILITIES: u8 = 0x34;
+const INTERRUPT_LINE: u8 = 0x3C;
+
+/// Command register bits enabling the I/O and memory BARs and DMA.
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+/// Command register bit keeping the function from asserting its interrupt pin.
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// Status register bit set when the function has a capability list.
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// Capability IDs.
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+/// The most capabilities a list can hold in 256 bytes, so a looping list still ends.
+const MAX_CAPABILITIES: usize = 48;
+
+/// MSI and MSI-X message control bits. MSI: enable, the number of vectors enabled (a power of
+/// two), 64-bit addresses and per-vector masking. MSI-X: the table size less one, masking of the
+/// whole function, and enable.
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_ENABLED_VECTORS: u16 = 0x7 << 4;
+const MSI_64_BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASK: u16 = 1 << 8;
+const MSIX_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+
+/// The BAR in the low bits of the MSI-X table register, and the size of a table entry with the
+/// offset of its vector control dword, whose low bit masks the entry.
+const MSIX_BAR_MASK: u32 = 0x7;
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_VECTOR_CONTROL: usize = 12;
+const MSIX_MASKED: u32 = 1 << 0;
+
+/// Messages are writes to this address range, which local APICs claim; the destination APIC id
+/// goes in bits 12 to 19.
+const MESSAGE_ADDRESS: u32 = 0xFEE0_0000;
+
+/// Header type bit marking a device with more than one function.
+const HEADER_MULTI_FUNCTION: u8 = 1 << 7;
+
+/// BAR bits: I/O space instead of memory, and a 64-bit memory BAR.
+const BAR_IO: u32 = 1 << 0;
+const BAR_64_BIT: u32 = 1 << 2;
+const BAR_MEMORY_MASK: u32 = !0xF;
+const BAR_IO_MASK: u32 = !0x3;
+
+/// Header types, in the low bits of the header type register: a plain function with six BARs and
+/// a PCI-to-PCI bridge with two.
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+/// BAR bit of prefetchable memory.
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// Vendor ID read from a function that does not exist.
+const NO_VENDOR: u16 = 0xFFFF;
+
+/// MCFG fields: the first allocation entry, and the base address, segment group and bus range in
+/// an entry.
+const MCFG_ENTRIES: usize = 44;
+const MCFG_ENTRY_SIZE: usize = 16;
+/// Configuration space of a bus in the ECAM window: 32 devices of 8 functions of 4 KiB.
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+/// The address and data port pair. Locked with interrupts disabled.
+static CONFIG: Mutex<(Port<u32>, Port<u32>)> =
+    Mutex::new((Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)));
+
+/// The ECAM window of segment group 0, once [`init`] has mapped it.
+static ECAM: Once<Ecam> = Once::new();
+
+/// The functions found by [`init`].
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+
+struct Ecam {
+    base: VirtAddr,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+/// Errors setting up message signalled interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciError {
+    /// The function lacks the capability.
+    NoCapability(u8),
+    /// Messages go to a local APIC, and it is not in use.
+    NoApic,
+    /// The MSI-X table is not in a memory BAR of the function.
+    NoTable,
+    Irq(IrqError),
+    Mmio(VmmError),
+}
+
+impl From<IrqError> for PciError {
+    fn from(error: IrqError) -> Self {
+        PciError::Irq(error)
+    }
+}
+
+impl From<VmmError> for PciError {
+    fn from(error: VmmError) -> Self {
+        PciError::Mmio(error)
+    }
+}
+
+/// The location of a function on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Reads the 32-bit register at `offset`, which is rounded down to a multiple of 4.
+    pub fn read(self, offset: u8) -> u32 {
+        if let Some(register) = self.ecam_register(offset) {
+            return unsafe { register.read_volatile() };
+        }
+        interrupts::without_interrupts(|| {
+            let (address, data) = &mut *CONFIG.lock();
+            unsafe {
+                address.write(self.config_address(offset));
+                data.read()
+            }
+        })
+    }
+
+    /// Writes the 32-bit register at `offset`, which is rounded down to a multiple of 4.
+    pub fn write(self, offset: u8, value: u32) {
+        if let Some(register) = self.ecam_register(offset) {
+            return unsafe { register.write_volatile(value) };
+        }
+        interrupts::without_interrupts(|| {
+            let (address, data) = &mut *CONFIG.lock();
+            unsafe {
+                address.write(self.config_address(offset));
+                data.write(value);
+            }
+        })
+    }
+
+    pub fn read_u16(self, offset: u8) -> u16 {
+        (self.read(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(self, offset: u8) -> u8 {
+        (self.read(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Writes the 16-bit register at `offset`, leaving the other half of its dword alone.
+    pub fn write_u16(self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read(offset) & !(0xFFFF << shift);
+        self.write(offset, dword | (u32::from(value) << shift));
+    }
+
+    /// The register at `offset` in the ECAM window, if there is one covering the bus.
+    fn ecam_register(self, offset: u8) -> Option<*mut u32> {
+        let ecam = ECAM.get()?;
+        if !(ecam.start_bus..=ecam.end_bus).contains(&self.bus) {
+            return None;
+        }
+        let offset = (u64::from(self.bus - ecam.start_bus) * ECAM_BUS_SIZE)
+            | (u64::from(self.device) << 15)
+            | (u64::from(self.function) << 12)
+            | u64::from(offset & 0xFC);
+        Some((ecam.base + offset).as_mut_ptr())
+    }
+
+    fn config_address(self, offset: u8) -> u32 {
+        CONFIG_ENABLE
+            | (u32::from(self.bus) << 16)
+            | (u32::from(self.device) << 11)
+            | (u32::from(self.function) << 8)
+            | u32::from(offset & 0xFC)
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// What a base address register points at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: PhysAddr,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+/// A function found on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    /// The legacy IRQ line the firmware routed the function's interrupt pin to, if any.
+    pub interrupt_line: Option<u8>,
+    /// The base address registers with their sizes. The upper half of a 64-bit BAR is `None`.
+    pub bars: [Option<Bar>; 6],
+}
+
+impl PciDevice {
+    fn read(address: PciAddress) -> Option<PciDevice> {
+        let ids = address.read(VENDOR_ID);
+        if ids as u16 == NO_VENDOR {
+            return None;
+        }
+        let class = address.read(CLASS);
+        let line = address.read_u8(INTERRUPT_LINE);
+        let bar_count = match address.read_u8(HEADER_TYPE) & HEADER_TYPE_MASK {
+            0 => 6,
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        let mut bars = [None; 6];
+        let mut index = 0;
+        while index < bar_count {
+            let (bar, slots) = size_bar(address, index);
+            bars[index as usize] = bar;
+            index += slots;
+        }
+        Some(PciDevice {
+            address,
+            vendor_id: ids as u16,
+            device_id: (ids >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            interrupt_line: (line < 16).then_some(line),
+            bars,
+        })
+    }
+
+    /// BAR `index`, or `None` if it is unused or the upper half of a 64-bit one.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        self.bars.get(usize::from(index)).copied().flatten()
+    }
+
+    /// The physical address memory BAR `index` points at, or `None` for an I/O or missing BAR.
+    pub fn memory_bar(&self, index: u8) -> Option<PhysAddr> {
+        if index > 5 {
+            return None;
+        }
+        let offset = BAR_0 + 4 * index;
+        let low = self.address.read(offset);
+        if low & BAR_IO != 0 {
+            return None;
+        }
+        let mut address = u64::from(low & BAR_MEMORY_MASK);
+        if low & BAR_64_BIT != 0 && index < 5 {
+            address |= u64::from(self.address.read(offset + 4)) << 32;
+        }
+        (address != 0).then(|| PhysAddr::new(address))
+    }
+
+    /// The first port I/O BAR `index` points at, or `None` for a memory or missing BAR.
+    pub fn io_bar(&self, index: u8) -> Option<u16> {
+        if index > 5 {
+            return None;
+        }
+        let bar = self.address.read(BAR_0 + 4 * index);
+        if bar & BAR_IO == 0 {
+            return None;
+        }
+        let port = (bar & BAR_IO_MASK) as u16;
+        (port != 0).then_some(port)
+    }
+
+    /// Lets the function respond to accesses to its BARs and access memory itself.
+    pub fn enable_bus_mastering(&self) {
+        let command = self.address.read_u16(COMMAND);
+        let enabled = COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER;
+        self.address.write_u16(COMMAND, command | enabled);
+    }
+
+    /// The function's capabilities, as pairs of ID and offset in configuration space.
+    pub fn capabilities(&self) -> Capabilities {
+        let first = if self.address.read_u16(STATUS) & STATUS_CAPABILITIES != 0 {
+            self.address.read_u8(CAPABILITIES)
+        } else {
+            0
+        };
+        Capabilities {
+            address: self.address,
+            next: first,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// The offset of the first capability with ID `id`.
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        self.capabilities()
+            .find(|&(capability, _)| capability == id)
+            .map(|(_, offset)| offset)
+    }
+
+    /// Gives the function an interrupt vector of its own, through MSI-X or else MSI, and returns
+    /// the vector. `handler` runs on the boot CPU and its IRQ line is disabled; a driver whose
+    /// device has neither capability, or when the APICs are not in use, keeps using
+    /// [`PciDevice::interrupt_line`].
+    pub fn enable_interrupt(
+        &self,
+        name: &'static str,
+        handler: IrqHandler,
+    ) -> Result<u8, PciError> {
+        match self.enable_msix(&[(name, handler)]) {
+            Ok(vectors) => Ok(vectors[0]),
+            Err(PciError::NoCapability(_)) => self.enable_msi(name, handler),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Enables MSI with a single vector for `handler`, and returns the vector.
+    pub fn enable_msi(&self, name: &'static str, handler: IrqHandler) -> Result<u8, PciError> {
+        let capability = self
+            .find_capability(CAPABILITY_MSI)
+            .ok_or(PciError::NoCapability(CAPABILITY_MSI))?;
+        let address = message_address()?;
+        let vector = crate::interruptsa::allocate_vector(name, handler)?;
+        let config = self.address;
+        let control = config.read_u16(capability + 2);
+        config.write_u16(
+            capability + 2,
+            control & !(MSI_ENABLE | MSI_ENABLED_VECTORS),
+        );
+        config.write(capability + 4, address);
+        let (data, mask) = if control & MSI_64_BIT != 0 {
+            config.write(capability + 8, 0);
+            (capability + 12, capability + 16)
+        } else {
+            (capability + 8, capability + 12)
+        };
+        config.write_u16(data, u16::from(vector));
+        if control & MSI_PER_VECTOR_MASK != 0 {
+            config.write(mask, 0);
+        }
+        self.disable_line();
+        config.write_u16(
+            capability + 2,
+            (control & !MSI_ENABLED_VECTORS) | MSI_ENABLE,
+        );
+        Ok(vector)
+    }
+
+    /// Enables MSI-X with a vector for each of `handlers`, in table order, and returns the
+    /// vectors. Table entries beyond them stay masked.
+    pub fn enable_msix(
+        &self,
+        handlers: &[(&'static str, IrqHandler)],
+    ) -> Result<Vec<u8>, PciError> {
+        let capability = self
+            .find_capability(CAPABILITY_MSIX)
+            .ok_or(PciError::NoCapability(CAPABILITY_MSIX))?;
+        let config = self.address;
+        let control = config.read_u16(capability + 2);
+        let size = usize::from(control & MSIX_TABLE_SIZE) + 1;
+        if handlers.len() > size {
+            return Err(PciError::Irq(IrqError::NoFreeVector));
+        }
+        let address = message_address()?;
+        let table = config.read(capability + 4);
+        let bar = (table & MSIX_BAR_MASK) as u8;
+        let Some(Bar::Memory { address: base, .. }) = self.bar(bar) else {
+            return Err(PciError::NoTable);
+        };
+        let base = base + u64::from(table & !MSIX_BAR_MASK);
+        let table = unsafe { crate::memory::map_mmio(base, size * MSIX_ENTRY_SIZE)? };
+
+        let mut vectors = Vec::with_capacity(handlers.len());
+        for &(name, handler) in handlers {
+            match crate::interruptsa::allocate_vector(name, handler) {
+                Ok(vector) => vectors.push(vector),
+                Err(error) => {
+                    vectors
+                        .into_iter()
+                        .for_each(crate::interruptsa::free_vector);
+                    return Err(error.into());
+                }
+            }
+        }
+
+        // The table is only written with every entry held back, and the BAR decoding.
+        self.enable_bus_mastering();
+        config.write_u16(capability + 2, control | MSIX_ENABLE | MSIX_FUNCTION_MASK);
+        for index in 0..size {
+            let entry: *mut u32 = (table + (index * MSIX_ENTRY_SIZE) as u64).as_mut_ptr();
+            unsafe {
+                match vectors.get(index) {
+                    Some(&vector) => {
+                        entry.write_volatile(address);
+                        entry.add(1).write_volatile(0);
+                        entry.add(2).write_volatile(u32::from(vector));
+                        entry.add(MSIX_VECTOR_CONTROL / 4).write_volatile(0);
+                    }
+                    None => entry
+                        .add(MSIX_VECTOR_CONTROL / 4)
+                        .write_volatile(MSIX_MASKED),
+                }
+            }
+        }
+        self.disable_line();
+        config.write_u16(
+            capability + 2,
+            (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+        );
+        Ok(vectors)
+    }
+
+    /// Lets the function write its messages, and stops it from asserting its IRQ line.
+    fn disable_line(&self) {
+        self.enable_bus_mastering();
+        let command = self.address.read_u16(COMMAND);
+        self.address
+            .write_u16(COMMAND, command | COMMAND_INTX_DISABLE);
+    }
+}
+
+/// The capability list of a function, see [`PciDevice::capabilities`].
+pub struct Capabilities {
+    address: PciAddress,
+    next: u8,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = (u8, u8);
+
+    fn next(&mut self) -> Option<(u8, u8)> {
+        // Pointers are dword aligned and below 0x40 is the header.
+        let offset = self.next & 0xFC;
+        if offset < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = self.address.read_u16(offset);
+        self.next = (header >> 8) as u8;
+        Some((header as u8, offset))
+    }
+}
+
+/// The message address that delivers to the boot CPU's local APIC.
+fn message_address() -> Result<u32, PciError> {
+    let id = crate::apic::local_id().ok_or(PciError::NoApic)?;
+    Ok(MESSAGE_ADDRESS | (u32::from(id) << 12))
+}
+
+/// Reads BAR `index` of the function at `address` and finds its size by writing all ones and
+/// seeing which address bits stick. Decoding is off meanwhile, so the function does not answer
+/// at the bogus address. Returns the BAR and how many registers it takes, 2 for a 64-bit one.
+fn size_bar(address: PciAddress, index: u8) -> (Option<Bar>, u8) {
+    let offset = BAR_0 + 4 * index;
+    let command = address.read_u16(COMMAND);
+    address.write_u16(COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+    let low = address.read(offset);
+    address.write(offset, u32::MAX);
+    let low_mask = address.read(offset);
+    address.write(offset, low);
+    let wide = low & BAR_IO == 0 && low & BAR_64_BIT != 0 && index < 5;
+    let (high, high_mask) = if wide {
+        let high = address.read(offset + 4);
+        address.write(offset + 4, u32::MAX);
+        let high_mask = address.read(offset + 4);
+        address.write(offset + 4, high);
+        (high, high_mask)
+    } else {
+        // A 32-bit BAR decodes none of the upper bits, as if they were all set.
+        (0, u32::MAX)
+    };
+    address.write_u16(COMMAND, command);
+
+    let slots = if wide { 2 } else { 1 };
+    if low_mask == 0 {
+        return (None, slots);
+    }
+    let bar = if low & BAR_IO != 0 {
+        let size = !(low_mask & BAR_IO_MASK) & 0xFFFF;
+        Bar::Io {
+            port: (low & BAR_IO_MASK) as u16,
+            size: size.wrapping_add(1),
+        }
+    } else {
+        let mask = (u64::from(high_mask) << 32) | u64::from(low_mask & BAR_MEMORY_MASK);
+        let address = (u64::from(high) << 32) | u64::from(low & BAR_MEMORY_MASK);
+        Bar::Memory {
+            address: PhysAddr::new_truncate(address),
+            size: (!mask).wrapping_add(1),
+            prefetchable: low & BAR_PREFETCHABLE != 0,
+        }
+    };
+    (Some(bar), slots)
+}
+
+/// Maps the ECAM window of segment group 0 if the MCFG table has one, then finds every function.
+/// Returns how many there are and whether ECAM is used. Call after [`crate::acpi`] and memory
+/// management are set up, and before drivers look for their devices.
+pub fn init() -> (usize, bool) {
+    let ecam = map_ecam();
+    if let Some(ecam) = ecam {
+        ECAM.call_once(|| ecam);
+    }
+    (iter().count(), ECAM.get().is_some())
+}
+
+/// Maps the configuration space the MCFG table gives for segment group 0.
+fn map_ecam() -> Option<Ecam> {
+    let mcfg = crate::acpi::find_table(b"MCFG")?;
+    let entry = mcfg
+        .get(MCFG_ENTRIES..)?
+        .chunks_exact(MCFG_ENTRY_SIZE)
+        .find(|entry| u16::from_le_bytes([entry[8], entry[9]]) == 0)?;
+    let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+    let (start_bus, end_bus) = (entry[10], entry[11]);
+    if end_bus < start_bus {
+        return None;
+    }
+    // The base address is that of bus 0, even if the window starts at a later bus.
+    let first = PhysAddr::new(base + u64::from(start_bus) * ECAM_BUS_SIZE);
+    let length = u64::from(end_bus - start_bus + 1) * ECAM_BUS_SIZE;
+    let base = unsafe { crate::memory::map_mmio(first, length as usize) }.ok()?;
+    Some(Ecam {
+        base,
+        start_bus,
+        end_bus,
+    })
+}
+
+/// Every function on the bus, in address order. Scans the bus on the first call.
+pub fn iter() -> impl Iterator<Item = &'static PciDevice> {
+    DEVICES.call_once(scan).iter()
+}
+
+/// Every function on the bus, in address order.
+pub fn devices() -> Vec<PciDevice> {
+    iter().copied().collect()
+}
+
+/// The first function with the given class and subclass.
+pub fn find_device(class: u8, subclass: u8) -> Option<PciDevice> {
+    iter()
+        .find(|device| device.class == class && device.subclass == subclass)
+        .copied()
+}
+
+fn scan() -> Vec<PciDevice> {
+    let mut found = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = PciAddress {
+                bus,
+                device,
+                function: 0,
+            };
+            let Some(function_0) = PciDevice::read(first) else {
+                continue;
+            };
+            found.push(function_0);
+            if first.read_u8(HEADER_TYPE) & HEADER_MULTI_FUNCTION == 0 {
+                continue;
+            }
+            for function in 1..8 {
+                let address = PciAddress { function, ..first };
+                found.extend(PciDevice::read(address));
+            }
+        }
+    }
+    found
+}
+
+/// A description of the class and subclass, like `SATA controller`.
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, _) => "unclassified device",
+        (0x01, 0x00) => "SCSI controller",
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x05) => "ATA controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "display controller",
+        (0x04, 0x01) => "audio device",
+        (0x04, 0x03) => "HD audio controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x09, _) => "input device controller",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus controller",
+        (0x0C, _) => "serial bus controller",
+        (0x0D, _) => "wireless controller",
+        _ => "unknown device",
+    }
+}
+
+/// The name of the vendor, for the vendors common in PCs and virtual machines.
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    Some(match vendor_id {
+        0x1002 => "AMD/ATI",
+        0x1022 => "AMD",
+        0x10DE => "NVIDIA",
+        0x10EC => "Realtek",
+        0x1234 => "QEMU",
+        0x14E4 => "Broadcom",
+        0x15AD => "VMware",
+        0x1AF4 => "Red Hat (virtio)",
+        0x1B36 => "Red Hat",
+        0x8086 => "Intel",
+        0x80EE => "VirtualBox",
+        _ => return None,
+    })
+}
@@ -0,0 +1,245 @@
+//! Per-CPU data.
+//!
+//! Every CPU has a small area whose address is loaded into its GS base, so the CPU can find its
+//! own index with a single `gs`-relative load. A per-CPU variable, declared with [`per_cpu!`],
+//! holds one value per possible CPU and picks the caller's by that index. Values belonging to one
+//! CPU are only touched by that CPU, so they need no locks, only protection from the interrupt
+//! handlers of the same CPU.
+//!
+//! The area also holds the two stack pointers the `syscall` entry swaps, at fixed offsets, since
+//! it runs before there is a kernel stack to work with. User mode has a GS base of its own, which
+//! a program may change by loading GS. Every way into the kernel from user mode does a `swapgs`
+//! first and another on the way back: the `syscall` and `int 0x80` entries themselves, and the
+//! interrupt and exception handlers through [`KernelGs`].
+//!
+//! The boot CPU is CPU 0, and the application processors are numbered in the order they start.
+//! Until [`init`] has run every caller is taken to be CPU 0.
+
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+/// Number of CPUs per-CPU variables have room for.
+pub const MAX_CPUS: usize = 16;
+
+/// Errors returned by [`init_cpu`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerCpuError {
+    /// The index is [`MAX_CPUS`] or more.
+    TooManyCpus,
+}
+
+/// Offset of the kernel stack pointer for system calls in the area.
+pub(crate) const KERNEL_STACK_OFFSET: usize = 8;
+
+/// Offset in the area where the `syscall` entry keeps the user stack pointer.
+pub(crate) const USER_STACK_OFFSET: usize = 16;
+
+/// The area GS points to. `repr(C)`, since [`cpu_id`] reads `id` at offset 0 and the `syscall`
+/// entry uses the stack pointers at their offsets.
+#[repr(C)]
+struct CpuArea {
+    id: usize,
+    kernel_stack: AtomicU64,
+    /// Only touched by the `syscall` entry.
+    #[allow(dead_code)]
+    user_stack: AtomicU64,
+}
+
+const _: () = assert!(core::mem::offset_of!(CpuArea, kernel_stack) == KERNEL_STACK_OFFSET);
+const _: () = assert!(core::mem::offset_of!(CpuArea, user_stack) == USER_STACK_OFFSET);
+
+static AREAS: [CpuArea; MAX_CPUS] = {
+    let mut areas = [const {
+        CpuArea {
+            id: 0,
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+        }
+    }; MAX_CPUS];
+    let mut id = 0;
+    while id < MAX_CPUS {
+        areas[id].id = id;
+        id += 1;
+    }
+    areas
+};
+
+/// Set once the boot CPU's GS base points to its area.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Points the boot CPU's GS base at the area of CPU 0. Must run early in boot, before anything
+/// relies on per-CPU data being the calling CPU's own.
+pub fn init() {
+    init_cpu(0).expect("CPU 0 has a per-CPU area");
+}
+
+/// Points the calling CPU's GS base at the area of CPU `id`, which then becomes its index. Each
+/// CPU must call this once, with an index no other CPU uses, before it enables interrupts.
+pub fn init_cpu(id: usize) -> Result<(), PerCpuError> {
+    let area = AREAS.get(id).ok_or(PerCpuError::TooManyCpus)?;
+    GsBase::write(VirtAddr::from_ptr(area));
+    KernelGsBase::write(VirtAddr::zero());
+    READY.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Clears the user GS base a program left behind, so the next one starts without it.
+pub(crate) fn reset_user_gs_base() {
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// Switches an interrupt or exception handler to the kernel's GS base if it interrupted user
+/// mode, and back to the program's when dropped if it returns there. Create it first thing in
+/// the handler, so it is dropped last. Also clears the alignment check flag, which would lift
+/// SMAP in the handler, see [`crate::usermode::uaccess::clear_access_flag`].
+pub(crate) struct KernelGs {
+    /// The frame the handler returns through, or `None` if it swapped by the GS base alone.
+    frame: Option<*const InterruptStackFrame>,
+    swapped: bool,
+}
+
+impl KernelGs {
+    /// Swaps if `stack_frame` shows the handler interrupted user mode.
+    #[inline(always)]
+    pub(crate) fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let swapped = stack_frame.code_segment & 3 == 3;
+        if swapped {
+            swapgs();
+        }
+        crate::usermode::uaccess::clear_access_flag();
+        KernelGs {
+            frame: Some(stack_frame),
+            swapped,
+        }
+    }
+
+    /// Swaps if the GS base is not a per-CPU area, for the handlers of the NMI, debug, machine
+    /// check and double fault exceptions. These can also interrupt the kernel between a `swapgs`
+    /// and the return to user mode, so the privilege level does not tell which base is loaded.
+    #[inline(always)]
+    pub(crate) fn enter_paranoid() -> Self {
+        let areas = AREAS.as_ptr_range();
+        let areas = areas.start as u64..areas.end as u64;
+        let swapped = !areas.contains(&GsBase::read().as_u64());
+        if swapped {
+            swapgs();
+        }
+        crate::usermode::uaccess::clear_access_flag();
+        KernelGs {
+            frame: None,
+            swapped,
+        }
+    }
+}
+
+impl Drop for KernelGs {
+    #[inline(always)]
+    fn drop(&mut self) {
+        // A handler sent to take a signal returns to ring 0 instead, and the signal entry swaps
+        // before it goes on to the program.
+        let to_user = match self.frame {
+            Some(frame) => {
+                let code_segment = unsafe { ptr::read_volatile(&(*frame).code_segment) };
+                code_segment & 3 == 3
+            }
+            None => self.swapped,
+        };
+        if to_user {
+            swapgs();
+        }
+    }
+}
+
+#[inline(always)]
+fn swapgs() {
+    unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+}
+
+/// The stack the `syscall` entry of the calling CPU switches to, that of the thread last switched
+/// to that runs user code.
+pub(crate) fn kernel_stack() -> VirtAddr {
+    VirtAddr::new(AREAS[cpu_id()].kernel_stack.load(Ordering::Relaxed))
+}
+
+/// Sets the stack the `syscall` entry of the calling CPU switches to.
+pub(crate) fn set_kernel_stack(top: VirtAddr) {
+    AREAS[cpu_id()]
+        .kernel_stack
+        .store(top.as_u64(), Ordering::Relaxed);
+}
+
+/// Index of the calling CPU, below [`MAX_CPUS`].
+#[inline]
+pub fn cpu_id() -> usize {
+    if !READY.load(Ordering::Relaxed) {
+        return 0;
+    }
+    let id: usize;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) id, options(nostack, preserves_flags, readonly));
+    }
+    id
+}
+
+/// A variable with one value per CPU. Declare it with [`per_cpu!`].
+pub struct PerCpu<T> {
+    values: [T; MAX_CPUS],
+}
+
+// Each CPU only reaches its own value, and only through `get` if `T` is `Sync` anyway.
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(values: [T; MAX_CPUS]) -> Self {
+        PerCpu { values }
+    }
+
+    /// Runs `f` on the calling CPU's value, with interrupts disabled so that nothing else on
+    /// this CPU can touch it meanwhile.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        interrupts::without_interrupts(|| f(&self.values[cpu_id()]))
+    }
+}
+
+impl<T: Sync> PerCpu<T> {
+    /// The calling CPU's value. For atomics and other types that are safe to share, which is
+    /// what interrupt handlers of the same CPU do with it.
+    #[inline]
+    pub fn get(&self) -> &T {
+        &self.values[cpu_id()]
+    }
+
+    /// The value of CPU `cpu`, for flagging work for another CPU.
+    pub fn get_cpu(&self, cpu: usize) -> &T {
+        &self.values[cpu]
+    }
+
+    /// The values of all CPUs, for summing up statistics.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.values.iter()
+    }
+}
+
+/// Declares per-CPU variables, each starting out as the given constant on every CPU.
+///
+/// ```ignore
+/// per_cpu! {
+///     static TICKS: AtomicU64 = AtomicU64::new(0);
+/// }
+/// TICKS.get().fetch_add(1, Ordering::Relaxed);
+/// ```
+#[macro_export]
+macro_rules! per_cpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpu<$ty> =
+                $crate::percpu::PerCpu::new([const { $init }; $crate::percpu::MAX_CPUS]);
+        )*
+    };
+}
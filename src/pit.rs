@@ -0,0 +1,72 @@
+//! The 8253/8254 programmable interval timer.
+//!
+//! Its input clock has a fixed, known frequency, which makes it the reference for calibrating
+//! the other timers.
+
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT input clock in Hz.
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const CHANNEL_2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+/// Port B of the keyboard controller, which gates channel 2 and reads its output.
+const PORT_B: u16 = 0x61;
+
+/// Port B bits: channel 2 gate, speaker enable and channel 2 output.
+const PORT_B_GATE: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUTPUT: u8 = 1 << 5;
+
+/// Command selecting channel 2, low then high count byte, mode 0 (interrupt on terminal count).
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+/// Command selecting channel 0, low then high count byte, mode 3 (square wave generator).
+const CHANNEL_0_PERIODIC: u8 = 0b0011_0110;
+
+/// Lowest frequency channel 0 can run at, with the largest 16-bit reload value.
+pub const MIN_FREQUENCY: u32 = BASE_FREQUENCY / 0xFFFF + 1;
+
+/// Programs channel 0, which drives the legacy timer IRQ, to fire about `hz` times per second.
+/// Returns the actual period in nanoseconds, which differs slightly since the input clock is
+/// divided by an integer.
+pub fn set_frequency(hz: u32) -> u64 {
+    let reload = (BASE_FREQUENCY / hz.max(MIN_FREQUENCY)).clamp(1, 0xFFFF) as u16;
+    let mut command = Port::<u8>::new(COMMAND_PORT);
+    let mut channel = Port::<u8>::new(CHANNEL_0_PORT);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        command.write(CHANNEL_0_PERIODIC);
+        channel.write(reload as u8);
+        channel.write((reload >> 8) as u8);
+    });
+    u64::from(reload) * 1_000_000_000 / u64::from(BASE_FREQUENCY)
+}
+
+/// Longest wait [`wait_ms_polled`] supports, limited by the 16-bit counter.
+pub const MAX_POLLED_WAIT_MS: u32 = 50;
+
+/// Busy-waits for `ms` milliseconds, at most [`MAX_POLLED_WAIT_MS`], using channel 2.
+///
+/// Channel 2 is not connected to an interrupt, so this works with interrupts disabled and does
+/// not disturb channel 0.
+pub fn wait_ms_polled(ms: u32) {
+    let count = (BASE_FREQUENCY / 1000 * ms.min(MAX_POLLED_WAIT_MS)) as u16;
+    let mut port_b = Port::<u8>::new(PORT_B);
+    let mut command = Port::<u8>::new(COMMAND_PORT);
+    let mut channel = Port::<u8>::new(CHANNEL_2_PORT);
+    unsafe {
+        // Gate off and speaker off while programming.
+        let value = port_b.read() & !(PORT_B_GATE | PORT_B_SPEAKER);
+        port_b.write(value);
+        command.write(CHANNEL_2_ONE_SHOT);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+        // Raising the gate starts the countdown; the output goes high at terminal count.
+        port_b.write(value | PORT_B_GATE);
+        while port_b.read() & PORT_B_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+        port_b.write(value);
+    }
+}
@@ -0,0 +1,79 @@
+//! Rebooting the machine.
+
+use x86_64::instructions::{interrupts, port::Port, tables};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+use crate::acpi;
+use crate::ps2;
+
+/// 8042 controller command that pulses the CPU reset line.
+const PULSE_RESET_LINE: u8 = 0xFE;
+
+/// Reset control register of PC chipsets, which is what the ACPI reset register points to on
+/// virtually all machines. Used when the FADT has no reset register in I/O space.
+const RESET_CONTROL_PORT: u16 = 0xCF9;
+/// Requests a full reset through [`RESET_CONTROL_PORT`]: the reset type is set first, then the
+/// reset is triggered.
+const FULL_RESET: u8 = 0x02;
+const RESET_CPU: u8 = 0x04;
+
+/// Restarts the machine.
+///
+/// Tries the keyboard controller reset line first, then the ACPI reset register, and finally
+/// forces a triple fault, which resets the CPU on any machine.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    crate::serial_println!("rebooting");
+    // The other CPUs must not run on into the reset, half way through whatever they do.
+    crate::smp::stop_others();
+    // Clean the screen up if the writer is free; never wait for it, since this may run in an
+    // interrupt handler that interrupted the writer's owner.
+    if let Some(mut writer) = crate::FRAME_BUFFER_WRITER.try_lock() {
+        if let Some(writer) = writer.as_mut() {
+            writer.hide_pointer();
+            writer.set_status(format_args!(" rebooting..."));
+        }
+    }
+
+    let _ = ps2::write_command(PULSE_RESET_LINE);
+    wait_for_reset();
+
+    let reset_register = acpi::fadt().and_then(|fadt| {
+        let register = fadt.reset_register?;
+        let port = u16::try_from(register.address).ok();
+        port.filter(|_| register.space == acpi::SPACE_SYSTEM_IO)
+            .map(|port| (port, fadt.reset_value))
+    });
+    match reset_register {
+        Some((port, value)) => unsafe { Port::<u8>::new(port).write(value) },
+        None => {
+            let mut port = Port::<u8>::new(RESET_CONTROL_PORT);
+            unsafe {
+                port.write(FULL_RESET);
+                port.write(FULL_RESET | RESET_CPU);
+            }
+        }
+    }
+    wait_for_reset();
+
+    // An empty IDT turns the next exception into a double fault and then a triple fault.
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        tables::lidt(&empty);
+    }
+    interrupts::int3();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Gives a reset request some time to take effect.
+fn wait_for_reset() {
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+}
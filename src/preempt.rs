@@ -0,0 +1,83 @@
+//! Preemption and interrupt nesting counters.
+//!
+//! The preemption count is raised by code that must not be switched away from, and the
+//! interrupt depth by every interrupt handler. The scheduler may only switch threads when both
+//! are zero, that is at the exit of the outermost interrupt handler of code that allowed it.
+//! Code that blocks on locks also held by interrupt-free code can check [`in_interrupt`] to catch
+//! being called from a handler. Both counters are per CPU. A thread is only moved to another CPU
+//! while its preemption count is zero, so it finds its own count where it left it.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::per_cpu;
+
+per_cpu! {
+    static PREEMPT_COUNT: AtomicUsize = AtomicUsize::new(0);
+    static IRQ_DEPTH: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// Disables preemption until the matching [`preempt_enable`]. Calls nest.
+pub fn preempt_disable() {
+    PREEMPT_COUNT.with(|count| count.fetch_add(1, Ordering::Relaxed));
+}
+
+/// Undoes one [`preempt_disable`].
+pub fn preempt_enable() {
+    let previous = PREEMPT_COUNT.with(|count| count.fetch_sub(1, Ordering::Relaxed));
+    assert!(previous > 0, "preempt_enable without preempt_disable");
+}
+
+/// Number of [`preempt_disable`] calls not yet undone.
+pub fn preempt_count() -> usize {
+    PREEMPT_COUNT.with(|count| count.load(Ordering::Relaxed))
+}
+
+/// Disables preemption while the guard is alive.
+pub struct PreemptGuard(());
+
+impl PreemptGuard {
+    pub fn new() -> PreemptGuard {
+        preempt_disable();
+        PreemptGuard(())
+    }
+}
+
+impl Default for PreemptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        preempt_enable();
+    }
+}
+
+/// Marks the start of an interrupt handler. Called by the interrupt dispatch code.
+pub(crate) fn irq_enter() {
+    IRQ_DEPTH.get().fetch_add(1, Ordering::Relaxed);
+}
+
+/// Marks the end of an interrupt handler. Returns whether this was the outermost handler and
+/// preemption is enabled, the point where the scheduler may switch threads.
+pub(crate) fn irq_exit() -> bool {
+    let previous = IRQ_DEPTH.get().fetch_sub(1, Ordering::Relaxed);
+    debug_assert!(previous > 0, "irq_exit without irq_enter");
+    previous == 1 && preempt_count() == 0
+}
+
+/// Number of interrupt handlers currently running, nested in each other.
+pub fn irq_depth() -> usize {
+    IRQ_DEPTH.with(|depth| depth.load(Ordering::Relaxed))
+}
+
+/// Returns whether the caller runs in an interrupt handler.
+pub fn in_interrupt() -> bool {
+    irq_depth() != 0
+}
+
+/// Returns whether the scheduler may switch away from the caller.
+pub fn preemptible() -> bool {
+    !in_interrupt() && preempt_count() == 0
+}
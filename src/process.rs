@@ -0,0 +1,516 @@
+//! User processes.
+//!
+//! A [`Process`] owns an [`AddressSpace`], a table of open files and the threads running in it.
+//! [`spawn_elf`] loads an executable into a new address space and starts the process's main
+//! thread, which enters user mode at the program's entry point and ends the process once the
+//! program exits or is ended by a signal. The threads of a process run with its page tables
+//! loaded.
+//!
+//! [`fork`] duplicates the calling process, sharing its memory copy-on-write, and [`exec`]
+//! replaces the program the calling process runs with another one. A program gets more memory
+//! for its heap or as anonymous mappings through [`UserMemory`].
+//!
+//! A process started by another one is its child. When a child ends, its memory and files are
+//! freed, but it stays in the process table as a zombie until the parent collects its status
+//! with [`wait_child`]. Processes started by the kernel have no parent and leave the table as
+//! soon as they end, as do the children of a process that ended. What is left of a process is
+//! freed once the last [`Arc`] to it is gone.
+//!
+//! Processes can be sent [`Signal`]s. The kernel sends [`Signal::Interrupt`] to the process in
+//! the foreground, and its descendants, when Ctrl+C is pressed, and a program that causes an
+//! exception gets the matching signal, which ends it unless it handles it.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::elf::{Elf, ElfError};
+use crate::memory::{AddressSpace, PagingError};
+use crate::sync::{self, MutexGuard, WaitQueue};
+use crate::thread::{self, ThreadError, ThreadId};
+use crate::usermode::{Context, STACK_SIZE, STACK_TOP};
+
+pub mod file;
+pub mod memory;
+pub mod signal;
+
+pub use file::{File, FileError, FileTable};
+pub use memory::{MemoryError, UserMemory};
+pub use signal::{Action, Signal, SignalError, Signals};
+
+/// Identifies a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        ProcessId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The process ID `id` names, whether or not there is such a process.
+    pub fn from_u64(id: u64) -> Self {
+        ProcessId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Errors returned when starting a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// The executable is malformed or could not be mapped.
+    Elf(ElfError),
+    /// A flat binary is empty or too large.
+    BadSize,
+    /// The arguments take up more than half of the stack.
+    ArgumentsTooLong,
+    /// The address space or the stack could not be set up.
+    Paging(PagingError),
+    /// The calling thread does not belong to a process.
+    NoProcess,
+    /// The calling process has no such child to wait for.
+    NoChild,
+    /// The main thread could not be started.
+    Thread(ThreadError),
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(error: ElfError) -> Self {
+        ProcessError::Elf(error)
+    }
+}
+
+impl From<PagingError> for ProcessError {
+    fn from(error: PagingError) -> Self {
+        ProcessError::Paging(error)
+    }
+}
+
+impl From<ThreadError> for ProcessError {
+    fn from(error: ThreadError) -> Self {
+        ProcessError::Thread(error)
+    }
+}
+
+/// How a process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The program exited with this status.
+    Exited(u64),
+    /// The program was ended by this signal.
+    Signaled(Signal),
+}
+
+impl ExitStatus {
+    /// Packs the status into a word the way `wait` does on Unix: the low byte of the exit status
+    /// in bits 8 to 15, or the number of the signal in the low seven bits.
+    pub fn to_wait_status(self) -> u64 {
+        match self {
+            ExitStatus::Exited(status) => (status & 0xff) << 8,
+            ExitStatus::Signaled(signal) => u64::from(signal.number()),
+        }
+    }
+}
+
+/// A user program with the address space it runs in.
+pub struct Process {
+    id: ProcessId,
+    /// Locked with interrupts disabled.
+    name: Mutex<String>,
+    /// The process that started this one, until it ends. Locked with interrupts disabled.
+    parent: Mutex<Option<ProcessId>>,
+    space: sync::Mutex<AddressSpace>,
+    /// Locked before `space` when both are.
+    memory: sync::Mutex<UserMemory>,
+    files: sync::Mutex<FileTable>,
+    threads: Mutex<Vec<ThreadId>>,
+    /// Locked with interrupts disabled.
+    signals: Mutex<Signals>,
+    /// Set when the process ends. Locked with interrupts disabled.
+    status: Mutex<Option<ExitStatus>>,
+    exited: WaitQueue,
+    /// Woken when a child ends.
+    child_exited: WaitQueue,
+}
+
+impl Process {
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    /// The name of the program the process runs.
+    pub fn name(&self) -> String {
+        interrupts::without_interrupts(|| self.name.lock().clone())
+    }
+
+    /// The process that started this one, if it has not ended.
+    pub fn parent(&self) -> Option<ProcessId> {
+        interrupts::without_interrupts(|| *self.parent.lock())
+    }
+
+    pub fn address_space(&self) -> MutexGuard<'_, AddressSpace> {
+        self.space.lock()
+    }
+
+    /// The heap and anonymous mappings. To change them, lock this first and then the address
+    /// space.
+    pub fn memory(&self) -> MutexGuard<'_, UserMemory> {
+        self.memory.lock()
+    }
+
+    pub fn files(&self) -> MutexGuard<'_, FileTable> {
+        self.files.lock()
+    }
+
+    /// The threads running in the process.
+    pub fn threads(&self) -> Vec<ThreadId> {
+        interrupts::without_interrupts(|| self.threads.lock().clone())
+    }
+
+    /// How the process ended, or `None` while it runs.
+    pub fn status(&self) -> Option<ExitStatus> {
+        interrupts::without_interrupts(|| *self.status.lock())
+    }
+
+    /// Waits for the process to end and returns how it did.
+    pub fn wait(&self) -> ExitStatus {
+        let mut status = None;
+        self.exited.wait_until(|| {
+            status = *self.status.lock();
+            status.is_some()
+        });
+        status.expect("process ended")
+    }
+
+    /// Like [`Process::wait`], with the process in the foreground meanwhile, so that Ctrl+C
+    /// interrupts it.
+    pub fn wait_in_foreground(&self) -> ExitStatus {
+        interrupts::without_interrupts(|| *FOREGROUND.lock() = Some(self.id));
+        let status = self.wait();
+        interrupts::without_interrupts(|| {
+            let mut foreground = FOREGROUND.lock();
+            if *foreground == Some(self.id) {
+                *foreground = None;
+            }
+        });
+        status
+    }
+
+    /// Sends `signal` to the process. Returns `false` if the process ignores it.
+    pub fn signal(&self, signal: Signal) -> bool {
+        interrupts::without_interrupts(|| self.signals.lock().post(signal))
+    }
+
+    /// Runs `f` on the signal state of the process, with interrupts disabled.
+    pub(crate) fn with_signals<R>(&self, f: impl FnOnce(&mut Signals) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.signals.lock()))
+    }
+
+    /// Frees the memory and files of the process, which no thread runs in any more, records how
+    /// it ended and wakes its waiters. The process leaves the process table now unless it has a
+    /// parent to collect its status, and so do its children that are zombies.
+    fn end(&self, status: ExitStatus) {
+        self.address_space().clear();
+        *self.files() = FileTable::default();
+        let (parent, reaped) = interrupts::without_interrupts(|| {
+            *self.status.lock() = Some(status);
+            let mut processes = PROCESSES.lock();
+            let mut reaped = Vec::new();
+            let children: Vec<_> = processes
+                .values()
+                .filter(|process| *process.parent.lock() == Some(self.id))
+                .cloned()
+                .collect();
+            for child in children {
+                *child.parent.lock() = None;
+                if child.status.lock().is_some() {
+                    reaped.extend(processes.remove(&child.id));
+                }
+            }
+            let parent = self
+                .parent
+                .lock()
+                .and_then(|id| processes.get(&id).cloned());
+            if parent.is_none() {
+                reaped.extend(processes.remove(&self.id));
+            }
+            (parent, reaped)
+        });
+        drop(reaped);
+        self.exited.wake_all();
+        if let Some(parent) = parent {
+            parent.signal(Signal::Child);
+            parent.child_exited.wake_all();
+        }
+    }
+}
+
+/// The running processes. Locked with interrupts disabled.
+static PROCESSES: Mutex<BTreeMap<ProcessId, Arc<Process>>> = Mutex::new(BTreeMap::new());
+
+/// The process the shell waits for, which Ctrl+C interrupts. Locked with interrupts disabled.
+static FOREGROUND: Mutex<Option<ProcessId>> = Mutex::new(None);
+
+/// Returns the running process `id`.
+pub fn get(id: ProcessId) -> Option<Arc<Process>> {
+    interrupts::without_interrupts(|| PROCESSES.lock().get(&id).cloned())
+}
+
+/// Returns the process the running thread belongs to.
+pub fn current() -> Option<Arc<Process>> {
+    get(thread::current_process()?)
+}
+
+/// Returns the processes in the process table, running or zombies, in the order they were
+/// started.
+pub fn list() -> Vec<Arc<Process>> {
+    interrupts::without_interrupts(|| PROCESSES.lock().values().cloned().collect())
+}
+
+/// Waits for the child `pid` of the calling process, or for any of its children if `None`, to
+/// end. Takes the child out of the process table and returns its ID and how it ended.
+pub fn wait_child(pid: Option<ProcessId>) -> Result<(ProcessId, ExitStatus), ProcessError> {
+    let parent = current().ok_or(ProcessError::NoProcess)?;
+    let mut result = Err(ProcessError::NoChild);
+    let mut reaped = None;
+    parent.child_exited.wait_until(|| {
+        let mut processes = PROCESSES.lock();
+        let mut children = processes.values().filter(|process| {
+            *process.parent.lock() == Some(parent.id) && pid.is_none_or(|pid| process.id == pid)
+        });
+        let mut found = false;
+        let ended = children.find_map(|child| {
+            found = true;
+            child.status.lock().map(|status| (child.id, status))
+        });
+        match ended {
+            Some((id, status)) => {
+                reaped = processes.remove(&id);
+                result = Ok((id, status));
+                true
+            }
+            // Nothing to wait for.
+            None => !found,
+        }
+    });
+    drop(reaped);
+    result
+}
+
+/// Sends [`Signal::Interrupt`] to the foreground process and to its descendants. Called by the
+/// keyboard interrupt handler on Ctrl+C.
+pub fn interrupt_foreground() {
+    interrupts::without_interrupts(|| {
+        let Some(foreground) = *FOREGROUND.lock() else {
+            return;
+        };
+        let processes = PROCESSES.lock();
+        let descends = |process: &Process| {
+            let mut id = Some(process.id);
+            // Parents are started before their children, so the chain ends.
+            while let Some(ancestor) = id {
+                if ancestor == foreground {
+                    return true;
+                }
+                id = processes
+                    .get(&ancestor)
+                    .and_then(|process| *process.parent.lock());
+            }
+            false
+        };
+        // Posted under the lock, so that the handler never drops the last reference to a
+        // process.
+        for process in processes.values().filter(|process| descends(process)) {
+            process.signals.lock().post(Signal::Interrupt);
+        }
+    });
+}
+
+/// Loads the executable `image` into a new process and starts it with the arguments `args`, the
+/// first of which is by convention the program's name.
+pub fn spawn_elf(image: &[u8], args: &[&str]) -> Result<Arc<Process>, ProcessError> {
+    let elf = Elf::parse(image)?;
+    let name = args.first().copied().unwrap_or("?");
+    spawn(name, args, |space| Ok((elf.load(space)?, elf.end())))
+}
+
+/// Starts a process named `name` once `load` has mapped its program into the new address space
+/// and returned the entry point and the end of the program, where the heap starts.
+///
+/// The program starts with the stack pointer at the argument count, followed by pointers to the
+/// arguments, a null pointer, an empty environment and an empty auxiliary vector, as on Linux.
+pub(crate) fn spawn(
+    name: &str,
+    args: &[&str],
+    load: impl FnOnce(&mut AddressSpace) -> Result<(u64, u64), ProcessError>,
+) -> Result<Arc<Process>, ProcessError> {
+    let block = stack_block(args)?;
+    let mut space = AddressSpace::new()?;
+    let (entry, end) = load(&mut space)?;
+    let stack = map_stack(&mut space, block)?;
+    crate::time::vdso::map(&mut space)?;
+    let parent = thread::current_process();
+    start(
+        name,
+        parent,
+        space,
+        UserMemory::new(end),
+        FileTable::standard(),
+        Signals::default(),
+        Context::new(entry, stack),
+    )
+}
+
+/// Duplicates the calling process. The child gets a copy-on-write copy of the parent's memory
+/// and of its open files, the parent's signal actions, and a single thread, which enters user
+/// mode with `context`.
+pub fn fork(context: Context) -> Result<Arc<Process>, ProcessError> {
+    let parent = current().ok_or(ProcessError::NoProcess)?;
+    let memory = parent.memory().clone();
+    let space = parent.address_space().fork()?;
+    let files = parent.files().clone();
+    let signals = parent.with_signals(|signals| signals.forked());
+    let id = Some(parent.id);
+    start(&parent.name(), id, space, memory, files, signals, context)
+}
+
+/// Replaces the program of the calling process with the executable `image`, started with the
+/// arguments `args` like [`spawn_elf`] does. Returns the context to return to user mode with.
+///
+/// The process is left as it was on any error but [`ProcessError::Paging`], which means that
+/// the old program is gone and the process cannot go on.
+pub fn exec(image: &[u8], args: &[&str]) -> Result<Context, ProcessError> {
+    let process = current().ok_or(ProcessError::NoProcess)?;
+    let elf = Elf::parse(image)?;
+    let block = stack_block(args)?;
+    let mut memory = process.memory();
+    let mut space = process.address_space();
+    space.clear();
+    process.files().close_on_exec();
+    process.with_signals(Signals::reset_handlers);
+    let entry = elf.load(&mut space).map_err(|error| match error {
+        ElfError::Paging(error) => ProcessError::Paging(error),
+        error => error.into(),
+    })?;
+    let stack = map_stack(&mut space, block)?;
+    crate::time::vdso::map(&mut space)?;
+    *memory = UserMemory::new(elf.end());
+    let name = args.first().copied().unwrap_or("?").to_string();
+    interrupts::without_interrupts(|| *process.name.lock() = name);
+    Ok(Context::new(entry, stack))
+}
+
+/// Resolves a write fault at `address` on a copy-on-write page of the running process. Returns
+/// whether the write should be retried.
+///
+/// Called from the page fault handler, which must not block on the address space. If it is
+/// locked this returns `true` without resolving anything: the write runs again, and faults again
+/// until the lock is free.
+pub(crate) fn handle_cow_fault(address: VirtAddr) -> bool {
+    let Some(process) = current() else {
+        return false;
+    };
+    let Some(mut space) = process.space.try_lock() else {
+        return true;
+    };
+    space.break_cow(address)
+}
+
+/// Adds a child of `parent` running in `space` to the process table and starts its main thread.
+fn start(
+    name: &str,
+    parent: Option<ProcessId>,
+    space: AddressSpace,
+    memory: UserMemory,
+    files: FileTable,
+    signals: Signals,
+    context: Context,
+) -> Result<Arc<Process>, ProcessError> {
+    let page_table = space.page_table();
+    let process = Arc::new(Process {
+        id: ProcessId::new(),
+        name: Mutex::new(name.to_string()),
+        parent: Mutex::new(parent),
+        space: sync::Mutex::new(space),
+        memory: sync::Mutex::new(memory),
+        files: sync::Mutex::new(files),
+        threads: Mutex::new(Vec::new()),
+        signals: Mutex::new(signals),
+        status: Mutex::new(None),
+        exited: WaitQueue::new(),
+        child_exited: WaitQueue::new(),
+    });
+    interrupts::without_interrupts(|| PROCESSES.lock().insert(process.id, process.clone()));
+    let main = {
+        let process = process.clone();
+        thread::spawn("user main", move || {
+            let thread = thread::current().expect("running in a thread");
+            interrupts::without_interrupts(|| process.threads.lock().push(thread));
+            thread::set_process(Some((process.id, page_table)));
+            let status = crate::usermode::enter(&context);
+            thread::set_process(None);
+            interrupts::without_interrupts(|| process.threads.lock().retain(|&t| t != thread));
+            process.end(status);
+        })
+    };
+    if let Err(error) = main {
+        interrupts::without_interrupts(|| PROCESSES.lock().remove(&process.id));
+        return Err(error.into());
+    }
+    Ok(process)
+}
+
+/// The initial stack pointer and the contents of the stack from there to its top for `args`.
+fn stack_block(args: &[&str]) -> Result<(u64, Vec<u8>), ProcessError> {
+    // The strings go at the top, below them argc, argv with its null, the null of envp and the
+    // AT_NULL pair of the auxiliary vector.
+    let strings: u64 = args.iter().map(|arg| arg.len() as u64 + 1).sum();
+    let words = (1 + args.len() + 1 + 1 + 2) as u64;
+    let strings_start = STACK_TOP.saturating_sub(strings);
+    let stack = strings_start.saturating_sub(words * 8) & !15;
+    if STACK_TOP - stack > STACK_SIZE / 2 {
+        return Err(ProcessError::ArgumentsTooLong);
+    }
+    let mut block = vec![0u8; (STACK_TOP - stack) as usize];
+    block[..8].copy_from_slice(&(args.len() as u64).to_le_bytes());
+    let mut string = strings_start;
+    for (index, arg) in args.iter().enumerate() {
+        let pointer = 8 * (1 + index);
+        block[pointer..pointer + 8].copy_from_slice(&string.to_le_bytes());
+        let offset = (string - stack) as usize;
+        block[offset..offset + arg.len()].copy_from_slice(arg.as_bytes());
+        string += arg.len() as u64 + 1;
+    }
+    Ok((stack, block))
+}
+
+/// Maps the stack into `space` and copies the `block` from [`stack_block`] to its top, returning
+/// the initial stack pointer.
+fn map_stack(
+    space: &mut AddressSpace,
+    (stack, block): (u64, Vec<u8>),
+) -> Result<u64, ProcessError> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    let pages = Page::range(
+        Page::containing_address(VirtAddr::new(STACK_TOP - STACK_SIZE)),
+        Page::containing_address(VirtAddr::new(STACK_TOP)),
+    );
+    for page in pages {
+        space.map_zeroed(page, flags)?;
+    }
+    space.write(VirtAddr::new(stack), &block)?;
+    Ok(stack)
+}
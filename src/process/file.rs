@@ -0,0 +1,185 @@
+//! Open files and file descriptors.
+//!
+//! Every process has a [`FileTable`] mapping small integers, the file descriptors, to the
+//! [`File`]s it has open. A new process starts with standard input, output and error open on
+//! `/dev/console`. [`super::fork`] copies the table, so the child has the same files open under
+//! the same descriptors, and [`super::exec`] closes those opened with close-on-exec.
+//!
+//! Files are opened through [`crate::vfs`], so a process reaches whatever is mounted: regular
+//! files as well as the devices under `/dev`.
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::sync;
+use crate::vfs::{self, OpenFile, VfsError};
+
+/// Most files a process can have open at once.
+pub const MAX_FILES: usize = 64;
+
+/// Errors returned by file operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileError {
+    /// No file is open under the descriptor.
+    BadDescriptor,
+    /// The process has [`MAX_FILES`] files open.
+    TooManyOpen,
+    /// The file could not be opened, read or written.
+    Vfs(VfsError),
+}
+
+impl From<VfsError> for FileError {
+    fn from(error: VfsError) -> Self {
+        FileError::Vfs(error)
+    }
+}
+
+/// Something a file descriptor refers to: a file opened through the VFS, with the position reads
+/// and writes go on from. Copies, such as those [`super::fork`] makes, share the position.
+#[derive(Clone)]
+pub struct File {
+    open: Arc<sync::Mutex<OpenFile>>,
+}
+
+impl File {
+    /// Opens the file or device at `path`, positioned at its start.
+    pub fn open(path: &str) -> Result<File, FileError> {
+        Ok(File {
+            open: Arc::new(sync::Mutex::new(vfs::open(path)?)),
+        })
+    }
+
+    /// Reads into `buffer` and returns how many bytes were read, 0 at the end of the file.
+    /// Reading the console waits for a key unless one was typed already.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, FileError> {
+        Ok(self.open.lock().read(buffer)?)
+    }
+
+    /// Writes `bytes` and returns how many were written.
+    pub fn write(&self, bytes: &[u8]) -> Result<usize, FileError> {
+        Ok(self.open.lock().write(bytes)?)
+    }
+}
+
+impl fmt::Debug for File {
+    /// Leaves the file out, since a read may hold it for as long as it waits.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("File").finish_non_exhaustive()
+    }
+}
+
+/// The UTF-8 bytes of typed characters that did not fit into the buffer of the last console
+/// read. Locked with interrupts disabled.
+static CONSOLE_PENDING: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+/// Reads typed characters as UTF-8, waiting for the first one.
+pub(crate) fn read_console(buffer: &mut [u8]) -> usize {
+    if buffer.is_empty() {
+        return 0;
+    }
+    let mut pending =
+        interrupts::without_interrupts(|| core::mem::take(&mut *CONSOLE_PENDING.lock()));
+    if pending.is_empty() {
+        push_char(&mut pending, crate::keyboard::read_char());
+    }
+    let mut read = 0;
+    loop {
+        let length = pending.len().min(buffer.len() - read);
+        buffer[read..read + length].copy_from_slice(&pending[..length]);
+        pending.drain(..length);
+        read += length;
+        if read == buffer.len() {
+            break;
+        }
+        match crate::keyboard::try_read_char() {
+            Some(c) => push_char(&mut pending, c),
+            None => break,
+        }
+    }
+    interrupts::without_interrupts(|| CONSOLE_PENDING.lock().extend(pending));
+    read
+}
+
+fn push_char(bytes: &mut Vec<u8>, c: char) {
+    let mut encoded = [0; 4];
+    bytes.extend_from_slice(c.encode_utf8(&mut encoded).as_bytes());
+}
+
+/// An open file and how it was opened.
+#[derive(Debug, Clone)]
+struct Descriptor {
+    file: File,
+    close_on_exec: bool,
+}
+
+/// The open files of a process, indexed by file descriptor.
+#[derive(Debug, Clone, Default)]
+pub struct FileTable {
+    descriptors: Vec<Option<Descriptor>>,
+}
+
+impl FileTable {
+    /// A table with standard input, output and error open on the console, or nothing open if
+    /// `/dev/console` cannot be opened.
+    pub(crate) fn standard() -> Self {
+        let console = File::open("/dev/console").ok().map(|file| Descriptor {
+            file,
+            close_on_exec: false,
+        });
+        FileTable {
+            descriptors: vec![console; 3],
+        }
+    }
+
+    /// The file open under `fd`.
+    pub fn get(&self, fd: usize) -> Result<File, FileError> {
+        let descriptor = self.descriptors.get(fd).and_then(Option::as_ref);
+        descriptor
+            .map(|descriptor| descriptor.file.clone())
+            .ok_or(FileError::BadDescriptor)
+    }
+
+    /// Opens `file` under the lowest free descriptor and returns it. The file is closed by
+    /// [`super::exec`] if `close_on_exec` is set.
+    pub fn insert(&mut self, file: File, close_on_exec: bool) -> Result<usize, FileError> {
+        let descriptor = Some(Descriptor {
+            file,
+            close_on_exec,
+        });
+        match self.descriptors.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.descriptors[fd] = descriptor;
+                Ok(fd)
+            }
+            None if self.descriptors.len() < MAX_FILES => {
+                self.descriptors.push(descriptor);
+                Ok(self.descriptors.len() - 1)
+            }
+            None => Err(FileError::TooManyOpen),
+        }
+    }
+
+    /// Closes `fd`, returning what it referred to.
+    pub fn remove(&mut self, fd: usize) -> Result<File, FileError> {
+        let descriptor = self.descriptors.get_mut(fd).and_then(Option::take);
+        descriptor
+            .map(|descriptor| descriptor.file)
+            .ok_or(FileError::BadDescriptor)
+    }
+
+    /// Closes the files opened with close-on-exec.
+    pub fn close_on_exec(&mut self) {
+        for descriptor in &mut self.descriptors {
+            if descriptor
+                .as_ref()
+                .is_some_and(|descriptor| descriptor.close_on_exec)
+            {
+                *descriptor = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! The memory a program asks for while it runs.
+//!
+//! The heap starts at the page after the program's last segment and ends at the program break,
+//! which the program moves with `brk`. Anonymous mappings made with `mmap` are placed top down
+//! from [`PROGRAM_END`], the first gap that fits, so the two grow towards each other and the
+//! break cannot be moved into a mapping. All of it is mapped to zeroed frames right away, and
+//! unmapped pages are freed right away; only the page tables stay until the process ends.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::memory::{AddressSpace, PagingError};
+use crate::usermode::PROGRAM_END;
+
+/// Errors returned when changing the memory of a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// The break would go below the start of the heap, or a range is empty or not page-aligned.
+    BadRange,
+    /// There is no room left in the part of the address space programs may use.
+    NoRoom,
+    /// A page could not be mapped.
+    Paging(PagingError),
+}
+
+impl From<PagingError> for MemoryError {
+    fn from(error: PagingError) -> Self {
+        MemoryError::Paging(error)
+    }
+}
+
+/// The heap and anonymous mappings of a process.
+#[derive(Debug, Clone)]
+pub struct UserMemory {
+    heap_start: u64,
+    brk: u64,
+    /// Start and end of each mapping, keyed by start.
+    mappings: BTreeMap<u64, u64>,
+}
+
+impl UserMemory {
+    /// An empty heap for a program whose segments end at `program_end`, and no mappings.
+    pub(crate) fn new(program_end: u64) -> Self {
+        let heap_start = page_up(program_end);
+        UserMemory {
+            heap_start,
+            brk: heap_start,
+            mappings: BTreeMap::new(),
+        }
+    }
+
+    /// The program break.
+    pub fn brk(&self) -> u64 {
+        self.brk
+    }
+
+    /// Moves the program break to `brk`, mapping or unmapping heap pages in `space` as needed.
+    pub fn set_brk(&mut self, space: &mut AddressSpace, brk: u64) -> Result<(), MemoryError> {
+        let limit = self.mappings.keys().next().copied().unwrap_or(PROGRAM_END);
+        if brk < self.heap_start {
+            return Err(MemoryError::BadRange);
+        }
+        if brk > limit {
+            return Err(MemoryError::NoRoom);
+        }
+        let (mapped, wanted) = (page_up(self.brk), page_up(brk));
+        if wanted > mapped {
+            map_range(space, mapped, wanted, data_flags())?;
+        } else {
+            unmap_range(space, wanted, mapped);
+        }
+        self.brk = brk;
+        Ok(())
+    }
+
+    /// Maps `length` bytes, rounded up to whole pages, of zeroed memory with `flags` and returns
+    /// the address.
+    pub fn map(
+        &mut self,
+        space: &mut AddressSpace,
+        length: u64,
+        flags: PageTableFlags,
+    ) -> Result<u64, MemoryError> {
+        let length = length
+            .checked_next_multiple_of(4096)
+            .filter(|&length| length > 0)
+            .ok_or(MemoryError::BadRange)?;
+        let mut top = PROGRAM_END;
+        for (&start, &end) in self.mappings.iter().rev() {
+            if top - end >= length {
+                break;
+            }
+            top = start;
+        }
+        let start = top
+            .checked_sub(length)
+            .filter(|&start| start >= page_up(self.brk))
+            .ok_or(MemoryError::NoRoom)?;
+        map_range(space, start, top, flags | PageTableFlags::USER_ACCESSIBLE)?;
+        self.mappings.insert(start, top);
+        Ok(start)
+    }
+
+    /// Unmaps the pages of the mappings in the `length` bytes at `address`, which must be
+    /// page-aligned. Pages of the range that are not part of a mapping are left alone.
+    pub fn unmap(
+        &mut self,
+        space: &mut AddressSpace,
+        address: u64,
+        length: u64,
+    ) -> Result<(), MemoryError> {
+        let end = address
+            .checked_add(length)
+            .and_then(|end| end.checked_next_multiple_of(4096))
+            .filter(|_| address % 4096 == 0 && length > 0)
+            .ok_or(MemoryError::BadRange)?;
+        let overlapping: Vec<_> = self
+            .mappings
+            .range(..end)
+            .filter(|&(_, &mapping_end)| mapping_end > address)
+            .map(|(&start, &mapping_end)| (start, mapping_end))
+            .collect();
+        for (start, mapping_end) in overlapping {
+            self.mappings.remove(&start);
+            unmap_range(space, start.max(address), mapping_end.min(end));
+            if start < address {
+                self.mappings.insert(start, address);
+            }
+            if mapping_end > end {
+                self.mappings.insert(end, mapping_end);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flags of heap pages.
+fn data_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE
+}
+
+/// Maps the pages from `start` to `end` to zeroed frames, or none of them if that fails.
+fn map_range(
+    space: &mut AddressSpace,
+    start: u64,
+    end: u64,
+    flags: PageTableFlags,
+) -> Result<(), MemoryError> {
+    for address in (start..end).step_by(4096) {
+        if let Err(error) = space.map_zeroed(page(address), flags) {
+            unmap_range(space, start, address);
+            return Err(error.into());
+        }
+    }
+    Ok(())
+}
+
+fn unmap_range(space: &mut AddressSpace, start: u64, end: u64) {
+    for address in (start..end).step_by(4096) {
+        // Every page of the range was mapped.
+        let _ = space.unmap(page(address));
+    }
+}
+
+fn page(address: u64) -> Page<Size4KiB> {
+    Page::containing_address(VirtAddr::new(address))
+}
+
+fn page_up(address: u64) -> u64 {
+    address.next_multiple_of(4096)
+}
@@ -0,0 +1,211 @@
+//! Signals, the asynchronous events a process can be sent.
+//!
+//! A signal sent to a process with [`super::Process::signal`] stays pending until the process
+//! next returns to user mode, from a system call, an interrupt or an exception. There the kernel
+//! takes the action the process chose for it: it ignores it, runs the handler the program
+//! registered, or, by default, ends the process. Signals are numbered as on Linux, and only the
+//! few the kernel itself sends are known.
+//!
+//! Sending a signal does not interrupt a blocking system call, so a process waiting in one only
+//! sees the signal once the call returns.
+
+/// A signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// Ctrl+C was pressed while the process ran in the foreground.
+    Interrupt = 2,
+    /// The program ran an invalid instruction.
+    IllegalInstruction = 4,
+    /// The program made a misaligned access with alignment checking on.
+    Bus = 7,
+    /// The program divided by zero, or an unmasked floating point exception occurred.
+    FloatingPoint = 8,
+    /// Ends the process. Cannot be caught, ignored or blocked.
+    Kill = 9,
+    /// The program accessed memory it may not.
+    SegmentationFault = 11,
+    /// Asks the process to end.
+    Terminate = 15,
+    /// A child of the process ended. Ignored by default.
+    Child = 17,
+}
+
+impl Signal {
+    const ALL: [Signal; 8] = [
+        Signal::Interrupt,
+        Signal::IllegalInstruction,
+        Signal::Bus,
+        Signal::FloatingPoint,
+        Signal::Kill,
+        Signal::SegmentationFault,
+        Signal::Terminate,
+        Signal::Child,
+    ];
+
+    /// The signal numbered `number`, if it is one the kernel knows.
+    pub fn from_number(number: u64) -> Option<Signal> {
+        Self::ALL
+            .into_iter()
+            .find(|signal| u64::from(signal.number()) == number)
+    }
+
+    pub fn number(self) -> u8 {
+        self as u8
+    }
+
+    /// The usual name, like `SIGINT`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Signal::Interrupt => "SIGINT",
+            Signal::IllegalInstruction => "SIGILL",
+            Signal::Bus => "SIGBUS",
+            Signal::FloatingPoint => "SIGFPE",
+            Signal::Kill => "SIGKILL",
+            Signal::SegmentationFault => "SIGSEGV",
+            Signal::Terminate => "SIGTERM",
+            Signal::Child => "SIGCHLD",
+        }
+    }
+
+    /// Whether the default action ends the process rather than ignore the signal.
+    fn terminates(self) -> bool {
+        self != Signal::Child
+    }
+
+    /// The signal's bit in a set of signals.
+    pub(crate) fn mask(self) -> u32 {
+        1 << self.number()
+    }
+}
+
+/// Errors returned when changing the action of a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    /// [`Signal::Kill`] always ends the process.
+    Uncatchable,
+}
+
+/// What a process does when it gets a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Ignore the signal or end the process, depending on the signal.
+    Default,
+    Ignore,
+    /// Call the program's handler at `entry` with the signal number, returning to `restorer`,
+    /// which must make the sigreturn system call.
+    Handler {
+        entry: u64,
+        restorer: u64,
+    },
+}
+
+/// Numbers go up to 31, so sets of signals fit into a `u32`.
+const SIGNAL_COUNT: usize = 32;
+
+/// The signal state of a process: the action for each signal, the signals sent and not yet
+/// taken, and those whose delivery is held back while their handler runs.
+#[derive(Debug, Clone)]
+pub struct Signals {
+    actions: [Action; SIGNAL_COUNT],
+    pending: u32,
+    blocked: u32,
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Signals {
+            actions: [Action::Default; SIGNAL_COUNT],
+            pending: 0,
+            blocked: 0,
+        }
+    }
+}
+
+impl Signals {
+    pub fn action(&self, signal: Signal) -> Action {
+        self.actions[usize::from(signal.number())]
+    }
+
+    /// Sets what to do with `signal` from now on. A pending `signal` that is now ignored is
+    /// discarded.
+    pub fn set_action(&mut self, signal: Signal, action: Action) -> Result<(), SignalError> {
+        if signal == Signal::Kill {
+            return Err(SignalError::Uncatchable);
+        }
+        self.actions[usize::from(signal.number())] = action;
+        if self.ignores(signal) {
+            self.pending &= !signal.mask();
+        }
+        Ok(())
+    }
+
+    /// Marks `signal` pending unless it is ignored. Returns whether it was.
+    pub(crate) fn post(&mut self, signal: Signal) -> bool {
+        if self.ignores(signal) {
+            return false;
+        }
+        self.pending |= signal.mask();
+        true
+    }
+
+    /// Marks `signal`, caused by the program itself, pending. If the program ignores or blocks
+    /// it, it gets the default action instead, as there is no point in going on.
+    pub(crate) fn force(&mut self, signal: Signal) {
+        if self.action(signal) == Action::Ignore || self.blocked & signal.mask() != 0 {
+            self.actions[usize::from(signal.number())] = Action::Default;
+            self.blocked &= !signal.mask();
+        }
+        self.pending |= signal.mask();
+    }
+
+    /// Whether a signal can be taken.
+    pub(crate) fn deliverable(&self) -> bool {
+        self.pending & !self.blocked != 0
+    }
+
+    /// Takes the lowest-numbered pending signal that is not blocked, with the action to take for
+    /// it. Ignored signals are never pending, so [`Action::Default`] means ending the process.
+    pub(crate) fn take(&mut self) -> Option<(Signal, Action)> {
+        let signal = Signal::ALL
+            .into_iter()
+            .find(|signal| self.pending & !self.blocked & signal.mask() != 0)?;
+        self.pending &= !signal.mask();
+        Some((signal, self.action(signal)))
+    }
+
+    /// The signals whose delivery is held back.
+    pub(crate) fn blocked(&self) -> u32 {
+        self.blocked
+    }
+
+    /// Holds back the signals in `blocked`, except [`Signal::Kill`].
+    pub(crate) fn set_blocked(&mut self, blocked: u32) {
+        self.blocked = blocked & !Signal::Kill.mask();
+    }
+
+    /// The state a forked child starts with: the same actions and blocked signals, and nothing
+    /// pending.
+    pub(crate) fn forked(&self) -> Signals {
+        Signals {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Forgets the handlers, which belong to the old program, when the process calls exec.
+    pub(crate) fn reset_handlers(&mut self) {
+        for action in &mut self.actions {
+            if let Action::Handler { .. } = action {
+                *action = Action::Default;
+            }
+        }
+    }
+
+    fn ignores(&self, signal: Signal) -> bool {
+        match self.action(signal) {
+            Action::Ignore => true,
+            Action::Default => !signal.terminates(),
+            Action::Handler { .. } => false,
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! The 8042 PS/2 controller.
+//!
+//! Low-level access to the data port (0x60) and the status/command port (0x64), plus the
+//! command-with-acknowledgement protocol spoken by the devices behind the controller. The
+//! functions here poll the controller, so callers must make sure the keyboard interrupt handler
+//! cannot steal the reply byte, either by running inside it or with interrupts disabled.
+
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+
+/// Status register bit set when a byte is waiting in the output buffer.
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// Status register bit set while the controller has not consumed the last byte written to it.
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// Status register bit set when the byte in the output buffer came from the auxiliary device.
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+/// Controller commands that read and write the configuration byte.
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+/// Controller command that routes the next data byte to the second (auxiliary) device.
+const WRITE_AUX: u8 = 0xD4;
+
+/// Configuration bit that enables the auxiliary device interrupt.
+pub const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+/// Configuration bit that stops the auxiliary device clock.
+pub const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+/// Configuration bit that makes the controller translate scancode set 2 into set 1.
+pub const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// Reply sent by a device that accepted a command or data byte.
+pub const ACK: u8 = 0xFA;
+/// Reply sent by a device that wants the last byte sent again.
+pub const RESEND: u8 = 0xFE;
+
+/// Number of status polls before giving up on the controller.
+const TIMEOUT_POLLS: usize = 100_000;
+/// Number of times a byte is sent before giving up on a device that keeps asking to resend.
+const MAX_ATTEMPTS: usize = 3;
+
+/// Errors reported by the PS/2 controller or device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller did not become ready in time.
+    Timeout,
+    /// The device kept asking for the byte to be resent.
+    Resend,
+    /// The device replied with something other than an acknowledgement.
+    UnexpectedReply(u8),
+}
+
+fn status() -> u8 {
+    let mut port = Port::<u8>::new(STATUS_PORT);
+    unsafe { port.read() }
+}
+
+fn wait_until(ready: impl Fn(u8) -> bool) -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT_POLLS {
+        if ready(status()) {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Writes a byte to the data port, which forwards it to the first PS/2 device.
+pub fn write_data(byte: u8) -> Result<(), Ps2Error> {
+    wait_until(|status| status & STATUS_INPUT_FULL == 0)?;
+    let mut port = Port::<u8>::new(DATA_PORT);
+    unsafe { port.write(byte) };
+    Ok(())
+}
+
+/// Writes a command byte to the controller itself.
+pub fn write_command(command: u8) -> Result<(), Ps2Error> {
+    wait_until(|status| status & STATUS_INPUT_FULL == 0)?;
+    let mut port = Port::<u8>::new(STATUS_PORT);
+    unsafe { port.write(command) };
+    Ok(())
+}
+
+/// Waits for the next byte from the controller or a device.
+pub fn read_data() -> Result<u8, Ps2Error> {
+    wait_until(|status| status & STATUS_OUTPUT_FULL != 0)?;
+    let mut port = Port::<u8>::new(DATA_PORT);
+    Ok(unsafe { port.read() })
+}
+
+/// Reads the controller configuration byte.
+pub fn read_config() -> Result<u8, Ps2Error> {
+    write_command(READ_CONFIG)?;
+    read_data()
+}
+
+/// Writes the controller configuration byte.
+pub fn write_config(config: u8) -> Result<(), Ps2Error> {
+    write_command(WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/// Returns the byte waiting in the output buffer, if any, without waiting for one.
+pub fn read_pending() -> Option<u8> {
+    if status() & STATUS_OUTPUT_FULL == 0 {
+        return None;
+    }
+    let mut port = Port::<u8>::new(DATA_PORT);
+    Some(unsafe { port.read() })
+}
+
+/// Discards any bytes waiting in the output buffer, such as keys pressed before a command.
+pub fn flush_output() {
+    while read_pending().is_some() {}
+}
+
+/// Returns whether the byte waiting in the output buffer came from the mouse.
+pub fn aux_data_pending() -> bool {
+    let status = status();
+    status & STATUS_OUTPUT_FULL != 0 && status & STATUS_AUX_DATA != 0
+}
+
+/// Sends a byte to the keyboard and waits for it to be acknowledged, resending it when asked to.
+pub fn send_keyboard_byte(byte: u8) -> Result<(), Ps2Error> {
+    send_acknowledged(|| write_data(byte))
+}
+
+/// Sends a byte to the mouse and waits for it to be acknowledged, resending it when asked to.
+pub fn send_mouse_byte(byte: u8) -> Result<(), Ps2Error> {
+    send_acknowledged(|| {
+        write_command(WRITE_AUX)?;
+        write_data(byte)
+    })
+}
+
+fn send_acknowledged(send: impl Fn() -> Result<(), Ps2Error>) -> Result<(), Ps2Error> {
+    for _ in 0..MAX_ATTEMPTS {
+        send()?;
+        match read_data()? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            reply => return Err(Ps2Error::UnexpectedReply(reply)),
+        }
+    }
+    Err(Ps2Error::Resend)
+}
@@ -0,0 +1,102 @@
+//! Block devices in memory.
+//!
+//! The bootloader can load an image file next to the kernel, the initial ramdisk. [`init`]
+//! registers it as `ram0`, a read-only [`BlockDevice`], and [`image`] hands out its bytes for
+//! code that reads archives or ELF files straight from memory. An image built into the kernel
+//! with `include_bytes!` works the same through [`RamDisk::from_image`], and [`RamDisk::new`]
+//! makes a writable disk on the heap.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use spin::Once;
+use x86_64::VirtAddr;
+
+use crate::block::{self, BlockDevice, BlockError, BLOCK_SIZE};
+use crate::sync::Mutex;
+
+/// The initial ramdisk, as the bootloader mapped it.
+static IMAGE: Once<&'static [u8]> = Once::new();
+
+enum Storage {
+    Image(&'static [u8]),
+    Heap(Mutex<Box<[u8]>>),
+}
+
+/// A block device whose blocks are in memory.
+pub struct RamDisk {
+    storage: Storage,
+    blocks: u64,
+}
+
+impl RamDisk {
+    /// A writable disk of `blocks` zeroed blocks on the heap.
+    pub fn new(blocks: usize) -> RamDisk {
+        RamDisk {
+            storage: Storage::Heap(Mutex::new(vec![0; blocks * BLOCK_SIZE].into_boxed_slice())),
+            blocks: blocks as u64,
+        }
+    }
+
+    /// A read-only disk holding `image`. A partial last block reads as if padded with zeros.
+    pub fn from_image(image: &'static [u8]) -> RamDisk {
+        RamDisk {
+            storage: Storage::Image(image),
+            blocks: image.len().div_ceil(BLOCK_SIZE) as u64,
+        }
+    }
+
+    fn check(&self, index: u64) -> Result<usize, BlockError> {
+        if index >= self.blocks {
+            return Err(BlockError::OutOfRange(index));
+        }
+        Ok(index as usize * BLOCK_SIZE)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        let start = self.check(index)?;
+        match &self.storage {
+            Storage::Image(image) => {
+                let block = &image[start..image.len().min(start + BLOCK_SIZE)];
+                buffer[..block.len()].copy_from_slice(block);
+                buffer[block.len()..].fill(0);
+            }
+            Storage::Heap(memory) => {
+                buffer.copy_from_slice(&memory.lock()[start..start + BLOCK_SIZE]);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        let start = self.check(index)?;
+        match &self.storage {
+            Storage::Image(_) => Err(BlockError::ReadOnly),
+            Storage::Heap(memory) => {
+                memory.lock()[start..start + BLOCK_SIZE].copy_from_slice(data);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Registers the initial ramdisk the bootloader loaded at `start`, `len` bytes long, as `ram0`.
+///
+/// # Safety
+///
+/// The memory must stay mapped and unchanged for as long as the kernel runs.
+pub unsafe fn init(start: VirtAddr, len: u64) {
+    let image = IMAGE.call_once(|| core::slice::from_raw_parts(start.as_ptr(), len as usize));
+    block::register("ram0", Arc::new(RamDisk::from_image(image)));
+}
+
+/// The bytes of the initial ramdisk, if the bootloader loaded one.
+pub fn image() -> Option<&'static [u8]> {
+    IMAGE.get().copied()
+}
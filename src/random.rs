@@ -0,0 +1,97 @@
+//! Random numbers for hardening, such as the address space layout randomization of the VMM.
+//!
+//! RDSEED draws straight from the CPU's entropy source and RDRAND from a generator it reseeds,
+//! so either is good enough for picking addresses. CPUs with neither get a generator seeded from
+//! the TSC, which only keeps addresses from being identical from boot to boot.
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::instructions::random::RdRand;
+
+/// CPUID leaf 7 EBX bit reporting RDSEED.
+const CPUID_RDSEED: u32 = 1 << 18;
+
+/// Both instructions may fail when the entropy source is drained, and should be retried a few
+/// times before giving up.
+const RETRIES: usize = 10;
+
+/// Where random numbers come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    RdSeed,
+    RdRand,
+    /// A generator seeded from the TSC, for CPUs without either instruction.
+    Tsc,
+}
+
+static SOURCE: Once<Source> = Once::new();
+
+/// State of the fallback generator, seeded on first use.
+static FALLBACK_STATE: AtomicU64 = AtomicU64::new(0);
+
+fn has_rdseed() -> bool {
+    __cpuid(0).eax >= 7 && __cpuid(7).ebx & CPUID_RDSEED != 0
+}
+
+/// Returns the best source the CPU offers.
+pub fn source() -> Source {
+    *SOURCE.call_once(|| {
+        if has_rdseed() {
+            Source::RdSeed
+        } else if RdRand::new().is_some() {
+            Source::RdRand
+        } else {
+            Source::Tsc
+        }
+    })
+}
+
+fn rdseed() -> Option<u64> {
+    (0..RETRIES).find_map(|_| {
+        let (value, ok): (u64, u8);
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack))
+        };
+        (ok != 0).then_some(value)
+    })
+}
+
+fn rdrand() -> Option<u64> {
+    let rdrand = RdRand::new()?;
+    (0..RETRIES).find_map(|_| rdrand.get_u64())
+}
+
+/// Steps the fallback generator, a SplitMix64 sequence starting from the TSC.
+fn fallback() -> u64 {
+    let _ = FALLBACK_STATE.compare_exchange(
+        0,
+        crate::time::tsc::rdtsc() | 1,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+    let mut z = FALLBACK_STATE
+        .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+        .wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Returns a random number from [`source`], falling back to the TSC generator if the instruction
+/// keeps failing.
+pub fn u64() -> u64 {
+    let value = match source() {
+        Source::RdSeed => rdseed().or_else(rdrand),
+        Source::RdRand => rdrand(),
+        Source::Tsc => None,
+    };
+    value.unwrap_or_else(fallback)
+}
+
+/// Returns a random number below `bound`, which must not be 0. The slight bias towards small
+/// numbers does not matter for the bounds used here.
+pub fn below(bound: u64) -> u64 {
+    u64() % bound
+}
@@ -0,0 +1,353 @@
+//! Line editing on top of the keyboard queue.
+//!
+//! [`LineEditor::read_line`] reads keys until Enter and redraws the line in place, so the cursor
+//! can move through the text instead of only erasing from the end. Supported keys:
+//!
+//! | Key                    | Action                                         |
+//! |------------------------|------------------------------------------------|
+//! | Left / Right           | move the cursor                                |
+//! | Home / End, Ctrl+A / E | move to the start / end of the line            |
+//! | Backspace / Delete     | delete before / under the cursor               |
+//! | Insert                 | toggle insert and overwrite mode               |
+//! | Up / Down              | recall older / newer lines from the history    |
+//! | Ctrl+U / Ctrl+K        | kill to the start / end of the line            |
+//! | Ctrl+Y, then Alt+Y     | yank the last kill, then cycle to older kills  |
+//!
+//! Only printable ASCII is accepted, since that is all the console font can draw.
+
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use crate::console::{self, ConsoleError};
+use crate::keyboard;
+
+/// Maximum number of characters in a line.
+pub const MAX_LINE: usize = 128;
+
+/// Number of lines remembered for recall with Up and Down.
+const HISTORY_SIZE: usize = 16;
+
+/// Number of killed pieces of text remembered for yanking.
+const KILL_RING_SIZE: usize = 4;
+
+/// A line of ASCII text with a fixed capacity.
+#[derive(Clone, Copy)]
+struct Line {
+    bytes: [u8; MAX_LINE],
+    len: usize,
+}
+
+impl Line {
+    const EMPTY: Line = Line {
+        bytes: [0; MAX_LINE],
+        len: 0,
+    };
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn as_str(&self) -> &str {
+        // Only printable ASCII is ever stored.
+        core::str::from_utf8(self.as_bytes()).unwrap_or("")
+    }
+
+    fn set(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(MAX_LINE);
+        self.bytes[..len].copy_from_slice(&bytes[..len]);
+        self.len = len;
+    }
+}
+
+/// A fixed-size ring of lines in which the newest entry replaces the oldest.
+struct LineRing<const N: usize> {
+    lines: [Line; N],
+    /// Index the next entry is written to.
+    next: usize,
+    count: usize,
+}
+
+impl<const N: usize> LineRing<N> {
+    const fn new() -> Self {
+        Self {
+            lines: [Line::EMPTY; N],
+            next: 0,
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.lines[self.next].set(bytes);
+        self.next = (self.next + 1) % N;
+        self.count = (self.count + 1).min(N);
+    }
+
+    /// Returns the entry `age` steps back, where 0 is the newest.
+    fn get(&self, age: usize) -> Option<&Line> {
+        if age >= self.count {
+            return None;
+        }
+        Some(&self.lines[(self.next + N - 1 - age) % N])
+    }
+}
+
+/// A text span inserted by the last yank, replaced when cycling with Alt+Y.
+#[derive(Clone, Copy)]
+struct Yank {
+    start: usize,
+    len: usize,
+    age: usize,
+}
+
+/// An editable input line with history and a kill ring that persist across reads.
+pub struct LineEditor {
+    line: Line,
+    cursor: usize,
+    overwrite: bool,
+    history: LineRing<HISTORY_SIZE>,
+    /// How far back in the history the line came from, if it was recalled.
+    history_age: Option<usize>,
+    /// The line being typed before browsing the history, restored when browsing past the newest
+    /// entry.
+    draft: Line,
+    kills: LineRing<KILL_RING_SIZE>,
+    last_yank: Option<Yank>,
+    /// Screen position of the first character of the line.
+    row: usize,
+    column: usize,
+    /// Number of characters that fit on the screen row after `column`.
+    capacity: usize,
+    /// Number of cells drawn by the last redraw, so shorter lines can erase the rest.
+    drawn: usize,
+}
+
+impl LineEditor {
+    pub const fn new() -> Self {
+        Self {
+            line: Line::EMPTY,
+            cursor: 0,
+            overwrite: false,
+            history: LineRing::new(),
+            history_age: None,
+            draft: Line::EMPTY,
+            kills: LineRing::new(),
+            last_yank: None,
+            row: 0,
+            column: 0,
+            capacity: MAX_LINE,
+            drawn: 0,
+        }
+    }
+
+    /// Reads a line at the current console position, returning it without the final newline.
+    ///
+    /// Keyboard echo is turned off while editing, because the editor draws the line itself.
+    pub fn read_line(&mut self) -> Result<&str, ConsoleError> {
+        let (row, column) = console::get_cursor()?;
+        let columns = console::with_writer(|writer| writer.text_columns())?;
+        self.row = row;
+        self.column = column;
+        // Leave room for the cursor cell after the last character.
+        self.capacity = columns.saturating_sub(column + 1).min(MAX_LINE);
+        self.line = Line::EMPTY;
+        self.cursor = 0;
+        self.history_age = None;
+        self.last_yank = None;
+        self.drawn = 0;
+
+        let echo = keyboard::echo_enabled();
+        keyboard::set_echo(false);
+        let result = self.edit();
+        keyboard::set_echo(echo);
+        result?;
+
+        if self.line.len > 0
+            && self.history.get(0).map(Line::as_bytes) != Some(self.line.as_bytes())
+        {
+            self.history.push(self.line.as_bytes());
+        }
+        Ok(self.line.as_str())
+    }
+
+    fn edit(&mut self) -> Result<(), ConsoleError> {
+        self.redraw(true)?;
+        loop {
+            let key = keyboard::read_key();
+            let yanking = matches!(key, DecodedKey::Unicode('\u{19}'))
+                || (key == DecodedKey::Unicode('y') && keyboard::modifiers().alt());
+            if !yanking {
+                self.last_yank = None;
+            }
+            match key {
+                DecodedKey::Unicode('\n') => break,
+                DecodedKey::Unicode('\u{8}') => self.backspace(),
+                DecodedKey::Unicode('\u{7f}') => self.delete(),
+                DecodedKey::Unicode('\u{1}') | DecodedKey::RawKey(KeyCode::Home) => self.cursor = 0,
+                DecodedKey::Unicode('\u{5}') | DecodedKey::RawKey(KeyCode::End) => {
+                    self.cursor = self.line.len
+                }
+                DecodedKey::Unicode('\u{15}') => self.kill(0, self.cursor),
+                DecodedKey::Unicode('\u{b}') => self.kill(self.cursor, self.line.len),
+                DecodedKey::Unicode('\u{19}') => self.yank(0),
+                DecodedKey::Unicode('y') if yanking => self.yank_pop(),
+                DecodedKey::Unicode(c @ ' '..='~') => self.type_byte(c as u8),
+                DecodedKey::RawKey(KeyCode::ArrowLeft) => {
+                    self.cursor = self.cursor.saturating_sub(1)
+                }
+                DecodedKey::RawKey(KeyCode::ArrowRight) => {
+                    self.cursor = (self.cursor + 1).min(self.line.len)
+                }
+                DecodedKey::RawKey(KeyCode::Insert) => self.overwrite = !self.overwrite,
+                DecodedKey::RawKey(KeyCode::ArrowUp) => self.recall_older(),
+                DecodedKey::RawKey(KeyCode::ArrowDown) => self.recall_newer(),
+                _ => continue,
+            }
+            self.redraw(true)?;
+        }
+        self.redraw(false)?;
+        console::set_cursor(self.row, self.column + self.line.len);
+        crate::println!();
+        Ok(())
+    }
+
+    /// Draws the line, with the cursor cell in reverse video if `show_cursor` is set.
+    fn redraw(&mut self, show_cursor: bool) -> Result<(), ConsoleError> {
+        let text = self.line.as_str();
+        let (before, rest) = text.split_at(self.cursor);
+        let (under, after) = match rest.chars().next() {
+            Some(c) => rest.split_at(c.len_utf8()),
+            None => (" ", ""),
+        };
+        let cells = before.len() + under.len() + after.len();
+        let padding = self.drawn.saturating_sub(cells);
+        self.drawn = cells;
+        let (reverse, normal) = if show_cursor {
+            ("\x1b[7m", "\x1b[27m")
+        } else {
+            ("", "")
+        };
+        console::with_writer(|writer| {
+            writer.write_fmt_at(
+                self.row,
+                self.column,
+                format_args!("{before}{reverse}{under}{normal}{after}{:padding$}", ""),
+            )
+        })?
+    }
+
+    /// Types a character at the cursor, inserting or overwriting depending on the mode.
+    fn type_byte(&mut self, byte: u8) {
+        if self.overwrite && self.cursor < self.line.len {
+            self.line.bytes[self.cursor] = byte;
+            self.cursor += 1;
+        } else {
+            self.insert(&[byte]);
+        }
+    }
+
+    /// Inserts `bytes` at the cursor, as far as they fit, and moves the cursor past them.
+    /// Returns the number of bytes inserted.
+    fn insert(&mut self, bytes: &[u8]) -> usize {
+        let count = bytes.len().min(self.capacity.saturating_sub(self.line.len));
+        let len = self.line.len;
+        self.line
+            .bytes
+            .copy_within(self.cursor..len, self.cursor + count);
+        self.line.bytes[self.cursor..self.cursor + count].copy_from_slice(&bytes[..count]);
+        self.line.len += count;
+        self.cursor += count;
+        count
+    }
+
+    /// Removes the bytes in `start..end` from the line.
+    fn remove(&mut self, start: usize, end: usize) {
+        let len = self.line.len;
+        self.line.bytes.copy_within(end..len, start);
+        self.line.len -= end - start;
+        if self.cursor > end {
+            self.cursor -= end - start;
+        } else if self.cursor > start {
+            self.cursor = start;
+        }
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.remove(self.cursor - 1, self.cursor);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.line.len {
+            self.remove(self.cursor, self.cursor + 1);
+        }
+    }
+
+    /// Moves the text in `start..end` into the kill ring.
+    fn kill(&mut self, start: usize, end: usize) {
+        if start == end {
+            return;
+        }
+        self.kills.push(&self.line.bytes[start..end]);
+        self.remove(start, end);
+    }
+
+    /// Inserts the kill `age` steps back at the cursor.
+    fn yank(&mut self, age: usize) {
+        let Some(killed) = self.kills.get(age).copied() else {
+            return;
+        };
+        let start = self.cursor;
+        let len = self.insert(killed.as_bytes());
+        self.last_yank = Some(Yank { start, len, age });
+    }
+
+    /// Replaces the text just yanked with the next older kill.
+    fn yank_pop(&mut self) {
+        let Some(last) = self.last_yank else {
+            return;
+        };
+        self.remove(last.start, last.start + last.len);
+        self.cursor = last.start;
+        let age = (last.age + 1) % self.kills.count.max(1);
+        self.yank(age);
+    }
+
+    fn recall_older(&mut self) {
+        let age = self.history_age.map_or(0, |age| age + 1);
+        let Some(line) = self.history.get(age).copied() else {
+            return;
+        };
+        if self.history_age.is_none() {
+            self.draft = self.line;
+        }
+        self.history_age = Some(age);
+        self.show_recalled(&line);
+    }
+
+    fn recall_newer(&mut self) {
+        let line = match self.history_age {
+            None => return,
+            Some(0) => {
+                self.history_age = None;
+                self.draft
+            }
+            Some(age) => {
+                self.history_age = Some(age - 1);
+                self.history.get(age - 1).copied().unwrap_or(Line::EMPTY)
+            }
+        };
+        self.show_recalled(&line);
+    }
+
+    fn show_recalled(&mut self, line: &Line) {
+        self.line
+            .set(&line.as_bytes()[..line.len.min(self.capacity)]);
+        self.cursor = self.line.len;
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
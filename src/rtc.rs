@@ -0,0 +1,205 @@
+//! The CMOS real-time clock.
+//!
+//! The RTC keeps the wall-clock date and time while the machine is off. Its registers are read
+//! through an index and a data port, usually in BCD and in the firmware's local time zone. It can
+//! also raise a periodic interrupt on IRQ 8.
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::interruptsa::{IrqError, IrqHandler};
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+/// Time and date registers.
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+/// Century register on practically all chipsets. The FADT names it, once it is parsed.
+const CENTURY: u8 = 0x32;
+
+/// Status register A: update in progress flag and periodic interrupt rate.
+const STATUS_A: u8 = 0x0A;
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE_MASK: u8 = 0x0F;
+/// Status register B: periodic interrupt enable, binary instead of BCD values, 24-hour mode.
+const STATUS_B: u8 = 0x0B;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Status register C, which must be read after each interrupt or no more are raised.
+const STATUS_C: u8 = 0x0C;
+
+/// Hours register bit marking PM in 12-hour mode.
+const HOURS_PM: u8 = 1 << 7;
+
+/// ISA IRQ of the RTC.
+const RTC_IRQ: u8 = 8;
+
+/// Base frequency the periodic interrupt is divided from, and the rates it supports.
+const BASE_FREQUENCY: u32 = 32_768;
+pub const MIN_PERIODIC_RATE: u32 = 2;
+pub const MAX_PERIODIC_RATE: u32 = 8192;
+
+/// Errors returned by [`enable_periodic_interrupt`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// The rate is not a power of two in `MIN_PERIODIC_RATE..=MAX_PERIODIC_RATE`.
+    UnsupportedRate(u32),
+    /// IRQ 8 could not be registered.
+    Irq(IrqError),
+}
+
+/// A wall-clock date and time as kept by the RTC, in the firmware's time zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Handler of the periodic interrupt.
+static PERIODIC_HANDLER: Mutex<Option<IrqHandler>> = Mutex::new(None);
+
+fn read_register(register: u8) -> u8 {
+    let mut index = Port::<u8>::new(INDEX_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+    // The index must not change between the two accesses.
+    interrupts::without_interrupts(|| unsafe {
+        index.write(register);
+        data.read()
+    })
+}
+
+fn write_register(register: u8, value: u8) {
+    let mut index = Port::<u8>::new(INDEX_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+    interrupts::without_interrupts(|| unsafe {
+        index.write(register);
+        data.write(value);
+    })
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Raw register values, compared to detect an update between two reads.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Registers([u8; 7]);
+
+fn read_registers() -> Registers {
+    while read_register(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    Registers([SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR, CENTURY].map(read_register))
+}
+
+/// Reads the current date and time.
+///
+/// The RTC updates its registers once a second, and values read during the update may be
+/// inconsistent, so the registers are read until two reads agree.
+pub fn now() -> DateTime {
+    let mut registers = read_registers();
+    loop {
+        let again = read_registers();
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+    let [second, minute, hour, day, month, year, century] = registers.0;
+    let status = read_register(STATUS_B);
+    let convert = |value: u8| {
+        if status & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    };
+    let mut hour_value = convert(hour & !HOURS_PM);
+    if status & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon.
+        hour_value %= 12;
+        if hour & HOURS_PM != 0 {
+            hour_value += 12;
+        }
+    }
+    // Without a sensible century register, assume the 21st century.
+    let century = match convert(century) {
+        century @ 19..=21 => u16::from(century),
+        _ => 20,
+    };
+    DateTime {
+        year: century * 100 + u16::from(convert(year)),
+        month: convert(month),
+        day: convert(day),
+        hour: hour_value,
+        minute: convert(minute),
+        second: convert(second),
+    }
+}
+
+fn interrupt_handler() {
+    // Acknowledge the interrupt, or the RTC raises no further ones.
+    read_register(STATUS_C);
+    let handler = *PERIODIC_HANDLER.lock();
+    if let Some(handler) = handler {
+        handler();
+    }
+}
+
+/// Runs `handler` `hz` times per second from the RTC periodic interrupt. `hz` must be a power of
+/// two from [`MIN_PERIODIC_RATE`] to [`MAX_PERIODIC_RATE`].
+pub fn enable_periodic_interrupt(hz: u32, handler: IrqHandler) -> Result<(), RtcError> {
+    if !hz.is_power_of_two() || !(MIN_PERIODIC_RATE..=MAX_PERIODIC_RATE).contains(&hz) {
+        return Err(RtcError::UnsupportedRate(hz));
+    }
+    // The frequency is 32768 >> (rate - 1).
+    let rate = (BASE_FREQUENCY / hz).trailing_zeros() as u8 + 1;
+    let was_enabled = interrupts::without_interrupts(|| {
+        let was_enabled = PERIODIC_HANDLER.lock().replace(handler).is_some();
+        let status_a = read_register(STATUS_A);
+        write_register(STATUS_A, (status_a & !STATUS_A_RATE_MASK) | rate);
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+        read_register(STATUS_C);
+        was_enabled
+    });
+    if !was_enabled {
+        if let Err(error) = crate::interruptsa::register_irq(RTC_IRQ, interrupt_handler) {
+            disable_periodic_interrupt();
+            return Err(RtcError::Irq(error));
+        }
+    }
+    Ok(())
+}
+
+/// Stops the periodic interrupt.
+pub fn disable_periodic_interrupt() {
+    interrupts::without_interrupts(|| {
+        let status_b = read_register(STATUS_B);
+        write_register(STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+        *PERIODIC_HANDLER.lock() = None;
+    });
+    crate::interruptsa::unregister_irq(RTC_IRQ);
+}
@@ -0,0 +1,61 @@
+//! Output over the first serial port (COM1), used for logs and screen captures that have to
+//! leave the machine, e.g. when running under QEMU with `-serial stdio`.
+//!
+//! Every line logged with `serial_println!` starts with the uptime in seconds, like
+//! `[   12.345678] `, so the log shows when each boot step and driver message happened. The
+//! wall-clock time of the boot is logged once, from the RTC, and the rest follows from it.
+
+use core::fmt::{Arguments, Write};
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::interrupts;
+
+/// I/O base port of COM1.
+const COM1: u16 = 0x3F8;
+
+pub static SERIAL1: Mutex<Option<SerialPort>> = Mutex::new(None);
+
+/// Initializes COM1. Output written before this is dropped.
+pub fn init() {
+    let mut port = unsafe { SerialPort::new(COM1) };
+    port.init();
+    *SERIAL1.lock() = Some(port);
+}
+
+/// Runs `f` with exclusive access to COM1, e.g. to send binary data with
+/// [`SerialPort::send_raw`]. Returns `None` if the port has not been initialized.
+pub fn with_port<R>(f: impl FnOnce(&mut SerialPort) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| SERIAL1.lock().as_mut().map(f))
+}
+
+#[doc(hidden)]
+pub fn _print(args: Arguments) {
+    interrupts::without_interrupts(|| {
+        if let Some(port) = &mut *SERIAL1.lock() {
+            port.write_fmt(args).unwrap();
+        }
+    });
+}
+
+/// Writes one log line with its timestamp.
+#[doc(hidden)]
+pub fn _log(args: Arguments) {
+    let uptime = core::time::Duration::from_nanos(crate::time::monotonic_ns());
+    _print(format_args!(
+        "[{:>5}.{:06}] {}\n",
+        uptime.as_secs(),
+        uptime.subsec_micros(),
+        args
+    ));
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial::_log(format_args!("")));
+    ($($arg:tt)*) => ($crate::serial::_log(format_args!($($arg)*)));
+}
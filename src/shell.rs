@@ -0,0 +1,595 @@
+//! A minimal command shell on the console, for inspecting the kernel while it runs.
+//!
+//! Each command is an entry in the `COMMANDS` table: a name, a one-line description for `help`,
+//! and a function that receives the rest of the line.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+
+use crate::readline::LineEditor;
+use crate::{print, println};
+
+/// A shell command.
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(args: &str),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "irqstat",
+        help: "show how often each interrupt vector was raised",
+        run: irqstat,
+    },
+    Command {
+        name: "heap",
+        help: "show heap usage and slab cache statistics",
+        run: heap,
+    },
+    Command {
+        name: "meminfo",
+        help: "summarize physical memory and heap usage",
+        run: meminfo,
+    },
+    Command {
+        name: "mappings",
+        help: "list page mappings: mappings <start> [end], addresses in hex",
+        run: mappings,
+    },
+    Command {
+        name: "threads",
+        help: "list the kernel threads",
+        run: threads,
+    },
+    Command {
+        name: "top",
+        help: "show CPU usage per thread: top [refreshes], one per second",
+        run: top,
+    },
+    Command {
+        name: "hello",
+        help: "run the embedded hello program in user mode",
+        run: hello,
+    },
+    Command {
+        name: "run",
+        help: "run an ELF program: run <path> [args], a bare name from /bin or built in",
+        run: run_command,
+    },
+    Command {
+        name: "echo",
+        help: "print the arguments with the echo program in user mode",
+        run: echo,
+    },
+    Command {
+        name: "fork",
+        help: "run the program that forks and execs echo in the child",
+        run: fork,
+    },
+    Command {
+        name: "signal",
+        help: "run the program that handles Ctrl+C and a page fault",
+        run: signal,
+    },
+    Command {
+        name: "sbrk",
+        help: "run the program that gets memory with sbrk and mmap",
+        run: sbrk,
+    },
+    Command {
+        name: "clock",
+        help: "run the program that reads the time page",
+        run: clock,
+    },
+    Command {
+        name: "ps",
+        help: "list the user processes",
+        run: ps,
+    },
+    Command {
+        name: "lspci",
+        help: "list the PCI functions: lspci [-v], -v with their BARs",
+        run: lspci,
+    },
+    Command {
+        name: "disks",
+        help: "list the block devices",
+        run: disks,
+    },
+    Command {
+        name: "ls",
+        help: "list a directory: ls [path]",
+        run: ls,
+    },
+    Command {
+        name: "cat",
+        help: "print a file: cat <path>",
+        run: cat,
+    },
+    Command {
+        name: "write",
+        help: "replace a file's contents with a line: write <path> <text>",
+        run: write,
+    },
+    Command {
+        name: "mkdir",
+        help: "make a directory: mkdir <path>",
+        run: mkdir,
+    },
+    Command {
+        name: "rm",
+        help: "remove a file or empty directory: rm <path>",
+        run: rm,
+    },
+    Command {
+        name: "mounts",
+        help: "list the mounted file systems",
+        run: mounts,
+    },
+    Command {
+        name: "sync",
+        help: "write every file system and block cache back to disk",
+        run: sync,
+    },
+];
+
+const PROMPT: &str = "> ";
+
+/// Reads and runs commands forever. Halts if there is no console to read from.
+pub fn run() -> ! {
+    let mut editor = LineEditor::new();
+    loop {
+        print!("{}", PROMPT);
+        match editor.read_line() {
+            Ok(line) => execute(line),
+            Err(_) => crate::task::run(),
+        }
+    }
+}
+
+/// Runs one command line.
+pub fn execute(line: &str) {
+    let line = line.trim();
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    if name.is_empty() {
+        return;
+    }
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(args.trim()),
+        None => println!("unknown command: {} (try help)", name),
+    }
+}
+
+fn help(_args: &str) {
+    for command in COMMANDS {
+        println!("  {:<10} {}", command.name, command.help);
+    }
+}
+
+fn irqstat(_args: &str) {
+    let stats = crate::interruptsa::stats();
+    println!("  vector  {:>10}  source", "count");
+    for (vector, count) in stats.iter() {
+        println!(
+            "  {:#04x}    {:>10}  {}",
+            vector,
+            count,
+            crate::interruptsa::vector_name(vector)
+        );
+    }
+    println!("  total   {:>10}", stats.total());
+    println!("  of which spurious: {}", stats.spurious());
+}
+
+fn heap(_args: &str) {
+    let stats = crate::memory::heap_stats();
+    println!(
+        "  {} of {} KiB in use",
+        stats.used / 1024,
+        stats.size / 1024
+    );
+    println!("  {} KiB from the early arena", stats.early / 1024);
+    println!(
+        "  size  {:>10}  {:>10}  {:>6}  {:>8}",
+        "hits", "misses", "slabs", "in use"
+    );
+    for cache in stats.slabs {
+        println!(
+            "  {:<4}  {:>10}  {:>10}  {:>6}  {:>8}",
+            cache.object_size,
+            cache.hits,
+            cache.misses,
+            cache.slabs,
+            cache.in_use()
+        );
+    }
+}
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * 1024;
+
+/// Width of the usage bars of `meminfo`, percentage included.
+const BAR_WIDTH: usize = 30;
+
+fn meminfo(_args: &str) {
+    let Some(stats) = crate::memory::stats() else {
+        println!("memory management is not set up");
+        return;
+    };
+    println!("  total     {:>8} MiB", stats.total / MIB);
+    println!("  usable    {:>8} MiB", stats.usable / MIB);
+    println!("  reserved  {:>8} MiB", stats.reserved / MIB);
+    let frame_kib = |frames: usize| frames as u64 * 4;
+    usage_bar(
+        "frames",
+        frame_kib(stats.frames.used()),
+        frame_kib(stats.frames.usable),
+    );
+    let latency = stats.frames.latency;
+    println!(
+        "  frame alloc avg {} max {} cycles, free avg {} max {} cycles",
+        latency.average_allocation_cycles(),
+        latency.max_allocation_cycles,
+        latency.average_free_cycles(),
+        latency.max_free_cycles
+    );
+    usage_bar(
+        "heap",
+        stats.heap.used as u64 / KIB,
+        stats.heap.size as u64 / KIB,
+    );
+}
+
+/// Prints a line with `label`, a bar of `used` out of `total` KiB, and the two numbers.
+fn usage_bar(label: &str, used: u64, total: u64) {
+    print!("  {:<8}  ", label);
+    if let Ok((row, column)) = crate::console::get_cursor() {
+        let _ = crate::tui::ProgressBar::new(row, column, BAR_WIDTH, total).set(used);
+        crate::console::set_cursor(row, column + BAR_WIDTH);
+    }
+    println!("  {} / {} KiB", used, total);
+}
+
+fn parse_address(text: &str) -> Option<x86_64::VirtAddr> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    let address = u64::from_str_radix(digits, 16).ok()?;
+    x86_64::VirtAddr::try_new(address).ok()
+}
+
+fn mappings(args: &str) {
+    let mut words = args.split_whitespace();
+    let start = words.next().and_then(parse_address);
+    let end = match words.next() {
+        Some(word) => parse_address(word),
+        // The last page of the address space, or of its lower half, has no end address and needs
+        // an explicit end.
+        None => start
+            .and_then(|start| start.as_u64().checked_add(4096))
+            .and_then(|end| x86_64::VirtAddr::try_new(end).ok()),
+    };
+    let (Some(start), Some(end)) = (start, end) else {
+        println!("usage: mappings <start> [end]");
+        return;
+    };
+    let mut any = false;
+    crate::memory::dump::for_each_mapping(start..end, |mapping| {
+        any = true;
+        println!("  {}", mapping);
+    });
+    if !any {
+        println!("  nothing mapped");
+    }
+}
+
+fn threads(_args: &str) {
+    let current = crate::thread::current();
+    println!(
+        "  {:>4}  {:<12}  {:<8}  {:>3}  state",
+        "id", "name", "priority", "cpu"
+    );
+    for thread in crate::thread::list() {
+        let marker = if Some(thread.id) == current { '*' } else { ' ' };
+        println!(
+            "{} {:>4}  {:<12}  {:<8}  {:>3}  {:?}",
+            marker,
+            thread.id.as_u64(),
+            thread.name,
+            format!("{:?}", thread.priority),
+            thread.cpu,
+            thread.state
+        );
+    }
+    println!(
+        "deferred work: {} pending, {} done",
+        crate::workqueue::pending(),
+        crate::workqueue::completed()
+    );
+}
+
+fn top(args: &str) {
+    let refreshes = match args {
+        "" => 1,
+        _ => match args.parse::<u32>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                println!("usage: top [refreshes]");
+                return;
+            }
+        },
+    };
+    for refresh in 0..refreshes {
+        if refresh > 0 {
+            crate::thread::sleep(core::time::Duration::from_millis(u64::from(
+                crate::thread::STATS_PERIOD_MS,
+            )));
+            println!();
+        }
+        let mut stats = crate::thread::stats();
+        for (cpu, stats) in stats.cpus.iter().enumerate() {
+            println!(
+                "  cpu {:>2}: {:>3}% idle, {} queued, {} switches",
+                cpu, stats.idle_percent, stats.queued, stats.switches
+            );
+        }
+        println!(
+            "  {:>4}  {:<12}  {:>4}  {:>8}  {:>8}  {:>3}",
+            "id", "name", "cpu%", "ticks", "switches", "cpu"
+        );
+        stats
+            .threads
+            .sort_by_key(|thread| Reverse((thread.cpu_percent, thread.ticks)));
+        for thread in stats.threads {
+            println!(
+                "  {:>4}  {:<12}  {:>4}  {:>8}  {:>8}  {:>3}",
+                thread.id.as_u64(),
+                thread.name,
+                thread.cpu_percent,
+                thread.ticks,
+                thread.switches,
+                thread.cpu
+            );
+        }
+    }
+}
+
+fn hello(_args: &str) {
+    match crate::usermode::run("hello", crate::usermode::hello()) {
+        Ok(status) => println!("{:?}", status),
+        Err(error) => println!("could not run the program: {:?}", error),
+    }
+}
+
+/// Runs the program at `path` with `args` after its name, and waits for it in the foreground.
+fn run_program(path: &str, args: &str) {
+    let image = match crate::usermode::read_program(path) {
+        Ok(image) => image,
+        Err(error) => {
+            println!("could not read {}: {:?}", path, error);
+            return;
+        }
+    };
+    let args: Vec<&str> = core::iter::once(path)
+        .chain(args.split_whitespace())
+        .collect();
+    match crate::process::spawn_elf(&image, &args) {
+        Ok(process) => println!("{:?}", process.wait_in_foreground()),
+        Err(error) => println!("could not run the program: {:?}", error),
+    }
+}
+
+fn run_command(args: &str) {
+    let args = args.trim();
+    match args.split_once(char::is_whitespace) {
+        Some((path, rest)) => run_program(path, rest),
+        None if !args.is_empty() => run_program(args, ""),
+        None => println!("usage: run <path> [args]"),
+    }
+}
+
+fn echo(args: &str) {
+    run_program("echo", args);
+}
+
+fn fork(_args: &str) {
+    run_program("fork", "");
+}
+
+fn signal(_args: &str) {
+    run_program("signal", "");
+}
+
+fn sbrk(_args: &str) {
+    run_program("heap", "");
+}
+
+fn clock(_args: &str) {
+    run_program("clock", "");
+}
+
+fn ps(_args: &str) {
+    println!(
+        "  {:>5}  {:>6}  {:<16}  {:<7}  threads",
+        "pid", "parent", "name", "state"
+    );
+    for process in crate::process::list() {
+        let parent = process
+            .parent()
+            .map_or(String::from("-"), |parent| parent.as_u64().to_string());
+        let state = if process.status().is_some() {
+            "zombie"
+        } else {
+            "running"
+        };
+        println!(
+            "  {:>5}  {:>6}  {:<16}  {:<7}  {}",
+            process.id().as_u64(),
+            parent,
+            process.name(),
+            state,
+            process.threads().len()
+        );
+    }
+}
+
+fn lspci(args: &str) {
+    let verbose = match args {
+        "" => false,
+        "-v" => true,
+        _ => {
+            println!("usage: lspci [-v]");
+            return;
+        }
+    };
+    for device in crate::pci::iter() {
+        let vendor = crate::pci::vendor_name(device.vendor_id).unwrap_or("unknown vendor");
+        println!(
+            "  {}  {:04x}:{:04x}  {} ({}), class {:02x}.{:02x}.{:02x}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            crate::pci::class_name(device.class, device.subclass),
+            vendor,
+            device.class,
+            device.subclass,
+            device.prog_if
+        );
+        if !verbose {
+            continue;
+        }
+        if let Some(line) = device.interrupt_line {
+            println!("      IRQ {}", line);
+        }
+        for (id, offset) in device.capabilities() {
+            let name = match id {
+                crate::pci::CAPABILITY_MSI => " (MSI)",
+                crate::pci::CAPABILITY_MSIX => " (MSI-X)",
+                _ => "",
+            };
+            println!("      capability {:#04x} at {:#04x}{}", id, offset, name);
+        }
+        for (index, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(crate::pci::Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                }) => println!(
+                    "      BAR {}: memory at {:#x}, {} KiB{}",
+                    index,
+                    address.as_u64(),
+                    size / 1024,
+                    if *prefetchable { ", prefetchable" } else { "" }
+                ),
+                Some(crate::pci::Bar::Io { port, size }) => {
+                    println!("      BAR {}: I/O at {:#x}, {} ports", index, port, size)
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+fn disks(_args: &str) {
+    println!("  {:<8}  {:>12}  {:>8}", "name", "blocks", "MiB");
+    for (name, device) in crate::block::devices() {
+        let blocks = device.block_count();
+        println!(
+            "  {:<8}  {:>12}  {:>8}",
+            name,
+            blocks,
+            blocks * crate::block::BLOCK_SIZE as u64 / (1024 * 1024)
+        );
+    }
+}
+
+fn ls(args: &str) {
+    let path = if args.is_empty() { "/" } else { args };
+    let mut entries = match crate::vfs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(error) => {
+            println!("ls: {}: {:?}", path, error);
+            return;
+        }
+    };
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        let child = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+        match (entry.kind, crate::vfs::stat(&child)) {
+            (crate::vfs::FileType::Directory, _) => println!("  {}/", entry.name),
+            (_, Ok(metadata)) => println!("  {:<24} {:>10}", entry.name, metadata.size),
+            (_, Err(_)) => println!("  {}", entry.name),
+        }
+    }
+}
+
+fn cat(args: &str) {
+    if args.is_empty() {
+        println!("usage: cat <path>");
+        return;
+    }
+    match crate::vfs::read(args) {
+        Ok(contents) => print!("{}", String::from_utf8_lossy(&contents)),
+        Err(error) => println!("cat: {}: {:?}", args, error),
+    }
+}
+
+fn write(args: &str) {
+    let Some((path, text)) = args.split_once(' ') else {
+        println!("usage: write <path> <text>");
+        return;
+    };
+    let written = match crate::vfs::create(path, crate::vfs::FileType::File) {
+        Ok(_) | Err(crate::vfs::VfsError::AlreadyExists) => crate::vfs::open(path),
+        Err(error) => Err(error),
+    }
+    .and_then(|mut file| {
+        file.truncate(0)?;
+        file.write(format!("{}\n", text).as_bytes())
+    });
+    if let Err(error) = written {
+        println!("write: {}: {:?}", path, error);
+    }
+}
+
+fn mkdir(args: &str) {
+    if args.is_empty() {
+        println!("usage: mkdir <path>");
+        return;
+    }
+    if let Err(error) = crate::vfs::create(args, crate::vfs::FileType::Directory) {
+        println!("mkdir: {}: {:?}", args, error);
+    }
+}
+
+fn rm(args: &str) {
+    if args.is_empty() {
+        println!("usage: rm <path>");
+        return;
+    }
+    if let Err(error) = crate::vfs::unlink(args) {
+        println!("rm: {}: {:?}", args, error);
+    }
+}
+
+fn mounts(_args: &str) {
+    for (path, name) in crate::vfs::mounts() {
+        println!("  {:<16} {}", path, name);
+    }
+}
+
+fn sync(_args: &str) {
+    if let Err(error) = crate::vfs::sync() {
+        println!("sync: {:?}", error);
+    }
+    if let Err(error) = crate::block::cache::sync() {
+        println!("sync: {:?}", error);
+    }
+}
@@ -0,0 +1,485 @@
+//! Starting the application processors.
+//!
+//! The boot CPU finds the other processors in the ACPI MADT and starts them one at a time: an
+//! INIT IPI resets a processor, and a startup IPI makes it run the trampoline below in real mode
+//! from a page under 1 MiB. The trampoline switches straight to long mode with the kernel's page
+//! tables, which map the trampoline page at its physical address while processors start, and
+//! jumps to [`ap_main`] on a stack of its own. There the processor sets up its per-CPU area, GDT,
+//! TSS and local APIC, loads the shared IDT, and becomes the idle thread of a new CPU of the
+//! scheduler, which then hands it threads like any other.
+//!
+//! CPUs are numbered in the order they come up, the boot CPU being CPU 0, and address each other
+//! with inter-processor interrupts through [`send_ipi`]. Two IPIs are registered here: one makes
+//! a CPU run the scheduler, the other makes it flush pages from its TLB after the page tables,
+//! which all CPUs share, changed. [`flush_tlb`] sends the latter to every other CPU and waits
+//! until all have flushed, so a page unmapped or write-protected on one CPU is not reachable
+//! through a stale TLB entry on another. [`stop_others`] halts the other CPUs with NMIs for a
+//! panic or a reboot.
+
+use core::arch::global_asm;
+use core::hint::spin_loop;
+use core::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::registers::control::{Cr0, Cr3, Cr4};
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::interruptsa::IrqError;
+use crate::memory::vmm;
+use crate::memory::PagingError;
+use crate::per_cpu;
+use crate::percpu::MAX_CPUS;
+
+/// Errors that keep the application processors from being started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    /// The local APIC, which sends the startup IPIs, is not in use.
+    ApicDisabled,
+    /// There is no MADT listing the processors.
+    NoMadt,
+    /// No free frame below 1 MiB is left for the trampoline.
+    NoLowMemory,
+    /// The trampoline page could not be mapped at its physical address.
+    Paging(PagingError),
+    /// The top-level page table lies above 4 GiB, out of reach of the trampoline.
+    PageTableTooHigh,
+    /// No vector was left for the IPIs.
+    Irq(IrqError),
+    /// There is no CPU with this index running.
+    NoSuchCpu(usize),
+}
+
+impl From<PagingError> for SmpError {
+    fn from(error: PagingError) -> Self {
+        SmpError::Paging(error)
+    }
+}
+
+impl From<IrqError> for SmpError {
+    fn from(error: IrqError) -> Self {
+        SmpError::Irq(error)
+    }
+}
+
+/// The trampoline must lie below this address, which a startup IPI can point to.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// How long a processor may take to come up after its startup IPIs.
+const STARTUP_TIMEOUT_MS: u64 = 100;
+
+global_asm!(
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_gdt",
+    ".global ap_gdtr",
+    ".global ap_far_jump",
+    ".global ap_long_mode",
+    ".global ap_cr3",
+    ".global ap_stack",
+    ".global ap_entry",
+    ".global ap_cpu",
+    // Copied to the start of a page and entered in real mode with CS at that page, so all
+    // addresses are offsets from the start until long mode.
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [ap_gdtr - ap_trampoline_start]",
+    // Physical address extension, which long mode paging needs.
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, dword ptr [ap_cr3 - ap_trampoline_start]",
+    "mov cr3, eax",
+    // Long mode and the no-execute bit, which the kernel's page tables use.
+    "mov ecx, 0xC0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    // Protection and paging at once, which activates long mode.
+    "mov eax, cr0",
+    "or eax, 0x80000001",
+    "mov cr0, eax",
+    // jmp far dword [ap_far_jump], into the 64-bit code segment.
+    ".byte 0x66, 0xFF, 0x2E",
+    ".2byte ap_far_jump - ap_trampoline_start",
+    ".code64",
+    "ap_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor eax, eax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov rsp, qword ptr [rip + ap_stack]",
+    "mov rdi, qword ptr [rip + ap_cpu]",
+    "mov rax, qword ptr [rip + ap_entry]",
+    "call rax",
+    "ud2",
+    // Null, 64-bit code and data descriptors, already marked accessed so the CPU need not
+    // write to the page.
+    ".balign 8",
+    "ap_gdt:",
+    ".8byte 0",
+    ".8byte 0x00AF9B000000FFFF",
+    ".8byte 0x00CF93000000FFFF",
+    "ap_gdtr:",
+    ".2byte 3 * 8 - 1",
+    ".4byte 0",
+    ".balign 8",
+    "ap_far_jump:",
+    ".4byte 0",
+    ".2byte 0x08",
+    ".balign 8",
+    "ap_cr3:",
+    ".8byte 0",
+    "ap_stack:",
+    ".8byte 0",
+    "ap_entry:",
+    ".8byte 0",
+    "ap_cpu:",
+    ".8byte 0",
+    "ap_trampoline_end:",
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_gdt: u8;
+    static ap_gdtr: u8;
+    static ap_far_jump: u8;
+    static ap_long_mode: u8;
+    static ap_cr3: u8;
+    static ap_stack: u8;
+    static ap_entry: u8;
+    static ap_cpu: u8;
+}
+
+/// Offset of a trampoline symbol from the start of the trampoline.
+fn offset(symbol: *const u8) -> usize {
+    symbol as usize - &raw const ap_trampoline_start as usize
+}
+
+/// The trampoline, copied to a page below 1 MiB.
+struct Trampoline {
+    frame: PhysFrame<Size4KiB>,
+    /// The page in the mapping of all physical memory, through which the fields are written.
+    virt: VirtAddr,
+}
+
+impl Trampoline {
+    fn write<T>(&self, symbol: *const u8, value: T) {
+        unsafe {
+            (self.virt + offset(symbol))
+                .as_mut_ptr::<T>()
+                .write_unaligned(value)
+        }
+    }
+
+    fn physical(&self, symbol: *const u8) -> u32 {
+        (self.frame.start_address().as_u64() + offset(symbol) as u64) as u32
+    }
+}
+
+/// Local APIC id of each CPU that has come up.
+static APIC_IDS: [AtomicU8; MAX_CPUS] = [const { AtomicU8::new(0) }; MAX_CPUS];
+
+/// Number of CPUs running, the boot CPU included.
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Control registers of the boot CPU, which the others copy, so that they also enforce write
+/// protection in the kernel.
+static BOOT_CR0: AtomicU64 = AtomicU64::new(0);
+static BOOT_CR4: AtomicU64 = AtomicU64::new(0);
+
+/// Starts every enabled processor the MADT lists, up to [`MAX_CPUS`] in all. Returns the number
+/// of CPUs running afterwards. Call once, after the scheduler is running on the boot CPU.
+pub fn init() -> Result<usize, SmpError> {
+    let own_id = crate::apic::local_id().ok_or(SmpError::ApicDisabled)?;
+    APIC_IDS[0].store(own_id, Ordering::Relaxed);
+    let processors = crate::apic::processors().ok_or(SmpError::NoMadt)?;
+    register_ipis()?;
+    BOOT_CR0.store(Cr0::read_raw(), Ordering::Relaxed);
+    BOOT_CR4.store(Cr4::read_raw(), Ordering::Relaxed);
+    let (level_4, _) = Cr3::read();
+    let cr3 = level_4.start_address().as_u64();
+    if cr3 >= 1 << 32 {
+        return Err(SmpError::PageTableTooHigh);
+    }
+    let frame = crate::memory::allocate_contiguous_frames(1, PhysAddr::new(LOW_MEMORY_END))
+        .ok_or(SmpError::NoLowMemory)?;
+    let trampoline = Trampoline {
+        frame,
+        virt: crate::memory::phys_to_virt(frame.start_address()).expect("memory initialized"),
+    };
+    let identity =
+        Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let mapped = unsafe { crate::memory::map_page(identity, frame, PageTableFlags::PRESENT) };
+    if let Err(error) = mapped {
+        unsafe { crate::memory::deallocate_frame(frame) };
+        return Err(error.into());
+    }
+
+    unsafe {
+        let start = &raw const ap_trampoline_start;
+        let length = offset(&raw const ap_trampoline_end);
+        core::ptr::copy_nonoverlapping(start, trampoline.virt.as_mut_ptr::<u8>(), length);
+    }
+    // The base of the GDT follows the 16-bit limit.
+    let gdt_base = (&raw const ap_gdtr).wrapping_add(2);
+    trampoline.write(gdt_base, trampoline.physical(&raw const ap_gdt));
+    trampoline.write(
+        &raw const ap_far_jump,
+        trampoline.physical(&raw const ap_long_mode),
+    );
+    trampoline.write(&raw const ap_cr3, cr3);
+    trampoline.write(&raw const ap_entry, ap_main as usize as u64);
+
+    let page = (frame.start_address().as_u64() / 4096) as u8;
+    for apic_id in processors.into_iter().filter(|&id| id != own_id) {
+        let cpu = ONLINE.load(Ordering::Acquire);
+        if cpu == MAX_CPUS {
+            crate::serial_println!("smp: only {} CPUs supported", MAX_CPUS);
+            break;
+        }
+        let stack = match vmm::alloc_stack(crate::thread::STACK_SIZE, "AP stack") {
+            Ok(stack) => stack,
+            Err(error) => {
+                crate::serial_println!("smp: no stack for APIC {}: {:?}", apic_id, error);
+                break;
+            }
+        };
+        trampoline.write(&raw const ap_stack, stack.as_u64());
+        trampoline.write(&raw const ap_cpu, cpu as u64);
+        APIC_IDS[cpu].store(apic_id, Ordering::Relaxed);
+        fence(Ordering::SeqCst);
+        if !start(apic_id, page, cpu) {
+            // Parked again, so it cannot come up late with the index of the next processor.
+            crate::apic::send_init(apic_id);
+            crate::serial_println!("smp: APIC {} did not start", apic_id);
+            if let Err(error) = unsafe { vmm::free_region(stack - 1u64) } {
+                crate::serial_println!("smp: AP stack not freed: {:?}", error);
+            }
+        }
+    }
+
+    unsafe {
+        if crate::memory::unmap_page(identity).is_ok() {
+            crate::memory::deallocate_frame(frame);
+        }
+    }
+    Ok(ONLINE.load(Ordering::Acquire))
+}
+
+/// Sends the INIT and startup IPIs to the processor `apic_id` and waits until it is running as
+/// CPU `cpu`.
+fn start(apic_id: u8, page: u8, cpu: usize) -> bool {
+    crate::apic::send_init(apic_id);
+    crate::thread::sleep(Duration::from_millis(10));
+    for _ in 0..2 {
+        crate::apic::send_startup(apic_id, page);
+        crate::thread::sleep(Duration::from_millis(1));
+        if ONLINE.load(Ordering::Acquire) > cpu {
+            return true;
+        }
+    }
+    let deadline = crate::time::uptime() + Duration::from_millis(STARTUP_TIMEOUT_MS);
+    while crate::time::uptime() < deadline {
+        if ONLINE.load(Ordering::Acquire) > cpu {
+            return true;
+        }
+        crate::thread::sleep(Duration::from_millis(1));
+    }
+    false
+}
+
+/// First Rust code of an application processor, on its own stack with interrupts disabled.
+extern "C" fn ap_main(cpu: usize) -> ! {
+    unsafe {
+        Cr0::write_raw(BOOT_CR0.load(Ordering::Relaxed));
+        Cr4::write_raw(BOOT_CR4.load(Ordering::Relaxed));
+    }
+    crate::percpu::init_cpu(cpu).expect("CPU index checked by the boot CPU");
+    if let Err(error) = crate::gdt::init_ap() {
+        panic!("CPU {}: no exception stacks: {:?}", cpu, error);
+    }
+    crate::usermode::syscall::init_cpu();
+    crate::usermode::uaccess::init_cpu();
+    crate::interruptsa::init_ap();
+    crate::apic::init_ap();
+    crate::thread::add_cpu(cpu);
+    ONLINE.fetch_add(1, Ordering::Release);
+    crate::thread::run_idle()
+}
+
+/// Number of CPUs running.
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// The CPU that called [`stop_others`], or `usize::MAX` while none has.
+static STOPPED_BY: AtomicUsize = AtomicUsize::new(usize::MAX);
+/// Number of CPUs halted by [`stop_others`].
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+/// Number of spins [`stop_others`] waits for the other CPUs to halt.
+const STOP_SPINS: usize = 10_000_000;
+
+/// Halts every other CPU for good, with an NMI so that it arrives even with interrupts disabled,
+/// and waits a while for them to halt. For the panic screen and reboot, which must not have other
+/// CPUs running on. If another CPU got here first, halts the calling one instead.
+pub fn stop_others() {
+    let own = crate::percpu::cpu_id();
+    if let Err(first) =
+        STOPPED_BY.compare_exchange(usize::MAX, own, Ordering::SeqCst, Ordering::SeqCst)
+    {
+        if first != own {
+            STOPPED.fetch_add(1, Ordering::Release);
+            halt_forever();
+        }
+        return;
+    }
+    if crate::apic::local_id().is_none() {
+        return;
+    }
+    let others = cpu_count().saturating_sub(1);
+    for cpu in (0..cpu_count()).filter(|&cpu| cpu != own) {
+        crate::apic::send_nmi(APIC_IDS[cpu].load(Ordering::Relaxed));
+    }
+    for _ in 0..STOP_SPINS {
+        if STOPPED.load(Ordering::Acquire) >= others {
+            break;
+        }
+        spin_loop();
+    }
+}
+
+/// Halts the calling CPU if [`stop_others`] was called on another one. Called on every NMI.
+pub(crate) fn on_nmi() {
+    let by = STOPPED_BY.load(Ordering::SeqCst);
+    if by != usize::MAX && by != crate::percpu::cpu_id() {
+        STOPPED.fetch_add(1, Ordering::Release);
+        halt_forever();
+    }
+}
+
+fn halt_forever() -> ! {
+    x86_64::instructions::interrupts::disable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Vectors of the IPIs registered by [`init`], or 0 before.
+static RESCHEDULE_VECTOR: AtomicU8 = AtomicU8::new(0);
+static TLB_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+fn register_ipis() -> Result<(), SmpError> {
+    if RESCHEDULE_VECTOR.load(Ordering::Relaxed) == 0 {
+        let vector = crate::interruptsa::register_ipi("reschedule IPI", reschedule_interrupt)?;
+        RESCHEDULE_VECTOR.store(vector, Ordering::Relaxed);
+    }
+    if TLB_VECTOR.load(Ordering::Relaxed) == 0 {
+        let vector = crate::interruptsa::register_ipi("TLB shootdown", tlb_interrupt)?;
+        TLB_VECTOR.store(vector, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Sends the interrupt `vector` to CPU `cpu`, which may be the calling one.
+pub fn send_ipi(cpu: usize, vector: u8) -> Result<(), SmpError> {
+    if cpu >= cpu_count() {
+        return Err(SmpError::NoSuchCpu(cpu));
+    }
+    if crate::apic::local_id().is_none() {
+        return Err(SmpError::ApicDisabled);
+    }
+    crate::apic::send_ipi(APIC_IDS[cpu].load(Ordering::Relaxed), vector);
+    Ok(())
+}
+
+/// Makes CPU `cpu` run the scheduler at its next interrupt exit. Does nothing for a CPU that is
+/// not running.
+pub fn send_reschedule(cpu: usize) {
+    let _ = send_ipi(cpu, RESCHEDULE_VECTOR.load(Ordering::Relaxed));
+}
+
+// A reschedule IPI only has to reach the interrupt exit, where the scheduler runs.
+fn reschedule_interrupt() {}
+
+/// Above this many pages a shootdown flushes the whole TLB rather than page by page.
+const FLUSH_ALL_PAGES: u64 = 32;
+
+/// Serializes shootdowns, whose range is passed in the statics below.
+static SHOOTDOWN: spin::Mutex<()> = spin::Mutex::new(());
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of CPUs that have yet to flush for the current shootdown.
+static SHOOTDOWN_LEFT: AtomicUsize = AtomicUsize::new(0);
+
+per_cpu! {
+    /// Set when the CPU is asked to flush, and cleared once it has.
+    static FLUSH_PENDING: AtomicBool = AtomicBool::new(false);
+}
+
+fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > FLUSH_ALL_PAGES {
+        x86_64::instructions::tlb::flush_all();
+        return;
+    }
+    for page in 0..pages {
+        x86_64::instructions::tlb::flush(start + page * Size4KiB::SIZE);
+    }
+}
+
+/// Carries out the shootdown asked of the calling CPU, if any.
+fn serve_shootdown() {
+    if FLUSH_PENDING.get().swap(false, Ordering::Acquire) {
+        flush_local(
+            VirtAddr::new(SHOOTDOWN_START.load(Ordering::Relaxed)),
+            SHOOTDOWN_PAGES.load(Ordering::Relaxed),
+        );
+        SHOOTDOWN_LEFT.fetch_sub(1, Ordering::Release);
+    }
+}
+
+fn tlb_interrupt() {
+    serve_shootdown();
+}
+
+/// Flushes the `pages` pages from `start` on from the TLB of every CPU, and returns once all
+/// have. Call after changing their page table entries, with no page table lock held, since the
+/// other CPUs must be able to take the IPI.
+pub fn flush_tlb(start: VirtAddr, pages: u64) {
+    crate::preempt::preempt_disable();
+    flush_local(start, pages);
+    let cpus = cpu_count();
+    let vector = TLB_VECTOR.load(Ordering::Relaxed);
+    if cpus > 1 && vector != 0 {
+        // A CPU waiting here with interrupts disabled still serves the shootdown under way, so
+        // two CPUs shooting down at once cannot wait for each other.
+        let guard = loop {
+            if let Some(guard) = SHOOTDOWN.try_lock() {
+                break guard;
+            }
+            serve_shootdown();
+            spin_loop();
+        };
+        let own = crate::percpu::cpu_id();
+        SHOOTDOWN_START.store(start.as_u64(), Ordering::Relaxed);
+        SHOOTDOWN_PAGES.store(pages, Ordering::Relaxed);
+        SHOOTDOWN_LEFT.store(cpus - 1, Ordering::Relaxed);
+        for cpu in (0..cpus).filter(|&cpu| cpu != own) {
+            FLUSH_PENDING.get_cpu(cpu).store(true, Ordering::Release);
+            let _ = send_ipi(cpu, vector);
+        }
+        while SHOOTDOWN_LEFT.load(Ordering::Acquire) != 0 {
+            spin_loop();
+        }
+        drop(guard);
+    }
+    crate::preempt::preempt_enable();
+}
@@ -0,0 +1,23 @@
+//! Synchronization primitives that block the waiting thread instead of spinning.
+//!
+//! A thread that finds a [`Mutex`] locked or a [`Semaphore`] exhausted parks on a [`WaitQueue`]
+//! and leaves the CPU to other threads until it is woken. Where no thread can block, before the
+//! scheduler is running or with preemption disabled, they spin instead. Interrupt handlers must
+//! still only use the `try_` methods, and `spin::Mutex` remains the lock for data shared with
+//! them. To hand work from an interrupt handler to a thread, send it over a [`channel()`]. Data
+//! that interrupt handlers read often and that rarely changes fits an [`Rcu`], which readers
+//! reach without any lock.
+
+pub mod channel;
+mod condvar;
+mod mutex;
+pub mod rcu;
+mod semaphore;
+mod wait_queue;
+
+pub use channel::{channel, Receiver, Sender};
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use rcu::{Rcu, RcuReadGuard};
+pub use semaphore::Semaphore;
+pub use wait_queue::WaitQueue;
@@ -0,0 +1,193 @@
+//! A bounded multi-producer, single-consumer channel.
+//!
+//! Sending pushes onto a lock-free queue and wakes the receiver without allocating, so interrupt
+//! handlers can hand work to a thread or task and return. The receiver either blocks its thread
+//! in [`Receiver::recv`] or awaits the channel as a [`Stream`].
+
+use alloc::sync::Arc;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::Stream;
+
+use super::WaitQueue;
+use crate::task::WakerSlot;
+
+/// Why [`Sender::try_send`] failed. Carries the value back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel holds as many values as its capacity.
+    Full(T),
+    /// The receiver has been dropped.
+    Disconnected(T),
+}
+
+impl<T> TrySendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(value) | TrySendError::Disconnected(value) => value,
+        }
+    }
+}
+
+/// Why [`Receiver::try_recv`] returned no value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No value is waiting, but more may be sent.
+    Empty,
+    /// No value is waiting, and every sender has been dropped.
+    Disconnected,
+}
+
+struct Shared<T> {
+    queue: ArrayQueue<T>,
+    /// The receiving thread, while blocked in [`Receiver::recv`].
+    thread: WaitQueue,
+    /// The receiving task, while awaiting the stream.
+    task: WakerSlot,
+    senders: AtomicUsize,
+    receiver_dropped: AtomicBool,
+}
+
+impl<T> Shared<T> {
+    fn wake(&self) {
+        self.thread.wake_one();
+        self.task.wake();
+    }
+}
+
+/// Creates a channel holding up to `capacity` values.
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: ArrayQueue::new(capacity),
+        thread: WaitQueue::new(),
+        task: WakerSlot::new(),
+        senders: AtomicUsize::new(1),
+        receiver_dropped: AtomicBool::new(false),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// The sending half of a channel. Clone it for each producer.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Queues `value` for the receiver and wakes it. Never blocks or allocates, so it is safe to
+    /// call from interrupt handlers.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.shared.receiver_dropped.load(Ordering::Acquire) {
+            return Err(TrySendError::Disconnected(value));
+        }
+        self.shared.queue.push(value).map_err(TrySendError::Full)?;
+        self.shared.wake();
+        Ok(())
+    }
+
+    /// Returns whether the receiver has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.receiver_dropped.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // The receiver may be waiting for a value that will now never come.
+            self.shared.wake();
+        }
+    }
+}
+
+/// The receiving half of a channel.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Takes the oldest value, if there is one.
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        if let Some(value) = self.shared.queue.pop() {
+            return Ok(value);
+        }
+        if self.shared.senders.load(Ordering::Acquire) == 0 {
+            // A last value may have been sent just before the sender was dropped.
+            return self.shared.queue.pop().ok_or(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    /// Takes the oldest value, blocking the thread until one is sent. Returns `None` once every
+    /// sender has been dropped and the channel is empty. Must not be called from interrupt
+    /// handlers.
+    pub fn recv(&self) -> Option<T> {
+        let mut received = None;
+        self.shared.thread.wait_until(|| match self.try_recv() {
+            Ok(value) => {
+                received = Some(value);
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => true,
+        });
+        received
+    }
+
+    /// Number of values waiting.
+    pub fn len(&self) -> usize {
+        self.shared.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.queue.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shared.queue.capacity()
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        self.shared.task.register(context.waker());
+        // A value sent before the waker was registered would not wake us.
+        match self.try_recv() {
+            Ok(value) => Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_dropped.store(true, Ordering::Release);
+    }
+}
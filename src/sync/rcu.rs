@@ -0,0 +1,139 @@
+//! Read-copy-update.
+//!
+//! An [`Rcu`] holds its value behind a pointer that readers load without taking a lock, so they
+//! never wait, not even in interrupt handlers. A writer builds a new value and swaps the pointer,
+//! then waits for a grace period before dropping the old one: until every CPU has passed through
+//! a quiescent state, where it cannot be inside a read section.
+//!
+//! Readers keep preemption disabled, so a CPU is quiescent whenever it switches threads and
+//! whenever an interrupt arrives while preemption is enabled. Each CPU counts these moments, and
+//! a grace period has passed once the count of every other CPU has moved.
+
+use alloc::boxed::Box;
+use core::hint::spin_loop;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use super::Mutex;
+use crate::per_cpu;
+use crate::preempt::PreemptGuard;
+
+per_cpu! {
+    /// Number of quiescent states the CPU has passed through.
+    static QUIESCENT: AtomicU64 = AtomicU64::new(0);
+}
+
+/// Records a quiescent state of the calling CPU. Called by the scheduler with interrupts
+/// disabled, after a switch and at interrupt exits to preemptible code.
+pub(crate) fn quiescent() {
+    QUIESCENT.get().fetch_add(1, Ordering::Release);
+}
+
+/// Spins between two reminders to a CPU that is slow to pass through a quiescent state.
+const NUDGE_SPINS: u32 = 10_000;
+
+/// Waits until every read section that was running on any CPU has ended. Must not be called
+/// from a read section or an interrupt handler.
+pub fn synchronize() {
+    debug_assert!(
+        !crate::preempt::in_interrupt(),
+        "RCU grace period awaited in an interrupt handler"
+    );
+    let own = crate::percpu::cpu_id();
+    for cpu in (0..crate::smp::cpu_count()).filter(|&cpu| cpu != own) {
+        let counter = QUIESCENT.get_cpu(cpu);
+        let start = counter.load(Ordering::Acquire);
+        let mut spins = 0;
+        // A CPU that was idle or in a long stretch of interrupt-free code takes its next
+        // interrupt at the reschedule IPI.
+        while counter.load(Ordering::Acquire) == start {
+            if spins % NUDGE_SPINS == 0 {
+                crate::smp::send_reschedule(cpu);
+            }
+            spins = spins.wrapping_add(1);
+            crate::thread::yield_now();
+            spin_loop();
+        }
+    }
+}
+
+/// Read-mostly data that readers reach without locking.
+pub struct Rcu<T> {
+    value: AtomicPtr<T>,
+    /// Serializes the writers.
+    writer: Mutex<()>,
+}
+
+unsafe impl<T: Send + Sync> Send for Rcu<T> {}
+unsafe impl<T: Send + Sync> Sync for Rcu<T> {}
+
+impl<T> Rcu<T> {
+    pub fn new(value: T) -> Self {
+        Rcu {
+            value: AtomicPtr::new(Box::into_raw(Box::new(value))),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Starts a read section, which lasts as long as the guard. Preemption stays disabled
+    /// meanwhile, so keep it short.
+    pub fn read(&self) -> RcuReadGuard<'_, T> {
+        let preempt = PreemptGuard::new();
+        let value = unsafe { &*self.value.load(Ordering::Acquire) };
+        RcuReadGuard {
+            value,
+            _preempt: preempt,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Publishes `value` and returns the previous value once no reader can see it any more.
+    pub fn replace(&self, value: T) -> T {
+        let _writer = self.writer.lock();
+        *self.swap(value)
+    }
+
+    /// Publishes the value `update` derives from the current one, then drops the current one
+    /// once no reader can see it any more. Concurrent updates are applied one after the other.
+    pub fn update(&self, update: impl FnOnce(&T) -> T) {
+        let _writer = self.writer.lock();
+        let new = update(unsafe { &*self.value.load(Ordering::Acquire) });
+        drop(self.swap(new));
+    }
+
+    /// Swaps in `value` and waits for the grace period of the old one. Called by the writer.
+    fn swap(&self, value: T) -> Box<T> {
+        let old = self
+            .value
+            .swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+        synchronize();
+        unsafe { Box::from_raw(old) }
+    }
+
+    /// Returns the value, which no reader can be looking at.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.value.load(Ordering::Relaxed) }
+    }
+}
+
+impl<T> Drop for Rcu<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.value.load(Ordering::Relaxed)) });
+    }
+}
+
+/// A read section of an [`Rcu`]. It stays on the CPU it started on, so it is not `Send`.
+pub struct RcuReadGuard<'a, T> {
+    value: &'a T,
+    _preempt: PreemptGuard,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for RcuReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
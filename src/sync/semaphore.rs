@@ -0,0 +1,48 @@
+//! A counting semaphore that blocks waiting threads.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::WaitQueue;
+
+/// A count of available resources. Taking one when none is left blocks until one is released.
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes one resource if one is available. Safe to call from interrupt handlers.
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Takes one resource, blocking until one is available.
+    pub fn acquire(&self) {
+        if !self.try_acquire() {
+            self.waiters.wait_until(|| self.try_acquire());
+        }
+    }
+
+    /// Returns one resource, waking a thread waiting for it. Safe to call from interrupt
+    /// handlers, which makes it the way for a driver to hand events to a thread.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Number of resources available.
+    pub fn available(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
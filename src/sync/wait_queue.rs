@@ -0,0 +1,86 @@
+//! Queues of blocked threads.
+
+use alloc::collections::VecDeque;
+use x86_64::instructions::interrupts;
+
+use crate::thread::{self, ThreadId};
+
+/// Threads waiting for a condition, woken in the order they started waiting.
+pub struct WaitQueue {
+    /// Locked with interrupts disabled, so it can be woken from interrupt handlers.
+    waiters: spin::Mutex<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: spin::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Returns once `condition` holds, blocking the current thread until it is woken each time
+    /// it does not. The thread joins the queue before checking `condition`, so a wakeup between
+    /// the check and blocking, even from another CPU, is not missed. `condition` runs with
+    /// interrupts disabled and may take what it waits for, such as a lock.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        let enabled = interrupts::are_enabled();
+        loop {
+            interrupts::disable();
+            let waiter = thread::current().filter(|_| crate::preempt::preemptible());
+            if let Some(current) = waiter {
+                self.waiters.lock().push_back(current);
+            }
+            if condition() {
+                if let Some(current) = waiter {
+                    self.remove(current);
+                }
+                break;
+            }
+            match waiter {
+                Some(current) => {
+                    thread::block();
+                    // Something else may have unblocked the thread first.
+                    self.remove(current);
+                }
+                None => {
+                    if enabled {
+                        interrupts::enable();
+                    }
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        if enabled {
+            interrupts::enable();
+        }
+    }
+
+    fn remove(&self, thread: ThreadId) {
+        self.waiters.lock().retain(|&waiter| waiter != thread);
+    }
+
+    /// Wakes the thread that has waited longest. Returns whether there was one. Safe to call
+    /// from interrupt handlers.
+    pub fn wake_one(&self) -> bool {
+        let waiter = interrupts::without_interrupts(|| self.waiters.lock().pop_front());
+        if let Some(waiter) = waiter {
+            thread::unblock(waiter);
+        }
+        waiter.is_some()
+    }
+
+    /// Wakes all waiting threads and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = interrupts::without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        for &waiter in &waiters {
+            thread::unblock(waiter);
+        }
+        waiters.len()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,86 @@
+//! Cooperative async tasks.
+//!
+//! A [`Task`] wraps a pinned future. Spawned tasks are polled by the [`executor`] whenever their
+//! waker has been woken, from the main loop and from [`block_on`], so they make progress while
+//! the kernel waits for something else. Drivers expose async APIs by parking the waker of the
+//! task waiting on them in a [`WakerSlot`] and waking it from their interrupt handler.
+
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub mod executor;
+
+pub use executor::{block_on, run, run_ready, spawn};
+
+/// Identifies a spawned task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// A future that runs to completion on the executor.
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/// Holds the waker of the task waiting for an event, for the code that signals the event.
+///
+/// Only one task can wait at a time; registering replaces the previous waker.
+pub struct WakerSlot(Mutex<Option<Waker>>);
+
+impl WakerSlot {
+    pub const fn new() -> Self {
+        WakerSlot(Mutex::new(None))
+    }
+
+    /// Stores the waker to wake on the next event.
+    pub fn register(&self, waker: &Waker) {
+        interrupts::without_interrupts(|| {
+            let mut slot = self.0.lock();
+            if !slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+                *slot = Some(waker.clone());
+            }
+        });
+    }
+
+    /// Wakes the registered task, if any. Safe to call from interrupt handlers.
+    pub fn wake(&self) {
+        if let Some(waker) = interrupts::without_interrupts(|| self.0.lock().take()) {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for WakerSlot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,158 @@
+//! The executor polling spawned tasks.
+//!
+//! Waking a task sets a flag in its waker and unblocks the thread waiting for it, so interrupt
+//! handlers can wake tasks without allocating. [`run_ready`] polls the tasks whose flag is set;
+//! [`block_on`] and [`run`] call it and block their thread whenever nothing is ready, leaving
+//! the CPU to other threads.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{Task, TaskId};
+use crate::thread::{self, ThreadId};
+
+struct TaskWaker {
+    woken: AtomicBool,
+    /// The thread blocked in [`block_on`] on the waker's future, if any.
+    thread: Option<ThreadId>,
+}
+
+impl TaskWaker {
+    /// Creates a waker that is already woken, so the task is polled once right away.
+    fn new(thread: Option<ThreadId>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
+            woken: AtomicBool::new(true),
+            thread,
+        })
+    }
+
+    /// Clears the flag and returns whether it was set.
+    fn take(&self) -> bool {
+        self.woken.swap(false, Ordering::AcqRel)
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        WOKEN.store(true, Ordering::Release);
+        if let Some(waiting) = self.thread {
+            thread::unblock(waiting);
+        }
+        if let Some(runner) = interrupts::without_interrupts(|| *RUNNER.lock()) {
+            thread::unblock(runner);
+        }
+    }
+}
+
+/// The thread last blocked in the outermost [`block_on`], which runs the spawned tasks once
+/// unblocked.
+static RUNNER: Mutex<Option<ThreadId>> = Mutex::new(None);
+
+/// Set whenever a task is spawned or woken, so the executor knows to look for ready tasks.
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+/// Set while [`run_ready`] polls tasks, so a task that blocks does not poll the others
+/// recursively.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Spawned tasks that have not completed. Never locked by interrupt handlers.
+static TASKS: Mutex<BTreeMap<TaskId, (Task, Arc<TaskWaker>)>> = Mutex::new(BTreeMap::new());
+
+/// Spawns a task that runs whenever the kernel waits. Must not be called from interrupt
+/// handlers.
+pub fn spawn(future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+    let task = Task::new(future);
+    let id = task.id();
+    TASKS.lock().insert(id, (task, TaskWaker::new(None)));
+    WOKEN.store(true, Ordering::Release);
+    id
+}
+
+/// Polls the tasks that were woken since they were last polled, until none is. Does nothing
+/// when called from a task.
+pub fn run_ready() {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return;
+    }
+    while WOKEN.swap(false, Ordering::AcqRel) {
+        let ready: Vec<TaskId> = TASKS
+            .lock()
+            .iter()
+            .filter(|(_, (_, waker))| waker.take())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in ready {
+            // The task is taken out of the map while it runs, so it can spawn others.
+            let Some((mut task, waker)) = TASKS.lock().remove(&id) else {
+                continue;
+            };
+            let context_waker = Waker::from(waker.clone());
+            if task
+                .poll(&mut Context::from_waker(&context_waker))
+                .is_pending()
+            {
+                TASKS.lock().insert(id, (task, waker));
+            }
+        }
+    }
+    RUNNING.store(false, Ordering::Release);
+}
+
+/// Runs `future` to completion, polling the spawned tasks while it is pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let state = TaskWaker::new(thread::current());
+    let waker = Waker::from(state.clone());
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if state.take() {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+        run_ready();
+        // Check and wait with interrupts disabled, so a wakeup arriving in between cannot be
+        // missed. Inside a task, the other tasks cannot run, so only our own wakeup counts.
+        interrupts::disable();
+        let outermost = !RUNNING.load(Ordering::Acquire);
+        if state.woken.load(Ordering::Acquire) || (outermost && WOKEN.load(Ordering::Acquire)) {
+            interrupts::enable();
+        } else {
+            wait(outermost);
+        }
+    }
+}
+
+/// Blocks the calling thread until a waker unblocks it, or halts the CPU until the next
+/// interrupt where the thread cannot block. Called with interrupts disabled, and returns with
+/// them enabled.
+fn wait(outermost: bool) {
+    match thread::current() {
+        Some(current) if crate::preempt::preemptible() => {
+            if outermost {
+                *RUNNER.lock() = Some(current);
+            }
+            thread::block();
+            interrupts::enable();
+        }
+        _ => interrupts::enable_and_hlt(),
+    }
+}
+
+/// Runs the spawned tasks forever, blocking while none is ready.
+pub fn run() -> ! {
+    block_on(core::future::pending())
+}
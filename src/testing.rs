@@ -0,0 +1,83 @@
+//! The kernel's test harness.
+//!
+//! `cargo test` builds the library as a kernel of its own, which the runner in `runner/` boots in
+//! QEMU. Its entry point brings the kernel up with [`init`](crate::init) and then runs every
+//! `#[test_case]` function, naming each on COM1. At the end it writes to QEMU's `isa-debug-exit`
+//! device, so QEMU exits with status 33 once every test passed and with 35 after the first panic.
+
+use core::fmt::{Arguments, Write};
+use core::panic::PanicInfo;
+use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+/// I/O port of the `isa-debug-exit` device.
+const EXIT_PORT: u16 = 0xF4;
+/// I/O base port of COM1, which the runner connects to its standard output.
+const COM1: u16 = 0x3F8;
+
+/// Values for the `isa-debug-exit` device. QEMU exits with `(value << 1) | 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+/// Ends the QEMU session. Without the device, halts instead.
+pub fn exit_qemu(code: ExitCode) -> ! {
+    unsafe { Port::<u32>::new(EXIT_PORT).write(code as u32) };
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Writes test results to COM1. The tests run one at a time, so the harness needs no lock.
+fn report(args: Arguments) {
+    let mut port = unsafe { SerialPort::new(COM1) };
+    let _ = port.write_fmt(args);
+}
+
+/// A test the runner calls, and names on the serial port.
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        report(format_args!("{}... ", core::any::type_name::<T>()));
+        self();
+        report(format_args!("ok\n"));
+    }
+}
+
+/// Runs `tests` one after another and exits QEMU. A failing test panics, which ends the run in
+/// [`panic`].
+pub fn run(tests: &[&dyn Testable]) {
+    unsafe { SerialPort::new(COM1) }.init();
+    report(format_args!("testing: running {} tests\n", tests.len()));
+    for test in tests {
+        test.run();
+    }
+    exit_qemu(ExitCode::Success);
+}
+
+/// Stores `value` little-endian at `offset` in `bytes`, for building on-disk structures in tests.
+pub fn put_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Stores `value` little-endian at `offset` in `bytes`.
+pub fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Stores `value` little-endian at `offset` in `bytes`.
+pub fn put_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Reports the panic of a failing test and exits QEMU.
+pub fn panic(info: &PanicInfo) -> ! {
+    report(format_args!("FAILED\n{}\n", info));
+    exit_qemu(ExitCode::Failure);
+}
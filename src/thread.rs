@@ -0,0 +1,1047 @@
+//! Preemptive kernel threads.
+//!
+//! Every spawned thread runs on its own stack from the [`vmm`], with a guard page beneath it. A
+//! switch pushes the flags and the callee-saved registers onto the old thread's stack as a
+//! [`Context`], saves the stack pointer, and pops the new thread's context from its stack;
+//! everything else the thread needs is already on its stack. The kernel is built without SSE, so
+//! there is no floating point state to save. Threads of a user process run with its page tables,
+//! which the switch loads along with the stack.
+//!
+//! Every thread has a [`Priority`], and each CPU one FIFO run queue per priority. The highest
+//! priority with a ready thread runs, its threads taking turns round-robin. The timer interrupt
+//! counts down the running thread's time slice; once it has run out, the thread goes to the back
+//! of its queue at the exit of the outermost interrupt handler, unless preemption is disabled. A
+//! thread can also give up its slice with [`yield_now`], or [`block`] until [`unblock`]ed. When
+//! no thread is ready, the CPU's idle thread halts it until an interrupt makes one ready.
+//!
+//! New threads go to the CPU with the least work, and a CPU that runs out of work takes a thread
+//! from the busiest one. A thread made ready for another CPU that should run at once is brought
+//! there with a reschedule IPI.
+//!
+//! The timer interrupt also charges each tick to the thread it interrupted. [`stats`] reports
+//! the ticks and switches of every thread, and its share of a CPU over the last
+//! [`STATS_PERIOD_MS`].
+//!
+//! So that busy threads cannot starve lower priorities, every [`BOOST_MS`] all waiting threads
+//! are boosted to the highest priority until they have next run. [`init`] adopts the
+//! boot context as the `main` thread, which is then scheduled like any other.
+//!
+//! A thread that has exited cannot free its own stack, which it is still running on. The
+//! `reaper` thread frees it once the switch away from it is complete, and then wakes whoever
+//! waits in [`JoinHandle::join`] for the thread's return value.
+//!
+//! [`futex_wait`] and [`futex_wake`] block and wake threads on the value of an atomic word, the
+//! primitive locks that keep their state in one word are built on.
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+use crate::memory::vmm::{self, VmmError};
+use crate::per_cpu;
+use crate::percpu;
+use crate::process::ProcessId;
+use crate::sync::WaitQueue;
+use crate::time::timer::{self, Wakeup};
+
+mod futex;
+
+pub use futex::{futex_wait, futex_wake};
+
+/// Stack size of spawned threads.
+pub const STACK_SIZE: usize = 64 * 1024;
+
+/// Length of a time slice.
+const SLICE_MS: u32 = 10;
+
+/// Interval between priority boosts.
+pub const BOOST_MS: u32 = 200;
+
+/// Interval over which [`stats`] measures CPU usage.
+pub const STATS_PERIOD_MS: u32 = 1000;
+
+/// Identifies a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    /// Waiting in the run queue.
+    Ready,
+    /// Waiting for [`unblock`].
+    Blocked,
+    /// Returned or called [`exit`]; its stack is freed by the reaper thread.
+    Exited,
+}
+
+/// Scheduling priorities, lowest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Background work, such as memory tests.
+    Low,
+    Normal,
+    /// Threads that must respond quickly, such as input handling.
+    High,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+}
+
+/// Errors returned by [`spawn`] and [`set_priority`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadError {
+    /// [`init`] has not run.
+    NotInitialized,
+    /// There is no thread with the id, or it has exited.
+    NotFound,
+    /// No stack could be allocated.
+    Stack(VmmError),
+}
+
+impl From<VmmError> for ThreadError {
+    fn from(error: VmmError) -> Self {
+        ThreadError::Stack(error)
+    }
+}
+
+/// The registers a switched-out thread keeps on its stack, in the order `thread_switch` pops
+/// them, followed by the address it returns to.
+#[repr(C)]
+struct Context {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbx: u64,
+    rbp: u64,
+    /// Saved so that a thread preempted while copying user memory keeps the alignment check flag
+    /// SMAP lets it through with, and no other thread gets it.
+    rflags: u64,
+    rip: u64,
+}
+
+struct Thread {
+    name: &'static str,
+    /// Stack pointer saved by the last switch away from the thread, pointing at a [`Context`].
+    rsp: u64,
+    /// Top of the stack, or `None` for a boot stack, which is never freed.
+    stack: Option<VirtAddr>,
+    state: ThreadState,
+    priority: Priority,
+    /// The priority the thread is queued at, raised above `priority` by a boost until it runs.
+    level: Priority,
+    /// The CPU the thread runs on, or whose run queue it waits in or will be put in.
+    cpu: usize,
+    /// Set while the thread runs, and until the switch away from it has saved its context. Only
+    /// then may it be queued, run on another CPU, or have its stack freed.
+    on_cpu: bool,
+    /// Set by an [`unblock`] that found the thread not blocked, so that the next [`block`]
+    /// returns at once instead of missing the wakeup.
+    wakeup_pending: bool,
+    /// Timer ticks the thread was running at.
+    ticks: u64,
+    /// Value of `ticks` when the current statistics period started.
+    period_start: u64,
+    /// Ticks the thread ran during the last complete statistics period.
+    period_ticks: u64,
+    /// Number of times the thread was switched to.
+    switches: u64,
+    /// While the thread runs user code, the kernel stack that interrupts in user mode start on.
+    user_kernel_stack: Option<VirtAddr>,
+    /// The process the thread belongs to, and the top-level page table loaded while it runs.
+    /// Kernel threads run with the kernel's.
+    process: Option<(ProcessId, PhysFrame)>,
+}
+
+impl Thread {
+    fn new(name: &'static str, rsp: u64, stack: Option<VirtAddr>, priority: Priority) -> Box<Self> {
+        Box::new(Thread {
+            name,
+            rsp,
+            stack,
+            state: ThreadState::Ready,
+            priority,
+            level: priority,
+            cpu: 0,
+            on_cpu: false,
+            wakeup_pending: false,
+            ticks: 0,
+            period_start: 0,
+            period_ticks: 0,
+            switches: 0,
+            user_kernel_stack: None,
+            process: None,
+        })
+    }
+
+    fn info(&self, id: ThreadId) -> ThreadInfo {
+        let period = u64::from(stats_period_ticks());
+        ThreadInfo {
+            id,
+            name: self.name,
+            state: self.state,
+            priority: self.priority,
+            cpu: self.cpu,
+            ticks: self.ticks,
+            switches: self.switches,
+            cpu_percent: (self.period_ticks * 100 / period).min(100) as u8,
+        }
+    }
+}
+
+/// A snapshot of a thread, see [`list`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: ThreadState,
+    pub priority: Priority,
+    /// The CPU the thread runs on, or last ran on.
+    pub cpu: usize,
+    /// Timer ticks the thread has run for.
+    pub ticks: u64,
+    /// Number of times the thread was switched to.
+    pub switches: u64,
+    /// Share of one CPU the thread used during the last statistics period.
+    pub cpu_percent: u8,
+}
+
+/// The state of one CPU, see [`stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuStats {
+    /// Threads waiting in the run queues.
+    pub queued: usize,
+    /// Number of switches between threads.
+    pub switches: u64,
+    /// Share of the last statistics period the CPU spent idle.
+    pub idle_percent: u8,
+}
+
+/// A snapshot of the threads and CPUs, see [`stats`].
+#[derive(Debug, Clone, Default)]
+pub struct SchedulerStats {
+    pub threads: Vec<ThreadInfo>,
+    /// Indexed by CPU.
+    pub cpus: Vec<CpuStats>,
+}
+
+/// The scheduling state of one CPU.
+struct Core {
+    /// The run queues, indexed by priority. The idle thread is never in them.
+    ready: [VecDeque<ThreadId>; Priority::COUNT],
+    current: ThreadId,
+    idle: ThreadId,
+    /// The thread the last switch went away from, until [`finish_switch`] has released it.
+    previous: Option<ThreadId>,
+    switches: u64,
+}
+
+impl Core {
+    fn new(idle: ThreadId, current: ThreadId) -> Self {
+        Core {
+            ready: core::array::from_fn(|_| VecDeque::new()),
+            current,
+            idle,
+            previous: None,
+            switches: 0,
+        }
+    }
+
+    /// Number of threads running or waiting to run, not counting the idle thread.
+    fn load(&self) -> usize {
+        let queued: usize = self.ready.iter().map(VecDeque::len).sum();
+        queued + usize::from(self.current != self.idle)
+    }
+}
+
+struct Scheduler {
+    /// Boxed so the saved stack pointers stay in place while the map changes.
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    /// Indexed by CPU.
+    cores: Vec<Core>,
+}
+
+impl Scheduler {
+    /// Puts a ready thread at the back of the queue of its level on its CPU, and makes the CPU
+    /// reschedule if the thread should take over from the one running there.
+    fn enqueue(&mut self, id: ThreadId) {
+        let thread = &self.threads[&id];
+        let (cpu, level) = (thread.cpu, thread.level);
+        self.cores[cpu].ready[level as usize].push_back(id);
+        if self.preempts_current(cpu, id) {
+            request_resched(cpu);
+        }
+    }
+
+    /// Takes the first thread of the highest non-empty queue at or above `min` on `cpu`. When
+    /// `cpu` would otherwise go idle, it takes a thread from the busiest other CPU instead.
+    fn dequeue(&mut self, cpu: usize, min: Priority) -> Option<ThreadId> {
+        let own = self.cores[cpu].ready[min as usize..]
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front());
+        if own.is_some() || min != Priority::Low {
+            return own;
+        }
+        let busiest = (0..self.cores.len())
+            .filter(|&other| other != cpu)
+            .max_by_key(|&other| {
+                self.cores[other]
+                    .ready
+                    .iter()
+                    .map(VecDeque::len)
+                    .sum::<usize>()
+            })?;
+        let stolen = self.cores[busiest]
+            .ready
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front())?;
+        self.threads.get_mut(&stolen).expect("queued thread").cpu = cpu;
+        Some(stolen)
+    }
+
+    /// Returns whether `id` should take over `cpu` from the thread running there.
+    fn preempts_current(&self, cpu: usize, id: ThreadId) -> bool {
+        let core = &self.cores[cpu];
+        core.current == core.idle || self.threads[&id].level > self.threads[&core.current].level
+    }
+
+    /// The CPU with the fewest threads to run, for a new thread.
+    fn least_loaded(&self) -> usize {
+        (0..self.cores.len())
+            .min_by_key(|&cpu| self.cores[cpu].load())
+            .unwrap_or(0)
+    }
+
+    /// Moves the threads waiting at lower priorities to the back of the highest queue of their
+    /// CPU.
+    fn boost(&mut self) {
+        let top = Priority::High;
+        for core in &mut self.cores {
+            for level in 0..top as usize {
+                while let Some(id) = core.ready[level].pop_front() {
+                    if let Some(thread) = self.threads.get_mut(&id) {
+                        thread.level = top;
+                    }
+                    core.ready[top as usize].push_back(id);
+                }
+            }
+        }
+    }
+
+    /// Starts a new statistics period, keeping the ticks of the one that ended.
+    fn end_stats_period(&mut self) {
+        for thread in self.threads.values_mut() {
+            thread.period_ticks = thread.ticks - thread.period_start;
+            thread.period_start = thread.ticks;
+        }
+    }
+}
+
+/// Makes `cpu` reschedule at its next interrupt exit, sending it an IPI if it is another CPU.
+fn request_resched(cpu: usize) {
+    NEED_RESCHED.get_cpu(cpu).store(true, Ordering::Relaxed);
+    if cpu != percpu::cpu_id() {
+        crate::smp::send_reschedule(cpu);
+    }
+}
+
+/// The threads. `None` until [`init`] has run. Only locked with interrupts disabled, and never
+/// held across a switch.
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+per_cpu! {
+    /// Timer ticks left in the running thread's time slice.
+    static SLICE_LEFT: AtomicU32 = AtomicU32::new(0);
+
+    /// Set by the timer interrupt when the time slice has run out, and when a thread of a higher
+    /// priority than the running one becomes ready.
+    static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+    /// The running thread, or [`NO_THREAD`] before [`init`], so [`current`] needs no lock.
+    static CURRENT: AtomicU64 = AtomicU64::new(NO_THREAD);
+}
+
+const NO_THREAD: u64 = u64::MAX;
+
+/// Timer ticks left until the next priority boost.
+static BOOST_LEFT: AtomicU32 = AtomicU32::new(0);
+
+/// Set by the timer interrupt when the next switch should boost the waiting threads.
+static BOOST_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Timer ticks left in the current statistics period, counted on the boot CPU.
+static STATS_PERIOD_LEFT: AtomicU32 = AtomicU32::new(0);
+
+global_asm!(
+    // thread_switch(old_rsp: *mut u64, new_rsp: u64)
+    ".global thread_switch",
+    "thread_switch:",
+    "pushfq",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "popfq",
+    "ret",
+    // A new thread's context returns here, with its entry function in r12.
+    ".global thread_trampoline",
+    "thread_trampoline:",
+    "mov rdi, r12",
+    "call {start}",
+    "ud2",
+    start = sym thread_start,
+);
+
+extern "C" {
+    fn thread_switch(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_trampoline();
+}
+
+/// What a new thread runs, boxed once more so that a thin pointer to it fits in a register.
+type Entry = Box<dyn FnOnce() + Send>;
+
+/// First Rust code of a new thread, reached from the switch that first ran it, with interrupts
+/// disabled. `entry` points at the thread's boxed [`Entry`].
+extern "C" fn thread_start(entry: usize) -> ! {
+    let entry = unsafe { Box::from_raw(entry as *mut Entry) };
+    if finish_switch() {
+        REAPER.wake_one();
+    }
+    interrupts::enable();
+    entry();
+    exit()
+}
+
+fn slice_ticks() -> u32 {
+    (crate::time::tick_rate() * SLICE_MS / 1000).max(1)
+}
+
+fn boost_ticks() -> u32 {
+    (crate::time::tick_rate() * BOOST_MS / 1000).max(1)
+}
+
+fn stats_period_ticks() -> u32 {
+    (crate::time::tick_rate() * STATS_PERIOD_MS / 1000).max(1)
+}
+
+/// Adopts the running boot context as the `main` thread and creates the idle thread of the boot
+/// CPU. Must run once, before any other function of this module.
+pub fn init() -> Result<(), ThreadError> {
+    let (idle_id, idle_thread) = create("idle", Box::new(idle), Priority::Low)?;
+    let main = ThreadId::new();
+    // The main thread runs the shell and the async tasks, such as the keyboard echo.
+    let mut main_thread = Thread::new("main", 0, None, Priority::High);
+    main_thread.state = ThreadState::Running;
+    main_thread.on_cpu = true;
+    interrupts::without_interrupts(|| {
+        *SCHEDULER.lock() = Some(Scheduler {
+            threads: BTreeMap::from([(main, main_thread), (idle_id, idle_thread)]),
+            cores: Vec::from([Core::new(idle_id, main)]),
+        });
+    });
+    CURRENT.get().store(main.0, Ordering::Relaxed);
+    SLICE_LEFT.get().store(slice_ticks(), Ordering::Relaxed);
+    BOOST_LEFT.store(boost_ticks(), Ordering::Relaxed);
+    spawn("reaper", reaper)?;
+    Ok(())
+}
+
+/// Adopts the boot context of an application processor as the idle thread of CPU `cpu`, which
+/// must be the next CPU after those already added. Called with interrupts disabled, before
+/// [`run_idle`].
+pub(crate) fn add_cpu(cpu: usize) {
+    let id = ThreadId::new();
+    let mut thread = Thread::new("idle", 0, None, Priority::Low);
+    thread.state = ThreadState::Running;
+    thread.on_cpu = true;
+    thread.cpu = cpu;
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("scheduler initialized");
+        assert_eq!(scheduler.cores.len(), cpu, "CPUs added out of order");
+        scheduler.threads.insert(id, thread);
+        scheduler.cores.push(Core::new(id, id));
+    }
+    CURRENT.get().store(id.0, Ordering::Relaxed);
+    SLICE_LEFT.get().store(slice_ticks(), Ordering::Relaxed);
+}
+
+/// Runs the idle thread of an application processor, until the scheduler switches to another.
+pub(crate) fn run_idle() -> ! {
+    idle();
+    unreachable!("idle thread returned");
+}
+
+/// Runs when no other thread is ready.
+fn idle() {
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Allocates a stack for a thread running `entry` and sets it up for the first switch to it.
+fn create(
+    name: &'static str,
+    entry: Entry,
+    priority: Priority,
+) -> Result<(ThreadId, Box<Thread>), ThreadError> {
+    let top = vmm::alloc_stack(STACK_SIZE, name)?;
+    // Popping the context leaves the stack pointer at the top, 16-byte aligned as the call in
+    // the trampoline expects.
+    let rsp = top - core::mem::size_of::<Context>() as u64;
+    unsafe {
+        rsp.as_mut_ptr::<Context>().write(Context {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: Box::into_raw(Box::new(entry)) as u64,
+            rbx: 0,
+            rbp: 0,
+            // Only the reserved bit: the trampoline starts with interrupts disabled.
+            rflags: 2,
+            rip: thread_trampoline as usize as u64,
+        });
+    }
+    let thread = Thread::new(name, rsp.as_u64(), Some(top), priority);
+    Ok((ThreadId::new(), thread))
+}
+
+/// Waits for a spawned thread to finish, see [`spawn`]. Dropping the handle lets the thread run
+/// on unwatched.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    /// Filled in when the thread returns.
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Returns whether the thread has finished and been cleaned up.
+    pub fn is_finished(&self) -> bool {
+        !exists(self.id)
+    }
+
+    /// Blocks until the thread has finished and its stack is freed. Returns the value the thread
+    /// returned, or `None` if it ended with [`exit`] instead.
+    pub fn join(self) -> Option<T> {
+        debug_assert!(current() != Some(self.id), "thread joined itself");
+        REAPED.wait_until(|| !exists(self.id));
+        self.result.lock().take()
+    }
+}
+
+/// Starts a thread running `f` on a new stack, at the back of the run queue of the least busy
+/// CPU. The thread exits when `f` returns, and [`JoinHandle::join`] returns what it returned.
+pub fn spawn<F, T>(name: &'static str, f: F) -> Result<JoinHandle<T>, ThreadError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_with_priority(name, f, Priority::Normal)
+}
+
+/// Like [`spawn`], but the thread starts with `priority` instead of [`Priority::Normal`].
+pub fn spawn_with_priority<F, T>(
+    name: &'static str,
+    f: F,
+    priority: Priority,
+) -> Result<JoinHandle<T>, ThreadError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    if interrupts::without_interrupts(|| SCHEDULER.lock().is_none()) {
+        return Err(ThreadError::NotInitialized);
+    }
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let entry = Box::new(move || {
+        let value = f();
+        *slot.lock() = Some(value);
+    });
+    let (id, mut thread) = create(name, entry, priority)?;
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("scheduler initialized");
+        thread.cpu = scheduler.least_loaded();
+        scheduler.threads.insert(id, thread);
+        scheduler.enqueue(id);
+    });
+    Ok(JoinHandle { id, result })
+}
+
+/// Returns whether the thread `id` is still known to the scheduler, which it is until the reaper
+/// has removed it.
+fn exists(id: ThreadId) -> bool {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .is_some_and(|scheduler| scheduler.threads.contains_key(&id))
+    })
+}
+
+/// Changes the priority of a thread. A waiting thread moves to the back of the new priority's
+/// queue.
+pub fn set_priority(id: ThreadId, priority: Priority) -> Result<(), ThreadError> {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().ok_or(ThreadError::NotInitialized)?;
+        let thread = scheduler
+            .threads
+            .get_mut(&id)
+            .ok_or(ThreadError::NotFound)?;
+        if thread.state == ThreadState::Exited {
+            return Err(ThreadError::NotFound);
+        }
+        let (state, level, cpu, on_cpu) = (thread.state, thread.level, thread.cpu, thread.on_cpu);
+        thread.priority = priority;
+        thread.level = priority;
+        let core = &mut scheduler.cores[cpu];
+        if state == ThreadState::Ready && !on_cpu && id != core.idle {
+            core.ready[level as usize].retain(|&queued| queued != id);
+            scheduler.enqueue(id);
+        }
+        // A running thread that lowered its priority may have to make way.
+        if id == scheduler.cores[cpu].current {
+            request_resched(cpu);
+        }
+        Ok(())
+    })
+}
+
+/// Returns the priority of a thread, or `None` if there is no such thread.
+pub fn priority(id: ThreadId) -> Option<Priority> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        Some(scheduler.as_ref()?.threads.get(&id)?.priority)
+    })
+}
+
+/// Switches to the first thread of the highest non-empty run queue and starts a new time slice.
+/// A running thread only gives way to threads of at least its priority, and then goes to the
+/// back of its queue; if it blocked or exited and nothing is ready, the idle thread runs. Must
+/// be called with interrupts disabled.
+fn schedule() {
+    let cpu = percpu::cpu_id();
+    SLICE_LEFT.get().store(slice_ticks(), Ordering::Relaxed);
+    NEED_RESCHED.get().store(false, Ordering::Relaxed);
+    let mut guard = SCHEDULER.lock();
+    let Some(scheduler) = guard.as_mut() else {
+        return;
+    };
+    if BOOST_PENDING.swap(false, Ordering::Relaxed) {
+        scheduler.boost();
+    }
+    let (previous, idle) = {
+        let core = &scheduler.cores[cpu];
+        (core.current, core.idle)
+    };
+    let (state, level) = {
+        let old = &scheduler.threads[&previous];
+        (old.state, old.level)
+    };
+    let running = state == ThreadState::Running;
+    let min = if running && previous != idle {
+        level
+    } else {
+        Priority::Low
+    };
+    let next = match scheduler.dequeue(cpu, min) {
+        Some(next) => next,
+        None if running => return,
+        None => idle,
+    };
+    let old = scheduler
+        .threads
+        .get_mut(&previous)
+        .expect("current thread");
+    if running {
+        // A boost lasts until the thread has run.
+        old.state = ThreadState::Ready;
+        old.level = old.priority;
+    }
+    let old_rsp: *mut u64 = &mut old.rsp;
+    let new = scheduler.threads.get_mut(&next).expect("ready thread");
+    new.state = ThreadState::Running;
+    new.on_cpu = true;
+    new.cpu = cpu;
+    new.switches += 1;
+    if let Some(top) = new.user_kernel_stack {
+        crate::gdt::set_kernel_stack(top);
+    }
+    load_page_table(new.process);
+    let new_rsp = new.rsp;
+    let core = &mut scheduler.cores[cpu];
+    core.current = next;
+    core.previous = Some(previous);
+    core.switches += 1;
+    CURRENT.get().store(next.0, Ordering::Relaxed);
+    drop(guard);
+    // The old thread stays on this CPU until the switch has saved its context, and is only
+    // queued after that, so no other CPU can switch to it halfway.
+    unsafe { thread_switch(old_rsp, new_rsp) };
+    if finish_switch() {
+        REAPER.wake_one();
+    }
+}
+
+/// Releases the thread the last switch on this CPU went away from, queueing it if it is ready.
+/// Runs right after every switch, in the thread switched to, and marks a quiescent state for RCU.
+/// Returns whether the thread has exited, so the reaper can free its stack.
+fn finish_switch() -> bool {
+    crate::sync::rcu::quiescent();
+    let cpu = percpu::cpu_id();
+    let mut guard = SCHEDULER.lock();
+    let Some(scheduler) = guard.as_mut() else {
+        return false;
+    };
+    let core = &mut scheduler.cores[cpu];
+    let Some(previous) = core.previous.take() else {
+        return false;
+    };
+    let idle = core.idle;
+    let Some(thread) = scheduler.threads.get_mut(&previous) else {
+        return false;
+    };
+    thread.on_cpu = false;
+    match thread.state {
+        ThreadState::Ready if previous != idle => scheduler.enqueue(previous),
+        ThreadState::Exited => return true,
+        _ => {}
+    }
+    false
+}
+
+/// Woken when a thread has exited, for the reaper to free its stack.
+static REAPER: WaitQueue = WaitQueue::new();
+
+/// Woken when the reaper has removed exited threads, for [`JoinHandle::join`].
+static REAPED: WaitQueue = WaitQueue::new();
+
+/// The `reaper` thread.
+fn reaper() {
+    loop {
+        REAPER.wait_until(|| {
+            SCHEDULER.lock().as_ref().is_some_and(|scheduler| {
+                scheduler
+                    .threads
+                    .values()
+                    .any(|thread| thread.state == ThreadState::Exited && !thread.on_cpu)
+            })
+        });
+        reap();
+        REAPED.wake_all();
+    }
+}
+
+/// Removes exited threads that are no longer on their stack, and frees their stacks.
+fn reap() {
+    let finished: Vec<Box<Thread>> = interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.lock();
+        let Some(scheduler) = guard.as_mut() else {
+            return Vec::new();
+        };
+        let ids: Vec<ThreadId> = scheduler
+            .threads
+            .iter()
+            .filter(|(_, thread)| thread.state == ThreadState::Exited && !thread.on_cpu)
+            .map(|(&id, _)| id)
+            .collect();
+        ids.iter()
+            .filter_map(|id| scheduler.threads.remove(id))
+            .collect()
+    });
+    for thread in finished {
+        if let Some(top) = thread.stack {
+            if let Err(error) = unsafe { vmm::free_region(top - 1u64) } {
+                crate::serial_println!("thread: stack of {} not freed: {:?}", thread.name, error);
+            }
+        }
+    }
+}
+
+/// Charges the tick to the running thread and counts down its time slice and, on the boot CPU,
+/// the time to the next boost and to the end of the statistics period. Called from the timer
+/// interrupt of every CPU.
+pub(crate) fn timer_tick() {
+    let cpu = percpu::cpu_id();
+    let mut period_over = false;
+    if cpu == 0 {
+        let boost = BOOST_LEFT.load(Ordering::Relaxed);
+        if boost <= 1 {
+            BOOST_PENDING.store(true, Ordering::Relaxed);
+            BOOST_LEFT.store(boost_ticks(), Ordering::Relaxed);
+        } else {
+            BOOST_LEFT.store(boost - 1, Ordering::Relaxed);
+        }
+        let period = STATS_PERIOD_LEFT.load(Ordering::Relaxed);
+        period_over = period <= 1;
+        let period = if period_over {
+            stats_period_ticks()
+        } else {
+            period - 1
+        };
+        STATS_PERIOD_LEFT.store(period, Ordering::Relaxed);
+    }
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        if let Some(core) = scheduler.cores.get(cpu) {
+            let current = core.current;
+            if let Some(thread) = scheduler.threads.get_mut(&current) {
+                thread.ticks += 1;
+            }
+        }
+        if period_over {
+            scheduler.end_stats_period();
+        }
+    }
+    let left = SLICE_LEFT.get().load(Ordering::Relaxed);
+    if left <= 1 {
+        NEED_RESCHED.get().store(true, Ordering::Relaxed);
+    } else {
+        SLICE_LEFT.get().store(left - 1, Ordering::Relaxed);
+    }
+}
+
+/// Switches threads if the time slice has run out. Called at the exit of the outermost
+/// interrupt handler, with preemption enabled, which is also a quiescent state for RCU.
+pub(crate) fn preempt() {
+    crate::sync::rcu::quiescent();
+    if NEED_RESCHED.get().load(Ordering::Relaxed) {
+        schedule();
+    }
+}
+
+/// Gives the rest of the time slice to the next ready thread. Does nothing where the thread may
+/// not be switched away from.
+pub fn yield_now() {
+    if crate::preempt::preemptible() {
+        interrupts::without_interrupts(schedule);
+    }
+}
+
+/// Sets the state of the running thread and switches away from it.
+fn leave(state: ThreadState) {
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            let core = &scheduler.cores[percpu::cpu_id()];
+            debug_assert!(core.current != core.idle, "idle thread blocked");
+            let current = core.current;
+            if let Some(thread) = scheduler.threads.get_mut(&current) {
+                if state == ThreadState::Blocked && core::mem::take(&mut thread.wakeup_pending) {
+                    return;
+                }
+                thread.state = state;
+            }
+        }
+        schedule();
+    });
+}
+
+/// Stops running the current thread until another thread or an interrupt handler calls
+/// [`unblock`] on it. Returns at once if that happened since the thread last blocked, so a
+/// wakeup sent between checking a condition and blocking is not missed; callers must check
+/// their condition again after every return.
+pub fn block() {
+    assert!(
+        crate::preempt::preemptible(),
+        "thread blocked where it may not be switched away from"
+    );
+    leave(ThreadState::Blocked);
+}
+
+/// Puts a blocked thread at the back of the run queue of its priority on its CPU, switching to it
+/// at that CPU's next interrupt exit if it has a higher priority than the running thread. If the
+/// thread is running and about to block, its next [`block`] returns at once instead. Safe to call
+/// from interrupt handlers.
+pub fn unblock(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_mut() else {
+            return;
+        };
+        let Some(thread) = scheduler.threads.get_mut(&id) else {
+            return;
+        };
+        match thread.state {
+            ThreadState::Blocked => {
+                thread.state = ThreadState::Ready;
+                thread.level = thread.priority;
+                // Still switching away, it is queued once the switch is done.
+                if !thread.on_cpu {
+                    scheduler.enqueue(id);
+                }
+            }
+            ThreadState::Running | ThreadState::Ready => thread.wakeup_pending = true,
+            ThreadState::Exited => {}
+        }
+    });
+}
+
+/// Blocks the running thread for at least `duration`. Where it cannot block, such as before
+/// [`init`] or in an interrupt handler, it spins instead.
+pub fn sleep(duration: Duration) {
+    sleep_until(crate::time::uptime() + duration);
+}
+
+/// Blocks the running thread until the uptime reaches `deadline`, see [`sleep`].
+pub fn sleep_until(deadline: Duration) {
+    let Some(current) = current().filter(|_| crate::preempt::preemptible()) else {
+        let left = deadline.saturating_sub(crate::time::uptime());
+        crate::time::sleep_busy(left.as_millis() as u64);
+        return;
+    };
+    interrupts::without_interrupts(|| {
+        while crate::time::uptime() < deadline {
+            let key = timer::add(deadline, Wakeup::Thread(current));
+            block();
+            // Something else may have unblocked the thread first.
+            timer::cancel(key);
+        }
+    });
+}
+
+/// Ends the running thread, whose [`JoinHandle::join`] then returns `None`.
+pub fn exit() -> ! {
+    leave(ThreadState::Exited);
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Sets or clears the kernel stack of the running thread for interrupts in user mode, and loads
+/// it into the TSS of this CPU. Called with interrupts disabled.
+pub(crate) fn set_user_kernel_stack(top: Option<VirtAddr>) {
+    if let Some(top) = top {
+        crate::gdt::set_kernel_stack(top);
+    }
+    let Some(current) = current() else {
+        return;
+    };
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        if let Some(thread) = scheduler.threads.get_mut(&current) {
+            thread.user_kernel_stack = top;
+        }
+    }
+}
+
+/// Moves the running thread into `process`, loading its top-level page table, or back out of
+/// it with `None`.
+pub(crate) fn set_process(process: Option<(ProcessId, PhysFrame)>) {
+    interrupts::without_interrupts(|| {
+        let Some(current) = current() else {
+            return;
+        };
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            if let Some(thread) = scheduler.threads.get_mut(&current) {
+                thread.process = process;
+            }
+        }
+        load_page_table(process);
+    });
+}
+
+/// Returns the process the running thread belongs to, or `None` for a kernel thread.
+pub fn current_process() -> Option<ProcessId> {
+    let current = current()?;
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let (process, _) = scheduler.as_ref()?.threads.get(&current)?.process?;
+        Some(process)
+    })
+}
+
+/// Loads the top-level page table of `process`, or the kernel's, unless it is loaded already.
+fn load_page_table(process: Option<(ProcessId, PhysFrame)>) {
+    let Some(frame) = process
+        .map(|(_, frame)| frame)
+        .or_else(crate::memory::kernel_page_table)
+    else {
+        return;
+    };
+    let (active, flags) = Cr3::read();
+    if active != frame {
+        unsafe { Cr3::write(frame, flags) };
+    }
+}
+
+/// Returns the id of the running thread, or `None` before [`init`].
+pub fn current() -> Option<ThreadId> {
+    let current = CURRENT.with(|current| current.load(Ordering::Relaxed));
+    (current != NO_THREAD).then_some(ThreadId(current))
+}
+
+/// Returns a snapshot of all threads.
+pub fn list() -> Vec<ThreadInfo> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_ref() else {
+            return Vec::new();
+        };
+        scheduler
+            .threads
+            .iter()
+            .map(|(&id, thread)| thread.info(id))
+            .collect()
+    })
+}
+
+/// Returns a snapshot of all threads with their CPU usage, and of the run queues of each CPU.
+pub fn stats() -> SchedulerStats {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_ref() else {
+            return SchedulerStats::default();
+        };
+        let threads = scheduler
+            .threads
+            .iter()
+            .map(|(&id, thread)| thread.info(id))
+            .collect();
+        let cpus = scheduler
+            .cores
+            .iter()
+            .map(|core| CpuStats {
+                queued: core.ready.iter().map(VecDeque::len).sum(),
+                switches: core.switches,
+                idle_percent: scheduler.threads[&core.idle].info(core.idle).cpu_percent,
+            })
+            .collect();
+        SchedulerStats { threads, cpus }
+    })
+}
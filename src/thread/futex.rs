@@ -0,0 +1,77 @@
+//! Waiting on a memory word.
+//!
+//! [`futex_wait`] blocks the calling thread as long as a 32-bit atomic still holds the value it
+//! expects, and [`futex_wake`] wakes threads waiting on it. The waiting threads are kept in a
+//! small hash table keyed by the address of the word, so the word itself can be anything, such
+//! as the state of a lock, and needs no space for a queue.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{block, current, unblock, ThreadId};
+
+const BUCKET_COUNT: usize = 64;
+
+/// Threads waiting on a word, with its address, in the order they started waiting. Locked with
+/// interrupts disabled, so a word can be woken from interrupt handlers.
+static BUCKETS: [Mutex<Vec<(usize, ThreadId)>>; BUCKET_COUNT] =
+    [const { Mutex::new(Vec::new()) }; BUCKET_COUNT];
+
+fn bucket(address: usize) -> &'static Mutex<Vec<(usize, ThreadId)>> {
+    // Fibonacci hashing, since the low bits of aligned addresses are all the same.
+    let hash = (address as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32;
+    &BUCKETS[hash as usize % BUCKET_COUNT]
+}
+
+/// Blocks the running thread if `word` holds `expected`, until a [`futex_wake`] on `word`.
+/// Returns `false` at once if `word` holds another value. The check and starting to wait are
+/// atomic with respect to [`futex_wake`], so a wakeup after the value changed is never missed.
+///
+/// Like [`block`], it may return without a wakeup; callers check `word` again. Where the thread
+/// cannot block, it spins until `word` changes.
+pub fn futex_wait(word: &AtomicU32, expected: u32) -> bool {
+    let address = word as *const AtomicU32 as usize;
+    let Some(thread) = current().filter(|_| crate::preempt::preemptible()) else {
+        if word.load(Ordering::Acquire) != expected {
+            return false;
+        }
+        while word.load(Ordering::Acquire) == expected {
+            core::hint::spin_loop();
+        }
+        return true;
+    };
+    let bucket = bucket(address);
+    interrupts::without_interrupts(|| {
+        {
+            let mut waiters = bucket.lock();
+            if word.load(Ordering::Acquire) != expected {
+                return false;
+            }
+            waiters.push((address, thread));
+        }
+        block();
+        // A wakeup for something else may have unblocked the thread first.
+        bucket.lock().retain(|&waiter| waiter != (address, thread));
+        true
+    })
+}
+
+/// Wakes up to `count` threads waiting on `word`, longest waiting first, and returns how many
+/// there were. Safe to call from interrupt handlers.
+pub fn futex_wake(word: &AtomicU32, count: usize) -> usize {
+    let address = word as *const AtomicU32 as usize;
+    interrupts::without_interrupts(|| {
+        let mut woken = 0;
+        bucket(address).lock().retain(|&(waiting_on, thread)| {
+            if waiting_on != address || woken == count {
+                return true;
+            }
+            unblock(thread);
+            woken += 1;
+            false
+        });
+        woken
+    })
+}
@@ -0,0 +1,189 @@
+//! Kernel timekeeping.
+//!
+//! The timer interrupt advances a monotonic tick counter and the uptime. The interrupt comes from
+//! the local APIC timer once it is calibrated, and from PIT channel 0 before that or on machines
+//! without an APIC. Both run at [`DEFAULT_TICK_RATE`] unless changed with [`set_tick_rate`].
+//!
+//! For finer measurements, such as profiling driver code paths, [`Instant`] reads the time stamp
+//! counter with nanosecond resolution.
+//!
+//! Programs read the ticks and the TSC calibration from the page [`vdso`] maps into every process.
+//!
+//! [`monotonic_ns`] reads the HPET counter where there is one, and falls back to the tick count
+//! otherwise.
+//!
+//! Async code waits with [`sleep`], and threads with [`thread::sleep`](crate::thread::sleep);
+//! both are woken by the tick that reaches their deadline.
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+pub(crate) mod timer;
+pub mod tsc;
+pub mod vdso;
+
+pub use timer::{sleep, sleep_until, Sleep};
+pub use tsc::rdtsc;
+
+/// Tick rate the timers are started with.
+pub const DEFAULT_TICK_RATE: u32 = 1000;
+
+/// Supported tick rates in Hz. The lower bound is set by the PIT's 16-bit counter.
+pub const MIN_TICK_RATE: u32 = crate::pit::MIN_FREQUENCY;
+pub const MAX_TICK_RATE: u32 = 10_000;
+
+/// Tick rate and period of the PIT as left by the firmware: about 18.2 Hz.
+const FIRMWARE_TICK_RATE: u32 = 18;
+const FIRMWARE_TICK_PERIOD_NS: u64 = 54_925_493;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TICK_RATE: AtomicU32 = AtomicU32::new(FIRMWARE_TICK_RATE);
+/// Actual length of a tick, which differs slightly from `1 / TICK_RATE`.
+static TICK_PERIOD_NS: AtomicU64 = AtomicU64::new(FIRMWARE_TICK_PERIOD_NS);
+/// Time since boot, as counted by the ticks.
+static UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Errors returned by [`set_tick_rate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeError {
+    /// The rate is outside `MIN_TICK_RATE..=MAX_TICK_RATE`.
+    UnsupportedRate(u32),
+}
+
+/// Counts a timer interrupt. Called from the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    let period = TICK_PERIOD_NS.load(Ordering::Relaxed);
+    let uptime = UPTIME_NS.fetch_add(period, Ordering::Relaxed) + period;
+    vdso::update(uptime);
+    timer::expire(uptime);
+}
+
+/// Number of timer interrupts since boot. Never decreases.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Number of ticks per second.
+pub fn tick_rate() -> u32 {
+    TICK_RATE.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the timer interrupt was enabled.
+pub fn uptime_ms() -> u64 {
+    UPTIME_NS.load(Ordering::Relaxed) / 1_000_000
+}
+
+/// Time since the timer interrupt was enabled, with the resolution of one tick.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(UPTIME_NS.load(Ordering::Relaxed))
+}
+
+/// Clock behind [`monotonic_ns`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// The HPET main counter.
+    Hpet,
+    /// The timer interrupt ticks, with the resolution of one tick.
+    Ticks,
+}
+
+/// Returns the clock [`monotonic_ns`] reads.
+pub fn clock_source() -> ClockSource {
+    if crate::hpet::nanos().is_some() {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Ticks
+    }
+}
+
+/// Nanoseconds since boot from the best available clock source, see [`clock_source`]. The HPET
+/// takes over from the ticks where they stood when it started, so the value never goes back.
+pub fn monotonic_ns() -> u64 {
+    crate::hpet::nanos().unwrap_or_else(|| UPTIME_NS.load(Ordering::Relaxed))
+}
+
+/// Changes the number of timer interrupts per second on the timer that drives them. With the
+/// local APIC timers, the other CPUs change their rate on their next tick.
+pub fn set_tick_rate(hz: u32) -> Result<(), TimeError> {
+    if !(MIN_TICK_RATE..=MAX_TICK_RATE).contains(&hz) {
+        return Err(TimeError::UnsupportedRate(hz));
+    }
+    let period = if crate::apic::timer_calibrated() {
+        crate::apic::start_timer(hz)
+    } else {
+        None
+    };
+    let period = period.unwrap_or_else(|| crate::pit::set_frequency(hz));
+    TICK_PERIOD_NS.store(period, Ordering::Relaxed);
+    TICK_RATE.store(hz, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Spins for at least `ms` milliseconds.
+///
+/// With interrupts enabled this watches the uptime; with interrupts disabled the ticks stop, so
+/// PIT channel 2 is polled instead.
+pub fn sleep_busy(ms: u64) {
+    if !interrupts::are_enabled() {
+        let mut remaining = ms;
+        while remaining > 0 {
+            let step = remaining.min(u64::from(crate::pit::MAX_POLLED_WAIT_MS));
+            crate::pit::wait_ms_polled(step as u32);
+            remaining -= step;
+        }
+        return;
+    }
+    // Round up, since the current tick is already partly over.
+    let deadline = uptime_ms() + ms + 1;
+    while uptime_ms() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Calibrates the time stamp counter. Returns its frequency in Hz.
+pub fn init() -> u64 {
+    tsc::calibrate()
+}
+
+/// A point in time read from the time stamp counter, for measuring short intervals.
+///
+/// Durations are only meaningful after [`init`], and only approximate without an invariant TSC
+/// (see [`tsc::is_invariant`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(tsc::rdtsc_ordered())
+    }
+
+    /// Time from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(tsc::cycles_to_nanos(self.0.saturating_sub(earlier.0)))
+    }
+
+    /// Time since `self`.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        Instant(self.0.saturating_add(tsc::nanos_to_cycles(nanos)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
@@ -0,0 +1,119 @@
+//! Timed wakeups.
+//!
+//! Sleeping threads and tasks are kept in a list sorted by deadline. The timer interrupt checks
+//! the earliest deadline on every tick and wakes whatever is due, so sleepers neither spin nor
+//! poll. Deadlines are uptimes, and are met with the resolution of one tick.
+
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::thread::ThreadId;
+
+/// What to do when a deadline passes.
+pub(crate) enum Wakeup {
+    Thread(ThreadId),
+    Waker(Waker),
+}
+
+/// Identifies a pending wakeup: its deadline in nanoseconds of uptime and a sequence number,
+/// which orders wakeups with the same deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct TimerKey(u64, u64);
+
+/// Pending wakeups in deadline order. Locked with interrupts disabled.
+static WAKEUPS: Mutex<BTreeMap<TimerKey, Wakeup>> = Mutex::new(BTreeMap::new());
+
+/// The earliest deadline in [`WAKEUPS`], or `u64::MAX`, so most ticks need not take the lock.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// Arranges for `wakeup` once the uptime reaches `deadline`.
+pub(crate) fn add(deadline: Duration, wakeup: Wakeup) -> TimerKey {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let deadline = nanos(deadline);
+    let key = TimerKey(deadline, SEQUENCE.fetch_add(1, Ordering::Relaxed));
+    interrupts::without_interrupts(|| {
+        WAKEUPS.lock().insert(key, wakeup);
+        NEXT_DEADLINE.fetch_min(deadline, Ordering::Relaxed);
+    });
+    key
+}
+
+/// Removes a wakeup that is no longer wanted. Does nothing if it has already happened.
+pub(crate) fn cancel(key: TimerKey) {
+    interrupts::without_interrupts(|| {
+        WAKEUPS.lock().remove(&key);
+    });
+}
+
+/// Wakes everything due at `now` nanoseconds of uptime. Called from the timer interrupt.
+pub(super) fn expire(now: u64) {
+    if now < NEXT_DEADLINE.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut wakeups = WAKEUPS.lock();
+    while let Some(entry) = wakeups.first_entry() {
+        if entry.key().0 > now {
+            break;
+        }
+        match entry.remove() {
+            Wakeup::Thread(thread) => crate::thread::unblock(thread),
+            Wakeup::Waker(waker) => waker.wake(),
+        }
+    }
+    let next = wakeups.first_key_value().map_or(u64::MAX, |(key, _)| key.0);
+    NEXT_DEADLINE.store(next, Ordering::Relaxed);
+}
+
+/// Waits asynchronously for `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(super::uptime() + duration)
+}
+
+/// Waits asynchronously until the uptime reaches `deadline`.
+pub fn sleep_until(deadline: Duration) -> Sleep {
+    Sleep {
+        deadline,
+        key: None,
+    }
+}
+
+/// The future returned by [`sleep`] and [`sleep_until`].
+pub struct Sleep {
+    deadline: Duration,
+    /// The wakeup registered by the last poll.
+    key: Option<TimerKey>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if let Some(key) = self.key.take() {
+            cancel(key);
+        }
+        if super::uptime() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let key = add(self.deadline, Wakeup::Waker(context.waker().clone()));
+        self.key = Some(key);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            cancel(key);
+        }
+    }
+}
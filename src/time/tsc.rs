@@ -0,0 +1,75 @@
+//! The time stamp counter.
+//!
+//! The TSC counts CPU cycles at a fixed rate on CPUs with an invariant TSC, which makes it the
+//! cheapest high-resolution clock. Its frequency is not reported reliably, so it is measured
+//! against the PIT at boot.
+
+use core::arch::x86_64::{__cpuid, _mm_lfence, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// CPUID leaf and EDX bit reporting an invariant TSC, which runs at a constant rate in all
+/// power states.
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+/// Length of the calibration run against the PIT.
+const CALIBRATION_MS: u32 = crate::pit::MAX_POLLED_WAIT_MS;
+
+/// Measured TSC frequency in Hz, or 0 before calibration.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the time stamp counter. The CPU may execute the read out of order with the
+/// surrounding code; use [`rdtsc_ordered`] to time short code paths.
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Reads the time stamp counter once all earlier instructions have completed.
+pub fn rdtsc_ordered() -> u64 {
+    unsafe {
+        _mm_lfence();
+        _rdtsc()
+    }
+}
+
+/// Returns whether the TSC runs at a constant rate regardless of frequency scaling and sleep
+/// states. Without it, TSC-based durations are only approximate.
+pub fn is_invariant() -> bool {
+    let max_leaf = __cpuid(0x8000_0000).eax;
+    max_leaf >= CPUID_POWER_MANAGEMENT
+        && __cpuid(CPUID_POWER_MANAGEMENT).edx & CPUID_INVARIANT_TSC != 0
+}
+
+/// Measures the TSC frequency against the PIT and returns it in Hz.
+pub(crate) fn calibrate() -> u64 {
+    let start = rdtsc_ordered();
+    crate::pit::wait_ms_polled(CALIBRATION_MS);
+    let cycles = rdtsc_ordered() - start;
+    let frequency = cycles * 1000 / u64::from(CALIBRATION_MS);
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    frequency
+}
+
+/// Measured TSC frequency in Hz, if calibrated.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Converts a number of TSC cycles to nanoseconds. Returns 0 before calibration.
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    match frequency() {
+        Some(frequency) => (u128::from(cycles) * 1_000_000_000 / u128::from(frequency)) as u64,
+        None => 0,
+    }
+}
+
+/// Converts nanoseconds to a number of TSC cycles. Returns 0 before calibration.
+pub fn nanos_to_cycles(nanos: u64) -> u64 {
+    match frequency() {
+        Some(frequency) => (u128::from(nanos) * u128::from(frequency) / 1_000_000_000) as u64,
+        None => 0,
+    }
+}
@@ -0,0 +1,87 @@
+//! The time page, through which programs read the time without a system call.
+//!
+//! A single frame holds a [`TimeData`], written by the timer interrupt on every tick and mapped
+//! read-only at [`TIME_PAGE`] into every process. Readers follow the sequence counter like a
+//! seqlock: read it, retry while it is odd, read the fields, and retry if it changed meanwhile.
+//! Between ticks a program gets finer time from the TSC: `uptime_ns` plus the cycles since `tsc`,
+//! times `tsc_to_ns`, shifted right by 32.
+//!
+//! The frame is the kernel's. Every page that maps it counts as a copy-on-write reference, which
+//! keeps address spaces from freeing it when they unmap it.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+use super::tsc;
+use crate::memory::{cow, AddressSpace, PagingError};
+use crate::usermode::TIME_PAGE;
+
+/// The contents of the time page. Fields are only ever written by the kernel.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TimeData {
+    /// Odd while the fields are being updated.
+    pub sequence: AtomicU64,
+    /// Timer interrupts since boot, as [`super::ticks`].
+    pub ticks: AtomicU64,
+    /// Ticks per second.
+    pub tick_rate: AtomicU64,
+    /// Nanoseconds since boot, as counted by the ticks.
+    pub uptime_ns: AtomicU64,
+    /// The time stamp counter at the last tick.
+    pub tsc: AtomicU64,
+    /// TSC frequency in Hz, 0 before calibration.
+    pub tsc_frequency: AtomicU64,
+    /// Nanoseconds per TSC cycle as a 32.32 fixed point number, 0 before calibration.
+    pub tsc_to_ns: AtomicU64,
+}
+
+/// The frame of the time page, allocated by the first [`map`].
+static FRAME: Once<PhysFrame> = Once::new();
+
+/// Publishes the state of the clock after a tick at `uptime_ns`. Called from [`super::tick`];
+/// does nothing until a process has mapped the page.
+pub(crate) fn update(uptime_ns: u64) {
+    let Some(data) = data() else {
+        return;
+    };
+    let (frequency, per_cycle) = match tsc::frequency() {
+        Some(frequency) => (frequency, (1_000_000_000u128 << 32) / u128::from(frequency)),
+        None => (0, 0),
+    };
+    // Only the boot CPU ticks, so there is a single writer.
+    data.sequence.fetch_add(1, Ordering::Release);
+    data.ticks.store(super::ticks(), Ordering::Relaxed);
+    data.tick_rate
+        .store(u64::from(super::tick_rate()), Ordering::Relaxed);
+    data.uptime_ns.store(uptime_ns, Ordering::Relaxed);
+    data.tsc.store(tsc::rdtsc(), Ordering::Relaxed);
+    data.tsc_frequency.store(frequency, Ordering::Relaxed);
+    data.tsc_to_ns.store(per_cycle as u64, Ordering::Relaxed);
+    data.sequence.fetch_add(1, Ordering::Release);
+}
+
+/// Maps the time page read-only at [`TIME_PAGE`] in `space`.
+pub fn map(space: &mut AddressSpace) -> Result<(), PagingError> {
+    let frame = *FRAME.try_call_once(|| {
+        let frame = crate::memory::allocate_frame().ok_or(PagingError::OutOfFrames)?;
+        let virt = crate::memory::phys_to_virt(frame.start_address()).expect("memory initialized");
+        unsafe { virt.as_mut_ptr::<u8>().write_bytes(0, 4096) };
+        Ok(frame)
+    })?;
+    let page = Page::containing_address(VirtAddr::new(TIME_PAGE));
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+    // Shared like the frames of a forked address space, and counted as such once mapped.
+    unsafe { space.map_page(page, frame, flags)? };
+    cow::share(frame);
+    Ok(())
+}
+
+fn data() -> Option<&'static TimeData> {
+    let frame = FRAME.get()?;
+    let virt = crate::memory::phys_to_virt(frame.start_address())?;
+    Some(unsafe { &*virt.as_ptr::<TimeData>() })
+}
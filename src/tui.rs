@@ -0,0 +1,286 @@
+//! Simple text-mode widgets drawn on the framebuffer console: boxes, progress bars and menus.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::console::{self, Attributes, ConsoleError};
+use crate::writer::FrameBufferWriter;
+
+/// Draws a box with a single-line border. `height` and `width` are in text cells and include the
+/// border. The optional title is drawn into the top edge.
+pub fn draw_box(
+    row: usize,
+    column: usize,
+    height: usize,
+    width: usize,
+    title: Option<&str>,
+) -> Result<(), ConsoleError> {
+    console::with_writer(|writer| draw_box_on(writer, row, column, height, width, title))?
+}
+
+fn draw_box_on(
+    writer: &mut FrameBufferWriter,
+    row: usize,
+    column: usize,
+    height: usize,
+    width: usize,
+    title: Option<&str>,
+) -> Result<(), ConsoleError> {
+    if height < 2 || width < 2 {
+        return Ok(());
+    }
+    let bottom = row + height - 1;
+    let right = column + width - 1;
+    put(writer, row, column, '┌')?;
+    put(writer, row, right, '┐')?;
+    put(writer, bottom, column, '└')?;
+    put(writer, bottom, right, '┘')?;
+    for c in (column + 1)..right {
+        put(writer, row, c, '─')?;
+        put(writer, bottom, c, '─')?;
+    }
+    for r in (row + 1)..bottom {
+        put(writer, r, column, '│')?;
+        put(writer, r, right, '│')?;
+    }
+    if let Some(title) = title {
+        // Leave a corner and a line segment on each side of the title.
+        let room = width.saturating_sub(4);
+        for (i, c) in title.chars().take(room).enumerate() {
+            put(writer, row, column + 2 + i, c)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a single character into a text cell.
+fn put(
+    writer: &mut FrameBufferWriter,
+    row: usize,
+    column: usize,
+    c: char,
+) -> Result<(), ConsoleError> {
+    let mut buf = [0; 4];
+    writer.write_at(row, column, c.encode_utf8(&mut buf))
+}
+
+/// A horizontal progress bar followed by a percentage, e.g. `████░░░░  50%`.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgressBar {
+    row: usize,
+    column: usize,
+    width: usize,
+    total: u64,
+}
+
+impl ProgressBar {
+    /// Width of the percentage label, including the leading space.
+    const LABEL_WIDTH: usize = 5;
+
+    /// Creates a bar occupying `width` cells, label included, that is full at `total` units.
+    pub const fn new(row: usize, column: usize, width: usize, total: u64) -> Self {
+        Self {
+            row,
+            column,
+            width,
+            total,
+        }
+    }
+
+    /// Redraws the bar for `done` out of `total` units.
+    pub fn set(&self, done: u64) -> Result<(), ConsoleError> {
+        let bar_width = self.width.saturating_sub(Self::LABEL_WIDTH);
+        let done = done.min(self.total);
+        let (filled, percent) = match self.total {
+            0 => (bar_width, 100),
+            total => (
+                (bar_width as u64 * done / total) as usize,
+                done * 100 / total,
+            ),
+        };
+        console::with_writer(|writer| {
+            for i in 0..bar_width {
+                let c = if i < filled { '█' } else { '░' };
+                put(writer, self.row, self.column + i, c)?;
+            }
+            writer.write_fmt_at(
+                self.row,
+                self.column + bar_width,
+                format_args!(" {:>3}%", percent),
+            )
+        })?
+    }
+}
+
+/// What happened when a key was offered to a [`Menu`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuEvent {
+    /// The key has no meaning for the menu.
+    Ignored,
+    /// The selection moved.
+    Moved,
+    /// The item with the given index was chosen with Enter.
+    Chosen(usize),
+}
+
+/// A vertical menu in a box, navigated with the arrow keys and confirmed with Enter.
+#[derive(Debug, Clone, Copy)]
+pub struct Menu {
+    row: usize,
+    column: usize,
+    items: &'static [&'static str],
+    selected: usize,
+}
+
+impl Menu {
+    pub const fn new(row: usize, column: usize, items: &'static [&'static str]) -> Self {
+        Self {
+            row,
+            column,
+            items,
+            selected: 0,
+        }
+    }
+
+    /// Index of the highlighted item.
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    fn item_width(&self) -> usize {
+        self.items
+            .iter()
+            .map(|item| item.chars().count())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Draws the menu with the selected item in reverse video.
+    pub fn draw(&self) -> Result<(), ConsoleError> {
+        console::with_writer(|writer| self.draw_on(writer))?
+    }
+
+    fn draw_on(&self, writer: &mut FrameBufferWriter) -> Result<(), ConsoleError> {
+        let item_width = self.item_width();
+        draw_box_on(
+            writer,
+            self.row,
+            self.column,
+            self.items.len() + 2,
+            item_width + 4,
+            None,
+        )?;
+        let attributes = writer.attributes();
+        for (i, item) in self.items.iter().enumerate() {
+            if i == self.selected {
+                writer.set_attributes(attributes | Attributes::REVERSE);
+            }
+            let result = writer.write_fmt_at(
+                self.row + 1 + i,
+                self.column + 1,
+                format_args!(" {:<width$} ", item, width = item_width),
+            );
+            writer.set_attributes(attributes);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Updates the selection for `key` and redraws the menu if it changed.
+    pub fn handle_key(&mut self, key: DecodedKey) -> MenuEvent {
+        let event = self.select(key);
+        if event == MenuEvent::Moved {
+            let _ = self.draw();
+        }
+        event
+    }
+
+    /// Updates the selection for `key` without redrawing.
+    fn select(&mut self, key: DecodedKey) -> MenuEvent {
+        match key {
+            DecodedKey::RawKey(KeyCode::ArrowUp) if self.selected > 0 => {
+                self.selected -= 1;
+                MenuEvent::Moved
+            }
+            DecodedKey::RawKey(KeyCode::ArrowDown) if self.selected + 1 < self.items.len() => {
+                self.selected += 1;
+                MenuEvent::Moved
+            }
+            DecodedKey::RawKey(KeyCode::ArrowUp | KeyCode::ArrowDown) => MenuEvent::Moved,
+            DecodedKey::Unicode('\n') => MenuEvent::Chosen(self.selected),
+            _ => MenuEvent::Ignored,
+        }
+    }
+}
+
+/// The menu that currently receives arrow keys from the keyboard handler.
+static ACTIVE_MENU: Mutex<Option<Menu>> = Mutex::new(None);
+
+/// Set when the active menu's selection moved while the writer was busy, so it is redrawn on the
+/// next timer tick.
+static MENU_STALE: AtomicBool = AtomicBool::new(false);
+
+/// The item chosen in the last active menu, until it is taken.
+static MENU_CHOICE: Mutex<Option<usize>> = Mutex::new(None);
+
+/// Draws `menu` and routes keyboard input to it until an item is chosen.
+pub fn show_menu(menu: Menu) -> Result<(), ConsoleError> {
+    menu.draw()?;
+    interrupts::without_interrupts(|| {
+        *MENU_CHOICE.lock() = None;
+        *ACTIVE_MENU.lock() = Some(menu);
+    });
+    Ok(())
+}
+
+/// Returns the chosen item index once the user has pressed Enter in the active menu.
+pub fn take_menu_choice() -> Option<usize> {
+    interrupts::without_interrupts(|| MENU_CHOICE.lock().take())
+}
+
+/// Offers a key to the active menu. Returns whether the menu consumed it.
+///
+/// Called from the keyboard interrupt handler.
+pub fn handle_key(key: DecodedKey) -> bool {
+    let mut active = ACTIVE_MENU.lock();
+    let Some(menu) = active.as_mut() else {
+        return false;
+    };
+    match menu.select(key) {
+        MenuEvent::Ignored => false,
+        MenuEvent::Moved => {
+            if console::try_with_writer(|writer| menu.draw_on(writer)).is_err() {
+                MENU_STALE.store(true, Ordering::Relaxed);
+            }
+            true
+        }
+        MenuEvent::Chosen(index) => {
+            *active = None;
+            *MENU_CHOICE.lock() = Some(index);
+            true
+        }
+    }
+}
+
+/// Returns whether the active menu needs a redraw that was deferred in the keyboard handler.
+pub(crate) fn menu_stale() -> bool {
+    MENU_STALE.load(Ordering::Relaxed)
+}
+
+/// Redraws the active menu if its redraw was deferred. Called from the timer tick with the writer
+/// already locked.
+pub(crate) fn redraw_stale_menu(writer: &mut FrameBufferWriter) {
+    if !MENU_STALE.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    match ACTIVE_MENU.try_lock() {
+        Some(menu) => {
+            if let Some(menu) = menu.as_ref() {
+                let _ = menu.draw_on(writer);
+            }
+        }
+        None => MENU_STALE.store(true, Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,774 @@
+//! Running code in ring 3.
+//!
+//! The main thread of a [`crate::process`] drops into user mode with an `iretq` to the user code
+//! segment, and comes back once the program has exited. Interrupts in user mode switch to the
+//! thread's kernel stack, right below the frame of [`enter`], so the scheduler handles them like
+//! any other and may switch threads in between. An exception in user mode sends the program a
+//! [`crate::process::Signal`], see [`signal`], instead of bringing down the kernel.
+//!
+//! Programs call the kernel through the interface in [`syscall`]. Every process has the user
+//! part of the address space to itself, so all programs are loaded at the same addresses. A
+//! thread enters user mode with a [`Context`] holding all of the program's registers, so that a
+//! forked child picks up where its parent was.
+
+pub mod signal;
+pub mod syscall;
+pub mod uaccess;
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::mem::offset_of;
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::{ExceptionVector, InterruptStackFrame};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::process::{ExitStatus, ProcessError, Signal};
+use crate::time::vdso::TimeData;
+use crate::vfs::VfsError;
+
+/// Start of the user part of the address space, in a top-level page table entry of its own.
+pub const USER_START: u64 = 0x4000_0000_0000;
+
+/// End of the user part of the address space.
+pub const USER_END: u64 = USER_START + 0x1000_0000;
+
+/// Where programs are loaded and start.
+const CODE_START: u64 = USER_START;
+
+/// Largest program [`run`] loads.
+const MAX_CODE_SIZE: usize = 16 * 4096;
+
+/// Top of the user stack.
+pub(crate) const STACK_TOP: u64 = USER_END;
+
+pub(crate) const STACK_SIZE: u64 = 4 * 4096;
+
+/// Where the read-only [`crate::time::vdso`] page is mapped in every process, with a guard page
+/// between it and the stack.
+pub const TIME_PAGE: u64 = STACK_TOP - STACK_SIZE - 2 * 4096;
+
+/// End of the part of the address space programs are loaded to, right below the time page.
+pub const PROGRAM_END: u64 = TIME_PAGE;
+
+global_asm!(
+    // usermode_enter(context, code_selector, data_selector) -> Exit
+    ".global usermode_enter",
+    "usermode_enter:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // Keep the stack 16-byte aligned for the call below and for the saved pointer.
+    "sub rsp, 8",
+    "cli",
+    "mov r12, rdi",
+    "mov r14, rsi",
+    "mov r15, rdx",
+    "mov rdi, rsp",
+    "call {entered}",
+    // The interrupt frame `iretq` pops: SS, RSP, RFLAGS, CS and RIP.
+    "push r15",
+    "push qword ptr [r12 + {rsp}]",
+    "push qword ptr [r12 + {rflags}]",
+    "push r14",
+    "push qword ptr [r12 + {rip}]",
+    // The program's registers, in the order of `Registers`, and r12 last as it points to them.
+    "mov r15, [r12]",
+    "mov r14, [r12 + 8]",
+    "mov r13, [r12 + 16]",
+    "mov r11, [r12 + 32]",
+    "mov r10, [r12 + 40]",
+    "mov r9, [r12 + 48]",
+    "mov r8, [r12 + 56]",
+    "mov rbp, [r12 + 64]",
+    "mov rdi, [r12 + 72]",
+    "mov rsi, [r12 + 80]",
+    "mov rdx, [r12 + 88]",
+    "mov rcx, [r12 + 96]",
+    "mov rbx, [r12 + 104]",
+    "mov rax, [r12 + 112]",
+    "mov r12, [r12 + 24]",
+    // The program runs with its own GS base, swapped back in on every entry to the kernel.
+    "swapgs",
+    "iretq",
+    // usermode_return(kernel_stack, status, signal) -> !, back to the caller of usermode_enter
+    // with the status in rax and the signal already in rdx
+    ".global usermode_return",
+    "usermode_return:",
+    "mov rsp, rdi",
+    "mov rax, rsi",
+    "add rsp, 8",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    entered = sym entered,
+    rip = const offset_of!(Context, rip),
+    rsp = const offset_of!(Context, rsp),
+    rflags = const offset_of!(Context, rflags),
+);
+
+// The program `hello` prints a greeting with a system call and exits with status 0. It is only
+// copied to user pages, never run where it is.
+global_asm!(
+    ".pushsection .rodata.usermode_hello, \"a\"",
+    ".global usermode_hello_start",
+    ".global usermode_hello_end",
+    "usermode_hello_start:",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "lea rsi, [rip + 2f]",
+    "mov edx, 3f - 2f",
+    "syscall",
+    "mov eax, {exit}",
+    "xor edi, edi",
+    "syscall",
+    "ud2",
+    "2:",
+    ".ascii \"Hello from ring 3!\\n\"",
+    "3:",
+    "usermode_hello_end:",
+    ".popsection",
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT,
+);
+
+// The program `echo`, an ELF executable that prints its arguments, one per line, and exits with
+// their count. Header, program header and code form a single read-only executable segment.
+global_asm!(
+    ".pushsection .rodata.usermode_echo, \"a\"",
+    ".balign 8",
+    ".global usermode_echo_start",
+    ".global usermode_echo_end",
+    "usermode_echo_start:",
+    // e_ident: ELF64, little endian, version 1, System V ABI.
+    ".byte 0x7f, 0x45, 0x4c, 0x46, 2, 1, 1, 0",
+    ".quad 0",
+    // e_type ET_EXEC, e_machine x86-64, e_version.
+    ".word 2, 0x3e",
+    ".long 1",
+    // e_entry, e_phoff, e_shoff, e_flags.
+    ".quad {base} + (.Lecho_code - usermode_echo_start)",
+    ".quad .Lecho_program_header - usermode_echo_start",
+    ".quad 0",
+    ".long 0",
+    // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx.
+    ".word 64, 56, 1, 0, 0, 0",
+    ".Lecho_program_header:",
+    // p_type PT_LOAD, p_flags readable and executable.
+    ".long 1, 5",
+    // p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_align.
+    ".quad 0, {base}, {base}",
+    ".quad usermode_echo_end - usermode_echo_start",
+    ".quad usermode_echo_end - usermode_echo_start",
+    ".quad 0x1000",
+    ".Lecho_code:",
+    "mov rbx, [rsp]",
+    "lea r12, [rsp + 8]",
+    ".Lecho_next:",
+    "test rbx, rbx",
+    "jz .Lecho_done",
+    "mov rsi, [r12]",
+    "xor edx, edx",
+    ".Lecho_length:",
+    "cmp byte ptr [rsi + rdx], 0",
+    "je .Lecho_print",
+    "inc rdx",
+    "jmp .Lecho_length",
+    ".Lecho_print:",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "syscall",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "lea rsi, [rip + .Lecho_newline]",
+    "mov edx, 1",
+    "syscall",
+    "add r12, 8",
+    "dec rbx",
+    "jmp .Lecho_next",
+    ".Lecho_done:",
+    "mov eax, {exit}",
+    "mov rdi, [rsp]",
+    "syscall",
+    "ud2",
+    ".Lecho_newline:",
+    ".byte 10",
+    "usermode_echo_end:",
+    ".popsection",
+    base = const USER_START,
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT,
+);
+
+// The program `fork`, an ELF executable that forks. The child replaces itself with `echo`, and
+// the parent waits for it, prints a line and exits with the child's wait status.
+global_asm!(
+    ".pushsection .rodata.usermode_fork, \"a\"",
+    ".balign 8",
+    ".global usermode_fork_start",
+    ".global usermode_fork_end",
+    "usermode_fork_start:",
+    ".byte 0x7f, 0x45, 0x4c, 0x46, 2, 1, 1, 0",
+    ".quad 0",
+    ".word 2, 0x3e",
+    ".long 1",
+    ".quad {base} + (.Lfork_code - usermode_fork_start)",
+    ".quad .Lfork_program_header - usermode_fork_start",
+    ".quad 0",
+    ".long 0",
+    ".word 64, 56, 1, 0, 0, 0",
+    ".Lfork_program_header:",
+    ".long 1, 5",
+    ".quad 0, {base}, {base}",
+    ".quad usermode_fork_end - usermode_fork_start",
+    ".quad usermode_fork_end - usermode_fork_start",
+    ".quad 0x1000",
+    ".Lfork_code:",
+    "mov eax, {fork}",
+    "syscall",
+    "test rax, rax",
+    "jz .Lfork_child",
+    // Room for the status, keeping the stack aligned.
+    "sub rsp, 16",
+    "mov rdi, rax",
+    "mov rsi, rsp",
+    "mov eax, {wait_pid}",
+    "syscall",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "lea rsi, [rip + .Lfork_parent]",
+    "mov edx, .Lfork_parent_end - .Lfork_parent",
+    "syscall",
+    "mov eax, {exit}",
+    "mov rdi, [rsp]",
+    "syscall",
+    "ud2",
+    ".Lfork_child:",
+    "mov eax, {exec}",
+    "lea rdi, [rip + .Lfork_echo]",
+    "lea rsi, [rip + .Lfork_argv]",
+    "syscall",
+    // Only reached if exec failed.
+    "mov eax, {exit}",
+    "mov edi, 1",
+    "syscall",
+    "ud2",
+    ".Lfork_parent:",
+    ".ascii \"fork: child reaped\\n\"",
+    ".Lfork_parent_end:",
+    ".Lfork_echo:",
+    ".asciz \"echo\"",
+    ".Lfork_greeting:",
+    ".asciz \"fork: child\"",
+    ".balign 8",
+    ".Lfork_argv:",
+    ".quad {base} + (.Lfork_echo - usermode_fork_start)",
+    ".quad {base} + (.Lfork_greeting - usermode_fork_start)",
+    ".quad 0",
+    "usermode_fork_end:",
+    ".popsection",
+    base = const USER_START,
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT,
+    fork = const syscall::SYS_FORK,
+    exec = const syscall::SYS_EXEC,
+    wait_pid = const syscall::SYS_WAIT_PID,
+);
+
+// The program `signal`, an ELF executable that handles SIGINT and SIGSEGV. It spins until the
+// SIGINT handler sets rbx in the saved registers, then faults, and the SIGSEGV handler exits with
+// status 0.
+global_asm!(
+    ".pushsection .rodata.usermode_signal, \"a\"",
+    ".balign 8",
+    ".global usermode_signal_start",
+    ".global usermode_signal_end",
+    "usermode_signal_start:",
+    ".byte 0x7f, 0x45, 0x4c, 0x46, 2, 1, 1, 0",
+    ".quad 0",
+    ".word 2, 0x3e",
+    ".long 1",
+    ".quad {base} + (.Lsignal_code - usermode_signal_start)",
+    ".quad .Lsignal_program_header - usermode_signal_start",
+    ".quad 0",
+    ".long 0",
+    ".word 64, 56, 1, 0, 0, 0",
+    ".Lsignal_program_header:",
+    ".long 1, 5",
+    ".quad 0, {base}, {base}",
+    ".quad usermode_signal_end - usermode_signal_start",
+    ".quad usermode_signal_end - usermode_signal_start",
+    ".quad 0x1000",
+    ".Lsignal_code:",
+    "mov eax, {sigaction}",
+    "mov edi, {sigint}",
+    "lea rsi, [rip + .Lsignal_on_interrupt]",
+    "lea rdx, [rip + .Lsignal_restorer]",
+    "syscall",
+    "mov eax, {sigaction}",
+    "mov edi, {sigsegv}",
+    "lea rsi, [rip + .Lsignal_on_fault]",
+    "lea rdx, [rip + .Lsignal_restorer]",
+    "syscall",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "lea rsi, [rip + .Lsignal_prompt]",
+    "mov edx, .Lsignal_prompt_end - .Lsignal_prompt",
+    "syscall",
+    "xor ebx, ebx",
+    ".Lsignal_wait:",
+    "pause",
+    "test rbx, rbx",
+    "jz .Lsignal_wait",
+    // Address 0 is never mapped.
+    "mov rax, [0]",
+    "ud2",
+    // Called with the restorer at [rsp] and the saved registers above it.
+    ".Lsignal_on_interrupt:",
+    "mov qword ptr [rsp + 8 + {saved_rbx}], 1",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "lea rsi, [rip + .Lsignal_interrupted]",
+    "mov edx, .Lsignal_interrupted_end - .Lsignal_interrupted",
+    "syscall",
+    "ret",
+    ".Lsignal_on_fault:",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "lea rsi, [rip + .Lsignal_faulted]",
+    "mov edx, .Lsignal_faulted_end - .Lsignal_faulted",
+    "syscall",
+    "mov eax, {exit}",
+    "xor edi, edi",
+    "syscall",
+    "ud2",
+    ".Lsignal_restorer:",
+    "mov eax, {sigreturn}",
+    "int 0x80",
+    "ud2",
+    ".Lsignal_prompt:",
+    ".ascii \"signal: press Ctrl+C\\n\"",
+    ".Lsignal_prompt_end:",
+    ".Lsignal_interrupted:",
+    ".ascii \"signal: caught SIGINT\\n\"",
+    ".Lsignal_interrupted_end:",
+    ".Lsignal_faulted:",
+    ".ascii \"signal: caught SIGSEGV\\n\"",
+    ".Lsignal_faulted_end:",
+    "usermode_signal_end:",
+    ".popsection",
+    base = const USER_START,
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT,
+    sigaction = const syscall::SYS_SIGACTION,
+    sigreturn = const syscall::SYS_SIGRETURN,
+    sigint = const Signal::Interrupt as u8,
+    sigsegv = const Signal::SegmentationFault as u8,
+    saved_rbx = const offset_of!(Context, registers) + offset_of!(Registers, rbx),
+);
+
+// The program `heap`, an ELF executable that grows its heap with sbrk and maps, fills and unmaps
+// two pages with mmap. It exits with status 0 if every call succeeded.
+global_asm!(
+    ".pushsection .rodata.usermode_heap, \"a\"",
+    ".balign 8",
+    ".global usermode_heap_start",
+    ".global usermode_heap_end",
+    "usermode_heap_start:",
+    ".byte 0x7f, 0x45, 0x4c, 0x46, 2, 1, 1, 0",
+    ".quad 0",
+    ".word 2, 0x3e",
+    ".long 1",
+    ".quad {base} + (.Lheap_code - usermode_heap_start)",
+    ".quad .Lheap_program_header - usermode_heap_start",
+    ".quad 0",
+    ".long 0",
+    ".word 64, 56, 1, 0, 0, 0",
+    ".Lheap_program_header:",
+    ".long 1, 5",
+    ".quad 0, {base}, {base}",
+    ".quad usermode_heap_end - usermode_heap_start",
+    ".quad usermode_heap_end - usermode_heap_start",
+    ".quad 0x1000",
+    ".Lheap_code:",
+    "mov eax, {sbrk}",
+    "mov edi, 4096",
+    "syscall",
+    "cmp rax, -1",
+    "je .Lheap_failed",
+    "mov byte ptr [rax + 4095], 1",
+    "mov eax, {mmap}",
+    "mov edi, 8192",
+    "mov esi, {read_write}",
+    "syscall",
+    "cmp rax, -1",
+    "je .Lheap_failed",
+    "mov rbx, rax",
+    "mov rdi, rax",
+    "mov ecx, 8192",
+    "mov al, 0x55",
+    "rep stosb",
+    "mov eax, {munmap}",
+    "mov rdi, rbx",
+    "mov esi, 8192",
+    "syscall",
+    "cmp rax, -1",
+    "je .Lheap_failed",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "lea rsi, [rip + .Lheap_done]",
+    "mov edx, .Lheap_done_end - .Lheap_done",
+    "syscall",
+    "mov eax, {exit}",
+    "xor edi, edi",
+    "syscall",
+    "ud2",
+    ".Lheap_failed:",
+    "mov eax, {exit}",
+    "mov edi, 1",
+    "syscall",
+    "ud2",
+    ".Lheap_done:",
+    ".ascii \"heap: sbrk and mmap work\\n\"",
+    ".Lheap_done_end:",
+    "usermode_heap_end:",
+    ".popsection",
+    base = const USER_START,
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT,
+    sbrk = const syscall::SYS_SBRK,
+    mmap = const syscall::SYS_MMAP,
+    munmap = const syscall::SYS_MUNMAP,
+    read_write = const syscall::PROT_READ | syscall::PROT_WRITE,
+);
+
+// The program `clock`, an ELF executable that waits for the tick count on the time page to move,
+// without making a system call, and exits with status 0.
+global_asm!(
+    ".pushsection .rodata.usermode_clock, \"a\"",
+    ".balign 8",
+    ".global usermode_clock_start",
+    ".global usermode_clock_end",
+    "usermode_clock_start:",
+    ".byte 0x7f, 0x45, 0x4c, 0x46, 2, 1, 1, 0",
+    ".quad 0",
+    ".word 2, 0x3e",
+    ".long 1",
+    ".quad {base} + (.Lclock_code - usermode_clock_start)",
+    ".quad .Lclock_program_header - usermode_clock_start",
+    ".quad 0",
+    ".long 0",
+    ".word 64, 56, 1, 0, 0, 0",
+    ".Lclock_program_header:",
+    ".long 1, 5",
+    ".quad 0, {base}, {base}",
+    ".quad usermode_clock_end - usermode_clock_start",
+    ".quad usermode_clock_end - usermode_clock_start",
+    ".quad 0x1000",
+    ".Lclock_code:",
+    "mov rbx, {ticks}",
+    "mov rcx, [rbx]",
+    ".Lclock_wait:",
+    "pause",
+    "cmp rcx, [rbx]",
+    "je .Lclock_wait",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "lea rsi, [rip + .Lclock_done]",
+    "mov edx, .Lclock_done_end - .Lclock_done",
+    "syscall",
+    "mov eax, {exit}",
+    "xor edi, edi",
+    "syscall",
+    "ud2",
+    ".Lclock_done:",
+    ".ascii \"clock: the time page ticks\\n\"",
+    ".Lclock_done_end:",
+    "usermode_clock_end:",
+    ".popsection",
+    base = const USER_START,
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT,
+    ticks = const TIME_PAGE + offset_of!(TimeData, ticks) as u64,
+);
+
+extern "C" {
+    fn usermode_enter(context: *const Context, code: u64, data: u64) -> Exit;
+    fn usermode_return(kernel_stack: u64, status: u64, signal: u64) -> !;
+    static usermode_hello_start: u8;
+    static usermode_hello_end: u8;
+    static usermode_echo_start: u8;
+    static usermode_echo_end: u8;
+    static usermode_fork_start: u8;
+    static usermode_fork_end: u8;
+    static usermode_signal_start: u8;
+    static usermode_signal_end: u8;
+    static usermode_heap_start: u8;
+    static usermode_heap_end: u8;
+    static usermode_clock_start: u8;
+    static usermode_clock_end: u8;
+}
+
+/// The embedded `hello` program, which prints a line and exits with status 0.
+pub fn hello() -> &'static [u8] {
+    unsafe {
+        let start = &raw const usermode_hello_start;
+        let length = &raw const usermode_hello_end as usize - start as usize;
+        core::slice::from_raw_parts(start, length)
+    }
+}
+
+/// The embedded `echo` program, an ELF executable for [`crate::process::spawn_elf`] that prints its
+/// arguments and exits with their count.
+pub fn echo() -> &'static [u8] {
+    unsafe {
+        let start = &raw const usermode_echo_start;
+        let length = &raw const usermode_echo_end as usize - start as usize;
+        core::slice::from_raw_parts(start, length)
+    }
+}
+
+/// The embedded `fork` program, an ELF executable whose child runs `echo`. The parent exits with
+/// the child's wait status.
+pub fn fork() -> &'static [u8] {
+    unsafe {
+        let start = &raw const usermode_fork_start;
+        let length = &raw const usermode_fork_end as usize - start as usize;
+        core::slice::from_raw_parts(start, length)
+    }
+}
+
+/// The embedded `signal` program, an ELF executable that catches Ctrl+C and a page fault, and
+/// exits with status 0 from the handler of the latter.
+pub fn signal() -> &'static [u8] {
+    unsafe {
+        let start = &raw const usermode_signal_start;
+        let length = &raw const usermode_signal_end as usize - start as usize;
+        core::slice::from_raw_parts(start, length)
+    }
+}
+
+/// The embedded `heap` program, an ELF executable that asks for memory with sbrk and mmap and
+/// exits with status 0 if it got it.
+pub fn heap() -> &'static [u8] {
+    unsafe {
+        let start = &raw const usermode_heap_start;
+        let length = &raw const usermode_heap_end as usize - start as usize;
+        core::slice::from_raw_parts(start, length)
+    }
+}
+
+/// The embedded `clock` program, an ELF executable that reads the ticks from the time page and
+/// exits with status 0 once they have moved.
+pub fn clock() -> &'static [u8] {
+    unsafe {
+        let start = &raw const usermode_clock_start;
+        let length = &raw const usermode_clock_end as usize - start as usize;
+        core::slice::from_raw_parts(start, length)
+    }
+}
+
+/// Directory a program named without a path is looked for in.
+const PROGRAM_DIRECTORY: &str = "/bin";
+
+/// Reads the ELF program at `path` from the file system, for [`crate::process::spawn_elf`] and
+/// `exec`. A name without a slash is looked for in `/bin`, and else taken to be one of the
+/// embedded programs, so those run without an initrd.
+pub fn read_program(path: &str) -> Result<Vec<u8>, VfsError> {
+    if path.contains('/') {
+        return crate::vfs::read(path);
+    }
+    match crate::vfs::read(&format!("{}/{}", PROGRAM_DIRECTORY, path)) {
+        Err(VfsError::NotFound) => embedded(path).map(<[u8]>::to_vec).ok_or(VfsError::NotFound),
+        result => result,
+    }
+}
+
+/// The embedded ELF program called `name`.
+fn embedded(name: &str) -> Option<&'static [u8]> {
+    match name {
+        "echo" => Some(echo()),
+        "fork" => Some(fork()),
+        "signal" => Some(signal()),
+        "heap" => Some(heap()),
+        "clock" => Some(clock()),
+        _ => None,
+    }
+}
+
+/// Runs the flat binary `program` in a new process, starting at its first byte, and waits for it
+/// to end in the foreground.
+pub fn run(name: &str, program: &[u8]) -> Result<ExitStatus, ProcessError> {
+    if program.is_empty() || program.len() > MAX_CODE_SIZE {
+        return Err(ProcessError::BadSize);
+    }
+    let process = crate::process::spawn(name, &[name], |space| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        for index in 0..program.len().div_ceil(4096) {
+            let page = Page::containing_address(VirtAddr::new(CODE_START)) + index as u64;
+            space.map_zeroed(page, flags)?;
+        }
+        space.write(VirtAddr::new(CODE_START), program)?;
+        Ok((CODE_START, CODE_START + program.len() as u64))
+    })?;
+    Ok(process.wait_in_foreground())
+}
+
+/// What `usermode_return` hands back to the caller of `usermode_enter`, in `rax` and `rdx`.
+#[repr(C)]
+struct Exit {
+    status: u64,
+    /// The number of the signal that ended the program, or [`NO_SIGNAL`].
+    signal: u64,
+}
+
+const NO_SIGNAL: u64 = u64::MAX;
+
+/// The registers of a program, in the order the entry stubs push them. Most are only there to be
+/// restored.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+#[allow(dead_code)]
+struct Registers {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+}
+
+/// What the `int 0x80` entry stub and `usermode_signal_entry` push, and the interrupt frame above
+/// it.
+#[repr(C)]
+#[allow(dead_code)]
+struct InterruptFrame {
+    registers: Registers,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+/// The user-mode state a thread enters a program with: at its entry point for a new program, or
+/// where the parent made the system call for a forked one.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct Context {
+    registers: Registers,
+    rip: u64,
+    rsp: u64,
+    rflags: u64,
+}
+
+impl Context {
+    /// Starts at `entry` with the stack pointer at `stack` and nothing in the other registers.
+    pub fn new(entry: u64, stack: u64) -> Self {
+        Context {
+            registers: Registers::default(),
+            rip: entry,
+            rsp: stack,
+            rflags: RFlags::INTERRUPT_FLAG.bits(),
+        }
+    }
+
+    /// The flags to return to the program with: those it may change itself, and interrupts
+    /// enabled.
+    fn rflags(&self) -> u64 {
+        let user = RFlags::CARRY_FLAG
+            | RFlags::PARITY_FLAG
+            | RFlags::AUXILIARY_CARRY_FLAG
+            | RFlags::ZERO_FLAG
+            | RFlags::SIGN_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::OVERFLOW_FLAG
+            | RFlags::ALIGNMENT_CHECK;
+        // Bit 1 is reserved and always set.
+        (self.rflags & user.bits()) | RFlags::INTERRUPT_FLAG.bits() | 2
+    }
+}
+
+/// Drops the running thread into user mode with `context` and returns once the program has
+/// exited. The thread must belong to the process the program is loaded in.
+pub(crate) fn enter(context: &Context) -> ExitStatus {
+    let (code, data) = crate::gdt::user_selectors();
+    let context = Context {
+        rflags: context.rflags(),
+        ..context.clone()
+    };
+    let exit = unsafe { usermode_enter(&context, u64::from(code.0), u64::from(data.0)) };
+    interrupts::enable();
+    match Signal::from_number(exit.signal) {
+        Some(signal) => ExitStatus::Signaled(signal),
+        None => ExitStatus::Exited(exit.status),
+    }
+}
+
+/// Called by `usermode_enter` with interrupts disabled, with the kernel stack pointer to return
+/// to, below which interrupts in user mode push their frames.
+extern "C" fn entered(kernel_stack: u64) {
+    crate::thread::set_user_kernel_stack(Some(VirtAddr::new(kernel_stack)));
+}
+
+/// Leaves user mode for good, returning `status`, or the `signal` that ended the program, from
+/// `usermode_enter`.
+fn leave(status: u64, signal: Option<Signal>) -> ! {
+    interrupts::disable();
+    crate::percpu::reset_user_gs_base();
+    let kernel_stack = crate::percpu::kernel_stack();
+    crate::thread::set_user_kernel_stack(None);
+    let signal = signal.map_or(NO_SIGNAL, |signal| u64::from(signal.number()));
+    unsafe { usermode_return(kernel_stack.as_u64(), status, signal) }
+}
+
+/// Sends the program the signal for the exception `vector` if `stack_frame` shows that it
+/// happened in user mode, and returns `true` once the handler may return, to the program's signal
+/// handler. Called first by the exception handlers that would otherwise report a kernel bug.
+pub(crate) fn on_exception(stack_frame: &mut InterruptStackFrame, vector: u8) -> bool {
+    const DIVISION: u8 = ExceptionVector::Division as u8;
+    const INVALID_OPCODE: u8 = ExceptionVector::InvalidOpcode as u8;
+    const X87_FLOATING_POINT: u8 = ExceptionVector::X87FloatingPoint as u8;
+    const ALIGNMENT_CHECK: u8 = ExceptionVector::AlignmentCheck as u8;
+    const SIMD_FLOATING_POINT: u8 = ExceptionVector::SimdFloatingPoint as u8;
+    if stack_frame.code_segment & 3 != 3 {
+        return false;
+    }
+    let signal = match vector {
+        DIVISION | X87_FLOATING_POINT | SIMD_FLOATING_POINT => Signal::FloatingPoint,
+        INVALID_OPCODE => Signal::IllegalInstruction,
+        ALIGNMENT_CHECK => Signal::Bus,
+        _ => Signal::SegmentationFault,
+    };
+    crate::serial_println!(
+        "usermode: {} at {:?}, sending {}",
+        crate::interruptsa::vector_name(vector),
+        stack_frame.instruction_pointer,
+        signal.name()
+    );
+    match crate::process::current() {
+        Some(process) => process.with_signals(|signals| signals.force(signal)),
+        None => leave(u64::MAX, Some(signal)),
+    }
+    signal::on_return(stack_frame);
+    true
+}
@@ -0,0 +1,238 @@
+//! Delivering signals to programs.
+//!
+//! The pending signals of a process are taken whenever the kernel is about to return to the
+//! program: at the end of a system call, and after an interrupt or exception in user mode. A
+//! signal whose action is to end the process leaves user mode for good. For one with a handler,
+//! the program's registers are saved on its stack, below the red zone, and the handler is called
+//! with the signal number in `rdi` and the stack pointer 8 bytes off a 16-byte boundary, as after
+//! a `call`. It returns to the restorer the program registered along with it, which must make the
+//! [`SYS_SIGRETURN`] call with `int 0x80` to load the saved registers again; `syscall` would lose
+//! `rcx` and `r11`. Above the return address the handler finds the saved [`Context`], and the
+//! signal stays blocked until it returns.
+//!
+//! Interrupt and exception handlers only see the interrupt frame, not the program's registers.
+//! So [`on_return`] points that frame at a stub in ring 0 instead, which saves the registers like
+//! the `int 0x80` entry does and delivers the signal from there.
+//!
+//! [`SYS_SIGRETURN`]: super::syscall::SYS_SIGRETURN
+
+use core::arch::global_asm;
+use core::cell::Cell;
+use core::mem::size_of;
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
+use x86_64::VirtAddr;
+
+use super::uaccess::{self, UserError};
+use super::{Context, InterruptFrame, USER_END, USER_START};
+use crate::per_cpu;
+use crate::process::{self, Action, Signal};
+
+/// Bytes below the stack pointer that the program may use without moving it, as in the System V
+/// ABI.
+const RED_ZONE: u64 = 128;
+
+/// What is saved on the program's stack while a handler runs.
+#[repr(C)]
+#[derive(Debug, Clone)]
+struct SignalFrame {
+    context: Context,
+    /// The signals blocked before the handler was called.
+    blocked: u64,
+}
+
+per_cpu! {
+    /// The interrupt frame [`on_return`] replaced, for [`interrupted`] to return to.
+    static INTERRUPTED: Cell<Option<InterruptStackFrameValue>> = Cell::new(None);
+}
+
+global_asm!(
+    // Where `on_return` sends an interrupt or exception handler that would have returned to the
+    // program, with the program's registers, interrupts disabled and the stack pointer at the top
+    // of the thread's kernel stack.
+    ".global usermode_signal_entry",
+    "usermode_signal_entry:",
+    // Room for the interrupt frame to return to the program with.
+    "sub rsp, 40",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "call {interrupted}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // The handler `on_return` redirected left the kernel's GS base loaded.
+    "swapgs",
+    "iretq",
+    interrupted = sym interrupted,
+);
+
+extern "C" {
+    fn usermode_signal_entry();
+}
+
+/// Makes an interrupt or exception handler that is about to return to user mode, as
+/// `stack_frame` shows, return to `usermode_signal_entry` instead if the running process has a
+/// signal to take. Called with interrupts disabled.
+pub(crate) fn on_return(stack_frame: &mut InterruptStackFrame) {
+    if stack_frame.code_segment & 3 != 3 {
+        return;
+    }
+    let pending = process::current()
+        .is_some_and(|process| process.with_signals(|signals| signals.deliverable()));
+    if !pending {
+        return;
+    }
+    INTERRUPTED.with(|interrupted| interrupted.set(Some(**stack_frame)));
+    let (code, data) = crate::gdt::kernel_selectors();
+    let entry = VirtAddr::new(usermode_signal_entry as usize as u64);
+    let stack = crate::percpu::kernel_stack();
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = entry;
+            frame.code_segment = u64::from(code.0);
+            // Only the reserved bit, so interrupts stay disabled.
+            frame.cpu_flags = 2;
+            frame.stack_pointer = stack;
+            frame.stack_segment = u64::from(data.0);
+        })
+    };
+}
+
+/// Called by `usermode_signal_entry` to take the signal [`on_return`] found, and return to the
+/// program through `frame`.
+extern "C" fn interrupted(frame: &mut InterruptFrame) {
+    let interrupted = INTERRUPTED
+        .with(Cell::take)
+        .expect("interrupt frame saved by on_return");
+    let mut context = Context {
+        registers: frame.registers.clone(),
+        rip: interrupted.instruction_pointer.as_u64(),
+        rsp: interrupted.stack_pointer.as_u64(),
+        rflags: interrupted.cpu_flags,
+    };
+    interrupts::enable();
+    deliver(&mut context);
+    interrupts::disable();
+    frame.registers = context.registers.clone();
+    frame.rip = context.rip;
+    frame.cs = interrupted.code_segment;
+    frame.rflags = context.rflags();
+    frame.rsp = context.rsp;
+    frame.ss = interrupted.stack_segment;
+}
+
+/// Takes the next signal of the running process, if any, before it returns to user mode with
+/// `context`. Ends the process, or changes `context` to call the signal's handler.
+pub(super) fn deliver(context: &mut Context) {
+    let Some(process) = process::current() else {
+        return;
+    };
+    let taken = process.with_signals(|signals| {
+        let (signal, action) = signals.take()?;
+        let blocked = signals.blocked();
+        if let Action::Handler { .. } = action {
+            signals.set_blocked(blocked | signal.mask());
+        }
+        Some((signal, action, blocked))
+    });
+    drop(process);
+    match taken {
+        None => {}
+        Some((signal, Action::Handler { entry, restorer }, blocked)) => {
+            if call_handler(context, signal, entry, restorer, blocked).is_err() {
+                crate::serial_println!(
+                    "usermode: no room on the stack for the {} handler, program ended",
+                    signal.name()
+                );
+                super::leave(u64::MAX, Some(Signal::SegmentationFault))
+            }
+        }
+        Some((signal, _, _)) => super::leave(u64::MAX, Some(signal)),
+    }
+}
+
+/// Saves `context` and the `blocked` signals on the program's stack and changes `context` to
+/// call the handler at `entry` for `signal`, returning to `restorer`.
+fn call_handler(
+    context: &mut Context,
+    signal: Signal,
+    entry: u64,
+    restorer: u64,
+    blocked: u32,
+) -> Result<(), UserError> {
+    let frame = SignalFrame {
+        context: context.clone(),
+        blocked: u64::from(blocked),
+    };
+    let address = context
+        .rsp
+        .checked_sub(RED_ZONE + size_of::<SignalFrame>() as u64)
+        .ok_or(UserError::BadAddress)?
+        & !15;
+    uaccess::copy_to_user(address, frame.as_bytes())?;
+    uaccess::write_u64(address - 8, restorer)?;
+    context.rip = entry;
+    context.rsp = address - 8;
+    context.rflags &= !RFlags::DIRECTION_FLAG.bits();
+    context.registers.rdi = u64::from(signal.number());
+    Ok(())
+}
+
+/// Restores the context and blocked signals a handler was called with, once it has returned to
+/// the restorer, which leaves the stack pointer at the saved frame. Returns the saved `rax`, for
+/// the system call to return. Ends the program if the frame is gone or was made to return
+/// outside the program.
+pub(super) fn sigreturn(context: &mut Context) -> u64 {
+    let mut bytes = [0; size_of::<SignalFrame>()];
+    let frame = uaccess::copy_from_user(&mut bytes, context.rsp).map(|()| {
+        // Every bit pattern is a valid frame, as it is made of integers only.
+        unsafe { bytes.as_ptr().cast::<SignalFrame>().read_unaligned() }
+    });
+    let frame = match frame {
+        Ok(frame) if (USER_START..USER_END).contains(&frame.context.rip) => frame,
+        _ => {
+            crate::serial_println!("usermode: bad signal frame, program ended");
+            super::leave(u64::MAX, Some(Signal::SegmentationFault))
+        }
+    };
+    if let Some(process) = process::current() {
+        process.with_signals(|signals| signals.set_blocked(frame.blocked as u32));
+    }
+    *context = frame.context;
+    context.registers.rax
+}
+
+impl SignalFrame {
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts((self as *const Self).cast::<u8>(), size_of::<Self>())
+        }
+    }
+}
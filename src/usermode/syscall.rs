@@ -0,0 +1,579 @@
+//! The system call interface.
+//!
+//! Programs enter the kernel with the `syscall` instruction, or with `int 0x80` where that is
+//! more convenient. The call number goes in `rax` and up to three arguments in `rdi`, `rsi` and
+//! `rdx`. The result comes back in `rax`, and [`SYSCALL_ERROR`] there means the call failed. All
+//! other registers are preserved, except `rcx` and `r11`, which the `syscall` instruction itself
+//! overwrites.
+//!
+//! Both entry stubs start with a `swapgs`, to reach the per-CPU area through the kernel's GS
+//! base, and swap back on the way out. `syscall` leaves the stack pointer alone, so its stub then
+//! switches to the thread's kernel stack, found in the per-CPU area. Calls run with interrupts
+//! enabled and may block. They see the program's registers as a [`Context`], which
+//! [`SYS_FORK`] copies into the child and [`SYS_EXEC`] replaces. Pointers they get are never
+//! dereferenced, only copied from and to with the helpers in [`super::uaccess`]. Before a call
+//! returns, the process takes a pending signal, see [`super::signal`].
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Efer, EferFlags};
+use x86_64::registers::model_specific::{LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use super::{uaccess, Context, InterruptFrame, Registers};
+use crate::process::{self, Action, File, ProcessError, ProcessId, Signal};
+
+/// Interrupt vector of system calls made with `int`.
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Writes `rdx` bytes from `rsi` to the file open under the descriptor in `rdi`. Returns the
+/// number of bytes written, which is at most 4096.
+pub const SYS_WRITE: u64 = 0;
+
+/// Ends the program with the status in `rdi`.
+pub const SYS_EXIT: u64 = 1;
+
+/// Waits for a typed character and returns it as a Unicode scalar value.
+pub const SYS_READ_KEY: u64 = 2;
+
+/// Sleeps for `rdi` milliseconds. Returns 0.
+pub const SYS_SLEEP_MS: u64 = 3;
+
+/// Duplicates the process. Returns the child's process ID in the parent and 0 in the child, which
+/// continues from the same point with a copy-on-write copy of the parent's memory.
+pub const SYS_FORK: u64 = 4;
+
+/// Replaces the program with the one at the path in `rdi`, started with the arguments in the
+/// null-terminated array at `rsi`. A name without a slash is looked for in `/bin` and among the
+/// embedded programs, see [`super::read_program`]. Does not return on success.
+pub const SYS_EXEC: u64 = 5;
+
+/// Waits for the child with the process ID in `rdi`, or for any child if it is 0, to end, and
+/// returns the child's process ID. Stores how it ended at `rsi` unless that is 0, packed by
+/// [`process::ExitStatus::to_wait_status`].
+pub const SYS_WAIT_PID: u64 = 6;
+
+/// Reads up to `rdx` bytes, but no more than 4096, from the file open under the descriptor in
+/// `rdi` to `rsi`. Returns the number of bytes read, 0 at the end of the file.
+pub const SYS_READ: u64 = 7;
+
+/// Opens the file at the path in `rdi` with the flags in `rsi`, a combination of
+/// [`OPEN_CLOSE_ON_EXEC`] or 0. Returns the lowest free file descriptor, now referring to it.
+pub const SYS_OPEN: u64 = 8;
+
+/// Closes the file descriptor in `rdi`. Returns 0.
+pub const SYS_CLOSE: u64 = 9;
+
+/// Sets what happens when the process gets the signal numbered `rdi`: the default action if `rsi`
+/// is [`SIGNAL_DEFAULT`], nothing if it is [`SIGNAL_IGNORE`], and otherwise a call of the handler
+/// at `rsi`, which returns to the restorer at `rdx`. Returns 0.
+pub const SYS_SIGACTION: u64 = 10;
+
+/// Returns from a signal handler to where the program was when the signal arrived. Made by the
+/// restorer, with the stack pointer where the handler's return left it.
+pub const SYS_SIGRETURN: u64 = 11;
+
+/// Sends the signal numbered `rsi` to the process with the ID in `rdi`. Returns 0.
+pub const SYS_KILL: u64 = 12;
+
+/// Moves the program break, the end of the heap, to `rdi` and returns it. Returns the break
+/// without moving it if `rdi` is 0.
+pub const SYS_BRK: u64 = 13;
+
+/// Moves the program break by the signed number of bytes in `rdi` and returns where it was.
+pub const SYS_SBRK: u64 = 14;
+
+/// Maps `rdi` bytes, rounded up to whole pages, of zeroed memory with the protection in `rsi`, a
+/// combination of [`PROT_READ`], [`PROT_WRITE`] and [`PROT_EXEC`], and returns its address. The
+/// memory may not be both writable and executable.
+pub const SYS_MMAP: u64 = 15;
+
+/// Unmaps the memory mapped with [`SYS_MMAP`] in the `rsi` bytes at `rdi`, which must be
+/// page-aligned. Returns 0.
+pub const SYS_MUNMAP: u64 = 16;
+
+/// Protection of [`SYS_MMAP`] that makes the memory readable, which it always is.
+pub const PROT_READ: u64 = 1;
+
+/// Protection of [`SYS_MMAP`] that makes the memory writable.
+pub const PROT_WRITE: u64 = 2;
+
+/// Protection of [`SYS_MMAP`] that makes the memory executable.
+pub const PROT_EXEC: u64 = 4;
+
+/// Handler of [`SYS_SIGACTION`] that selects the default action.
+pub const SIGNAL_DEFAULT: u64 = 0;
+
+/// Handler of [`SYS_SIGACTION`] that ignores the signal.
+pub const SIGNAL_IGNORE: u64 = 1;
+
+/// Flag of [`SYS_OPEN`] that closes the file when the process calls [`SYS_EXEC`].
+pub const OPEN_CLOSE_ON_EXEC: u64 = 1;
+
+/// Returned in `rax` for an unknown call or bad arguments.
+pub const SYSCALL_ERROR: u64 = u64::MAX;
+
+/// Longest string a call takes, including the terminating null.
+const MAX_STRING: usize = 256;
+
+/// Most bytes [`SYS_READ`] and [`SYS_WRITE`] transfer at once.
+const MAX_TRANSFER: u64 = 4096;
+
+/// Most arguments [`SYS_EXEC`] takes.
+const MAX_ARGS: usize = 32;
+
+/// A call gets the arguments and the program's registers, which it may change.
+type Call = fn(args: [u64; 3], context: &mut Context) -> u64;
+
+const CALL_COUNT: usize = 17;
+
+/// The calls, indexed by number.
+const CALLS: [Call; CALL_COUNT] = {
+    let mut calls: [Call; CALL_COUNT] = [unknown; CALL_COUNT];
+    calls[SYS_WRITE as usize] = write;
+    calls[SYS_EXIT as usize] = exit;
+    calls[SYS_READ_KEY as usize] = read_key;
+    calls[SYS_SLEEP_MS as usize] = sleep_ms;
+    calls[SYS_FORK as usize] = fork;
+    calls[SYS_EXEC as usize] = exec;
+    calls[SYS_WAIT_PID as usize] = wait_pid;
+    calls[SYS_READ as usize] = read;
+    calls[SYS_OPEN as usize] = open;
+    calls[SYS_CLOSE as usize] = close;
+    calls[SYS_SIGACTION as usize] = sigaction;
+    calls[SYS_SIGRETURN as usize] = sigreturn;
+    calls[SYS_KILL as usize] = kill;
+    calls[SYS_BRK as usize] = brk;
+    calls[SYS_SBRK as usize] = sbrk;
+    calls[SYS_MMAP as usize] = mmap;
+    calls[SYS_MUNMAP as usize] = munmap;
+    calls
+};
+
+global_asm!(
+    // The target of `syscall`, with interrupts disabled by SFMASK, the user stack still loaded,
+    // the return address in rcx and the flags in r11.
+    ".global usermode_syscall_entry",
+    "usermode_syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_stack}], rsp",
+    "mov rsp, gs:[{kernel_stack}]",
+    "push qword ptr gs:[{user_stack}]",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "call {syscall}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // Interrupts are disabled again, so nothing runs in ring 0 on the user stack.
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    // The int 0x80 gate, which saves the registers the same way below the interrupt frame.
+    ".global usermode_int80_entry",
+    "usermode_int80_entry:",
+    // Calls from ring 0 are refused, but still must not swap GS.
+    "test qword ptr [rsp + 8], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    // The gate keeps the program's alignment check flag, which would lift SMAP in ring 0.
+    "pushfq",
+    "btr qword ptr [rsp], {alignment_check}",
+    "popfq",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "call {interrupt}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "test qword ptr [rsp + 8], 3",
+    "jz 3f",
+    "swapgs",
+    "3:",
+    "iretq",
+    alignment_check = const RFlags::ALIGNMENT_CHECK.bits().trailing_zeros(),
+    user_stack = const crate::percpu::USER_STACK_OFFSET,
+    kernel_stack = const crate::percpu::KERNEL_STACK_OFFSET,
+    syscall = sym syscall,
+    interrupt = sym interrupt,
+);
+
+extern "C" {
+    fn usermode_syscall_entry();
+    fn usermode_int80_entry();
+}
+
+/// Enables `syscall` on the calling CPU and points it at the entry stub. Every CPU calls this
+/// once its GDT is loaded.
+pub fn init_cpu() {
+    let (kernel_code, kernel_data) = crate::gdt::kernel_selectors();
+    let (user_code, user_data) = crate::gdt::user_selectors();
+    Star::write(user_code, user_data, kernel_code, kernel_data).expect("GDT laid out for sysret");
+    LStar::write(VirtAddr::new(usermode_syscall_entry as usize as u64));
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+/// Address of the entry stub of [`SYSCALL_VECTOR`], for the IDT.
+pub(crate) fn interrupt_entry() -> VirtAddr {
+    VirtAddr::new(usermode_int80_entry as usize as u64)
+}
+
+/// What the `syscall` entry stub pushes.
+#[repr(C)]
+struct SyscallFrame {
+    registers: Registers,
+    rsp: u64,
+}
+
+/// Dispatches a system call made with `int 0x80`.
+extern "C" fn interrupt(frame: &mut InterruptFrame) {
+    crate::interruptsa::count(SYSCALL_VECTOR);
+    if frame.cs & 3 != 3 {
+        frame.registers.rax = SYSCALL_ERROR;
+        return;
+    }
+    let mut context = Context {
+        registers: frame.registers.clone(),
+        rip: frame.rip,
+        rsp: frame.rsp,
+        rflags: frame.rflags,
+    };
+    dispatch(&mut context);
+    frame.rip = context.rip;
+    frame.rsp = context.rsp;
+    frame.rflags = context.rflags();
+    frame.registers = context.registers;
+}
+
+/// Dispatches a system call made with `syscall`, which keeps the return address in `rcx` and the
+/// flags in `r11`, and returns with `sysret`, which takes them from there.
+extern "C" fn syscall(frame: &mut SyscallFrame) {
+    let mut context = Context {
+        registers: frame.registers.clone(),
+        rip: frame.registers.rcx,
+        rsp: frame.rsp,
+        rflags: frame.registers.r11,
+    };
+    dispatch(&mut context);
+    frame.rsp = context.rsp;
+    frame.registers = context.registers.clone();
+    frame.registers.rcx = context.rip;
+    frame.registers.r11 = context.rflags();
+}
+
+/// Runs the call in `context` with interrupts enabled, leaving the result in `rax`, and takes a
+/// pending signal.
+fn dispatch(context: &mut Context) {
+    let registers = &context.registers;
+    let args = [registers.rdi, registers.rsi, registers.rdx];
+    let number = registers.rax;
+    interrupts::enable();
+    context.registers.rax = usize::try_from(number)
+        .ok()
+        .and_then(|number| CALLS.get(number))
+        .map_or(SYSCALL_ERROR, |call| call(args, context));
+    super::signal::deliver(context);
+    interrupts::disable();
+}
+
+fn unknown(_args: [u64; 3], _context: &mut Context) -> u64 {
+    SYSCALL_ERROR
+}
+
+/// Copies the strings the null-terminated array of pointers at `address` points to out of the
+/// calling process.
+fn user_strings(address: u64) -> Option<Vec<String>> {
+    let mut strings = Vec::new();
+    for index in 0..=MAX_ARGS as u64 {
+        let pointer = uaccess::read_u64(address.checked_add(index * 8)?).ok()?;
+        match pointer {
+            0 => return Some(strings),
+            string if strings.len() < MAX_ARGS => {
+                strings.push(uaccess::read_string(string, MAX_STRING).ok()?)
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// The file the calling process has open under `fd`.
+fn file(fd: u64) -> Option<File> {
+    let process = process::current()?;
+    let file = process.files().get(usize::try_from(fd).ok()?).ok();
+    file
+}
+
+fn write([fd, address, length]: [u64; 3], _context: &mut Context) -> u64 {
+    let Some(file) = file(fd) else {
+        return SYSCALL_ERROR;
+    };
+    let mut bytes = vec![0; length.min(MAX_TRANSFER) as usize];
+    if uaccess::copy_from_user(&mut bytes, address).is_err() {
+        return SYSCALL_ERROR;
+    }
+    file.write(&bytes)
+        .map_or(SYSCALL_ERROR, |written| written as u64)
+}
+
+fn read([fd, address, length]: [u64; 3], _context: &mut Context) -> u64 {
+    let Some(file) = file(fd) else {
+        return SYSCALL_ERROR;
+    };
+    let mut bytes = vec![0; length.min(MAX_TRANSFER) as usize];
+    // Fail before waiting for input that would be lost.
+    if uaccess::check_range(address, bytes.len()).is_err() {
+        return SYSCALL_ERROR;
+    }
+    let Ok(read) = file.read(&mut bytes) else {
+        return SYSCALL_ERROR;
+    };
+    match uaccess::copy_to_user(address, &bytes[..read]) {
+        Ok(()) => read as u64,
+        Err(_) => SYSCALL_ERROR,
+    }
+}
+
+fn open([path, flags, _]: [u64; 3], _context: &mut Context) -> u64 {
+    if flags & !OPEN_CLOSE_ON_EXEC != 0 {
+        return SYSCALL_ERROR;
+    }
+    let Ok(path) = uaccess::read_string(path, MAX_STRING) else {
+        return SYSCALL_ERROR;
+    };
+    let (Ok(file), Some(process)) = (File::open(&path), process::current()) else {
+        return SYSCALL_ERROR;
+    };
+    let fd = process
+        .files()
+        .insert(file, flags & OPEN_CLOSE_ON_EXEC != 0);
+    fd.map_or(SYSCALL_ERROR, |fd| fd as u64)
+}
+
+fn close([fd, _, _]: [u64; 3], _context: &mut Context) -> u64 {
+    let Some(process) = process::current() else {
+        return SYSCALL_ERROR;
+    };
+    let closed = usize::try_from(fd)
+        .ok()
+        .and_then(|fd| process.files().remove(fd).ok());
+    closed.map_or(SYSCALL_ERROR, |_| 0)
+}
+
+fn exit([status, _, _]: [u64; 3], _context: &mut Context) -> u64 {
+    super::leave(status, None)
+}
+
+fn read_key(_args: [u64; 3], _context: &mut Context) -> u64 {
+    u64::from(crate::keyboard::read_char())
+}
+
+fn sleep_ms([ms, _, _]: [u64; 3], _context: &mut Context) -> u64 {
+    crate::thread::sleep(Duration::from_millis(ms));
+    0
+}
+
+fn fork(_args: [u64; 3], context: &mut Context) -> u64 {
+    let mut child = context.clone();
+    child.registers.rax = 0;
+    match process::fork(child) {
+        Ok(child) => child.id().as_u64(),
+        Err(_) => SYSCALL_ERROR,
+    }
+}
+
+fn exec([path, args, _]: [u64; 3], context: &mut Context) -> u64 {
+    let path = uaccess::read_string(path, MAX_STRING).ok();
+    let (Some(path), Some(strings)) = (path, user_strings(args)) else {
+        return SYSCALL_ERROR;
+    };
+    let Ok(image) = super::read_program(&path) else {
+        return SYSCALL_ERROR;
+    };
+    let args: Vec<&str> = strings.iter().map(String::as_str).collect();
+    match process::exec(&image, &args) {
+        Ok(new) => {
+            *context = new;
+            0
+        }
+        Err(ProcessError::Paging(error)) => {
+            crate::serial_println!("usermode: exec of {} failed: {:?}", path, error);
+            drop(args);
+            drop(image);
+            drop(strings);
+            drop(path);
+            // There is no program left to return to.
+            super::leave(SYSCALL_ERROR, None)
+        }
+        Err(_) => SYSCALL_ERROR,
+    }
+}
+
+fn wait_pid([pid, status, _]: [u64; 3], _context: &mut Context) -> u64 {
+    if status != 0 && uaccess::check_range(status, 8).is_err() {
+        return SYSCALL_ERROR;
+    }
+    let pid = (pid != 0).then(|| ProcessId::from_u64(pid));
+    let Ok((child, ended)) = process::wait_child(pid) else {
+        return SYSCALL_ERROR;
+    };
+    // The child is gone either way, so its ID is returned even if the status cannot be stored.
+    if status != 0 {
+        let _ = uaccess::write_u64(status, ended.to_wait_status());
+    }
+    child.as_u64()
+}
+
+fn sigaction([signal, handler, restorer]: [u64; 3], _context: &mut Context) -> u64 {
+    let action = match handler {
+        SIGNAL_DEFAULT => Action::Default,
+        SIGNAL_IGNORE => Action::Ignore,
+        entry => Action::Handler { entry, restorer },
+    };
+    let user = super::USER_START..super::USER_END;
+    if let Action::Handler { entry, restorer } = action {
+        if !user.contains(&entry) || !user.contains(&restorer) {
+            return SYSCALL_ERROR;
+        }
+    }
+    let (Some(signal), Some(process)) = (Signal::from_number(signal), process::current()) else {
+        return SYSCALL_ERROR;
+    };
+    let set = process.with_signals(|signals| signals.set_action(signal, action));
+    set.map_or(SYSCALL_ERROR, |()| 0)
+}
+
+fn sigreturn(_args: [u64; 3], context: &mut Context) -> u64 {
+    super::signal::sigreturn(context)
+}
+
+fn kill([pid, signal, _]: [u64; 3], _context: &mut Context) -> u64 {
+    let process = process::get(ProcessId::from_u64(pid));
+    let (Some(process), Some(signal)) = (process, Signal::from_number(signal)) else {
+        return SYSCALL_ERROR;
+    };
+    process.signal(signal);
+    0
+}
+
+fn brk([address, _, _]: [u64; 3], _context: &mut Context) -> u64 {
+    let Some(process) = process::current() else {
+        return SYSCALL_ERROR;
+    };
+    let mut memory = process.memory();
+    if address != 0 {
+        let moved = memory.set_brk(&mut process.address_space(), address);
+        if moved.is_err() {
+            return SYSCALL_ERROR;
+        }
+    }
+    memory.brk()
+}
+
+fn sbrk([increment, _, _]: [u64; 3], _context: &mut Context) -> u64 {
+    let Some(process) = process::current() else {
+        return SYSCALL_ERROR;
+    };
+    let mut memory = process.memory();
+    let old = memory.brk();
+    let Some(new) = old.checked_add_signed(increment as i64) else {
+        return SYSCALL_ERROR;
+    };
+    match memory.set_brk(&mut process.address_space(), new) {
+        Ok(()) => old,
+        Err(_) => SYSCALL_ERROR,
+    }
+}
+
+fn mmap([length, protection, _]: [u64; 3], _context: &mut Context) -> u64 {
+    if protection & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return SYSCALL_ERROR;
+    }
+    let mut flags = PageTableFlags::PRESENT;
+    if protection & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if protection & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let Some(process) = process::current() else {
+        return SYSCALL_ERROR;
+    };
+    let mut memory = process.memory();
+    let mapped = memory.map(&mut process.address_space(), length, flags);
+    mapped.unwrap_or(SYSCALL_ERROR)
+}
+
+fn munmap([address, length, _]: [u64; 3], _context: &mut Context) -> u64 {
+    let Some(process) = process::current() else {
+        return SYSCALL_ERROR;
+    };
+    let mut memory = process.memory();
+    let unmapped = memory.unmap(&mut process.address_space(), address, length);
+    unmapped.map_or(SYSCALL_ERROR, |()| 0)
+}
@@ -0,0 +1,199 @@
+//! Copying to and from user memory.
+//!
+//! System calls get pointers from the program, which may point anywhere. The helpers here check
+//! that a range lies in the user part of the address space and then copy it with a single
+//! instruction the page fault handler knows about: if it faults on memory the program has not
+//! mapped, or may not write, [`fixup`] resumes it at the instruction after, and the copy reports
+//! [`UserError::Fault`] instead of bringing down the kernel. Faults on copy-on-write pages are
+//! resolved as usual, so copies into them just work.
+//!
+//! The user memory must be that of the running process, whose page tables are loaded during its
+//! system calls.
+//!
+//! Where the CPU supports them, [`init_cpu`] turns on SMEP, which faults on the kernel running
+//! code from user pages, and SMAP, which faults on any kernel access to user pages while the
+//! alignment check flag is clear. Only the copy sets that flag, with `stac`, and clears it again
+//! with `clac`, so a stray user pointer anywhere else brings down the kernel at once instead of
+//! being read or written. Programs may set the flag too, so every entry from them clears it: the
+//! `syscall` and `int 0x80` entries, and the interrupt and exception handlers through
+//! [`clear_access_flag`].
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use super::{USER_END, USER_START};
+
+/// Errors returned when copying to or from user memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserError {
+    /// The range reaches outside the user part of the address space.
+    BadAddress,
+    /// Part of the range is not mapped, or not writable for a copy to it.
+    Fault,
+    /// A string has no terminating null within the limit.
+    TooLong,
+    /// A string is not valid UTF-8.
+    NotUtf8,
+}
+
+/// CPUID leaf 7 EBX bits reporting SMEP and SMAP.
+const CPUID_SMEP: u32 = 1 << 7;
+const CPUID_SMAP: u32 = 1 << 20;
+
+/// Set once SMAP is on, which every CPU supports if the boot CPU does. Without it `stac` and
+/// `clac` do not exist.
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+global_asm!(
+    // uaccess_copy(to, from, length) -> bytes not copied. A fault in the `rep movsb` leaves the
+    // count of bytes still to copy in rcx and continues right after it.
+    ".global uaccess_copy",
+    ".global uaccess_copy_fault",
+    ".global uaccess_copy_fixup",
+    "uaccess_copy:",
+    "mov rcx, rdx",
+    "uaccess_copy_fault:",
+    "rep movsb",
+    "uaccess_copy_fixup:",
+    "mov rax, rcx",
+    "ret",
+);
+
+extern "C" {
+    fn uaccess_copy(to: *mut u8, from: *const u8, length: usize) -> usize;
+    static uaccess_copy_fault: u8;
+    static uaccess_copy_fixup: u8;
+}
+
+/// Turns on SMEP and SMAP on the calling CPU, as far as it supports them. Every CPU calls this
+/// once at boot.
+pub fn init_cpu() {
+    let features = if __cpuid(0).eax >= 7 {
+        __cpuid(7).ebx
+    } else {
+        0
+    };
+    let mut flags = Cr4Flags::empty();
+    if features & CPUID_SMEP != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features & CPUID_SMAP != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+        SMAP.store(true, Ordering::Relaxed);
+    }
+    unsafe { Cr4::update(|cr4| cr4.insert(flags)) };
+}
+
+/// Whether SMAP is on.
+pub fn smap_enabled() -> bool {
+    SMAP.load(Ordering::Relaxed)
+}
+
+/// Clears the alignment check flag if SMAP is on. Interrupt and exception gates keep the flag of
+/// the code they interrupt, which a program may set, so every handler calls this on entry. The
+/// flag comes back with the rest of the interrupted code's flags when the handler returns.
+#[inline(always)]
+pub(crate) fn clear_access_flag() {
+    if smap_enabled() {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+}
+
+/// Copies `length` bytes with user memory made accessible for the duration, returning how many
+/// were not copied.
+unsafe fn copy(to: *mut u8, from: *const u8, length: usize) -> usize {
+    let smap = smap_enabled();
+    if smap {
+        asm!("stac", options(nostack));
+    }
+    let left = uaccess_copy(to, from, length);
+    if smap {
+        asm!("clac", options(nostack));
+    }
+    left
+}
+
+/// Returns whether the `length` bytes at `address` lie in the user part of the address space.
+pub fn check_range(address: u64, length: usize) -> Result<(), UserError> {
+    let end = address
+        .checked_add(length as u64)
+        .ok_or(UserError::BadAddress)?;
+    if address < USER_START || end > USER_END {
+        return Err(UserError::BadAddress);
+    }
+    Ok(())
+}
+
+/// Fills `to` from user memory at `from`.
+pub fn copy_from_user(to: &mut [u8], from: u64) -> Result<(), UserError> {
+    check_range(from, to.len())?;
+    match unsafe { copy(to.as_mut_ptr(), from as *const u8, to.len()) } {
+        0 => Ok(()),
+        _ => Err(UserError::Fault),
+    }
+}
+
+/// Copies `from` to user memory at `to`. Part of it may have been copied if that fails.
+pub fn copy_to_user(to: u64, from: &[u8]) -> Result<(), UserError> {
+    check_range(to, from.len())?;
+    match unsafe { copy(to as *mut u8, from.as_ptr(), from.len()) } {
+        0 => Ok(()),
+        _ => Err(UserError::Fault),
+    }
+}
+
+pub fn read_u64(address: u64) -> Result<u64, UserError> {
+    let mut bytes = [0; 8];
+    copy_from_user(&mut bytes, address)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn write_u64(address: u64, value: u64) -> Result<(), UserError> {
+    copy_to_user(address, &value.to_le_bytes())
+}
+
+/// Copies the null-terminated string at `address`, of at most `max` bytes with the null.
+pub fn read_string(address: u64, max: usize) -> Result<String, UserError> {
+    let mut bytes = Vec::new();
+    let mut next = address;
+    while bytes.len() < max {
+        // Read up to the end of the page, which is mapped if the first byte is.
+        let length = (4096 - (next % 4096) as usize).min(max - bytes.len());
+        let start = bytes.len();
+        bytes.resize(start + length, 0);
+        copy_from_user(&mut bytes[start..], next)?;
+        if let Some(null) = bytes[start..].iter().position(|&byte| byte == 0) {
+            bytes.truncate(start + null);
+            return String::from_utf8(bytes).map_err(|_| UserError::NotUtf8);
+        }
+        next += length as u64;
+    }
+    Err(UserError::TooLong)
+}
+
+/// Resumes a copy that faulted on user memory after the faulting instruction. Returns `false` if
+/// `stack_frame` shows the fault happened anywhere else. Called by the page fault handler once
+/// it is clear the fault cannot be resolved.
+pub(crate) fn fixup(stack_frame: &mut InterruptStackFrame) -> bool {
+    let (fault, resume) = unsafe {
+        (
+            VirtAddr::new(&raw const uaccess_copy_fault as u64),
+            VirtAddr::new(&raw const uaccess_copy_fixup as u64),
+        )
+    };
+    if stack_frame.code_segment & 3 != 0 || stack_frame.instruction_pointer != fault {
+        return false;
+    }
+    unsafe {
+        stack_frame
+            .as_mut()
+            .update(|frame| frame.instruction_pointer = resume)
+    };
+    true
+}
@@ -0,0 +1,489 @@
+//! The virtual file system: one tree of paths over every mounted file system.
+//!
+//! A file system driver implements [`FileSystem`], whose root is a [`Dir`]. Directories look up
+//! their entries by name and hand out [`Node`]s, each either a [`File`] or another [`Dir`]; both
+//! are [`Inode`]s with [`Metadata`]. [`mount`] attaches a file system at an absolute path, and the
+//! functions here resolve a path by picking the mount with the longest matching prefix and walking
+//! the rest of the path from its root. A mount point does not need to exist in the file system
+//! around it; directories list the mount points beneath them as subdirectories.
+//!
+//! Paths are absolute. `.` and `..` are resolved by the path text alone, before any file system
+//! sees them.
+//!
+//! [`fat32`] reads and writes FAT32 volumes, [`ext2`] reads ext2 ones, and [`tar`] serves tar
+//! archives held in memory, like the initial ramdisk. [`devfs`] puts the devices under `/dev`,
+//! [`procfs`] the kernel's statistics under `/proc`, and [`tmpfs`] keeps files in memory at
+//! `/tmp`.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::block::cache::{self, BlockCache};
+use crate::block::{self, BlockDevice, BlockError};
+
+pub mod devfs;
+pub mod ext2;
+pub mod fat32;
+pub mod procfs;
+pub mod tar;
+pub mod tmpfs;
+
+/// Errors returned by file system operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    /// Nothing exists at the path.
+    NotFound,
+    /// A path component that should be a directory is a file.
+    NotADirectory,
+    /// The operation needs a file but found a directory.
+    IsADirectory,
+    /// There is already something at the path.
+    AlreadyExists,
+    /// The directory to remove still has entries.
+    NotEmpty,
+    /// The path is not absolute or has an empty name.
+    InvalidPath,
+    /// The file system cannot be changed.
+    ReadOnly,
+    /// The file system is full.
+    NoSpace,
+    /// The file system's data makes no sense.
+    Corrupt,
+    /// A file system is mounted there already, or is busy.
+    Busy,
+    /// The file system uses features the driver does not know.
+    Unsupported,
+    /// The device under the file system failed.
+    Block(BlockError),
+}
+
+impl From<BlockError> for VfsError {
+    fn from(error: BlockError) -> Self {
+        VfsError::Block(error)
+    }
+}
+
+/// What an inode is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    /// A device read and written byte by byte, like the console.
+    CharDevice,
+    /// A device read and written in blocks.
+    BlockDevice,
+    /// A symbolic link, whose contents are the path it points to.
+    Symlink,
+}
+
+/// What `stat` reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileType,
+    /// Size in bytes; 0 for directories and devices that do not know.
+    pub size: u64,
+    /// Number of the inode, unique within its file system.
+    pub inode: u64,
+}
+
+/// An entry of a directory listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileType,
+}
+
+/// Something in a file system.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+}
+
+/// A file, or a device that is read and written like one. Offsets are in bytes.
+pub trait File: Inode {
+    /// Reads into `buffer` from `offset` and returns how many bytes were read, 0 at the end.
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError>;
+
+    /// Writes `data` at `offset`, growing the file if needed, and returns how many bytes were
+    /// written.
+    fn write_at(&self, _offset: u64, _data: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Cuts the file to `size` bytes, or grows it with zeros.
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+}
+
+/// A directory.
+pub trait Dir: Inode {
+    /// The entry called `name`.
+    fn lookup(&self, name: &str) -> Result<Node, VfsError>;
+
+    /// All entries, without `.` and `..`.
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError>;
+
+    /// Makes an empty file or directory called `name`.
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Node, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    /// Removes the entry called `name`, which must be an empty directory if it is one.
+    fn unlink(&self, _name: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+}
+
+/// An inode, with what it is known.
+#[derive(Clone)]
+pub enum Node {
+    File(Arc<dyn File>),
+    Dir(Arc<dyn Dir>),
+}
+
+impl Node {
+    pub fn metadata(&self) -> Metadata {
+        match self {
+            Node::File(file) => file.metadata(),
+            Node::Dir(dir) => dir.metadata(),
+        }
+    }
+
+    fn into_dir(self) -> Result<Arc<dyn Dir>, VfsError> {
+        match self {
+            Node::Dir(dir) => Ok(dir),
+            Node::File(_) => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn into_file(self) -> Result<Arc<dyn File>, VfsError> {
+        match self {
+            Node::File(file) => Ok(file),
+            Node::Dir(_) => Err(VfsError::IsADirectory),
+        }
+    }
+}
+
+/// A mountable file system.
+pub trait FileSystem: Send + Sync {
+    /// Short name of the driver, like `fat32`.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Dir>;
+
+    /// Writes everything the file system holds back to its device.
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// The mounted file systems. Locked with interrupts disabled, and never while a file system does
+/// I/O.
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Attaches `fs` at the absolute `path`.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+    let path = normalize(path)?;
+    interrupts::without_interrupts(|| {
+        let mut mounts = MOUNTS.lock();
+        if mounts.iter().any(|mount| mount.path == path) {
+            return Err(VfsError::Busy);
+        }
+        mounts.push(Mount { path, fs });
+        Ok(())
+    })
+}
+
+/// Detaches the file system mounted at `path` after syncing it, and returns it.
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, VfsError> {
+    let path = normalize(path)?;
+    let fs = interrupts::without_interrupts(|| {
+        let mut mounts = MOUNTS.lock();
+        let index = mounts.iter().position(|mount| mount.path == path);
+        index.map(|index| mounts.remove(index).fs)
+    })
+    .ok_or(VfsError::NotFound)?;
+    fs.sync()?;
+    Ok(fs)
+}
+
+/// The mount points with the name of the file system at each, in the order they were mounted.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    interrupts::without_interrupts(|| {
+        let mounts = MOUNTS.lock();
+        mounts
+            .iter()
+            .map(|mount| (mount.path.clone(), mount.fs.name()))
+            .collect()
+    })
+}
+
+/// Mounts at `path` the file system `open` finds on the first block device it accepts, reading
+/// it through a block cache over the device's request queue, and returns the device's name.
+pub fn mount_first<F: FileSystem + 'static>(
+    path: &str,
+    open: impl Fn(Arc<dyn BlockDevice>) -> Result<F, VfsError>,
+) -> Option<String> {
+    let (name, fs) = block::devices().into_iter().find_map(|(name, device)| {
+        let device: Arc<dyn BlockDevice> = match block::queue::get(&name) {
+            Ok(queue) => queue,
+            Err(_) => device,
+        };
+        let cache = BlockCache::new(device, cache::DEFAULT_CAPACITY);
+        Some((name, open(cache).ok()?))
+    })?;
+    mount(path, Arc::new(fs)).ok()?;
+    Some(name)
+}
+
+/// Writes back every mounted file system. Returns the first error, after trying all of them.
+pub fn sync() -> Result<(), VfsError> {
+    let filesystems: Vec<_> =
+        interrupts::without_interrupts(|| MOUNTS.lock().iter().map(|m| m.fs.clone()).collect());
+    let mut result = Ok(());
+    for fs in filesystems {
+        let synced = fs.sync();
+        if result.is_ok() {
+            result = synced;
+        }
+    }
+    result
+}
+
+/// Turns `path` into the form mount points are kept in: absolute, no `.` or `..`, no repeated or
+/// trailing slashes.
+fn normalize(path: &str) -> Result<String, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    let mut normalized = String::new();
+    for component in &components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// The part of the normalized `path` beneath the mount point `mount`, or `None` if it is not
+/// beneath it.
+fn strip_mount<'a>(path: &'a str, mount: &str) -> Option<&'a str> {
+    if mount == "/" {
+        return Some(path);
+    }
+    let rest = path.strip_prefix(mount)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+/// The file system whose mount point is the longest prefix of `path`, with the rest of the path.
+fn find_mount(path: &str) -> Option<(Arc<dyn FileSystem>, String)> {
+    interrupts::without_interrupts(|| {
+        let mounts = MOUNTS.lock();
+        mounts
+            .iter()
+            .filter_map(|mount| Some((mount, strip_mount(path, &mount.path)?)))
+            .max_by_key(|(mount, _)| mount.path.len())
+            .map(|(mount, rest)| (mount.fs.clone(), String::from(rest)))
+    })
+}
+
+/// The names of the mount points directly beneath the normalized `path`.
+fn child_mounts(path: &str) -> Vec<String> {
+    interrupts::without_interrupts(|| {
+        let mounts = MOUNTS.lock();
+        mounts
+            .iter()
+            .filter_map(|mount| {
+                let rest = strip_mount(&mount.path, path)?.strip_prefix('/')?;
+                (!rest.is_empty() && !rest.contains('/')).then(|| String::from(rest))
+            })
+            .collect()
+    })
+}
+
+/// A directory with nothing in it, standing in for the directories above mount points that no
+/// file system provides.
+struct Empty;
+
+impl Inode for Empty {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::Directory,
+            size: 0,
+            inode: 0,
+        }
+    }
+}
+
+impl Dir for Empty {
+    fn lookup(&self, _name: &str) -> Result<Node, VfsError> {
+        Err(VfsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Ok(Vec::new())
+    }
+}
+
+/// Finds the inode at the normalized `path`.
+fn resolve_normalized(path: &str) -> Result<Node, VfsError> {
+    let found = match find_mount(path) {
+        Some((fs, rest)) => rest
+            .split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(Node::Dir(fs.root()), |node, name| {
+                node.into_dir()?.lookup(name)
+            }),
+        None => Err(VfsError::NotFound),
+    };
+    match found {
+        Err(VfsError::NotFound) if !child_mounts(path).is_empty() => Ok(Node::Dir(Arc::new(Empty))),
+        found => found,
+    }
+}
+
+/// Finds the inode at `path`.
+pub fn lookup(path: &str) -> Result<Node, VfsError> {
+    resolve_normalized(&normalize(path)?)
+}
+
+/// The metadata of whatever is at `path`.
+pub fn stat(path: &str) -> Result<Metadata, VfsError> {
+    Ok(lookup(path)?.metadata())
+}
+
+/// The entries of the directory at `path`, with the mount points beneath it.
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, VfsError> {
+    let path = normalize(path)?;
+    let mut entries = resolve_normalized(&path)?.into_dir()?.read_dir()?;
+    for name in child_mounts(&path) {
+        if !entries.iter().any(|entry| entry.name == name) {
+            entries.push(DirEntry {
+                name,
+                kind: FileType::Directory,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Splits the normalized `path` into its parent directory and its last component.
+fn split_parent(path: &str) -> Result<(Arc<dyn Dir>, &str), VfsError> {
+    let (parent, name) = path.rsplit_once('/').ok_or(VfsError::InvalidPath)?;
+    if name.is_empty() {
+        return Err(VfsError::InvalidPath);
+    }
+    let parent = if parent.is_empty() { "/" } else { parent };
+    Ok((resolve_normalized(parent)?.into_dir()?, name))
+}
+
+/// Makes an empty file or directory at `path`.
+pub fn create(path: &str, kind: FileType) -> Result<Node, VfsError> {
+    let path = normalize(path)?;
+    let (parent, name) = split_parent(&path)?;
+    parent.create(name, kind)
+}
+
+/// Removes the file or empty directory at `path`.
+pub fn unlink(path: &str) -> Result<(), VfsError> {
+    let path = normalize(path)?;
+    if !child_mounts(&path).is_empty() || find_mount(&path).is_some_and(|(_, rest)| rest.is_empty())
+    {
+        return Err(VfsError::Busy);
+    }
+    let (parent, name) = split_parent(&path)?;
+    parent.unlink(name)
+}
+
+/// Where [`OpenFile::seek`] counts from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// A file opened by path, with a position that reads and writes advance.
+pub struct OpenFile {
+    file: Arc<dyn File>,
+    offset: u64,
+}
+
+impl OpenFile {
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let read = self.file.read_at(self.offset, buffer)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, VfsError> {
+        let written = self.file.write_at(self.offset, data)?;
+        self.offset += written as u64;
+        Ok(written)
+    }
+
+    /// Moves the position and returns it. Positions before the start are clamped to it.
+    pub fn seek(&mut self, position: SeekFrom) -> u64 {
+        self.offset = match position {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(delta) => self.offset.saturating_add_signed(delta),
+            SeekFrom::End(delta) => self.file.metadata().size.saturating_add_signed(delta),
+        };
+        self.offset
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.file.metadata()
+    }
+
+    /// Cuts or grows the file to `size` bytes. The position stays where it is.
+    pub fn truncate(&mut self, size: u64) -> Result<(), VfsError> {
+        self.file.truncate(size)
+    }
+
+    /// Reads from the position to the end of the file.
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, VfsError> {
+        let mut contents = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            let read = self.read(&mut chunk)?;
+            if read == 0 {
+                return Ok(contents);
+            }
+            contents.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+/// Opens the file at `path`, positioned at its start.
+pub fn open(path: &str) -> Result<OpenFile, VfsError> {
+    Ok(OpenFile {
+        file: lookup(path)?.into_file()?,
+        offset: 0,
+    })
+}
+
+/// The whole contents of the file at `path`.
+pub fn read(path: &str) -> Result<Vec<u8>, VfsError> {
+    open(path)?.read_to_end()
+}
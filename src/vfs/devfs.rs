@@ -0,0 +1,314 @@
+//! The device file system, mounted at `/dev`.
+//!
+//! Drivers [`register`] a [`File`] under a name and it shows up as `/dev/<name>`, so programs
+//! and shell commands reach devices by path. [`init`] registers the console, the first serial
+//! port, `null`, `zero` and every block device known by then; a driver that finds a disk later
+//! adds it with [`register_block`]. Block devices read and write like files, with byte offsets.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{Dir, DirEntry, File, FileSystem, FileType, Inode, Metadata, Node, VfsError};
+use crate::block::{self, BlockDevice, BLOCK_SIZE};
+
+/// A registered device, with the inode number devfs gave it.
+struct Device {
+    inode: u64,
+    file: Arc<dyn File>,
+}
+
+/// The registered devices by name. Locked with interrupts disabled.
+static DEVICES: Mutex<BTreeMap<String, Arc<Device>>> = Mutex::new(BTreeMap::new());
+
+/// Inode number of the next device; 1 is the root.
+static NEXT_INODE: AtomicU64 = AtomicU64::new(2);
+
+impl Inode for Device {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.inode,
+            ..self.file.metadata()
+        }
+    }
+}
+
+impl File for Device {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        self.file.read_at(offset, buffer)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        self.file.write_at(offset, data)
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        self.file.truncate(size)
+    }
+}
+
+/// Makes `file` available as `/dev/<name>`.
+pub fn register(name: &str, file: Arc<dyn File>) -> Result<(), VfsError> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(VfsError::InvalidPath);
+    }
+    interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        if devices.contains_key(name) {
+            return Err(VfsError::AlreadyExists);
+        }
+        let inode = NEXT_INODE.fetch_add(1, Ordering::Relaxed);
+        devices.insert(String::from(name), Arc::new(Device { inode, file }));
+        Ok(())
+    })
+}
+
+/// Makes the block device `device` available as `/dev/<name>`.
+pub fn register_block(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), VfsError> {
+    register(name, Arc::new(BlockFile { device }))
+}
+
+/// Removes `/dev/<name>`. Files already open on it keep working.
+pub fn unregister(name: &str) -> Result<(), VfsError> {
+    interrupts::without_interrupts(|| DEVICES.lock().remove(name))
+        .map(|_| ())
+        .ok_or(VfsError::NotFound)
+}
+
+/// The directory of every registered device.
+struct DevDir;
+
+impl Inode for DevDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::Directory,
+            size: 0,
+            inode: 1,
+        }
+    }
+}
+
+impl Dir for DevDir {
+    fn lookup(&self, name: &str) -> Result<Node, VfsError> {
+        let device = interrupts::without_interrupts(|| DEVICES.lock().get(name).cloned());
+        Ok(Node::File(device.ok_or(VfsError::NotFound)?))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let devices: Vec<_> = interrupts::without_interrupts(|| {
+            let devices = DEVICES.lock();
+            devices
+                .iter()
+                .map(|(name, device)| (name.clone(), device.clone()))
+                .collect()
+        });
+        // The metadata comes from the drivers, so it is asked for without the lock.
+        Ok(devices
+            .into_iter()
+            .map(|(name, device)| DirEntry {
+                name,
+                kind: device.metadata().kind,
+            })
+            .collect())
+    }
+}
+
+/// The file system at `/dev`. All instances show the same devices.
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        Arc::new(DevDir)
+    }
+}
+
+fn char_device() -> Metadata {
+    Metadata {
+        kind: FileType::CharDevice,
+        size: 0,
+        inode: 0,
+    }
+}
+
+/// The keyboard for reading and the screen for writing.
+struct Console;
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        char_device()
+    }
+}
+
+impl File for Console {
+    /// Waits for a key unless one was typed already.
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(crate::process::file::read_console(buffer))
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        crate::print!("{}", String::from_utf8_lossy(data));
+        Ok(data.len())
+    }
+}
+
+/// COM1, byte for byte.
+struct Serial;
+
+impl Inode for Serial {
+    fn metadata(&self) -> Metadata {
+        char_device()
+    }
+}
+
+impl File for Serial {
+    /// Returns the bytes received so far, without waiting for more.
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let received = crate::serial::with_port(|port| {
+            let mut read = 0;
+            while read < buffer.len() {
+                match port.try_receive() {
+                    Ok(byte) => buffer[read] = byte,
+                    Err(_) => break,
+                }
+                read += 1;
+            }
+            read
+        });
+        Ok(received.unwrap_or(0))
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        crate::serial::with_port(|port| data.iter().for_each(|&byte| port.send_raw(byte)));
+        Ok(data.len())
+    }
+}
+
+/// Reads nothing and discards what is written to it.
+struct Null;
+
+impl Inode for Null {
+    fn metadata(&self) -> Metadata {
+        char_device()
+    }
+}
+
+impl File for Null {
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        Ok(data.len())
+    }
+}
+
+/// Reads zeros forever and discards what is written to it.
+struct Zero;
+
+impl Inode for Zero {
+    fn metadata(&self) -> Metadata {
+        char_device()
+    }
+}
+
+impl File for Zero {
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+
+    fn write_at(&self, _offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        Ok(data.len())
+    }
+}
+
+/// A block device as a file. Partial blocks are read, changed and written back.
+struct BlockFile {
+    device: Arc<dyn BlockDevice>,
+}
+
+impl BlockFile {
+    fn size(&self) -> u64 {
+        self.device.block_count() * BLOCK_SIZE as u64
+    }
+}
+
+impl Inode for BlockFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::BlockDevice,
+            size: self.size(),
+            inode: 0,
+        }
+    }
+}
+
+impl File for BlockFile {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let length = buffer
+            .len()
+            .min(self.size().saturating_sub(offset) as usize);
+        let mut block = [0; BLOCK_SIZE];
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let within = (position % BLOCK_SIZE as u64) as usize;
+            let count = (BLOCK_SIZE - within).min(length - done);
+            self.device
+                .read_block(position / BLOCK_SIZE as u64, &mut block)?;
+            buffer[done..done + count].copy_from_slice(&block[within..within + count]);
+            done += count;
+        }
+        Ok(length)
+    }
+
+    /// Writes up to the end of the device; a device cannot grow.
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let length = data.len().min(self.size().saturating_sub(offset) as usize);
+        if length == 0 && !data.is_empty() {
+            return Err(VfsError::NoSpace);
+        }
+        let mut block = [0; BLOCK_SIZE];
+        let mut done = 0;
+        while done < length {
+            let position = offset + done as u64;
+            let index = position / BLOCK_SIZE as u64;
+            let within = (position % BLOCK_SIZE as u64) as usize;
+            let count = (BLOCK_SIZE - within).min(length - done);
+            if count < BLOCK_SIZE {
+                self.device.read_block(index, &mut block)?;
+            }
+            block[within..within + count].copy_from_slice(&data[done..done + count]);
+            self.device.write_block(index, &block)?;
+            done += count;
+        }
+        Ok(length)
+    }
+}
+
+/// Registers the standard devices and the block devices found so far, and mounts devfs at
+/// `/dev`. Returns how many devices there are.
+pub fn init() -> Result<usize, VfsError> {
+    let standard: [(&str, Arc<dyn File>); 4] = [
+        ("console", Arc::new(Console)),
+        ("serial", Arc::new(Serial)),
+        ("null", Arc::new(Null)),
+        ("zero", Arc::new(Zero)),
+    ];
+    for (name, file) in standard {
+        register(name, file)?;
+    }
+    for (name, device) in block::devices() {
+        register_block(&name, device)?;
+    }
+    super::mount("/dev", Arc::new(DevFs))?;
+    Ok(interrupts::without_interrupts(|| DEVICES.lock().len()))
+}
@@ -0,0 +1,641 @@
+//! ext2 file systems, read-only.
+//!
+//! The superblock, 1024 bytes into the volume, gives the block size and how the blocks and
+//! inodes are split into block groups. The group descriptors after it locate each group's inode
+//! table, which holds the inodes of the group in order. An inode lists the first 12 blocks of its
+//! data directly and the rest through one singly, one doubly and one triply indirect block; a
+//! block number of 0 is a hole that reads as zeros. Directories are files of variable-length
+//! entries naming inodes.
+//!
+//! Volumes whose incompatible features go beyond file types in directory entries are refused,
+//! so are ext4 volumes with extents or 64-bit block numbers. Symbolic links read as the path they
+//! point to.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{Dir, DirEntry, File, FileSystem, FileType, Inode, Metadata, Node, VfsError};
+use crate::block::{BlockDevice, BLOCK_SIZE};
+
+/// Byte offset of the superblock, and its fields.
+const SUPERBLOCK: u64 = 1024;
+const SUPER_INODES_COUNT: usize = 0;
+const SUPER_FIRST_DATA_BLOCK: usize = 20;
+const SUPER_LOG_BLOCK_SIZE: usize = 24;
+const SUPER_INODES_PER_GROUP: usize = 40;
+const SUPER_MAGIC: usize = 56;
+const SUPER_REVISION: usize = 76;
+const SUPER_INODE_SIZE: usize = 88;
+const SUPER_FEATURE_INCOMPAT: usize = 96;
+const MAGIC: u16 = 0xEF53;
+
+/// Incompatible features this driver reads: file types in directory entries, and block groups
+/// whose metadata is packed together, which only moves it.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+/// Size of a group descriptor, and its field with the first block of the inode table.
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+const GROUP_INODE_TABLE: usize = 8;
+
+/// Inode size of revision 0 volumes.
+const DEFAULT_INODE_SIZE: u64 = 128;
+const ROOT_INODE: u32 = 2;
+
+/// Inode fields.
+const INODE_MODE: usize = 0;
+const INODE_SIZE: usize = 4;
+const INODE_FLAGS: usize = 32;
+const INODE_BLOCKS: usize = 40;
+const INODE_SIZE_HIGH: usize = 108;
+/// Mode bits giving the type of an inode.
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_CHAR_DEVICE: u16 = 0x2000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_BLOCK_DEVICE: u16 = 0x6000;
+const MODE_REGULAR: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xA000;
+/// Inode flag of ext4 files mapped by extents instead of block lists.
+const FLAG_EXTENTS: u32 = 0x0008_0000;
+
+/// Block pointers in an inode: 12 direct ones, then the indirect ones.
+const DIRECT_BLOCKS: u64 = 12;
+const POINTERS: usize = 15;
+/// A symbolic link shorter than this keeps its path in the block pointers.
+const FAST_SYMLINK_MAX: u64 = 60;
+
+/// Directory entry fields.
+const DIRENT_INODE: usize = 0;
+const DIRENT_RECORD_LENGTH: usize = 4;
+const DIRENT_NAME_LENGTH: usize = 6;
+const DIRENT_NAME: usize = 8;
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// The layout of a volume and the device it is on.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    /// First block of each group's inode table.
+    inode_tables: Vec<u32>,
+    /// Whether directory entries have a type byte after the name length.
+    file_types: bool,
+}
+
+impl Volume {
+    /// Reads `buffer.len()` bytes from byte `offset` of the volume, which must be sector aligned
+    /// and span whole sectors.
+    fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        let first = offset / BLOCK_SIZE as u64;
+        for (index, chunk) in buffer.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            let sector: &mut [u8; BLOCK_SIZE] = chunk.try_into().unwrap();
+            self.device.read_block(first + index as u64, sector)?;
+        }
+        Ok(())
+    }
+
+    fn read_sector(&self, offset: u64) -> Result<[u8; BLOCK_SIZE], VfsError> {
+        let mut sector = [0; BLOCK_SIZE];
+        self.read(offset, &mut sector)?;
+        Ok(sector)
+    }
+
+    fn inode(&self, number: u32) -> Result<RawInode, VfsError> {
+        if number == 0 || number > self.inode_count {
+            return Err(VfsError::Corrupt);
+        }
+        let group = ((number - 1) / self.inodes_per_group) as usize;
+        let index = u64::from((number - 1) % self.inodes_per_group);
+        let table = *self.inode_tables.get(group).ok_or(VfsError::Corrupt)?;
+        let offset = u64::from(table) * self.block_size + index * self.inode_size;
+        let sector = self.read_sector(offset / BLOCK_SIZE as u64 * BLOCK_SIZE as u64)?;
+        // Inodes are at least 128 bytes and a power of two, so they do not cross sectors.
+        let raw = &sector[(offset % BLOCK_SIZE as u64) as usize..];
+        let mode = u16_at(raw, INODE_MODE);
+        if u32_at(raw, INODE_FLAGS) & FLAG_EXTENTS != 0 {
+            return Err(VfsError::Unsupported);
+        }
+        let mut size = u64::from(u32_at(raw, INODE_SIZE));
+        if mode & MODE_TYPE_MASK == MODE_REGULAR {
+            size |= u64::from(u32_at(raw, INODE_SIZE_HIGH)) << 32;
+        }
+        let mut blocks = [0; POINTERS];
+        for (index, block) in blocks.iter_mut().enumerate() {
+            *block = u32_at(raw, INODE_BLOCKS + 4 * index);
+        }
+        Ok(RawInode {
+            number,
+            mode,
+            size,
+            blocks,
+        })
+    }
+
+    /// The volume block holding block `index` of the data of `inode`, 0 for a hole.
+    fn data_block(&self, inode: &RawInode, index: u64) -> Result<u32, VfsError> {
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.blocks[index as usize]);
+        }
+        let pointers = self.block_size / 4;
+        let mut index = index - DIRECT_BLOCKS;
+        let mut span = 1;
+        for (level, &block) in inode.blocks[DIRECT_BLOCKS as usize..].iter().enumerate() {
+            span *= pointers;
+            if index < span {
+                return self.walk(block, level as u32 + 1, index);
+            }
+            index -= span;
+        }
+        Err(VfsError::Corrupt)
+    }
+
+    /// Follows `levels` levels of indirect blocks from `block` to entry `index`.
+    fn walk(&self, mut block: u32, levels: u32, mut index: u64) -> Result<u32, VfsError> {
+        let pointers = self.block_size / 4;
+        for level in (0..levels).rev() {
+            if block == 0 {
+                return Ok(0);
+            }
+            let per_entry = pointers.pow(level);
+            let offset = u64::from(block) * self.block_size + index / per_entry * 4;
+            index %= per_entry;
+            let sector = self.read_sector(offset / BLOCK_SIZE as u64 * BLOCK_SIZE as u64)?;
+            block = u32_at(&sector, (offset % BLOCK_SIZE as u64) as usize);
+        }
+        Ok(block)
+    }
+
+    /// Reads from byte `offset` of the data of `inode` into `buffer`, which must not reach past
+    /// the end.
+    fn read_data(&self, inode: &RawInode, offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let block = self.data_block(inode, position / self.block_size)?;
+            let within = position % self.block_size;
+            let from = (within % BLOCK_SIZE as u64) as usize;
+            let count = (BLOCK_SIZE - from).min(buffer.len() - done);
+            let target = &mut buffer[done..done + count];
+            if block == 0 {
+                target.fill(0);
+            } else {
+                let sector_start = within / BLOCK_SIZE as u64 * BLOCK_SIZE as u64;
+                let sector = self.read_sector(u64::from(block) * self.block_size + sector_start)?;
+                target.copy_from_slice(&sector[from..from + count]);
+            }
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// The entries of the directory `inode`, without `.` and `..`.
+    fn read_dir(&self, inode: &RawInode) -> Result<Vec<RawEntry>, VfsError> {
+        let mut entries = Vec::new();
+        let mut block = vec![0; self.block_size as usize];
+        let mut offset = 0;
+        while offset < inode.size {
+            let length = (inode.size - offset).min(self.block_size) as usize;
+            self.read_data(inode, offset, &mut block[..length])?;
+            let mut at = 0;
+            while at + DIRENT_NAME <= length {
+                let record = usize::from(u16_at(&block, at + DIRENT_RECORD_LENGTH));
+                let name_length = if self.file_types {
+                    usize::from(block[at + DIRENT_NAME_LENGTH])
+                } else {
+                    usize::from(u16_at(&block, at + DIRENT_NAME_LENGTH))
+                };
+                if record < DIRENT_NAME || record % 4 != 0 || at + record > length {
+                    return Err(VfsError::Corrupt);
+                }
+                if DIRENT_NAME + name_length > record {
+                    return Err(VfsError::Corrupt);
+                }
+                let number = u32_at(&block, at + DIRENT_INODE);
+                let name = &block[at + DIRENT_NAME..at + DIRENT_NAME + name_length];
+                // Unused entries have inode 0.
+                if number != 0 && name != b"." && name != b".." {
+                    entries.push(RawEntry {
+                        name: String::from_utf8_lossy(name).into_owned(),
+                        inode: number,
+                    });
+                }
+                at += record;
+            }
+            offset += self.block_size;
+        }
+        Ok(entries)
+    }
+
+    fn node(self: &Arc<Self>, inode: RawInode) -> Node {
+        if inode.kind() == FileType::Directory {
+            Node::Dir(Arc::new(Ext2Dir {
+                volume: self.clone(),
+                inode,
+            }))
+        } else {
+            Node::File(Arc::new(Ext2File {
+                volume: self.clone(),
+                inode,
+            }))
+        }
+    }
+}
+
+/// The fields of an inode this driver uses.
+struct RawInode {
+    number: u32,
+    mode: u16,
+    size: u64,
+    blocks: [u32; POINTERS],
+}
+
+impl RawInode {
+    fn kind(&self) -> FileType {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            MODE_CHAR_DEVICE => FileType::CharDevice,
+            MODE_BLOCK_DEVICE => FileType::BlockDevice,
+            _ => FileType::File,
+        }
+    }
+
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: self.kind(),
+            size: self.size,
+            inode: u64::from(self.number),
+        }
+    }
+}
+
+/// A directory entry.
+struct RawEntry {
+    name: String,
+    inode: u32,
+}
+
+/// An ext2 directory.
+struct Ext2Dir {
+    volume: Arc<Volume>,
+    inode: RawInode,
+}
+
+impl Inode for Ext2Dir {
+    fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+}
+
+impl Dir for Ext2Dir {
+    fn lookup(&self, name: &str) -> Result<Node, VfsError> {
+        let entry = self
+            .volume
+            .read_dir(&self.inode)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or(VfsError::NotFound)?;
+        let inode = self.volume.inode(entry.inode)?;
+        Ok(self.volume.node(inode))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let mut entries = Vec::new();
+        for entry in self.volume.read_dir(&self.inode)? {
+            // Without file types in the entries, the inodes have to say.
+            let kind = self.volume.inode(entry.inode)?.kind();
+            entries.push(DirEntry {
+                name: entry.name,
+                kind,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+/// An ext2 file, symbolic link or device node. Device nodes have no data.
+struct Ext2File {
+    volume: Arc<Volume>,
+    inode: RawInode,
+}
+
+impl Inode for Ext2File {
+    fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+}
+
+impl File for Ext2File {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let inode = &self.inode;
+        if offset >= inode.size || buffer.is_empty() {
+            return Ok(0);
+        }
+        let length = buffer.len().min((inode.size - offset) as usize);
+        if inode.kind() == FileType::Symlink && inode.size < FAST_SYMLINK_MAX {
+            let target: Vec<u8> = inode
+                .blocks
+                .iter()
+                .flat_map(|block| block.to_le_bytes())
+                .collect();
+            let start = offset as usize;
+            buffer[..length].copy_from_slice(&target[start..start + length]);
+            return Ok(length);
+        }
+        self.volume
+            .read_data(inode, offset, &mut buffer[..length])?;
+        Ok(length)
+    }
+}
+
+/// An ext2 volume.
+pub struct Ext2 {
+    root: Arc<Ext2Dir>,
+}
+
+impl Ext2 {
+    /// Reads the superblock and the group descriptors of `device`. Fails with
+    /// [`VfsError::Corrupt`] unless it holds an ext2 volume, and with [`VfsError::Unsupported`]
+    /// if the volume needs features this driver does not have.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Ext2, VfsError> {
+        let mut superblock = [0; 2 * BLOCK_SIZE];
+        let first = SUPERBLOCK / BLOCK_SIZE as u64;
+        for (index, sector) in superblock.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            device.read_block(first + index as u64, sector.try_into().unwrap())?;
+        }
+        if u16_at(&superblock, SUPER_MAGIC) != MAGIC {
+            return Err(VfsError::Corrupt);
+        }
+        let incompatible = u32_at(&superblock, SUPER_FEATURE_INCOMPAT);
+        if incompatible & !INCOMPAT_SUPPORTED != 0 {
+            return Err(VfsError::Unsupported);
+        }
+        let log_block_size = u32_at(&superblock, SUPER_LOG_BLOCK_SIZE);
+        let inode_count = u32_at(&superblock, SUPER_INODES_COUNT);
+        let inodes_per_group = u32_at(&superblock, SUPER_INODES_PER_GROUP);
+        let inode_size = match u32_at(&superblock, SUPER_REVISION) {
+            0 => DEFAULT_INODE_SIZE,
+            _ => u64::from(u16_at(&superblock, SUPER_INODE_SIZE)),
+        };
+        let valid = log_block_size <= 6
+            && inodes_per_group != 0
+            && inode_size.is_power_of_two()
+            && (DEFAULT_INODE_SIZE..=BLOCK_SIZE as u64).contains(&inode_size);
+        if !valid {
+            return Err(VfsError::Corrupt);
+        }
+        let block_size = 1024u64 << log_block_size;
+        // The group descriptors start in the block after the superblock.
+        let descriptors = u64::from(u32_at(&superblock, SUPER_FIRST_DATA_BLOCK)) + 1;
+        let groups = inode_count.div_ceil(inodes_per_group) as u64;
+        let length = (groups * GROUP_DESCRIPTOR_SIZE).next_multiple_of(BLOCK_SIZE as u64);
+        let mut table = vec![0; length as usize];
+        let mut volume = Volume {
+            device,
+            block_size,
+            inode_count,
+            inodes_per_group,
+            inode_size,
+            inode_tables: Vec::new(),
+            file_types: incompatible & INCOMPAT_FILETYPE != 0,
+        };
+        volume.read(descriptors * block_size, &mut table)?;
+        volume.inode_tables = table
+            .chunks_exact(GROUP_DESCRIPTOR_SIZE as usize)
+            .take(groups as usize)
+            .map(|descriptor| u32_at(descriptor, GROUP_INODE_TABLE))
+            .collect();
+        let root = volume.inode(ROOT_INODE)?;
+        if root.kind() != FileType::Directory {
+            return Err(VfsError::Corrupt);
+        }
+        Ok(Ext2 {
+            root: Arc::new(Ext2Dir {
+                volume: Arc::new(volume),
+                inode: root,
+            }),
+        })
+    }
+}
+
+impl FileSystem for Ext2 {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        self.root.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+    use crate::testing::{put_u16, put_u32};
+
+    /// The test volume has 1024-byte blocks: the superblock in block 1, the group descriptor in
+    /// block 2, an inode table of 16 inodes in blocks 3 and 4, and data after them.
+    const BLOCK: usize = 1024;
+    const BLOCKS: usize = 32;
+    const INODE_TABLE: u32 = 3;
+    const INODES: u32 = 16;
+    const ROOT_DATA: u32 = 5;
+    const HELLO: u32 = 12;
+    const HELLO_DATA: u32 = 6;
+    /// A sparse file with a direct block, a hole and blocks behind the singly and doubly
+    /// indirect ones.
+    const SPARSE: u32 = 13;
+    const SPARSE_DIRECT: u32 = 7;
+    const SINGLY: u32 = 8;
+    const SINGLY_DATA: u32 = 9;
+    const DOUBLY: u32 = 10;
+    const DOUBLY_INNER: u32 = 11;
+    const DOUBLY_DATA: u32 = 12;
+    const SPARSE_BLOCKS: u64 = DIRECT_BLOCKS + 256 + 2;
+    const LINK: u32 = 14;
+
+    fn block(image: &mut [u8], number: u32) -> &mut [u8] {
+        let start = number as usize * BLOCK;
+        &mut image[start..start + BLOCK]
+    }
+
+    fn put_inode(image: &mut [u8], number: u32, mode: u16, size: u32, blocks: &[u32]) {
+        let start = INODE_TABLE as usize * BLOCK + (number as usize - 1) * 128;
+        let raw = &mut image[start..start + 128];
+        put_u16(raw, INODE_MODE, mode);
+        put_u32(raw, INODE_SIZE, size);
+        for (index, &block) in blocks.iter().enumerate() {
+            put_u32(raw, INODE_BLOCKS + 4 * index, block);
+        }
+    }
+
+    /// Writes a directory entry at `at`, reaching to the end of the block if `last`, and returns
+    /// where the next one goes.
+    fn put_entry(block: &mut [u8], at: usize, inode: u32, name: &str, last: bool) -> usize {
+        let record = if last {
+            BLOCK - at
+        } else {
+            (DIRENT_NAME + name.len()).next_multiple_of(4)
+        };
+        put_u32(block, at + DIRENT_INODE, inode);
+        put_u16(block, at + DIRENT_RECORD_LENGTH, record as u16);
+        block[at + DIRENT_NAME_LENGTH] = name.len() as u8;
+        block[at + DIRENT_NAME..at + DIRENT_NAME + name.len()].copy_from_slice(name.as_bytes());
+        at + record
+    }
+
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; BLOCKS * BLOCK];
+        let superblock = block(&mut image, 1);
+        put_u32(superblock, SUPER_INODES_COUNT, INODES);
+        put_u32(superblock, SUPER_FIRST_DATA_BLOCK, 1);
+        put_u32(superblock, SUPER_INODES_PER_GROUP, INODES);
+        put_u16(superblock, SUPER_MAGIC, MAGIC);
+        put_u32(superblock, SUPER_FEATURE_INCOMPAT, INCOMPAT_FILETYPE);
+        put_u32(block(&mut image, 2), GROUP_INODE_TABLE, INODE_TABLE);
+
+        put_inode(
+            &mut image,
+            ROOT_INODE,
+            MODE_DIRECTORY | 0o755,
+            BLOCK as u32,
+            &[ROOT_DATA],
+        );
+        let root = block(&mut image, ROOT_DATA);
+        let mut at = put_entry(root, 0, ROOT_INODE, ".", false);
+        at = put_entry(root, at, ROOT_INODE, "..", false);
+        at = put_entry(root, at, HELLO, "hello", false);
+        at = put_entry(root, at, 0, "deleted", false);
+        at = put_entry(root, at, SPARSE, "sparse", false);
+        put_entry(root, at, LINK, "link", true);
+
+        put_inode(&mut image, HELLO, MODE_REGULAR | 0o644, 5, &[HELLO_DATA]);
+        block(&mut image, HELLO_DATA)[..5].copy_from_slice(b"hello");
+
+        let size = (SPARSE_BLOCKS * BLOCK as u64) as u32;
+        let mut blocks = [0; POINTERS];
+        blocks[0] = SPARSE_DIRECT;
+        blocks[DIRECT_BLOCKS as usize] = SINGLY;
+        blocks[DIRECT_BLOCKS as usize + 1] = DOUBLY;
+        put_inode(&mut image, SPARSE, MODE_REGULAR | 0o644, size, &blocks);
+        block(&mut image, SPARSE_DIRECT).fill(1);
+        put_u32(block(&mut image, SINGLY), 3 * 4, SINGLY_DATA);
+        block(&mut image, SINGLY_DATA).fill(2);
+        put_u32(block(&mut image, DOUBLY), 0, DOUBLY_INNER);
+        put_u32(block(&mut image, DOUBLY_INNER), 0, DOUBLY_DATA);
+        block(&mut image, DOUBLY_DATA).fill(3);
+
+        let target = b"/bin/hello\0\0";
+        let pointers: Vec<u32> = target
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        put_inode(&mut image, LINK, MODE_SYMLINK | 0o777, 10, &pointers);
+        image
+    }
+
+    fn mount(image: &[u8]) -> Result<Ext2, VfsError> {
+        let disk = RamDisk::new(image.len() / BLOCK_SIZE);
+        for (index, sector) in image.chunks_exact(BLOCK_SIZE).enumerate() {
+            disk.write_block(index as u64, sector.try_into().unwrap())?;
+        }
+        Ext2::new(Arc::new(disk))
+    }
+
+    fn file(fs: &Ext2, name: &str) -> Arc<dyn File> {
+        match fs.root().lookup(name) {
+            Ok(Node::File(file)) => file,
+            _ => panic!("no file {}", name),
+        }
+    }
+
+    #[test_case]
+    fn maps_direct_and_indirect_blocks() {
+        let fs = mount(&image()).unwrap();
+        let volume = &fs.root.volume;
+        let inode = volume.inode(SPARSE).unwrap();
+        let singly = DIRECT_BLOCKS;
+        let doubly = DIRECT_BLOCKS + 256;
+        assert_eq!(volume.data_block(&inode, 0), Ok(SPARSE_DIRECT));
+        assert_eq!(volume.data_block(&inode, 1), Ok(0));
+        assert_eq!(volume.data_block(&inode, singly + 3), Ok(SINGLY_DATA));
+        assert_eq!(volume.data_block(&inode, singly + 4), Ok(0));
+        assert_eq!(volume.data_block(&inode, doubly), Ok(DOUBLY_DATA));
+        assert_eq!(volume.data_block(&inode, doubly + 1), Ok(0));
+        assert_eq!(volume.data_block(&inode, doubly + 256), Ok(0));
+        // Past the triply indirect block.
+        let end = doubly + 256 * 256 + 256 * 256 * 256;
+        assert_eq!(volume.data_block(&inode, end - 1), Ok(0));
+        assert_eq!(volume.data_block(&inode, end), Err(VfsError::Corrupt));
+    }
+
+    #[test_case]
+    fn reads_files_through_holes() {
+        let fs = mount(&image()).unwrap();
+        let mut buffer = [0xFF; 8];
+        assert_eq!(file(&fs, "hello").read_at(0, &mut buffer), Ok(5));
+        assert_eq!(&buffer[..5], b"hello");
+        let sparse = file(&fs, "sparse");
+        assert_eq!(sparse.metadata().size, SPARSE_BLOCKS * BLOCK as u64);
+        let mut buffer = vec![0xFF; 2 * BLOCK];
+        assert_eq!(sparse.read_at(BLOCK as u64 / 2, &mut buffer), Ok(2 * BLOCK));
+        assert!(buffer[..BLOCK / 2].iter().all(|&byte| byte == 1));
+        assert!(buffer[BLOCK / 2..].iter().all(|&byte| byte == 0));
+        let singly = (DIRECT_BLOCKS + 3) * BLOCK as u64;
+        assert_eq!(sparse.read_at(singly + 1000, &mut buffer), Ok(2 * BLOCK));
+        assert!(buffer[..24].iter().all(|&byte| byte == 2));
+        assert!(buffer[24..].iter().all(|&byte| byte == 0));
+        let doubly = (DIRECT_BLOCKS + 256) * BLOCK as u64;
+        assert_eq!(sparse.read_at(doubly, &mut buffer), Ok(2 * BLOCK));
+        assert!(buffer[..BLOCK].iter().all(|&byte| byte == 3));
+        assert!(buffer[BLOCK..].iter().all(|&byte| byte == 0));
+        assert_eq!(
+            sparse.read_at(doubly + 2 * BLOCK as u64, &mut buffer),
+            Ok(0)
+        );
+    }
+
+    #[test_case]
+    fn lists_directories_and_links() {
+        let fs = mount(&image()).unwrap();
+        let entries = fs.root().read_dir().unwrap();
+        let listed: Vec<(&str, FileType)> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.kind))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("hello", FileType::File),
+                ("sparse", FileType::File),
+                ("link", FileType::Symlink),
+            ]
+        );
+        assert_eq!(fs.root().lookup("deleted").err(), Some(VfsError::NotFound));
+        let mut target = [0; 16];
+        assert_eq!(file(&fs, "link").read_at(0, &mut target), Ok(10));
+        assert_eq!(&target[..10], b"/bin/hello");
+    }
+
+    #[test_case]
+    fn refuses_other_volumes() {
+        let mut image = image();
+        put_u32(block(&mut image, 1), SUPER_FEATURE_INCOMPAT, 0x0040);
+        assert_eq!(mount(&image).err(), Some(VfsError::Unsupported));
+        put_u16(block(&mut image, 1), SUPER_MAGIC, 0);
+        assert_eq!(mount(&image).err(), Some(VfsError::Corrupt));
+    }
+}
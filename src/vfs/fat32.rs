@@ -0,0 +1,1263 @@
+//! FAT32 file systems.
+//!
+//! The boot sector's BIOS parameter block gives the layout of the volume: reserved sectors, then
+//! the file allocation tables, then the data area divided into clusters. A file or directory is a
+//! chain of clusters, each FAT entry naming the cluster after it. Directories are arrays of
+//! 32-byte entries; long names are spread over extra entries in front of the 8.3 entry, stored
+//! last part first, and are only trusted if their checksum matches the 8.3 name. Names are looked
+//! up ignoring ASCII case, as FAT does.
+//!
+//! Changes are written to the device, usually a block cache, one at a time. Free clusters are
+//! searched for in the FAT from the hint the FSInfo sector keeps, and every copy of the FAT is
+//! updated. A file's directory entry is rewritten whenever its size or first cluster changes, and
+//! all handles to a file share one `FatFile`, so they agree on both. New names that are not valid
+//! 8.3 names get a long name and a generated `BASE~N.EXT` alias. [`FileSystem::sync`] writes the
+//! free cluster count back to the FSInfo sector and flushes the device, which writes back a
+//! cache.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use super::{Dir, DirEntry, File, FileSystem, FileType, Inode, Metadata, Node, VfsError};
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::sync::Mutex;
+
+/// Boot sector fields.
+const BPB_BYTES_PER_SECTOR: usize = 11;
+const BPB_SECTORS_PER_CLUSTER: usize = 13;
+const BPB_RESERVED_SECTORS: usize = 14;
+const BPB_FAT_COUNT: usize = 16;
+const BPB_ROOT_ENTRIES: usize = 17;
+const BPB_TOTAL_SECTORS_16: usize = 19;
+const BPB_FAT_SIZE_16: usize = 22;
+const BPB_TOTAL_SECTORS_32: usize = 32;
+const BPB_FAT_SIZE_32: usize = 36;
+const BPB_ROOT_CLUSTER: usize = 44;
+const BPB_FS_INFO: usize = 48;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+/// FSInfo sector fields: its three signatures, the free cluster count and the cluster to start
+/// looking for free ones at, either of which may be unknown.
+const FS_INFO_LEAD: (usize, u32) = (0, 0x4161_5252);
+const FS_INFO_STRUCT: (usize, u32) = (484, 0x6141_7272);
+const FS_INFO_TRAIL: (usize, u32) = (508, 0xAA55_0000);
+const FS_INFO_FREE: usize = 488;
+const FS_INFO_NEXT: usize = 492;
+const FS_INFO_UNKNOWN: u32 = u32::MAX;
+
+/// FAT entries keep their top four bits for themselves.
+const FAT_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+/// A free cluster, a cluster marked bad, the values from which on an entry ends its chain, and
+/// the value written to end one.
+const FAT_FREE: u32 = 0;
+const FAT_BAD: u32 = 0x0FFF_FFF7;
+const FAT_END: u32 = 0x0FFF_FFF8;
+const FAT_END_MARK: u32 = 0x0FFF_FFFF;
+/// Number of the first cluster of the data area.
+const FIRST_CLUSTER: u32 = 2;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: usize = BLOCK_SIZE / ENTRY_SIZE;
+/// First name byte of the entry that ends a directory, and of a deleted entry.
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+/// Directory entry fields.
+const ENTRY_ATTRIBUTES: usize = 11;
+const ENTRY_CASE: usize = 12;
+const ENTRY_CREATION_TIME: usize = 14;
+const ENTRY_CREATION_DATE: usize = 16;
+const ENTRY_ACCESS_DATE: usize = 18;
+const ENTRY_CLUSTER_HIGH: usize = 20;
+const ENTRY_WRITE_TIME: usize = 22;
+const ENTRY_WRITE_DATE: usize = 24;
+const ENTRY_CLUSTER_LOW: usize = 26;
+const ENTRY_SIZE_FIELD: usize = 28;
+
+/// Attribute bits, and the combination that marks a long name entry.
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_ARCHIVE: u8 = 0x20;
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+/// Case bits Windows NT sets for an 8.3 name whose base or extension is all lowercase.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXTENSION: u8 = 0x10;
+
+/// Long name entry fields: the sequence number, flagged on the last part, the checksum of the
+/// 8.3 name and the byte ranges of the 13 UTF-16 units.
+const LONG_SEQUENCE_MASK: u8 = 0x1F;
+const LONG_LAST: u8 = 0x40;
+const LONG_CHECKSUM: usize = 13;
+const LONG_NAME_RANGES: [Range<usize>; 3] = [1..11, 14..26, 28..32];
+const LONG_NAME_UNITS: usize = 13;
+/// Longest name in UTF-16 units.
+const LONG_NAME_MAX: usize = 255;
+
+/// Characters besides letters and digits allowed in 8.3 names, and those no name may have.
+const SHORT_NAME_SPECIAL: &[u8] = b"!#$%&'()-@^_`{}~";
+const FORBIDDEN: &str = "\"*/:<>?\\|";
+
+/// Free cluster bookkeeping, mirrored in the FSInfo sector.
+struct Allocation {
+    /// Number of free clusters, or [`FS_INFO_UNKNOWN`].
+    free: u32,
+    /// Where the search for a free cluster starts.
+    next: u32,
+}
+
+/// The layout of a volume and the device it is on.
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_size: u64,
+    fat_count: u64,
+    data_start: u64,
+    root_cluster: u32,
+    /// Number of the cluster after the last one.
+    cluster_end: u32,
+    fs_info: Option<u64>,
+    /// Held by every change to the volume, so changes do not interleave.
+    allocation: Mutex<Allocation>,
+    /// The files with a handle, by the position of their directory entry.
+    files: Mutex<BTreeMap<u64, Weak<FatFile>>>,
+}
+
+impl Volume {
+    fn read_sector(&self, lba: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), VfsError> {
+        Ok(self.device.read_block(lba, buffer)?)
+    }
+
+    fn write_sector(&self, lba: u64, data: &[u8; BLOCK_SIZE]) -> Result<(), VfsError> {
+        Ok(self.device.write_block(lba, data)?)
+    }
+
+    fn cluster_bytes(&self) -> u64 {
+        self.sectors_per_cluster * BLOCK_SIZE as u64
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - FIRST_CLUSTER) * self.sectors_per_cluster
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<u32, VfsError> {
+        if !(FIRST_CLUSTER..self.cluster_end).contains(&cluster) {
+            return Err(VfsError::Corrupt);
+        }
+        Ok(cluster)
+    }
+
+    /// The sector of the first FAT holding the entry of `cluster`, and the entry's offset in it.
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = u64::from(cluster) * 4;
+        (
+            offset / BLOCK_SIZE as u64,
+            (offset % BLOCK_SIZE as u64) as usize,
+        )
+    }
+
+    /// The cluster after `cluster` in its chain, or `None` at the end.
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, VfsError> {
+        let (sector_index, at) = self.fat_position(cluster);
+        let mut sector = [0; BLOCK_SIZE];
+        self.read_sector(self.fat_start + sector_index, &mut sector)?;
+        match u32_at(&sector, at) & FAT_ENTRY_MASK {
+            FAT_END.. => Ok(None),
+            FAT_BAD => Err(VfsError::Corrupt),
+            next => self.check_cluster(next).map(Some),
+        }
+    }
+
+    /// Sets the FAT entry of `cluster` in every FAT.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), VfsError> {
+        let (sector_index, at) = self.fat_position(cluster);
+        let mut sector = [0; BLOCK_SIZE];
+        for fat in 0..self.fat_count {
+            let lba = self.fat_start + fat * self.fat_size + sector_index;
+            self.read_sector(lba, &mut sector)?;
+            let entry = (u32_at(&sector, at) & !FAT_ENTRY_MASK) | value;
+            sector[at..at + 4].copy_from_slice(&entry.to_le_bytes());
+            self.write_sector(lba, &sector)?;
+        }
+        Ok(())
+    }
+
+    /// The clusters of the chain starting at `first`. A chain longer than the volume loops.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, VfsError> {
+        let mut clusters = Vec::new();
+        let mut next = Some(self.check_cluster(first)?);
+        while let Some(cluster) = next {
+            if clusters.len() as u32 >= self.cluster_end {
+                return Err(VfsError::Corrupt);
+            }
+            clusters.push(cluster);
+            next = self.next_cluster(cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    /// Takes a free cluster, zeroes it and appends it to the chain ending at `previous`.
+    fn allocate(
+        &self,
+        allocation: &mut Allocation,
+        previous: Option<u32>,
+    ) -> Result<u32, VfsError> {
+        let count = self.cluster_end - FIRST_CLUSTER;
+        let start = self.check_cluster(allocation.next).unwrap_or(FIRST_CLUSTER) - FIRST_CLUSTER;
+        let mut sector = [0; BLOCK_SIZE];
+        let mut loaded = None;
+        for step in 0..count {
+            let cluster = FIRST_CLUSTER + (start + step) % count;
+            let (sector_index, at) = self.fat_position(cluster);
+            if loaded != Some(sector_index) {
+                self.read_sector(self.fat_start + sector_index, &mut sector)?;
+                loaded = Some(sector_index);
+            }
+            if u32_at(&sector, at) & FAT_ENTRY_MASK != FAT_FREE {
+                continue;
+            }
+            let zeros = [0; BLOCK_SIZE];
+            for index in 0..self.sectors_per_cluster {
+                self.write_sector(self.cluster_sector(cluster) + index, &zeros)?;
+            }
+            self.set_fat_entry(cluster, FAT_END_MARK)?;
+            if let Some(previous) = previous {
+                self.set_fat_entry(previous, cluster)?;
+            }
+            allocation.next = cluster + 1;
+            if allocation.free != FS_INFO_UNKNOWN {
+                allocation.free = allocation.free.saturating_sub(1);
+            }
+            return Ok(cluster);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    /// Marks `clusters` free.
+    fn release(&self, allocation: &mut Allocation, clusters: &[u32]) -> Result<(), VfsError> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, FAT_FREE)?;
+            if allocation.free != FS_INFO_UNKNOWN {
+                allocation.free += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the directory starting at `cluster`.
+    fn read_dir(&self, cluster: u32) -> Result<Directory, VfsError> {
+        let mut sectors = Vec::new();
+        for cluster in self.chain(cluster)? {
+            let first = self.cluster_sector(cluster);
+            sectors.extend(first..first + self.sectors_per_cluster);
+        }
+        let mut directory = Directory {
+            sectors,
+            entries: Vec::new(),
+            used: Vec::new(),
+        };
+        let mut long_name = LongName::default();
+        let mut sector = [0; BLOCK_SIZE];
+        for (sector_index, &lba) in directory.sectors.iter().enumerate() {
+            self.read_sector(lba, &mut sector)?;
+            for (index, raw) in sector.chunks_exact(ENTRY_SIZE).enumerate() {
+                let slot = sector_index * ENTRIES_PER_SECTOR + index;
+                let location = lba * ENTRIES_PER_SECTOR as u64 + index as u64;
+                match raw[0] {
+                    ENTRY_END => return Ok(directory),
+                    ENTRY_DELETED => long_name = LongName::default(),
+                    _ if raw[ENTRY_ATTRIBUTES] == ATTRIBUTE_LONG_NAME => long_name.add(raw, slot),
+                    _ => {
+                        let name = core::mem::take(&mut long_name);
+                        let entry = Entry::parse(raw, name, slot, location);
+                        directory.entries.extend(entry);
+                    }
+                }
+                directory.used.push(raw[0] != ENTRY_DELETED);
+            }
+        }
+        Ok(directory)
+    }
+
+    /// Writes the 32-byte directory entry at `location`.
+    fn write_entry(&self, location: u64, raw: &[u8; ENTRY_SIZE]) -> Result<(), VfsError> {
+        self.modify_entry(location, |entry| entry.copy_from_slice(raw))
+    }
+
+    /// Reads the sector with the directory entry at `location`, lets `change` modify the entry
+    /// and writes the sector back.
+    fn modify_entry(&self, location: u64, change: impl FnOnce(&mut [u8])) -> Result<(), VfsError> {
+        let lba = location / ENTRIES_PER_SECTOR as u64;
+        let at = (location % ENTRIES_PER_SECTOR as u64) as usize * ENTRY_SIZE;
+        let mut sector = [0; BLOCK_SIZE];
+        self.read_sector(lba, &mut sector)?;
+        change(&mut sector[at..at + ENTRY_SIZE]);
+        self.write_sector(lba, &sector)
+    }
+
+    /// The node for `entry` of a directory. Files come from the shared handles if one is open.
+    fn node(self: &Arc<Self>, entry: Entry) -> Node {
+        if entry.directory {
+            return Node::Dir(Arc::new(FatDir {
+                volume: self.clone(),
+                cluster: entry.cluster,
+                inode: entry.location,
+            }));
+        }
+        let mut files = self.files.lock();
+        if let Some(file) = files.get(&entry.location).and_then(Weak::upgrade) {
+            return Node::File(file);
+        }
+        files.retain(|_, file| file.strong_count() > 0);
+        let file = Arc::new(FatFile {
+            volume: self.clone(),
+            location: entry.location,
+            state: Mutex::new(FileState {
+                cluster: entry.cluster,
+                size: entry.size,
+                unlinked: false,
+            }),
+        });
+        files.insert(entry.location, Arc::downgrade(&file));
+        Node::File(file)
+    }
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// A directory as read from the disk.
+struct Directory {
+    /// The sectors the directory's clusters cover.
+    sectors: Vec<u64>,
+    entries: Vec<Entry>,
+    /// Whether each slot up to the end of the directory holds an entry.
+    used: Vec<bool>,
+}
+
+impl Directory {
+    fn find(&self, name: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The position on the volume of slot `slot`.
+    fn location(&self, slot: usize) -> u64 {
+        let lba = self.sectors[slot / ENTRIES_PER_SECTOR];
+        lba * ENTRIES_PER_SECTOR as u64 + (slot % ENTRIES_PER_SECTOR) as u64
+    }
+
+    /// The first of `count` free slots in a row, if the directory's clusters have them.
+    fn free_slots(&self, count: usize) -> Option<usize> {
+        let mut run = 0;
+        for slot in 0..self.sectors.len() * ENTRIES_PER_SECTOR {
+            let used = self.used.get(slot).copied().unwrap_or(false);
+            run = if used { 0 } else { run + 1 };
+            if run == count {
+                return Some(slot + 1 - count);
+            }
+        }
+        None
+    }
+}
+
+/// The parts of a long name collected so far.
+#[derive(Default)]
+struct LongName {
+    units: Vec<u16>,
+    /// Sequence number the next part must have, counting down to 1.
+    expected: u8,
+    checksum: u8,
+    /// Slot of the first part.
+    start: usize,
+}
+
+impl LongName {
+    fn add(&mut self, raw: &[u8], slot: usize) {
+        let sequence = raw[0] & LONG_SEQUENCE_MASK;
+        if raw[0] & LONG_LAST != 0 {
+            *self = LongName {
+                units: vec![0xFFFF; usize::from(sequence) * LONG_NAME_UNITS],
+                expected: sequence,
+                checksum: raw[LONG_CHECKSUM],
+                start: slot,
+            };
+        }
+        if sequence == 0 || sequence != self.expected || raw[LONG_CHECKSUM] != self.checksum {
+            *self = LongName::default();
+            return;
+        }
+        let start = usize::from(sequence - 1) * LONG_NAME_UNITS;
+        let units = LONG_NAME_RANGES
+            .iter()
+            .flat_map(|range| raw[range.clone()].chunks_exact(2))
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+        for (slot, unit) in self.units[start..start + LONG_NAME_UNITS]
+            .iter_mut()
+            .zip(units)
+        {
+            *slot = unit;
+        }
+        self.expected = sequence - 1;
+    }
+
+    /// The name, if every part arrived and they belong to the 8.3 name `short`.
+    fn finish(self, short: &[u8]) -> Option<String> {
+        if self.units.is_empty() || self.expected != 0 || self.checksum != checksum(short) {
+            return None;
+        }
+        // The name ends at a zero unit, and the rest of the last part is padded with 0xFFFF.
+        let units = self
+            .units
+            .into_iter()
+            .take_while(|&unit| unit != 0 && unit != 0xFFFF);
+        char::decode_utf16(units)
+            .collect::<Result<String, _>>()
+            .ok()
+    }
+}
+
+/// The checksum of an 8.3 name that its long name entries carry.
+fn checksum(short: &[u8]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// A file or subdirectory as its directory entry describes it.
+struct Entry {
+    name: String,
+    short: [u8; 11],
+    directory: bool,
+    cluster: u32,
+    size: u32,
+    /// The slots of the long name parts and the 8.3 entry.
+    slots: Range<usize>,
+    /// Position of the 8.3 entry on the volume, in entries from the start.
+    location: u64,
+}
+
+impl Entry {
+    /// Parses the 8.3 entry `raw` in slot `slot`, preceded by the long name parts in
+    /// `long_name`. Returns `None` for the volume label and the `.` and `..` entries.
+    fn parse(raw: &[u8], long_name: LongName, slot: usize, location: u64) -> Option<Entry> {
+        let attributes = raw[ENTRY_ATTRIBUTES];
+        if attributes & ATTRIBUTE_VOLUME_ID != 0 || raw[0] == b'.' {
+            return None;
+        }
+        let start = long_name.start;
+        let (name, first) = match long_name.finish(&raw[..11]) {
+            Some(name) => (name, start),
+            None => (short_name(raw), slot),
+        };
+        let cluster = (u32::from(u16::from_le_bytes([
+            raw[ENTRY_CLUSTER_HIGH],
+            raw[ENTRY_CLUSTER_HIGH + 1],
+        ])) << 16)
+            | u32::from(u16::from_le_bytes([
+                raw[ENTRY_CLUSTER_LOW],
+                raw[ENTRY_CLUSTER_LOW + 1],
+            ]));
+        Some(Entry {
+            name,
+            short: raw[..11].try_into().unwrap(),
+            directory: attributes & ATTRIBUTE_DIRECTORY != 0,
+            cluster,
+            size: u32_at(raw, ENTRY_SIZE_FIELD),
+            slots: first..slot + 1,
+            location,
+        })
+    }
+
+    fn kind(&self) -> FileType {
+        if self.directory {
+            FileType::Directory
+        } else {
+            FileType::File
+        }
+    }
+}
+
+/// The 8.3 name of `raw` as `BASE.EXT`, lowercased where the case bits say so.
+fn short_name(raw: &[u8]) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.trim_end_matches(' ');
+        if lower {
+            text.to_ascii_lowercase()
+        } else {
+            String::from(text)
+        }
+    };
+    let mut name = part(&raw[..8], raw[ENTRY_CASE] & CASE_LOWER_BASE != 0);
+    let extension = part(&raw[8..11], raw[ENTRY_CASE] & CASE_LOWER_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+fn short_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || SHORT_NAME_SPECIAL.contains(&byte)
+}
+
+/// Whether a new file may be called `name`. Trailing dots and spaces are refused rather than
+/// dropped, as other systems would.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.ends_with(['.', ' '])
+        && name.encode_utf16().count() <= LONG_NAME_MAX
+        && !name.chars().any(|c| c < ' ' || FORBIDDEN.contains(c))
+}
+
+/// The 8.3 name and case bits that store `name` exactly, if there are any: a base of up to 8 and
+/// an extension of up to 3 characters, each all uppercase or all lowercase.
+fn short_form(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || name.ends_with('.') {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    for (part, at, lower_bit) in [
+        (base, 0, CASE_LOWER_BASE),
+        (extension, 8, CASE_LOWER_EXTENSION),
+    ] {
+        let bytes = part.as_bytes();
+        if !bytes.iter().all(|&byte| short_name_byte(byte)) {
+            return None;
+        }
+        let lower = bytes.iter().any(u8::is_ascii_lowercase);
+        if lower && bytes.iter().any(u8::is_ascii_uppercase) {
+            return None;
+        }
+        if lower {
+            case |= lower_bit;
+        }
+        short[at..at + bytes.len()].copy_from_slice(part.to_ascii_uppercase().as_bytes());
+    }
+    Some((short, case))
+}
+
+/// A `BASE~N.EXT` alias for `name` that no entry of `directory` has.
+fn alias(name: &str, directory: &Directory) -> Result<[u8; 11], VfsError> {
+    let (base, extension) = match name.rsplit_once('.') {
+        Some((base, extension)) if !base.is_empty() => (base, extension),
+        _ => (name, ""),
+    };
+    let clean = |part: &str, length: usize| -> Vec<u8> {
+        part.bytes()
+            .filter(|&byte| byte != b'.' && byte != b' ')
+            .map(|byte| {
+                if short_name_byte(byte) {
+                    byte.to_ascii_uppercase()
+                } else {
+                    b'_'
+                }
+            })
+            .take(length)
+            .collect()
+    };
+    let (base, extension) = (clean(base, 6), clean(extension, 3));
+    for number in 1..1_000_000 {
+        let suffix = format!("~{}", number);
+        let kept = base.len().min(8 - suffix.len());
+        let mut short = [b' '; 11];
+        short[..kept].copy_from_slice(&base[..kept]);
+        short[kept..kept + suffix.len()].copy_from_slice(suffix.as_bytes());
+        short[8..8 + extension.len()].copy_from_slice(&extension);
+        if !directory.entries.iter().any(|entry| entry.short == short) {
+            return Ok(short);
+        }
+    }
+    Err(VfsError::NoSpace)
+}
+
+/// The current time and date in the FAT encoding, two-second resolution and years from 1980.
+fn timestamp() -> (u16, u16) {
+    let now = crate::rtc::now();
+    let time =
+        (u16::from(now.hour) << 11) | (u16::from(now.minute) << 5) | u16::from(now.second / 2);
+    let date =
+        (now.year.saturating_sub(1980) << 9) | (u16::from(now.month) << 5) | u16::from(now.day);
+    (time, date)
+}
+
+/// An 8.3 entry, stamped with the current time.
+fn short_entry(short: &[u8; 11], attributes: u8, case: u8, cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0; ENTRY_SIZE];
+    raw[..11].copy_from_slice(short);
+    raw[ENTRY_ATTRIBUTES] = attributes;
+    raw[ENTRY_CASE] = case;
+    let (time, date) = timestamp();
+    for (at, value) in [
+        (ENTRY_CREATION_TIME, time),
+        (ENTRY_CREATION_DATE, date),
+        (ENTRY_ACCESS_DATE, date),
+        (ENTRY_WRITE_TIME, time),
+        (ENTRY_WRITE_DATE, date),
+    ] {
+        raw[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+    set_cluster(&mut raw, cluster);
+    raw
+}
+
+fn set_cluster(raw: &mut [u8], cluster: u32) {
+    raw[ENTRY_CLUSTER_HIGH..ENTRY_CLUSTER_HIGH + 2]
+        .copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    raw[ENTRY_CLUSTER_LOW..ENTRY_CLUSTER_LOW + 2].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// Long name part `part`, counted from 1, of the name `units`.
+fn long_entry(units: &[u16], part: usize, last: bool, checksum: u8) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0; ENTRY_SIZE];
+    raw[0] = part as u8 | if last { LONG_LAST } else { 0 };
+    raw[ENTRY_ATTRIBUTES] = ATTRIBUTE_LONG_NAME;
+    raw[LONG_CHECKSUM] = checksum;
+    let start = (part - 1) * LONG_NAME_UNITS;
+    let offsets = LONG_NAME_RANGES
+        .iter()
+        .flat_map(|range| range.clone().step_by(2));
+    for (index, at) in offsets.enumerate() {
+        // The name is ended by a zero unit if it does not fill the part, then padded.
+        let unit = match (start + index).cmp(&units.len()) {
+            core::cmp::Ordering::Less => units[start + index],
+            core::cmp::Ordering::Equal => 0,
+            core::cmp::Ordering::Greater => 0xFFFF,
+        };
+        raw[at..at + 2].copy_from_slice(&unit.to_le_bytes());
+    }
+    raw
+}
+
+/// A FAT32 directory.
+struct FatDir {
+    volume: Arc<Volume>,
+    cluster: u32,
+    /// The position of the directory's entry, or 0 for the root.
+    inode: u64,
+}
+
+impl Inode for FatDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::Directory,
+            size: 0,
+            inode: self.inode,
+        }
+    }
+}
+
+impl Dir for FatDir {
+    fn lookup(&self, name: &str) -> Result<Node, VfsError> {
+        let mut directory = self.volume.read_dir(self.cluster)?;
+        let index = directory
+            .entries
+            .iter()
+            .position(|entry| entry.name.eq_ignore_ascii_case(name))
+            .ok_or(VfsError::NotFound)?;
+        Ok(self.volume.node(directory.entries.swap_remove(index)))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let directory = self.volume.read_dir(self.cluster)?;
+        Ok(directory
+            .entries
+            .into_iter()
+            .map(|entry| DirEntry {
+                kind: entry.kind(),
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Node, VfsError> {
+        let directory_kind = match kind {
+            FileType::File => false,
+            FileType::Directory => true,
+            _ => return Err(VfsError::InvalidPath),
+        };
+        if !valid_name(name) {
+            return Err(VfsError::InvalidPath);
+        }
+        let volume = &self.volume;
+        let mut allocation = volume.allocation.lock();
+        let mut directory = volume.read_dir(self.cluster)?;
+        if directory.find(name).is_some() {
+            return Err(VfsError::AlreadyExists);
+        }
+        let exact = short_form(name)
+            .filter(|(short, _)| !directory.entries.iter().any(|entry| entry.short == *short));
+        let (short, case, units) = match exact {
+            Some((short, case)) => (short, case, Vec::new()),
+            None => (alias(name, &directory)?, 0, name.encode_utf16().collect()),
+        };
+        let parts = units.len().div_ceil(LONG_NAME_UNITS);
+        let start = loop {
+            if let Some(start) = directory.free_slots(parts + 1) {
+                break start;
+            }
+            let last = (*directory.sectors.last().unwrap() - volume.data_start)
+                / volume.sectors_per_cluster;
+            let cluster = volume.allocate(&mut allocation, Some(FIRST_CLUSTER + last as u32))?;
+            let first = volume.cluster_sector(cluster);
+            directory
+                .sectors
+                .extend(first..first + volume.sectors_per_cluster);
+        };
+        let (attributes, cluster) = if directory_kind {
+            let cluster = volume.allocate(&mut allocation, None)?;
+            let parent = if self.cluster == volume.root_cluster {
+                0
+            } else {
+                self.cluster
+            };
+            let location = volume.cluster_sector(cluster) * ENTRIES_PER_SECTOR as u64;
+            let dot = short_entry(b".          ", ATTRIBUTE_DIRECTORY, 0, cluster);
+            let dot_dot = short_entry(b"..         ", ATTRIBUTE_DIRECTORY, 0, parent);
+            volume.write_entry(location, &dot)?;
+            volume.write_entry(location + 1, &dot_dot)?;
+            (ATTRIBUTE_DIRECTORY, cluster)
+        } else {
+            (ATTRIBUTE_ARCHIVE, 0)
+        };
+        let sum = checksum(&short);
+        for part in 1..=parts {
+            let slot = start + parts - part;
+            let raw = long_entry(&units, part, part == parts, sum);
+            volume.write_entry(directory.location(slot), &raw)?;
+        }
+        let location = directory.location(start + parts);
+        volume.write_entry(location, &short_entry(&short, attributes, case, cluster))?;
+        drop(allocation);
+        Ok(volume.node(Entry {
+            name: String::from(name),
+            short,
+            directory: directory_kind,
+            cluster,
+            size: 0,
+            slots: start..start + parts + 1,
+            location,
+        }))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), VfsError> {
+        let volume = &self.volume;
+        let mut allocation = volume.allocation.lock();
+        let directory = volume.read_dir(self.cluster)?;
+        let entry = directory.find(name).ok_or(VfsError::NotFound)?;
+        if entry.directory && !volume.read_dir(entry.cluster)?.entries.is_empty() {
+            return Err(VfsError::NotEmpty);
+        }
+        let mut cluster = entry.cluster;
+        if let Some(file) = volume.files.lock().remove(&entry.location) {
+            if let Some(file) = file.upgrade() {
+                // The handle knows the clusters better if it wrote to the file.
+                let mut state = file.state.lock();
+                state.unlinked = true;
+                cluster = state.cluster;
+            }
+        }
+        for slot in entry.slots.clone() {
+            volume.modify_entry(directory.location(slot), |raw| raw[0] = ENTRY_DELETED)?;
+        }
+        if cluster != 0 {
+            let clusters = volume.chain(cluster)?;
+            volume.release(&mut allocation, &clusters)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a file's data is. An empty file has no clusters, and cluster 0.
+#[derive(Debug, Clone, Copy)]
+struct FileState {
+    cluster: u32,
+    size: u32,
+    /// Set once the file's entry is gone and its clusters are free.
+    unlinked: bool,
+}
+
+/// A FAT32 file.
+struct FatFile {
+    volume: Arc<Volume>,
+    /// Position of the file's 8.3 entry.
+    location: u64,
+    state: Mutex<FileState>,
+}
+
+impl FatFile {
+    /// Writes `data` at `offset`, which must not be past the end, allocating the clusters
+    /// needed. The caller holds the volume's allocation lock.
+    fn write_locked(
+        &self,
+        allocation: &mut Allocation,
+        state: &mut FileState,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), VfsError> {
+        let volume = &self.volume;
+        let cluster_bytes = volume.cluster_bytes();
+        let end = offset + data.len() as u64;
+        let mut clusters = match state.cluster {
+            0 => Vec::new(),
+            first => volume.chain(first)?,
+        };
+        while (clusters.len() as u64) < end.div_ceil(cluster_bytes) {
+            clusters.push(volume.allocate(allocation, clusters.last().copied())?);
+            if state.cluster == 0 {
+                state.cluster = clusters[0];
+                self.update_entry(state)?;
+            }
+        }
+        let mut sector = [0; BLOCK_SIZE];
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let cluster = clusters[(position / cluster_bytes) as usize];
+            let within = position % cluster_bytes;
+            let lba = volume.cluster_sector(cluster) + within / BLOCK_SIZE as u64;
+            let from = (within % BLOCK_SIZE as u64) as usize;
+            let count = (BLOCK_SIZE - from).min(data.len() - done);
+            if count < BLOCK_SIZE {
+                volume.read_sector(lba, &mut sector)?;
+            }
+            sector[from..from + count].copy_from_slice(&data[done..done + count]);
+            volume.write_sector(lba, &sector)?;
+            done += count;
+        }
+        state.size = state.size.max(end as u32);
+        self.update_entry(state)
+    }
+
+    /// Fills the file with zeros up to `size`.
+    fn grow_locked(
+        &self,
+        allocation: &mut Allocation,
+        state: &mut FileState,
+        size: u64,
+    ) -> Result<(), VfsError> {
+        let zeros = [0; BLOCK_SIZE];
+        while u64::from(state.size) < size {
+            let length = (size - u64::from(state.size)).min(BLOCK_SIZE as u64) as usize;
+            self.write_locked(allocation, state, u64::from(state.size), &zeros[..length])?;
+        }
+        Ok(())
+    }
+
+    /// Writes the first cluster and the size to the file's directory entry.
+    fn update_entry(&self, state: &FileState) -> Result<(), VfsError> {
+        let (time, date) = timestamp();
+        self.volume.modify_entry(self.location, |raw| {
+            set_cluster(raw, state.cluster);
+            raw[ENTRY_SIZE_FIELD..ENTRY_SIZE_FIELD + 4].copy_from_slice(&state.size.to_le_bytes());
+            raw[ENTRY_WRITE_TIME..ENTRY_WRITE_TIME + 2].copy_from_slice(&time.to_le_bytes());
+            raw[ENTRY_WRITE_DATE..ENTRY_WRITE_DATE + 2].copy_from_slice(&date.to_le_bytes());
+        })
+    }
+}
+
+impl Inode for FatFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::File,
+            size: u64::from(self.state.lock().size),
+            inode: self.location,
+        }
+    }
+}
+
+impl File for FatFile {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let state = *self.state.lock();
+        let size = u64::from(state.size);
+        if offset >= size || buffer.is_empty() {
+            return Ok(0);
+        }
+        let length = buffer.len().min((size - offset) as usize);
+        let volume = &self.volume;
+        let cluster_bytes = volume.cluster_bytes();
+        let mut cluster = volume.check_cluster(state.cluster)?;
+        for _ in 0..offset / cluster_bytes {
+            cluster = volume.next_cluster(cluster)?.ok_or(VfsError::Corrupt)?;
+        }
+        let mut sector = [0; BLOCK_SIZE];
+        let mut done = 0;
+        while done < length {
+            let within = (offset + done as u64) % cluster_bytes;
+            volume.read_sector(
+                volume.cluster_sector(cluster) + within / BLOCK_SIZE as u64,
+                &mut sector,
+            )?;
+            let from = (within % BLOCK_SIZE as u64) as usize;
+            let count = (BLOCK_SIZE - from).min(length - done);
+            buffer[done..done + count].copy_from_slice(&sector[from..from + count]);
+            done += count;
+            if (within + count as u64) == cluster_bytes && done < length {
+                cluster = volume.next_cluster(cluster)?.ok_or(VfsError::Corrupt)?;
+            }
+        }
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(data.len() as u64);
+        if end.is_none_or(|end| end > u64::from(u32::MAX)) {
+            return Err(VfsError::NoSpace);
+        }
+        let mut allocation = self.volume.allocation.lock();
+        let mut state = self.state.lock();
+        if state.unlinked {
+            return Err(VfsError::NotFound);
+        }
+        self.grow_locked(&mut allocation, &mut state, offset)?;
+        self.write_locked(&mut allocation, &mut state, offset, data)?;
+        Ok(data.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        if size > u64::from(u32::MAX) {
+            return Err(VfsError::NoSpace);
+        }
+        let volume = &self.volume;
+        let mut allocation = volume.allocation.lock();
+        let mut state = self.state.lock();
+        if state.unlinked {
+            return Err(VfsError::NotFound);
+        }
+        if size >= u64::from(state.size) {
+            return self.grow_locked(&mut allocation, &mut state, size);
+        }
+        if state.cluster != 0 {
+            let clusters = volume.chain(state.cluster)?;
+            let kept = size.div_ceil(volume.cluster_bytes()) as usize;
+            if kept == 0 {
+                state.cluster = 0;
+            } else if kept < clusters.len() {
+                volume.set_fat_entry(clusters[kept - 1], FAT_END_MARK)?;
+            }
+            volume.release(&mut allocation, clusters.get(kept..).unwrap_or_default())?;
+        }
+        state.size = size as u32;
+        self.update_entry(&state)
+    }
+}
+
+/// A FAT32 volume.
+pub struct Fat32 {
+    volume: Arc<Volume>,
+}
+
+impl Fat32 {
+    /// Reads the boot sector of `device` and fails with [`VfsError::Corrupt`] unless it
+    /// describes a FAT32 volume with 512-byte sectors.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Fat32, VfsError> {
+        let mut boot = [0; BLOCK_SIZE];
+        device.read_block(0, &mut boot)?;
+        let u16_at = |at: usize| u64::from(u16::from_le_bytes([boot[at], boot[at + 1]]));
+        let sectors_per_cluster = u64::from(boot[BPB_SECTORS_PER_CLUSTER]);
+        let fat_count = u64::from(boot[BPB_FAT_COUNT]);
+        let fat_size = u64::from(u32_at(&boot, BPB_FAT_SIZE_32));
+        // FAT12 and FAT16 have a fixed root directory and a 16-bit FAT size.
+        let fat32 = boot[BLOCK_SIZE - 2..] == BOOT_SIGNATURE
+            && u16_at(BPB_BYTES_PER_SECTOR) == BLOCK_SIZE as u64
+            && sectors_per_cluster.is_power_of_two()
+            && fat_count != 0
+            && u16_at(BPB_ROOT_ENTRIES) == 0
+            && u16_at(BPB_FAT_SIZE_16) == 0
+            && fat_size != 0;
+        if !fat32 {
+            return Err(VfsError::Corrupt);
+        }
+        let total_sectors = match u16_at(BPB_TOTAL_SECTORS_16) {
+            0 => u64::from(u32_at(&boot, BPB_TOTAL_SECTORS_32)),
+            sectors => sectors,
+        };
+        let reserved = u16_at(BPB_RESERVED_SECTORS);
+        let data_start = reserved + fat_count * fat_size;
+        let clusters = total_sectors
+            .checked_sub(data_start)
+            .ok_or(VfsError::Corrupt)?
+            / sectors_per_cluster;
+        // The FAT has to have an entry for every cluster.
+        let clusters = clusters.min(fat_size * BLOCK_SIZE as u64 / 4 - u64::from(FIRST_CLUSTER));
+        if total_sectors > device.block_count() {
+            return Err(VfsError::Corrupt);
+        }
+        let cluster_end = FIRST_CLUSTER + clusters as u32;
+        let fs_info = Some(u16_at(BPB_FS_INFO)).filter(|&sector| (1..reserved).contains(&sector));
+        let mut allocation = Allocation {
+            free: FS_INFO_UNKNOWN,
+            next: FIRST_CLUSTER,
+        };
+        if let Some(sector) = fs_info {
+            let mut info = [0; BLOCK_SIZE];
+            device.read_block(sector, &mut info)?;
+            if fs_info_valid(&info) {
+                let free = u32_at(&info, FS_INFO_FREE);
+                if free <= clusters as u32 {
+                    allocation.free = free;
+                }
+                allocation.next = u32_at(&info, FS_INFO_NEXT);
+            }
+        }
+        let volume = Volume {
+            device,
+            sectors_per_cluster,
+            fat_start: reserved,
+            fat_size,
+            fat_count,
+            data_start,
+            root_cluster: u32_at(&boot, BPB_ROOT_CLUSTER),
+            cluster_end,
+            fs_info,
+            allocation: Mutex::new(allocation),
+            files: Mutex::new(BTreeMap::new()),
+        };
+        volume.check_cluster(volume.root_cluster)?;
+        Ok(Fat32 {
+            volume: Arc::new(volume),
+        })
+    }
+}
+
+fn fs_info_valid(info: &[u8]) -> bool {
+    [FS_INFO_LEAD, FS_INFO_STRUCT, FS_INFO_TRAIL]
+        .iter()
+        .all(|&(at, signature)| u32_at(info, at) == signature)
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        Arc::new(FatDir {
+            volume: self.volume.clone(),
+            cluster: self.volume.root_cluster,
+            inode: 0,
+        })
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        let volume = &self.volume;
+        let allocation = volume.allocation.lock();
+        if let Some(sector) = volume.fs_info {
+            let mut info = [0; BLOCK_SIZE];
+            volume.read_sector(sector, &mut info)?;
+            if fs_info_valid(&info) {
+                info[FS_INFO_FREE..FS_INFO_FREE + 4]
+                    .copy_from_slice(&allocation.free.to_le_bytes());
+                info[FS_INFO_NEXT..FS_INFO_NEXT + 4]
+                    .copy_from_slice(&allocation.next.to_le_bytes());
+                volume.write_sector(sector, &info)?;
+            }
+        }
+        Ok(volume.device.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ramdisk::RamDisk;
+    use crate::testing::{put_u16, put_u32};
+
+    /// The test volume: the boot sector, FSInfo, two one-sector FATs and 64 one-sector clusters,
+    /// the first of them the root directory.
+    const RESERVED: u64 = 2;
+    const FATS: u64 = 2;
+    const CLUSTERS: u32 = 64;
+    const SECTORS: u64 = RESERVED + FATS + CLUSTERS as u64;
+
+    /// Sets the FAT entry of `cluster` to `value` in every FAT of the image.
+    fn set_fat_entry(disk: &Arc<dyn BlockDevice>, cluster: u32, value: u32) {
+        let mut fat = [0; BLOCK_SIZE];
+        for index in 0..FATS {
+            disk.read_block(RESERVED + index, &mut fat).unwrap();
+            put_u32(&mut fat, cluster as usize * 4, value);
+            disk.write_block(RESERVED + index, &fat).unwrap();
+        }
+    }
+
+    /// An image with an empty root directory.
+    fn formatted() -> Arc<dyn BlockDevice> {
+        let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(SECTORS as usize));
+        let mut boot = [0; BLOCK_SIZE];
+        put_u16(&mut boot, BPB_BYTES_PER_SECTOR, BLOCK_SIZE as u16);
+        boot[BPB_SECTORS_PER_CLUSTER] = 1;
+        put_u16(&mut boot, BPB_RESERVED_SECTORS, RESERVED as u16);
+        boot[BPB_FAT_COUNT] = FATS as u8;
+        put_u32(&mut boot, BPB_TOTAL_SECTORS_32, SECTORS as u32);
+        put_u32(&mut boot, BPB_FAT_SIZE_32, 1);
+        put_u32(&mut boot, BPB_ROOT_CLUSTER, FIRST_CLUSTER);
+        put_u16(&mut boot, BPB_FS_INFO, 1);
+        boot[BLOCK_SIZE - 2..].copy_from_slice(&BOOT_SIGNATURE);
+        disk.write_block(0, &boot).unwrap();
+        let mut info = [0; BLOCK_SIZE];
+        for (at, signature) in [FS_INFO_LEAD, FS_INFO_STRUCT, FS_INFO_TRAIL] {
+            put_u32(&mut info, at, signature);
+        }
+        put_u32(&mut info, FS_INFO_FREE, CLUSTERS - 1);
+        put_u32(&mut info, FS_INFO_NEXT, FIRST_CLUSTER + 1);
+        disk.write_block(1, &info).unwrap();
+        // The first two entries hold the media byte and an end mark; the root is one cluster.
+        set_fat_entry(&disk, 0, 0x0FFF_FFF8);
+        set_fat_entry(&disk, 1, FAT_END_MARK);
+        set_fat_entry(&disk, FIRST_CLUSTER, FAT_END_MARK);
+        disk
+    }
+
+    fn free_clusters(fs: &Fat32) -> u32 {
+        fs.volume.allocation.lock().free
+    }
+
+    #[test_case]
+    fn reads_the_layout() {
+        let fs = Fat32::new(formatted()).unwrap();
+        let volume = &fs.volume;
+        assert_eq!(volume.data_start, RESERVED + FATS);
+        assert_eq!(volume.cluster_end, FIRST_CLUSTER + CLUSTERS);
+        assert_eq!(free_clusters(&fs), CLUSTERS - 1);
+        assert_eq!(volume.chain(FIRST_CLUSTER), Ok(vec![FIRST_CLUSTER]));
+        assert!(fs.root().read_dir().unwrap().is_empty());
+    }
+
+    #[test_case]
+    fn reads_files_across_clusters() {
+        let disk = formatted();
+        let mut root = [0; BLOCK_SIZE];
+        root[..11].copy_from_slice(b"HELLO   TXT");
+        root[ENTRY_CASE] = CASE_LOWER_BASE;
+        put_u16(&mut root, ENTRY_CLUSTER_LOW, 3);
+        put_u32(&mut root, ENTRY_SIZE_FIELD, 600);
+        disk.write_block(RESERVED + FATS, &root).unwrap();
+        set_fat_entry(&disk, 3, 4);
+        set_fat_entry(&disk, 4, FAT_END_MARK);
+        disk.write_block(RESERVED + FATS + 1, &[b'a'; BLOCK_SIZE])
+            .unwrap();
+        disk.write_block(RESERVED + FATS + 2, &[b'b'; BLOCK_SIZE])
+            .unwrap();
+        let fs = Fat32::new(disk).unwrap();
+        let names: Vec<String> = fs
+            .root()
+            .read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["hello.TXT"]);
+        let Ok(Node::File(file)) = fs.root().lookup("HELLO.txt") else {
+            panic!("no file found");
+        };
+        let mut read = [0; 700];
+        assert_eq!(file.read_at(500, &mut read), Ok(100));
+        assert_eq!(&read[..12], &[b'a'; 12]);
+        assert_eq!(&read[12..100], &[b'b'; 88]);
+    }
+
+    #[test_case]
+    fn looping_chains_are_corrupt() {
+        let disk = formatted();
+        let fs = Fat32::new(disk.clone()).unwrap();
+        let volume = &fs.volume;
+        set_fat_entry(&disk, 3, 4);
+        set_fat_entry(&disk, 4, 3);
+        assert_eq!(volume.chain(3), Err(VfsError::Corrupt));
+        set_fat_entry(&disk, 4, FAT_BAD);
+        assert_eq!(volume.chain(3), Err(VfsError::Corrupt));
+        set_fat_entry(&disk, 4, CLUSTERS + FIRST_CLUSTER);
+        assert_eq!(volume.chain(3), Err(VfsError::Corrupt));
+    }
+
+    #[test_case]
+    fn chains_grow_in_every_fat_and_shrink() {
+        let fs = Fat32::new(formatted()).unwrap();
+        let volume = &fs.volume;
+        let mut allocation = volume.allocation.lock();
+        let first = volume.allocate(&mut allocation, None).unwrap();
+        let second = volume.allocate(&mut allocation, Some(first)).unwrap();
+        assert_eq!(volume.chain(first), Ok(vec![first, second]));
+        assert_eq!(allocation.free, CLUSTERS - 3);
+        let mut fat = [0; BLOCK_SIZE];
+        volume.read_sector(RESERVED + 1, &mut fat).unwrap();
+        assert_eq!(u32_at(&fat, first as usize * 4), second);
+        volume.release(&mut allocation, &[first, second]).unwrap();
+        assert_eq!(volume.next_cluster(first), Err(VfsError::Corrupt));
+        assert_eq!(allocation.free, CLUSTERS - 1);
+        // The search for a free cluster wraps around to the ones just released.
+        allocation.next = FIRST_CLUSTER + CLUSTERS - 1;
+        assert_eq!(
+            volume.allocate(&mut allocation, None),
+            Ok(FIRST_CLUSTER + CLUSTERS - 1)
+        );
+        assert_eq!(volume.allocate(&mut allocation, None), Ok(first));
+    }
+
+    #[test_case]
+    fn creates_and_unlinks_files() {
+        let fs = Fat32::new(formatted()).unwrap();
+        let root = fs.root();
+        let Ok(Node::File(file)) = root.create("Notes of a long name.text", FileType::File) else {
+            panic!("no file created");
+        };
+        let data: Vec<u8> = (0..1200).map(|byte| byte as u8).collect();
+        assert_eq!(file.write_at(0, &data), Ok(data.len()));
+        assert_eq!(free_clusters(&fs), CLUSTERS - 4);
+        drop(file);
+        assert_eq!(
+            root.create("NOTES OF A LONG NAME.TEXT", FileType::File)
+                .err(),
+            Some(VfsError::AlreadyExists)
+        );
+        let Ok(Node::File(file)) = root.lookup("notes of a long name.text") else {
+            panic!("no file found");
+        };
+        let mut read = vec![0; data.len() + 1];
+        assert_eq!(file.read_at(0, &mut read), Ok(data.len()));
+        assert_eq!(&read[..data.len()], &data[..]);
+        let names: Vec<String> = root
+            .read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["Notes of a long name.text"]);
+        root.unlink("Notes of a long name.text").unwrap();
+        assert_eq!(file.write_at(0, b"gone"), Err(VfsError::NotFound));
+        assert_eq!(
+            root.lookup("notes of a long name.text").err(),
+            Some(VfsError::NotFound)
+        );
+        assert_eq!(free_clusters(&fs), CLUSTERS - 1);
+    }
+
+    #[test_case]
+    fn removes_only_empty_directories() {
+        let fs = Fat32::new(formatted()).unwrap();
+        let root = fs.root();
+        let Ok(Node::Dir(dir)) = root.create("BIN", FileType::Directory) else {
+            panic!("no directory created");
+        };
+        dir.create("sh", FileType::File).unwrap();
+        assert_eq!(root.unlink("bin"), Err(VfsError::NotEmpty));
+        dir.unlink("SH").unwrap();
+        root.unlink("bin").unwrap();
+        assert!(root.read_dir().unwrap().is_empty());
+        assert_eq!(free_clusters(&fs), CLUSTERS - 1);
+    }
+}
@@ -0,0 +1,214 @@
+//! The process file system, mounted at `/proc`.
+//!
+//! Its files hold no data. Each is text written on every read from the kernel's own statistics:
+//! `meminfo` from the frame allocator and the heap, `interrupts` from the interrupt counters,
+//! `uptime` from the clock, and `threads/<id>` from the scheduler, one file per thread that
+//! exists at the time. Sizes read as 0, since the text is not known before it is written.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use super::{Dir, DirEntry, File, FileSystem, FileType, Inode, Metadata, Node, VfsError};
+use crate::thread::{self, ThreadInfo};
+
+const ROOT_INODE: u64 = 1;
+const THREADS_INODE: u64 = 2;
+/// Inode numbers of the thread files start here, followed by the thread's id.
+const THREAD_INODES: u64 = 0x1000;
+
+/// The files of the root directory with their inode numbers.
+const FILES: [(&str, u64, fn() -> String); 3] = [
+    ("meminfo", 3, meminfo),
+    ("interrupts", 4, interrupts),
+    ("uptime", 5, uptime),
+];
+
+/// A file whose contents are made when it is read.
+struct Generated {
+    inode: u64,
+    generate: Box<dyn Fn() -> Result<String, VfsError> + Send + Sync>,
+}
+
+impl Inode for Generated {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::File,
+            size: 0,
+            inode: self.inode,
+        }
+    }
+}
+
+impl File for Generated {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let text = (self.generate)()?;
+        let text = text.as_bytes();
+        let start = text.len().min(offset.try_into().unwrap_or(usize::MAX));
+        let length = buffer.len().min(text.len() - start);
+        buffer[..length].copy_from_slice(&text[start..start + length]);
+        Ok(length)
+    }
+}
+
+fn directory(inode: u64) -> Metadata {
+    Metadata {
+        kind: FileType::Directory,
+        size: 0,
+        inode,
+    }
+}
+
+/// The root directory.
+struct ProcDir;
+
+impl Inode for ProcDir {
+    fn metadata(&self) -> Metadata {
+        directory(ROOT_INODE)
+    }
+}
+
+impl Dir for ProcDir {
+    fn lookup(&self, name: &str) -> Result<Node, VfsError> {
+        if name == "threads" {
+            return Ok(Node::Dir(Arc::new(ThreadsDir)));
+        }
+        let &(_, inode, generate) = FILES
+            .iter()
+            .find(|(file, _, _)| *file == name)
+            .ok_or(VfsError::NotFound)?;
+        Ok(Node::File(Arc::new(Generated {
+            inode,
+            generate: Box::new(move || Ok(generate())),
+        })))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let mut entries: Vec<DirEntry> = FILES
+            .iter()
+            .map(|(name, _, _)| DirEntry {
+                name: String::from(*name),
+                kind: FileType::File,
+            })
+            .collect();
+        entries.push(DirEntry {
+            name: String::from("threads"),
+            kind: FileType::Directory,
+        });
+        Ok(entries)
+    }
+}
+
+/// `threads`, with a file per thread named by its id.
+struct ThreadsDir;
+
+impl Inode for ThreadsDir {
+    fn metadata(&self) -> Metadata {
+        directory(THREADS_INODE)
+    }
+}
+
+impl Dir for ThreadsDir {
+    fn lookup(&self, name: &str) -> Result<Node, VfsError> {
+        let id: u64 = name.parse().map_err(|_| VfsError::NotFound)?;
+        // Leading zeros or a plus sign would name the same thread twice.
+        if format!("{}", id) != name || find_thread(id).is_none() {
+            return Err(VfsError::NotFound);
+        }
+        Ok(Node::File(Arc::new(Generated {
+            inode: THREAD_INODES + id,
+            // The thread may be gone by the time the file is read.
+            generate: Box::new(move || {
+                find_thread(id)
+                    .map(|thread| thread_status(&thread))
+                    .ok_or(VfsError::NotFound)
+            }),
+        })))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Ok(thread::list()
+            .into_iter()
+            .map(|thread| DirEntry {
+                name: format!("{}", thread.id.as_u64()),
+                kind: FileType::File,
+            })
+            .collect())
+    }
+}
+
+fn find_thread(id: u64) -> Option<ThreadInfo> {
+    thread::list()
+        .into_iter()
+        .find(|thread| thread.id.as_u64() == id)
+}
+
+fn meminfo() -> String {
+    let mut text = String::new();
+    if let Some(stats) = crate::memory::stats() {
+        let frames_kib = |frames: usize| frames as u64 * 4;
+        let lines = [
+            ("MemTotal", stats.total / 1024),
+            ("MemUsable", stats.usable / 1024),
+            ("MemReserved", stats.reserved / 1024),
+            ("FramesUsed", frames_kib(stats.frames.used())),
+            ("FramesFree", frames_kib(stats.frames.free)),
+            ("HeapSize", stats.heap.size as u64 / 1024),
+            ("HeapUsed", stats.heap.used as u64 / 1024),
+        ];
+        for (name, kib) in lines {
+            let _ = writeln!(text, "{:<12} {:>10} kB", format!("{}:", name), kib);
+        }
+    }
+    text
+}
+
+fn interrupts() -> String {
+    let stats = crate::interruptsa::stats();
+    let mut text = String::new();
+    for (vector, count) in stats.iter() {
+        let name = crate::interruptsa::vector_name(vector);
+        let _ = writeln!(text, "{:#04x}: {:>10}  {}", vector, count, name);
+    }
+    let _ = writeln!(text, "SPU: {:>10}  spurious", stats.spurious());
+    text
+}
+
+/// Seconds since boot, with hundredths.
+fn uptime() -> String {
+    let uptime = crate::time::uptime();
+    format!("{}.{:02}\n", uptime.as_secs(), uptime.subsec_millis() / 10)
+}
+
+fn thread_status(thread: &ThreadInfo) -> String {
+    let mut text = String::new();
+    let _ = writeln!(text, "Name:\t{}", thread.name);
+    let _ = writeln!(text, "State:\t{:?}", thread.state);
+    let _ = writeln!(text, "Priority:\t{:?}", thread.priority);
+    let _ = writeln!(text, "Cpu:\t{}", thread.cpu);
+    let _ = writeln!(text, "Ticks:\t{}", thread.ticks);
+    let _ = writeln!(text, "Switches:\t{}", thread.switches);
+    let _ = writeln!(text, "CpuPercent:\t{}", thread.cpu_percent);
+    text
+}
+
+/// The file system at `/proc`.
+pub struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        Arc::new(ProcDir)
+    }
+}
+
+/// Mounts procfs at `/proc`.
+pub fn init() -> Result<(), VfsError> {
+    super::mount("/proc", Arc::new(ProcFs))
+}
@@ -0,0 +1,476 @@
+//! Tar archives, read-only, such as the initial ramdisk.
+//!
+//! A USTAR archive is a run of 512-byte headers, each followed by the member's data padded to a
+//! whole block, and ends with a block of zeros. [`Tar::new`] walks it once and builds the tree;
+//! files read straight from the archive, which stays where the bootloader put it, so mounting
+//! costs no copies. Directories missing from the archive are made up from the paths below them.
+//!
+//! Besides files and directories, symbolic links read as their target, hard links share the
+//! inode of the member they name, and device nodes are listed with no data. The GNU long name
+//! member is understood; pax headers are skipped.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{Dir, DirEntry, File, FileSystem, FileType, Inode, Metadata, Node, VfsError};
+
+const BLOCK: usize = 512;
+
+/// Header fields, as offset and length.
+const NAME: (usize, usize) = (0, 100);
+const SIZE: (usize, usize) = (124, 12);
+const CHECKSUM: (usize, usize) = (148, 8);
+const TYPE: usize = 156;
+const LINK_NAME: (usize, usize) = (157, 100);
+const MAGIC: (usize, usize) = (257, 5);
+const PREFIX: (usize, usize) = (345, 155);
+
+/// Member types. Old archives mark files with a zero byte.
+const TYPE_FILE: u8 = b'0';
+const TYPE_OLD_FILE: u8 = 0;
+const TYPE_HARD_LINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_CHAR_DEVICE: u8 = b'3';
+const TYPE_BLOCK_DEVICE: u8 = b'4';
+const TYPE_DIRECTORY: u8 = b'5';
+const TYPE_CONTIGUOUS: u8 = b'7';
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+
+fn field(header: &[u8], (offset, length): (usize, usize)) -> &[u8] {
+    &header[offset..offset + length]
+}
+
+/// A string field, which ends at its first zero byte or fills the field.
+fn text(header: &'static [u8], at: (usize, usize)) -> &'static [u8] {
+    let bytes = &header[at.0..at.0 + at.1];
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+/// A number field, in octal digits padded with spaces or zeros.
+fn octal(bytes: &[u8]) -> Result<u64, VfsError> {
+    let mut value: u64 = 0;
+    for &byte in bytes.iter().skip_while(|&&b| b == b' ') {
+        match byte {
+            b'0'..=b'7' => {
+                value = value
+                    .checked_mul(8)
+                    .ok_or(VfsError::Corrupt)?
+                    .wrapping_add(u64::from(byte - b'0'));
+            }
+            b' ' | 0 => break,
+            _ => return Err(VfsError::Corrupt),
+        }
+    }
+    Ok(value)
+}
+
+/// Whether the checksum field matches the header, summed with the field read as spaces.
+fn checksum_valid(header: &[u8]) -> Result<bool, VfsError> {
+    let (start, length) = CHECKSUM;
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(index, &byte)| match index {
+            i if (start..start + length).contains(&i) => u64::from(b' '),
+            _ => u64::from(byte),
+        })
+        .sum();
+    Ok(octal(field(header, CHECKSUM))? == sum)
+}
+
+/// A member while the tree is built.
+struct Member {
+    inode: u64,
+    kind: FileType,
+    data: &'static [u8],
+    children: BTreeMap<String, Member>,
+}
+
+impl Member {
+    fn directory(inode: u64) -> Member {
+        Member {
+            inode,
+            kind: FileType::Directory,
+            data: &[],
+            children: BTreeMap::new(),
+        }
+    }
+
+    /// The member at the components of `path`, if there is one.
+    fn find(&self, path: &str) -> Option<&Member> {
+        path.split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+            .try_fold(self, |member, name| member.children.get(name))
+    }
+
+    fn freeze(self) -> Node {
+        if self.kind == FileType::Directory {
+            let entries = self
+                .children
+                .into_iter()
+                .map(|(name, member)| (name, member.freeze()))
+                .collect();
+            Node::Dir(Arc::new(TarDir {
+                inode: self.inode,
+                entries,
+            }))
+        } else {
+            Node::File(Arc::new(TarFile {
+                inode: self.inode,
+                kind: self.kind,
+                data: self.data,
+            }))
+        }
+    }
+}
+
+/// A directory of an archive.
+struct TarDir {
+    inode: u64,
+    entries: BTreeMap<String, Node>,
+}
+
+impl Inode for TarDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::Directory,
+            size: 0,
+            inode: self.inode,
+        }
+    }
+}
+
+impl Dir for TarDir {
+    fn lookup(&self, name: &str) -> Result<Node, VfsError> {
+        self.entries.get(name).cloned().ok_or(VfsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                kind: node.metadata().kind,
+            })
+            .collect())
+    }
+}
+
+/// A file, symbolic link or device node of an archive.
+struct TarFile {
+    inode: u64,
+    kind: FileType,
+    data: &'static [u8],
+}
+
+impl Inode for TarFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: self.kind,
+            size: self.data.len() as u64,
+            inode: self.inode,
+        }
+    }
+}
+
+impl File for TarFile {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let start = self.data.len().min(offset.try_into().unwrap_or(usize::MAX));
+        let length = buffer.len().min(self.data.len() - start);
+        buffer[..length].copy_from_slice(&self.data[start..start + length]);
+        Ok(length)
+    }
+}
+
+/// A tar archive mounted as a file system.
+pub struct Tar {
+    root: Arc<dyn Dir>,
+    members: usize,
+}
+
+impl Tar {
+    /// Reads the tree of `archive`. Fails with [`VfsError::Corrupt`] if it is not a USTAR archive
+    /// or a header is damaged.
+    pub fn new(archive: &'static [u8]) -> Result<Tar, VfsError> {
+        let mut root = Member::directory(1);
+        let mut next_inode = 2;
+        let mut members = 0;
+        let mut long_name: Option<&'static [u8]> = None;
+        let mut offset = 0;
+        while offset + BLOCK <= archive.len() {
+            let header = &archive[offset..offset + BLOCK];
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            // Both the POSIX "ustar\0" and the GNU "ustar " magic.
+            if field(header, MAGIC) != b"ustar" || !checksum_valid(header)? {
+                return Err(VfsError::Corrupt);
+            }
+            let size = octal(field(header, SIZE))?;
+            let start = offset + BLOCK;
+            let end = usize::try_from(size)
+                .ok()
+                .and_then(|size| start.checked_add(size))
+                .filter(|&end| end <= archive.len())
+                .ok_or(VfsError::Corrupt)?;
+            offset = end.next_multiple_of(BLOCK);
+            let data = &archive[start..end];
+
+            let kind = match header[TYPE] {
+                TYPE_GNU_LONG_NAME => {
+                    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                    long_name = Some(&data[..end]);
+                    continue;
+                }
+                TYPE_FILE | TYPE_OLD_FILE | TYPE_CONTIGUOUS | TYPE_HARD_LINK => FileType::File,
+                TYPE_DIRECTORY => FileType::Directory,
+                TYPE_SYMLINK => FileType::Symlink,
+                TYPE_CHAR_DEVICE => FileType::CharDevice,
+                TYPE_BLOCK_DEVICE => FileType::BlockDevice,
+                // pax headers and anything newer.
+                _ => {
+                    long_name = None;
+                    continue;
+                }
+            };
+            let path = match long_name.take() {
+                Some(name) => String::from_utf8_lossy(name).into_owned(),
+                None => {
+                    let (prefix, name) = (text(header, PREFIX), text(header, NAME));
+                    let mut path = String::from_utf8_lossy(prefix).into_owned();
+                    if !path.is_empty() {
+                        path.push('/');
+                    }
+                    path.push_str(&String::from_utf8_lossy(name));
+                    path
+                }
+            };
+
+            let (inode, data) = match header[TYPE] {
+                TYPE_HARD_LINK => {
+                    let target = String::from_utf8_lossy(text(header, LINK_NAME));
+                    let target = root.find(&target).ok_or(VfsError::Corrupt)?;
+                    if target.kind != FileType::File {
+                        return Err(VfsError::Corrupt);
+                    }
+                    (target.inode, target.data)
+                }
+                kind => {
+                    next_inode += 1;
+                    let data = match kind {
+                        TYPE_SYMLINK => text(header, LINK_NAME),
+                        TYPE_FILE | TYPE_OLD_FILE | TYPE_CONTIGUOUS => data,
+                        _ => &[],
+                    };
+                    (next_inode - 1, data)
+                }
+            };
+            insert(&mut root, &path, kind, inode, data, &mut next_inode)?;
+            members += 1;
+        }
+        Ok(Tar {
+            root: root.freeze().into_dir()?,
+            members,
+        })
+    }
+
+    /// How many members the archive has, directories included.
+    pub fn members(&self) -> usize {
+        self.members
+    }
+}
+
+/// Puts a member at `path` beneath `root`, making up the directories on the way. A directory
+/// listed again keeps its entries.
+fn insert(
+    root: &mut Member,
+    path: &str,
+    kind: FileType,
+    inode: u64,
+    data: &'static [u8],
+    next_inode: &mut u64,
+) -> Result<(), VfsError> {
+    let mut components: Vec<&str> = path
+        .split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .collect();
+    if components.contains(&"..") {
+        return Err(VfsError::InvalidPath);
+    }
+    // "./" is the root itself.
+    let Some(name) = components.pop() else {
+        return Ok(());
+    };
+    let mut directory = root;
+    for component in components {
+        directory = directory
+            .children
+            .entry(String::from(component))
+            .or_insert_with(|| {
+                *next_inode += 1;
+                Member::directory(*next_inode - 1)
+            });
+        if directory.kind != FileType::Directory {
+            return Err(VfsError::NotADirectory);
+        }
+    }
+    match directory.children.get_mut(name) {
+        Some(existing) if existing.kind == FileType::Directory && kind == FileType::Directory => {}
+        // A later member replaces an earlier one of the same name, as extracting would.
+        _ => {
+            let member = Member {
+                inode,
+                kind,
+                data,
+                children: BTreeMap::new(),
+            };
+            directory.children.insert(String::from(name), member);
+        }
+    }
+    Ok(())
+}
+
+impl FileSystem for Tar {
+    fn name(&self) -> &'static str {
+        "tar"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        self.root.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::format;
+    use alloc::vec;
+
+    fn put(header: &mut [u8], (offset, length): (usize, usize), value: &[u8]) {
+        header[offset..offset + value.len().min(length)].copy_from_slice(value);
+    }
+
+    /// Appends a member to `archive`.
+    fn member(archive: &mut Vec<u8>, name: &str, kind: u8, link: &str, data: &[u8]) {
+        let mut header = [0; BLOCK];
+        put(&mut header, NAME, name.as_bytes());
+        put(&mut header, SIZE, format!("{:011o}", data.len()).as_bytes());
+        header[TYPE] = kind;
+        put(&mut header, LINK_NAME, link.as_bytes());
+        put(&mut header, MAGIC, b"ustar\0");
+        header[MAGIC.0 + 6..MAGIC.0 + 8].copy_from_slice(b"00");
+        put(&mut header, CHECKSUM, b"        ");
+        let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+        put(&mut header, CHECKSUM, format!("{:06o}\0 ", sum).as_bytes());
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(BLOCK), 0);
+    }
+
+    fn finish(mut archive: Vec<u8>) -> &'static [u8] {
+        archive.extend_from_slice(&[0; 2 * BLOCK]);
+        Box::leak(archive.into_boxed_slice())
+    }
+
+    fn contents(node: Node) -> Vec<u8> {
+        let Node::File(file) = node else {
+            panic!("not a file");
+        };
+        let mut data = vec![0; file.metadata().size as usize];
+        assert_eq!(file.read_at(0, &mut data), Ok(data.len()));
+        data
+    }
+
+    #[test_case]
+    fn reads_numbers_in_octal() {
+        assert_eq!(octal(b"00000001750\0"), Ok(0o1750));
+        assert_eq!(octal(b"   17 \0"), Ok(0o17));
+        assert_eq!(octal(b"\0\0"), Ok(0));
+        assert_eq!(octal(b"0018"), Err(VfsError::Corrupt));
+    }
+
+    #[test_case]
+    fn builds_the_tree() {
+        let long = "a/path/longer/than/the/hundred/bytes/of/a/name/field/which/needs/a/gnu/long/name/member.txt";
+        let mut archive = Vec::new();
+        member(&mut archive, "./", TYPE_DIRECTORY, "", &[]);
+        member(&mut archive, "bin/sh", TYPE_FILE, "", b"#!shell");
+        member(&mut archive, "bin/ls", TYPE_SYMLINK, "sh", &[]);
+        member(&mut archive, "bin/sh2", TYPE_HARD_LINK, "./bin/sh", &[]);
+        member(&mut archive, "pax", b'x', "", b"12 path=x\n");
+        member(&mut archive, "dev/tty", TYPE_CHAR_DEVICE, "", &[]);
+        member(
+            &mut archive,
+            "././@LongLink",
+            TYPE_GNU_LONG_NAME,
+            "",
+            long.as_bytes(),
+        );
+        member(&mut archive, "truncated", TYPE_FILE, "", b"long");
+        member(&mut archive, "bin/", TYPE_DIRECTORY, "", &[]);
+        let tar = Tar::new(finish(archive)).unwrap();
+        assert_eq!(tar.members(), 7);
+
+        let root = tar.root();
+        let names: Vec<String> = root
+            .read_dir()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["a", "bin", "dev"]);
+        let Ok(Node::Dir(bin)) = root.lookup("bin") else {
+            panic!("no bin directory");
+        };
+        let sh = bin.lookup("sh").ok().unwrap();
+        let sh2 = bin.lookup("sh2").ok().unwrap();
+        assert_eq!(sh.metadata().inode, sh2.metadata().inode);
+        assert_eq!(contents(sh2), b"#!shell");
+        let ls = bin.lookup("ls").ok().unwrap();
+        assert_eq!(ls.metadata().kind, FileType::Symlink);
+        assert_eq!(contents(ls), b"sh");
+        let Ok(Node::Dir(dev)) = root.lookup("dev") else {
+            panic!("no dev directory");
+        };
+        let tty = dev.lookup("tty").ok().unwrap().metadata();
+        assert_eq!((tty.kind, tty.size), (FileType::CharDevice, 0));
+        let mut node = Node::Dir(root);
+        for name in long.split('/') {
+            let Node::Dir(dir) = node else {
+                panic!("{} is under a file", name);
+            };
+            node = dir.lookup(name).ok().unwrap();
+        }
+        assert_eq!(contents(node), b"long");
+    }
+
+    #[test_case]
+    fn rejects_damaged_archives() {
+        let mut archive = Vec::new();
+        member(&mut archive, "file", TYPE_FILE, "", b"data");
+        let mut damaged = archive.clone();
+        damaged[NAME.0] = b'F';
+        assert_eq!(Tar::new(finish(damaged)).err(), Some(VfsError::Corrupt));
+        let mut cut = archive.clone();
+        cut.truncate(BLOCK + 2);
+        assert_eq!(
+            Tar::new(Box::leak(cut.into_boxed_slice())).err(),
+            Some(VfsError::Corrupt)
+        );
+
+        let mut escaping = Vec::new();
+        member(&mut escaping, "../file", TYPE_FILE, "", b"data");
+        assert_eq!(
+            Tar::new(finish(escaping)).err(),
+            Some(VfsError::InvalidPath)
+        );
+        let mut dangling = Vec::new();
+        member(&mut dangling, "link", TYPE_HARD_LINK, "missing", &[]);
+        assert_eq!(Tar::new(finish(dangling)).err(), Some(VfsError::Corrupt));
+    }
+}
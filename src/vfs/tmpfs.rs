@@ -0,0 +1,176 @@
+//! An in-memory file system, mounted at `/tmp`.
+//!
+//! Files are byte vectors on the heap and directories are maps from names to nodes, so
+//! everything in a tmpfs is gone when it is unmounted or the machine restarts. It supports every
+//! operation of the VFS, which makes it the place to try out code that writes.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Dir, DirEntry, File, FileSystem, FileType, Inode, Metadata, Node, VfsError};
+use crate::sync::Mutex;
+
+/// A tmpfs directory.
+struct TmpDir {
+    inode: u64,
+    /// Inode number of the next node, shared by the whole file system.
+    next_inode: Arc<AtomicU64>,
+    entries: Mutex<BTreeMap<String, Node>>,
+}
+
+impl TmpDir {
+    fn new(inode: u64, next_inode: Arc<AtomicU64>) -> TmpDir {
+        TmpDir {
+            inode,
+            next_inode,
+            entries: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Inode for TmpDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::Directory,
+            size: 0,
+            inode: self.inode,
+        }
+    }
+}
+
+impl Dir for TmpDir {
+    fn lookup(&self, name: &str) -> Result<Node, VfsError> {
+        self.entries
+            .lock()
+            .get(name)
+            .cloned()
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let entries = self.entries.lock();
+        Ok(entries
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                kind: node.metadata().kind,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Node, VfsError> {
+        let mut entries = self.entries.lock();
+        if entries.contains_key(name) {
+            return Err(VfsError::AlreadyExists);
+        }
+        let inode = self.next_inode.fetch_add(1, Ordering::Relaxed);
+        let node = match kind {
+            FileType::File => Node::File(Arc::new(TmpFile {
+                inode,
+                data: Mutex::new(Vec::new()),
+            })),
+            FileType::Directory => Node::Dir(Arc::new(TmpDir::new(inode, self.next_inode.clone()))),
+            _ => return Err(VfsError::Unsupported),
+        };
+        entries.insert(String::from(name), node.clone());
+        Ok(node)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), VfsError> {
+        let mut entries = self.entries.lock();
+        match entries.get(name).ok_or(VfsError::NotFound)? {
+            Node::Dir(dir) if !dir.read_dir()?.is_empty() => return Err(VfsError::NotEmpty),
+            _ => {}
+        }
+        // Open files keep their data until they are closed.
+        entries.remove(name);
+        Ok(())
+    }
+}
+
+/// A tmpfs file.
+struct TmpFile {
+    inode: u64,
+    data: Mutex<Vec<u8>>,
+}
+
+impl Inode for TmpFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: FileType::File,
+            size: self.data.lock().len() as u64,
+            inode: self.inode,
+        }
+    }
+}
+
+impl File for TmpFile {
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let data = self.data.lock();
+        let start = data.len().min(offset.try_into().unwrap_or(usize::MAX));
+        let length = buffer.len().min(data.len() - start);
+        buffer[..length].copy_from_slice(&data[start..start + length]);
+        Ok(length)
+    }
+
+    fn write_at(&self, offset: u64, bytes: &[u8]) -> Result<usize, VfsError> {
+        let start = usize::try_from(offset).map_err(|_| VfsError::NoSpace)?;
+        let end = start.checked_add(bytes.len()).ok_or(VfsError::NoSpace)?;
+        let mut data = self.data.lock();
+        if end > data.len() {
+            data.try_reserve(end - data.len())
+                .map_err(|_| VfsError::NoSpace)?;
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        let size = usize::try_from(size).map_err(|_| VfsError::NoSpace)?;
+        let mut data = self.data.lock();
+        if size > data.len() {
+            data.try_reserve(size - data.len())
+                .map_err(|_| VfsError::NoSpace)?;
+        }
+        data.resize(size, 0);
+        Ok(())
+    }
+}
+
+/// An empty in-memory file system.
+pub struct TmpFs {
+    root: Arc<TmpDir>,
+}
+
+impl TmpFs {
+    pub fn new() -> TmpFs {
+        TmpFs {
+            root: Arc::new(TmpDir::new(1, Arc::new(AtomicU64::new(2)))),
+        }
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Dir> {
+        self.root.clone()
+    }
+}
+
+/// Mounts an empty tmpfs at `/tmp`.
+pub fn init() -> Result<(), VfsError> {
+    super::mount("/tmp", Arc::new(TmpFs::new()))
+}
@@ -0,0 +1,310 @@
+//! VirtIO devices on the PCI bus.
+//!
+//! VirtIO is the paravirtual device interface of QEMU and other hypervisors. The driver and the
+//! device exchange buffers through virtqueues in shared memory: the driver puts chains of
+//! descriptors pointing at its buffers into the available ring and notifies the device, which
+//! hands them back through the used ring once it is done with them and raises an interrupt.
+//!
+//! Only the legacy interface is spoken, through the I/O port registers behind BAR 0, which
+//! QEMU's transitional devices offer by default. [`block`] drives the block device.
+
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::memory::dma::{self, DmaBuffer, DmaError};
+use crate::pci::PciDevice;
+use crate::sync::WaitQueue;
+
+pub mod block;
+
+/// PCI vendor of every VirtIO device.
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+/// Legacy registers, from the start of the I/O BAR.
+const DEVICE_FEATURES: u16 = 0x00;
+const DRIVER_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0C;
+const QUEUE_SELECT: u16 = 0x0E;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+const ISR_STATUS: u16 = 0x13;
+/// Device-specific configuration, without MSI-X.
+const DEVICE_CONFIG: u16 = 0x14;
+
+/// Device status bits.
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FAILED: u8 = 1 << 7;
+
+/// Descriptor flags: the chain goes on, and the device writes the buffer rather than reads it.
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+
+/// Legacy virtqueues place the used ring on the next page after the available ring.
+const QUEUE_ALIGN: usize = 4096;
+
+/// Errors returned when setting up a VirtIO device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// The device has no I/O BAR, so it does not speak the legacy interface.
+    NotLegacy,
+    /// The device does not have the queue.
+    NoQueue(u16),
+    /// No DMA memory for a queue.
+    Dma(DmaError),
+}
+
+impl From<DmaError> for VirtioError {
+    fn from(error: DmaError) -> Self {
+        VirtioError::Dma(error)
+    }
+}
+
+/// The legacy register block of a device.
+#[derive(Debug, Clone, Copy)]
+pub struct Transport {
+    base: u16,
+}
+
+impl Transport {
+    /// Resets `device`, lets it access memory and tells it a driver is there. The driver then
+    /// picks features and sets up its queues before calling [`Transport::ready`].
+    pub fn new(device: &PciDevice) -> Result<Transport, VirtioError> {
+        let base = device.io_bar(0).ok_or(VirtioError::NotLegacy)?;
+        device.enable_bus_mastering();
+        let transport = Transport { base };
+        transport.set_status(0);
+        transport.set_status(STATUS_ACKNOWLEDGE);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(transport)
+    }
+
+    fn read_u8(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn read_u16(&self, register: u16) -> u16 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn read_u32(&self, register: u16) -> u32 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write_u16(&self, register: u16, value: u16) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    fn write_u32(&self, register: u16, value: u32) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    fn set_status(&self, status: u8) {
+        unsafe { Port::new(self.base + DEVICE_STATUS).write(status) }
+    }
+
+    /// Accepts the features in `wanted` the device offers, and returns those.
+    pub fn negotiate(&self, wanted: u32) -> u32 {
+        let features = self.read_u32(DEVICE_FEATURES) & wanted;
+        self.write_u32(DRIVER_FEATURES, features);
+        features
+    }
+
+    /// Reads the dword at `offset` in the device-specific configuration.
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        self.read_u32(DEVICE_CONFIG + offset)
+    }
+
+    /// Sets up queue `index` with as many entries as the device wants.
+    pub fn queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        self.write_u16(QUEUE_SELECT, index);
+        let size = self.read_u16(QUEUE_SIZE);
+        if size == 0 {
+            return Err(VirtioError::NoQueue(index));
+        }
+        let queue = Virtqueue::new(index, size)?;
+        self.write_u32(QUEUE_ADDRESS, (queue.memory.phys.as_u64() / 4096) as u32);
+        Ok(queue)
+    }
+
+    /// Tells the device the driver is set up.
+    pub fn ready(&self) {
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device the driver gave up on it.
+    pub fn fail(&self) {
+        self.set_status(self.read_u8(DEVICE_STATUS) | STATUS_FAILED);
+    }
+
+    /// Tells the device there are new buffers in `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        // The descriptors and the ring must be visible before the device looks at them.
+        fence(Ordering::SeqCst);
+        self.write_u16(QUEUE_NOTIFY, queue.index);
+    }
+
+    /// Makes the device's interrupt, on the legacy IRQ `line`, wake the threads in [`wait`].
+    /// Returns `false` if the line is taken by another driver, in which case [`wait`] has to
+    /// poll.
+    pub fn enable_interrupt(&self, line: u8) -> bool {
+        interrupts::without_interrupts(|| {
+            let mut devices = DEVICES.lock();
+            devices.ports.push(self.base + ISR_STATUS);
+            if devices.lines.contains(&line) {
+                return true;
+            }
+            if crate::interruptsa::register_irq(line, interrupt_handler).is_err() {
+                return false;
+            }
+            devices.lines.push(line);
+            true
+        })
+    }
+}
+
+/// A buffer in a descriptor chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    /// Physical address and length.
+    pub address: u64,
+    pub length: u32,
+    /// Whether the device writes the buffer rather than reads it.
+    pub writable: bool,
+}
+
+/// A virtqueue in the legacy layout: the descriptor table and the available ring, then the used
+/// ring on a page of its own.
+#[derive(Debug)]
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    /// Offset of the used ring in `memory`.
+    used: usize,
+    /// Index into the available ring the next chain goes to.
+    next_available: u16,
+    /// Entries of the used ring taken so far.
+    last_used: u16,
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16) -> Result<Virtqueue, DmaError> {
+        let entries = usize::from(size);
+        let available = 16 * entries + 6 + 2 * entries;
+        let used = available.next_multiple_of(QUEUE_ALIGN);
+        let memory = dma::alloc_coherent(used + 6 + 8 * entries)?;
+        Ok(Virtqueue {
+            index,
+            size,
+            memory,
+            used,
+            next_available: 0,
+            last_used: 0,
+        })
+    }
+
+    fn write<T>(&mut self, offset: usize, value: T) {
+        unsafe { ptr::write_volatile((self.memory.virt + offset).as_mut_ptr::<T>(), value) }
+    }
+
+    fn read<T>(&self, offset: usize) -> T {
+        unsafe { ptr::read_volatile((self.memory.virt + offset).as_ptr::<T>()) }
+    }
+
+    /// Offers `chain` to the device, in the descriptors from 0 on, which the caller must not be
+    /// using for a chain still in flight. Call [`Transport::notify`] afterwards.
+    pub fn submit(&mut self, chain: &[Buffer]) {
+        assert!(
+            chain.len() <= usize::from(self.size),
+            "chain longer than the queue"
+        );
+        for (index, buffer) in chain.iter().enumerate() {
+            let offset = 16 * index;
+            let last = index + 1 == chain.len();
+            let mut flags = if last { 0 } else { DESCRIPTOR_NEXT };
+            if buffer.writable {
+                flags |= DESCRIPTOR_WRITE;
+            }
+            self.write(offset, buffer.address);
+            self.write(offset + 8, buffer.length);
+            self.write(offset + 12, flags);
+            self.write(offset + 14, index as u16 + 1);
+        }
+        let available = 16 * usize::from(self.size);
+        let slot = usize::from(self.next_available % self.size);
+        self.write(available + 4 + 2 * slot, 0u16);
+        self.next_available = self.next_available.wrapping_add(1);
+        // The entry must be in the ring before the index that publishes it.
+        fence(Ordering::SeqCst);
+        self.write(available + 2, self.next_available);
+    }
+
+    /// Whether the device has returned a chain not yet taken with [`Virtqueue::take_used`].
+    pub fn has_used(&self) -> bool {
+        self.read::<u16>(self.used + 2) != self.last_used
+    }
+
+    /// Takes the next chain the device returned, with the number of bytes it wrote.
+    pub fn take_used(&mut self) -> Option<u32> {
+        if !self.has_used() {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = usize::from(self.last_used % self.size);
+        let written = self.read::<u32>(self.used + 4 + 8 * slot + 4);
+        self.last_used = self.last_used.wrapping_add(1);
+        Some(written)
+    }
+}
+
+/// Devices with their interrupt enabled.
+struct Interrupts {
+    /// ISR status port of each device.
+    ports: Vec<u16>,
+    /// IRQ lines the handler is registered on.
+    lines: Vec<u8>,
+}
+
+/// Locked with interrupts disabled.
+static DEVICES: Mutex<Interrupts> = Mutex::new(Interrupts {
+    ports: Vec::new(),
+    lines: Vec::new(),
+});
+static INTERRUPT: WaitQueue = WaitQueue::new();
+
+/// Register reads before a device that does not answer is given up on.
+const POLL_LIMIT: u32 = 10_000_000;
+
+/// Waits until `done` holds, which should check a queue for used chains. Sleeps until the
+/// device interrupts if `interrupts` is set and interrupts are enabled, and polls otherwise.
+/// Returns `false` if polling gave up.
+pub fn wait(interrupts: bool, mut done: impl FnMut() -> bool) -> bool {
+    if interrupts && interrupts::are_enabled() {
+        INTERRUPT.wait_until(done);
+        return true;
+    }
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+fn interrupt_handler() {
+    // Reading the ISR status acknowledges the interrupt. Lines may be shared, so every device
+    // is asked.
+    for &isr in &DEVICES.lock().ports {
+        unsafe { Port::<u8>::new(isr).read() };
+    }
+    block::interrupt();
+    INTERRUPT.wake_all();
+}
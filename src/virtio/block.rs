@@ -0,0 +1,341 @@
+//! VirtIO block devices.
+//!
+//! Each disk has a single request queue. A request is a chain of three buffers: a header naming
+//! the operation and the first sector, the data of up to 128 sectors, and a status byte the
+//! device fills in. The header and the status live in one DMA page per disk and the data in a
+//! bounce buffer next to it, so one request runs at a time. Where the device's interrupt is
+//! handled, [`interrupt`] finishes a transfer begun by [`BlockDevice::transfer`].
+
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::block::queue::BioOp;
+use crate::block::{self, BlockDevice, BlockError, Done, InFlight, BLOCK_SIZE};
+use crate::memory::dma::{self, DmaBuffer};
+use crate::pci::PciDevice;
+use crate::sync::Semaphore;
+use crate::virtio::{self, Buffer, Transport, VirtioError, Virtqueue};
+
+/// PCI device ID of the transitional block device.
+const DEVICE_ID: u16 = 0x1001;
+
+/// The device accepts flush requests.
+const FEATURE_FLUSH: u32 = 1 << 9;
+
+/// Offset of the capacity, in sectors, in the device configuration.
+const CONFIG_CAPACITY: u16 = 0;
+
+/// Request types.
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+/// Status the device reports for a request that succeeded.
+const STATUS_OK: u8 = 0;
+
+/// Layout of the request page: the header and the status byte.
+const HEADER: usize = 0;
+const HEADER_SIZE: u32 = 16;
+const STATUS: usize = 16;
+
+/// Most sectors one request moves, a bounce buffer full.
+const MAX_TRANSFER_BLOCKS: usize = 128;
+
+/// The disks whose interrupt is handled, for [`interrupt`]. Locked with interrupts disabled.
+static DISKS: Mutex<Vec<Arc<VirtioDisk>>> = Mutex::new(Vec::new());
+
+/// The queue of a disk with the memory its requests are built in.
+struct Requests {
+    queue: Virtqueue,
+    memory: DmaBuffer,
+    bounce: DmaBuffer,
+    /// The transfer [`interrupt`] finishes, if one is running.
+    in_flight: Option<InFlight>,
+}
+
+/// What a request moves through the bounce buffer.
+enum Data<'a> {
+    None,
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+/// A VirtIO block device.
+pub struct VirtioDisk {
+    transport: Transport,
+    sectors: u64,
+    /// Whether the device's interrupt is handled, so waiting threads can sleep.
+    interrupts: bool,
+    /// Whether the device has a write cache that [`BlockDevice::flush`] has to empty.
+    flush: bool,
+    /// Taken for the whole of a request, and given back by [`interrupt`] for a transfer it
+    /// finishes.
+    slot: Semaphore,
+    /// Locked with interrupts disabled.
+    requests: Mutex<Requests>,
+}
+
+impl VirtioDisk {
+    /// Checks the sectors `bytes` long from `index` on.
+    fn check(&self, index: u64, bytes: usize) -> Result<(), BlockError> {
+        let count = bytes.div_ceil(BLOCK_SIZE).max(1) as u64;
+        match index.checked_add(count) {
+            Some(end) if end <= self.sectors => Ok(()),
+            _ => Err(BlockError::OutOfRange(index)),
+        }
+    }
+
+    /// Offers the device a request of type `kind` for the sectors from `sector` on, moving
+    /// `length` bytes of the bounce buffer, into memory if `read` is set. The caller holds the
+    /// slot.
+    fn issue(&self, requests: &mut Requests, kind: u32, sector: u64, length: usize, read: bool) {
+        let base = requests.memory.virt;
+        unsafe {
+            ptr::write_volatile((base + HEADER).as_mut_ptr::<u32>(), kind);
+            ptr::write_volatile((base + HEADER + 4).as_mut_ptr::<u32>(), 0);
+            ptr::write_volatile((base + HEADER + 8).as_mut_ptr::<u64>(), sector);
+            ptr::write_volatile((base + STATUS).as_mut_ptr::<u8>(), u8::MAX);
+        }
+        let phys = requests.memory.phys.as_u64();
+        let header = Buffer {
+            address: phys + HEADER as u64,
+            length: HEADER_SIZE,
+            writable: false,
+        };
+        let buffer = Buffer {
+            address: requests.bounce.phys.as_u64(),
+            length: length as u32,
+            writable: read,
+        };
+        let status = Buffer {
+            address: phys + STATUS as u64,
+            length: 1,
+            writable: true,
+        };
+        if length != 0 {
+            requests.queue.submit(&[header, buffer, status]);
+        } else {
+            requests.queue.submit(&[header, status]);
+        }
+        self.transport.notify(&requests.queue);
+    }
+
+    /// Takes the request the device returned and whether it succeeded.
+    fn outcome(requests: &mut Requests) -> Result<(), BlockError> {
+        requests.queue.take_used();
+        let status = requests.memory.virt + STATUS;
+        match unsafe { ptr::read_volatile(status.as_ptr::<u8>()) } {
+            STATUS_OK => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    /// Sends a request of type `kind` for the sectors from `sector` on and waits for it. With
+    /// `data` the request moves the data through the bounce buffer, into memory if `read` is
+    /// set.
+    fn request(&self, kind: u32, sector: u64, data: Data) -> Result<(), BlockError> {
+        self.slot.acquire();
+        let result = self.execute(kind, sector, data);
+        self.slot.release();
+        result
+    }
+
+    /// [`Self::request`] with the slot taken.
+    fn execute(&self, kind: u32, sector: u64, data: Data) -> Result<(), BlockError> {
+        interrupts::without_interrupts(|| {
+            let mut requests = self.requests.lock();
+            match &data {
+                Data::None => self.issue(&mut requests, kind, sector, 0, false),
+                Data::Read(buffer) => self.issue(&mut requests, kind, sector, buffer.len(), true),
+                Data::Write(data) => {
+                    requests.bounce.as_mut_slice()[..data.len()].copy_from_slice(data);
+                    self.issue(&mut requests, kind, sector, data.len(), false);
+                }
+            }
+        });
+        let used = || interrupts::without_interrupts(|| self.requests.lock().queue.has_used());
+        if !virtio::wait(self.interrupts, used) {
+            return Err(BlockError::Timeout);
+        }
+        interrupts::without_interrupts(|| {
+            let mut requests = self.requests.lock();
+            Self::outcome(&mut requests)?;
+            if let Data::Read(buffer) = data {
+                buffer.copy_from_slice(&requests.bounce.as_slice()[..buffer.len()]);
+            }
+            Ok(())
+        })
+    }
+
+    /// Finishes the transfer in flight if the device returned it.
+    fn complete(&self) {
+        let mut requests = self.requests.lock();
+        if requests.in_flight.is_none() || !requests.queue.has_used() {
+            return;
+        }
+        let mut transfer = requests.in_flight.take().unwrap();
+        let result = Self::outcome(&mut requests);
+        if result.is_ok() && transfer.op == BioOp::Read {
+            let length = transfer.buffer.len();
+            transfer
+                .buffer
+                .copy_from_slice(&requests.bounce.as_slice()[..length]);
+        }
+        drop(requests);
+        self.slot.release();
+        transfer.finish(result);
+    }
+}
+
+impl fmt::Debug for VirtioDisk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VirtioDisk")
+            .field("transport", &self.transport)
+            .field("sectors", &self.sectors)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BlockDevice for VirtioDisk {
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_block(&self, index: u64, buffer: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        self.read_blocks(index, buffer)
+    }
+
+    fn write_block(&self, index: u64, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        self.write_blocks(index, data)
+    }
+
+    fn read_blocks(&self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check(start, buffer.len())?;
+        let chunks = buffer.chunks_mut(MAX_TRANSFER_BLOCKS * BLOCK_SIZE);
+        for (index, chunk) in chunks.enumerate() {
+            let sector = start + (index * MAX_TRANSFER_BLOCKS) as u64;
+            self.request(REQUEST_IN, sector, Data::Read(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        self.check(start, data.len())?;
+        let chunks = data.chunks(MAX_TRANSFER_BLOCKS * BLOCK_SIZE);
+        for (index, chunk) in chunks.enumerate() {
+            let sector = start + (index * MAX_TRANSFER_BLOCKS) as u64;
+            self.request(REQUEST_OUT, sector, Data::Write(chunk))?;
+        }
+        Ok(())
+    }
+
+    fn transfer(&self, op: BioOp, start: u64, buffer: Vec<u8>, done: Done) {
+        let length = buffer.len();
+        // Without the interrupt nothing would finish the request.
+        if !self.interrupts
+            || !interrupts::are_enabled()
+            || length == 0
+            || length > MAX_TRANSFER_BLOCKS * BLOCK_SIZE
+        {
+            return block::transfer_now(self, op, start, buffer, done);
+        }
+        if let Err(error) = self.check(start, length) {
+            return done(Err(error));
+        }
+        self.slot.acquire();
+        interrupts::without_interrupts(|| {
+            let mut requests = self.requests.lock();
+            let kind = match op {
+                BioOp::Read => REQUEST_IN,
+                BioOp::Write => {
+                    requests.bounce.as_mut_slice()[..length].copy_from_slice(&buffer);
+                    REQUEST_OUT
+                }
+            };
+            self.issue(&mut requests, kind, start, length, op == BioOp::Read);
+            requests.in_flight = Some(InFlight { op, buffer, done });
+        });
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.flush {
+            return Ok(());
+        }
+        self.request(REQUEST_FLUSH, 0, Data::None)
+    }
+}
+
+/// Sets up the block device `device`.
+fn attach(device: &PciDevice) -> Result<VirtioDisk, VirtioError> {
+    let transport = Transport::new(device)?;
+    let features = transport.negotiate(FEATURE_FLUSH);
+    let sectors = u64::from(transport.config_u32(CONFIG_CAPACITY))
+        | (u64::from(transport.config_u32(CONFIG_CAPACITY + 4)) << 32);
+    let requests = transport
+        .queue(0)
+        .and_then(|queue| {
+            let memory = dma::alloc_coherent(4096)?;
+            let bounce = dma::alloc_coherent(MAX_TRANSFER_BLOCKS * BLOCK_SIZE)?;
+            Ok(Requests {
+                queue,
+                memory,
+                bounce,
+                in_flight: None,
+            })
+        })
+        .inspect_err(|_| transport.fail())?;
+    let interrupts = device
+        .interrupt_line
+        .is_some_and(|line| transport.enable_interrupt(line));
+    transport.ready();
+    Ok(VirtioDisk {
+        transport,
+        sectors,
+        interrupts,
+        flush: features & FEATURE_FLUSH != 0,
+        slot: Semaphore::new(1),
+        requests: Mutex::new(requests),
+    })
+}
+
+/// Sets up every VirtIO block device on the PCI bus and registers it as a block device. Returns
+/// how many there are.
+pub fn init() -> usize {
+    let mut found = 0;
+    for device in crate::pci::devices()
+        .into_iter()
+        .filter(|device| (device.vendor_id, device.device_id) == (virtio::VENDOR_ID, DEVICE_ID))
+    {
+        let disk = match attach(&device) {
+            Ok(disk) => disk,
+            Err(error) => {
+                crate::serial_println!("virtio: {:?} not usable: {:?}", device.address, error);
+                continue;
+            }
+        };
+        crate::serial_println!(
+            "virtio: virtio{} is {} MiB",
+            found,
+            disk.sectors * BLOCK_SIZE as u64 / (1024 * 1024)
+        );
+        let disk = Arc::new(disk);
+        if disk.interrupts {
+            interrupts::without_interrupts(|| DISKS.lock().push(disk.clone()));
+        }
+        block::register(&format!("virtio{}", found), disk);
+        found += 1;
+    }
+    found
+}
+
+/// Finishes the transfers the disks returned. Called by the VirtIO interrupt handler.
+pub(super) fn interrupt() {
+    for disk in DISKS.lock().iter() {
+        disk.complete();
+    }
+}
@@ -0,0 +1,161 @@
+//! NMI watchdog.
+//!
+//! Once the local APIC timer drives the timer interrupt, the PIT is free. The watchdog runs it
+//! at its lowest rate and delivers its IRQ as an NMI, which arrives even while interrupts are
+//! disabled. Each NMI checks that the tick count, the heartbeat of the timer interrupt, has moved
+//! on. If it stands still for longer than the timeout, the kernel is stuck with interrupts
+//! disabled, for example spinning on a lock its own interrupted code holds, and the watchdog
+//! reports where it was interrupted.
+//!
+//! Other NMIs, from hardware errors, must still reach the NMI handler. The PIT has no status to
+//! tell its NMI apart, so the watchdog leaves alone NMIs that come with the error bits of system
+//! control port B set, and those that arrive well before the PIT period since the last one is
+//! over.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::time::tsc;
+
+/// Timeout the watchdog is enabled with at boot.
+pub const DEFAULT_TIMEOUT_MS: u64 = 1000;
+
+/// ISA IRQ of PIT channel 0.
+const PIT_IRQ: u8 = 0;
+
+/// RFLAGS interrupt enable flag.
+const INTERRUPT_FLAG: u64 = 1 << 9;
+
+/// System control port B, whose top bits report the sources of hardware error NMIs: a PCI
+/// system error and an I/O channel check.
+const PORT_B: u16 = 0x61;
+const PORT_B_ERRORS: u8 = (1 << 7) | (1 << 6);
+
+/// Errors returned by [`enable`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogError {
+    /// The APICs are not in use, so the PIT cannot be delivered as an NMI.
+    ApicRequired,
+    /// The PIT still drives the timer interrupt.
+    PitInUse,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TIMEOUT_NS: AtomicU64 = AtomicU64::new(0);
+/// Period of the PIT, the time between two checks.
+static PERIOD_NS: AtomicU64 = AtomicU64::new(0);
+/// Tick count seen by the last check.
+static LAST_TICKS: AtomicU64 = AtomicU64::new(0);
+/// How long the tick count has not moved.
+static STALLED_NS: AtomicU64 = AtomicU64::new(0);
+/// Set once a stall has been reported, until the ticks move again.
+static REPORTED: AtomicBool = AtomicBool::new(false);
+/// Number of stalls reported since boot.
+static STALLS: AtomicU64 = AtomicU64::new(0);
+/// TSC when the watchdog last took an NMI as its own.
+static LAST_NMI_TSC: AtomicU64 = AtomicU64::new(0);
+/// TSC cycles that must pass before an NMI counts as the PIT's, half the period. 0 if the TSC is
+/// not calibrated, in which case only the error bits are checked.
+static MIN_GAP_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the watchdog, reporting when the timer interrupt has not run for `timeout_ms`.
+pub fn enable(timeout_ms: u64) -> Result<(), WatchdogError> {
+    if !crate::apic::is_enabled() {
+        return Err(WatchdogError::ApicRequired);
+    }
+    if !crate::apic::timer_running() {
+        return Err(WatchdogError::PitInUse);
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let period = crate::pit::set_frequency(crate::pit::MIN_FREQUENCY);
+        PERIOD_NS.store(period, Ordering::Relaxed);
+        MIN_GAP_CYCLES.store(tsc::nanos_to_cycles(period / 2), Ordering::Relaxed);
+        LAST_NMI_TSC.store(tsc::rdtsc(), Ordering::Relaxed);
+        TIMEOUT_NS.store(timeout_ms * 1_000_000, Ordering::Relaxed);
+        LAST_TICKS.store(crate::time::ticks(), Ordering::Relaxed);
+        STALLED_NS.store(0, Ordering::Relaxed);
+        REPORTED.store(false, Ordering::Relaxed);
+        ENABLED.store(true, Ordering::Relaxed);
+        if !crate::apic::route_irq_as_nmi(PIT_IRQ) {
+            ENABLED.store(false, Ordering::Relaxed);
+            return Err(WatchdogError::ApicRequired);
+        }
+        Ok(())
+    })
+}
+
+/// Stops the watchdog.
+pub fn disable() {
+    crate::apic::mask_irq(PIT_IRQ);
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// Returns whether the watchdog is running.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Number of stalls the watchdog has reported since boot.
+pub fn stalls() -> u64 {
+    STALLS.load(Ordering::Relaxed)
+}
+
+/// Checks the heartbeat if the NMI is the watchdog's. Called from the NMI handler; returns
+/// whether the NMI was the watchdog's, and otherwise leaves it to the handler.
+pub(crate) fn on_nmi(stack_frame: &InterruptStackFrame) -> bool {
+    if !is_enabled() || !claim_nmi() {
+        return false;
+    }
+    let ticks = crate::time::ticks();
+    if LAST_TICKS.swap(ticks, Ordering::Relaxed) != ticks {
+        STALLED_NS.store(0, Ordering::Relaxed);
+        REPORTED.store(false, Ordering::Relaxed);
+        return true;
+    }
+    let stalled = STALLED_NS.fetch_add(PERIOD_NS.load(Ordering::Relaxed), Ordering::Relaxed);
+    if stalled >= TIMEOUT_NS.load(Ordering::Relaxed) && !REPORTED.swap(true, Ordering::Relaxed) {
+        STALLS.fetch_add(1, Ordering::Relaxed);
+        report(stalled, stack_frame);
+    }
+    true
+}
+
+/// Decides whether the current NMI came from the PIT, and if so, records when it arrived.
+fn claim_nmi() -> bool {
+    let errors = unsafe { Port::<u8>::new(PORT_B).read() } & PORT_B_ERRORS;
+    if errors != 0 {
+        return false;
+    }
+    let now = tsc::rdtsc();
+    let last = LAST_NMI_TSC.load(Ordering::Relaxed);
+    if now.wrapping_sub(last) < MIN_GAP_CYCLES.load(Ordering::Relaxed) {
+        return false;
+    }
+    LAST_NMI_TSC.store(now, Ordering::Relaxed);
+    true
+}
+
+/// Reports a stall on the serial port. An NMI can interrupt any code, including code holding its
+/// lock, so the port is not waited for. The console is left alone: writing to it can take the
+/// scheduler's locks to defer output.
+fn report(stalled_ns: u64, stack_frame: &InterruptStackFrame) {
+    let interrupts = if stack_frame.cpu_flags & INTERRUPT_FLAG != 0 {
+        "enabled"
+    } else {
+        "disabled"
+    };
+    let args = format_args!(
+        "WATCHDOG: no timer tick for {} ms\n RIP: {:?}\n RSP: {:?}\n interrupts {}\n",
+        stalled_ns / 1_000_000,
+        stack_frame.instruction_pointer,
+        stack_frame.stack_pointer,
+        interrupts
+    );
+    if let Some(mut serial) = crate::serial::SERIAL1.try_lock() {
+        if let Some(port) = serial.as_mut() {
+            let _ = port.write_fmt(args);
+        }
+    }
+}
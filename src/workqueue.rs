@@ -0,0 +1,130 @@
+//! Deferred work.
+//!
+//! Interrupt handlers should do as little as possible while interrupts are disabled. Whatever
+//! can wait, such as drawing, waking up a driver thread or anything that takes a blocking lock,
+//! they queue here instead, and the `worker` thread runs it soon after with interrupts enabled.
+//! Queueing never blocks, so it is safe from any context.
+//!
+//! A [`Work`] item is a static function that is queued at most once until it has run, which
+//! suits work that catches up on everything pending, and never allocates. [`queue`] takes any
+//! closure, boxing it.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crossbeam_queue::ArrayQueue;
+use spin::Once;
+
+use crate::sync::WaitQueue;
+use crate::thread::{self, Priority, ThreadError};
+
+/// Number of items that can wait for the worker.
+const QUEUE_SIZE: usize = 256;
+
+/// Errors returned when queueing work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkError {
+    /// [`init`] has not run, or failed.
+    NotInitialized,
+    /// [`QUEUE_SIZE`] items are already waiting.
+    Full,
+    /// The worker thread could not be started.
+    Thread(ThreadError),
+}
+
+impl From<ThreadError> for WorkError {
+    fn from(error: ThreadError) -> Self {
+        WorkError::Thread(error)
+    }
+}
+
+/// A function run by the worker whenever it has been scheduled.
+pub struct Work {
+    function: fn(),
+    /// Set while the item waits in the queue.
+    pending: AtomicBool,
+}
+
+impl Work {
+    pub const fn new(function: fn()) -> Self {
+        Work {
+            function,
+            pending: AtomicBool::new(false),
+        }
+    }
+
+    /// Queues the item for the worker, unless it is already waiting there. Never allocates.
+    pub fn schedule(&'static self) -> Result<(), WorkError> {
+        if self.pending.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        push(Item::Work(self)).inspect_err(|_| self.pending.store(false, Ordering::Release))
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    fn run(&self) {
+        // Cleared first, so the function can be scheduled again while it runs.
+        self.pending.store(false, Ordering::Release);
+        (self.function)();
+    }
+}
+
+enum Item {
+    Work(&'static Work),
+    Closure(Box<dyn FnOnce() + Send>),
+}
+
+static QUEUE: Once<ArrayQueue<Item>> = Once::new();
+
+/// The worker, while it waits for items.
+static WORKER: WaitQueue = WaitQueue::new();
+
+/// Number of items the worker has run.
+static COMPLETED: AtomicU64 = AtomicU64::new(0);
+
+fn push(item: Item) -> Result<(), WorkError> {
+    let queue = QUEUE.get().ok_or(WorkError::NotInitialized)?;
+    queue.push(item).map_err(|_| WorkError::Full)?;
+    WORKER.wake_one();
+    Ok(())
+}
+
+/// Queues `f` to run on the worker thread.
+pub fn queue(f: impl FnOnce() + Send + 'static) -> Result<(), WorkError> {
+    push(Item::Closure(Box::new(f)))
+}
+
+/// Starts the worker thread. Call after [`thread::init`]. Until then, and if this fails, all
+/// queueing fails with [`WorkError::NotInitialized`].
+pub fn init() -> Result<(), WorkError> {
+    // High priority, so deferred work runs before the threads that interrupts were waking up.
+    thread::spawn_with_priority("worker", worker, Priority::High)?;
+    QUEUE.call_once(|| ArrayQueue::new(QUEUE_SIZE));
+    Ok(())
+}
+
+fn worker() {
+    let queue = QUEUE.wait();
+    loop {
+        WORKER.wait_until(|| !queue.is_empty());
+        while let Some(item) = queue.pop() {
+            match item {
+                Item::Work(work) => work.run(),
+                Item::Closure(f) => f(),
+            }
+            COMPLETED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Number of items waiting for the worker.
+pub fn pending() -> usize {
+    QUEUE.get().map_or(0, |queue| queue.len())
+}
+
+/// Number of items the worker has run since boot.
+pub fn completed() -> u64 {
+    COMPLETED.load(Ordering::Relaxed)
+}
@@ -1,15 +1,35 @@
+mod ansi;
+mod attributes;
+mod box_drawing;
+mod color;
 mod constants;
+mod dirty;
+mod pointer;
+mod wrap;
 
+use alloc::vec::Vec;
 use core::{
     fmt::{self, Write},
+    ops::Range,
     ptr,
 };
 
+use ansi::{Action, AnsiParser, Csi};
+use attributes::{BlinkCell, BlinkCells};
 use bootloader_api::info::{FrameBufferInfo, PixelFormat};
+use box_drawing::Shape;
 use constants::font_constants;
 use constants::font_constants::{BACKUP_CHAR, CHAR_RASTER_HEIGHT, FONT_WEIGHT};
+use dirty::DirtyRows;
+use pointer::{Pointer, POINTER_HEIGHT, POINTER_WIDTH};
+use wrap::LineBuffer;
 use noto_sans_mono_bitmap::{get_raster, RasterizedChar};
 
+pub use attributes::Attributes;
+pub use color::Color;
+pub use dirty::DirtyRect;
+pub use wrap::WrapMode;
+
 /// Additional vertical space between lines
 const LINE_SPACING: usize = 2;
 
@@ -19,6 +39,16 @@ const LETTER_SPACING: usize = 0;
 /// Padding from the border. Prevent that font is too close to border.
 const BORDER_PADDING: usize = 1;
 
+/// Height of a single text row, including the spacing below it.
+const LINE_HEIGHT: usize = font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
+
+/// Width of a single text column, including the spacing after it.
+const COLUMN_WIDTH: usize = font_constants::CHAR_RASTER_WIDTH + LETTER_SPACING;
+
+/// Number of text rows at the top of the screen reserved for the status bar. These rows are
+/// never scrolled or cleared by normal output.
+const STATUS_ROWS: usize = 1;
+
 /// Returns the raster of the given char or the raster of [`font_constants::BACKUP_CHAR`].
 fn get_char_raster(c: char) -> RasterizedChar {
     fn get(c: char) -> Option<RasterizedChar> {
@@ -29,6 +59,19 @@ fn get_char_raster(c: char) -> RasterizedChar {
 
 
 
+/// Errors returned by the bounds-checked cursor API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleError {
+    /// The row is inside the status bar or below the last text row.
+    RowOutOfBounds { row: usize, rows: usize },
+    /// The column is past the last text column.
+    ColumnOutOfBounds { column: usize, columns: usize },
+    /// No framebuffer console has been initialized.
+    Unavailable,
+    /// The writer is in use by the code an interrupt handler interrupted.
+    Busy,
+}
+
 /// Allows logging text to a pixel-based framebuffer.
 #[derive(Debug)]
 pub struct FrameBufferWriter<'a> {
@@ -36,6 +79,21 @@ pub struct FrameBufferWriter<'a> {
     info: FrameBufferInfo,
     x_pos: usize,
     y_pos: usize,
+    /// Text rows that may contain drawn pixels.
+    rows: DirtyRows,
+    /// Pixels changed since the last call to [`FrameBufferWriter::take_dirty`].
+    dirty: Option<DirtyRect>,
+    /// Attributes applied to newly written characters.
+    attributes: Attributes,
+    foreground: Color,
+    background: Color,
+    ansi: AnsiParser,
+    blink: BlinkCells,
+    pointer: Pointer,
+    wrap_mode: WrapMode,
+    line: LineBuffer,
+    /// Number of times the text has scrolled, so saved positions can follow it.
+    scrolls: usize,
 }
 
 impl<'a> FrameBufferWriter<'a> {
@@ -46,7 +104,26 @@ impl<'a> FrameBufferWriter<'a> {
             info,
             x_pos: 0,
             y_pos: 0,
+            rows: DirtyRows::new(),
+            dirty: None,
+            attributes: Attributes::NONE,
+            foreground: Color::DEFAULT_FOREGROUND,
+            background: Color::BLACK,
+            ansi: AnsiParser::new(),
+            blink: BlinkCells::new(),
+            pointer: Pointer::new(),
+            wrap_mode: WrapMode::Wrap,
+            line: LineBuffer::new(),
+            scrolls: 0,
         };
+        // Nothing is known about the initial contents, so wipe the whole buffer once.
+        logger.framebuffer.fill(0);
+        logger.dirty = Some(DirtyRect {
+            x: 0,
+            y: 0,
+            width: logger.width(),
+            height: logger.height(),
+        });
         logger.clear();
         logger
     }
@@ -58,25 +135,375 @@ impl<'a> FrameBufferWriter<'a> {
 
     fn carriage_return(&mut self) {
         self.x_pos = BORDER_PADDING;
+        self.line.reset();
+    }
+
+    /// Returns how text is handled at the right edge of the screen.
+    pub fn wrap_mode(&self) -> WrapMode {
+        self.wrap_mode
+    }
+
+    /// Sets how text is handled at the right edge of the screen, starting with the next line.
+    pub fn set_wrap_mode(&mut self, wrap_mode: WrapMode) {
+        self.wrap_mode = wrap_mode;
     }
 
-    /// Erases all text on the screen. Resets `self.x_pos` and `self.y_pos`.
+    /// Erases all text below the status bar. Resets `self.x_pos` and `self.y_pos`.
+    ///
+    /// Only rows that were drawn into since the last clear are touched.
     pub fn clear(&mut self) {
+        self.hide_pointer_for_drawing();
+        self.x_pos = BORDER_PADDING;
+        self.y_pos = STATUS_ROWS * LINE_HEIGHT + BORDER_PADDING;
+        for row in STATUS_ROWS..self.text_rows() {
+            if self.rows.is_dirty(row) {
+                self.fill_row(row);
+                self.rows.set(row, false);
+            }
+        }
+        self.blink.clear_below(STATUS_ROWS * LINE_HEIGHT);
+        self.restore_pointer_after_drawing();
+    }
+
+    /// Moves all text below the status bar up by one row, discarding the top row.
+    ///
+    /// Rows that are known to be blank are neither copied nor cleared.
+    fn scroll(&mut self) {
+        let rows = self.text_rows();
+        if rows <= STATUS_ROWS {
+            return;
+        }
+        for row in (STATUS_ROWS + 1)..rows {
+            let src_dirty = self.rows.is_dirty(row);
+            if src_dirty {
+                self.copy_row(row, row - 1);
+            } else if self.rows.is_dirty(row - 1) {
+                self.fill_row(row - 1);
+            }
+            self.rows.set(row - 1, src_dirty);
+        }
+        if self.rows.is_dirty(rows - 1) {
+            self.fill_row(rows - 1);
+            self.rows.set(rows - 1, false);
+        }
+        self.blink.scroll(STATUS_ROWS * LINE_HEIGHT, LINE_HEIGHT);
+        self.y_pos = self.y_pos.saturating_sub(LINE_HEIGHT);
+        self.scrolls = self.scrolls.wrapping_add(1);
+    }
+
+    /// Replaces the contents of the status bar with the formatted text.
+    ///
+    /// The text is drawn on a single line and cut off at the right edge; control characters are
+    /// ignored. The write position of normal output is left untouched.
+    pub fn set_status(&mut self, args: fmt::Arguments) {
+        self.hide_pointer_for_drawing();
+        let (x_pos, y_pos) = (self.x_pos, self.y_pos);
+        for row in 0..STATUS_ROWS.min(self.text_rows()) {
+            if self.rows.is_dirty(row) {
+                self.fill_row(row);
+                self.rows.set(row, false);
+            }
+        }
         self.x_pos = BORDER_PADDING;
         self.y_pos = BORDER_PADDING;
-        self.framebuffer.fill(0);
+        let _ = StatusLine(self).write_fmt(args);
+        self.restore_pointer_after_drawing();
+        self.x_pos = x_pos;
+        self.y_pos = y_pos;
+    }
+
+    fn write_status_char(&mut self, c: char) {
+        if c.is_control() || self.text_rows() < STATUS_ROWS {
+            return;
+        }
+        if self.x_pos + font_constants::CHAR_RASTER_WIDTH >= self.width() {
+            return;
+        }
+        self.x_pos += self.draw_glyph(self.x_pos, self.y_pos, c, Attributes::NONE) + LETTER_SPACING;
+    }
+
+    /// Returns the attributes applied to newly written characters.
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    /// Sets the attributes applied to newly written characters. Text already on screen keeps
+    /// the attributes it was drawn with.
+    pub fn set_attributes(&mut self, attributes: Attributes) {
+        self.attributes = attributes;
+    }
+
+    /// Toggles the visibility of all characters drawn with [`Attributes::BLINK`].
+    pub fn blink(&mut self) {
+        self.hide_pointer_for_drawing();
+        self.blink.visible = !self.blink.visible;
+        // Drawing needs the writer, so the cells are looked up one at a time rather than copied.
+        for slot in 0..BlinkCells::SLOTS {
+            if let Some(cell) = self.blink.get(slot) {
+                self.draw_glyph(cell.x, cell.y, cell.c, cell.attributes);
+            }
+        }
+        self.restore_pointer_after_drawing();
+    }
+
+    /// Shows the mouse pointer with its tip at the given pixel position.
+    pub fn show_pointer(&mut self, x: usize, y: usize) {
+        self.pointer.enabled = true;
+        self.move_pointer(x, y);
+    }
+
+    /// Moves the mouse pointer, restoring the pixels it covered at its old position.
+    pub fn move_pointer(&mut self, x: usize, y: usize) {
+        self.hide_pointer_for_drawing();
+        self.pointer.x = x.min(self.width().saturating_sub(1));
+        self.pointer.y = y.min(self.height().saturating_sub(1));
+        self.restore_pointer_after_drawing();
+    }
+
+    /// Removes the mouse pointer from the screen.
+    pub fn hide_pointer(&mut self) {
+        self.hide_pointer_for_drawing();
+        self.pointer.enabled = false;
+    }
+
+    /// Returns the pointer position, if it is shown.
+    pub fn pointer_position(&self) -> Option<(usize, usize)> {
+        self.pointer
+            .enabled
+            .then_some((self.pointer.x, self.pointer.y))
+    }
+
+    /// Restores the pixels under the pointer so that drawing below it does not end up in the
+    /// saved background.
+    fn hide_pointer_for_drawing(&mut self) {
+        if !self.pointer.drawn {
+            return;
+        }
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        for (x, y) in self.pointer_pixels() {
+            let offset = self.pixel_offset(self.pointer.x + x, self.pointer.y + y);
+            let saved = self.pointer.saved_mut(x, y, bytes_per_pixel);
+            self.framebuffer[offset..offset + saved.len()].copy_from_slice(saved);
+        }
+        self.pointer.drawn = false;
+        self.mark_pointer_dirty();
+    }
+
+    /// Saves the pixels under the pointer and draws the sprite on top of them.
+    fn restore_pointer_after_drawing(&mut self) {
+        if !self.pointer.enabled || self.pointer.drawn {
+            return;
+        }
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        for (x, y) in self.pointer_pixels() {
+            let offset = self.pixel_offset(self.pointer.x + x, self.pointer.y + y);
+            let saved = self.pointer.saved_mut(x, y, bytes_per_pixel);
+            saved.copy_from_slice(&self.framebuffer[offset..offset + saved.len()]);
+            if let Some(intensity) = pointer::sprite_pixel(x, y) {
+                self.write_pixel(self.pointer.x + x, self.pointer.y + y, intensity);
+            }
+        }
+        self.pointer.drawn = true;
+        self.mark_pointer_dirty();
+    }
+
+    /// Sprite coordinates of the pointer pixels that are on screen.
+    fn pointer_pixels(&self) -> impl Iterator<Item = (usize, usize)> {
+        let width = POINTER_WIDTH.min(self.width() - self.pointer.x);
+        let height = POINTER_HEIGHT.min(self.height() - self.pointer.y);
+        (0..height).flat_map(move |y| (0..width).map(move |x| (x, y)))
+    }
+
+    fn mark_pointer_dirty(&mut self) {
+        let dirty = DirtyRect {
+            x: self.pointer.x,
+            y: self.pointer.y,
+            width: POINTER_WIDTH.min(self.width() - self.pointer.x),
+            height: POINTER_HEIGHT.min(self.height() - self.pointer.y),
+        };
+        // The pointer is not text, so only the flush rectangle is updated.
+        self.dirty = Some(match self.dirty {
+            Some(current) => current.union(dirty),
+            None => dirty,
+        });
+    }
+
+    /// Size in bytes of a raw copy of the framebuffer, as produced by
+    /// [`FrameBufferWriter::snapshot_into`].
+    pub fn snapshot_len(&self) -> usize {
+        self.framebuffer.len()
+    }
+
+    /// Returns a raw copy of the framebuffer contents, in the pixel format of the framebuffer.
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.framebuffer.to_vec()
+    }
+
+    /// Copies the raw framebuffer contents into `buf`. Returns `None` if `buf` is smaller than
+    /// [`FrameBufferWriter::snapshot_len`].
+    pub fn snapshot_into(&self, buf: &mut [u8]) -> Option<usize> {
+        let len = self.framebuffer.len();
+        buf.get_mut(..len)?.copy_from_slice(self.framebuffer);
+        Some(len)
+    }
+
+    /// Streams the screen contents as a binary PPM (`P6`) image, one byte at a time.
+    pub fn write_ppm(&self, mut emit: impl FnMut(u8)) {
+        struct Header<'f, F: FnMut(u8)>(&'f mut F);
+        impl<F: FnMut(u8)> Write for Header<'_, F> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                s.bytes().for_each(&mut *self.0);
+                Ok(())
+            }
+        }
+        let _ = write!(
+            Header(&mut emit),
+            "P6\n{} {}\n255\n",
+            self.width(),
+            self.height()
+        );
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let offset = (y * self.info.stride + x) * bytes_per_pixel;
+                let pixel = &self.framebuffer[offset..offset + bytes_per_pixel];
+                let channel = |i: usize| pixel.get(i).copied().unwrap_or(0);
+                let rgb = match self.info.pixel_format {
+                    PixelFormat::Bgr => [channel(2), channel(1), channel(0)],
+                    PixelFormat::U8 => [channel(0); 3],
+                    PixelFormat::Unknown {
+                        red_position,
+                        green_position,
+                        blue_position,
+                    } => {
+                        let value = u32::from_le_bytes([
+                            channel(0),
+                            channel(1),
+                            channel(2),
+                            channel(3),
+                        ]);
+                        let extract = |position: u8| {
+                            value.checked_shr(u32::from(position)).unwrap_or(0) as u8
+                        };
+                        [
+                            extract(red_position),
+                            extract(green_position),
+                            extract(blue_position),
+                        ]
+                    }
+                    _ => [channel(0), channel(1), channel(2)],
+                };
+                rgb.into_iter().for_each(&mut emit);
+            }
+        }
+    }
+
+    /// Returns the bounding rectangle of every pixel changed since the previous call, and resets
+    /// the tracking. A double-buffered present only needs to copy this region.
+    pub fn take_dirty(&mut self) -> Option<DirtyRect> {
+        self.dirty.take()
+    }
+
+    /// Number of complete text rows that fit on the screen, including the status bar.
+    pub fn text_rows(&self) -> usize {
+        self.height() / LINE_HEIGHT
+    }
+
+    /// Number of text columns that fit on a line before output wraps.
+    pub fn text_columns(&self) -> usize {
+        self.width().saturating_sub(1) / COLUMN_WIDTH
+    }
+
+    /// Byte range of the framebuffer covering the given text row.
+    fn row_bytes(&self, row: usize) -> Range<usize> {
+        let row_len = self.info.stride * self.info.bytes_per_pixel * LINE_HEIGHT;
+        row * row_len..(row + 1) * row_len
+    }
+
+    fn fill_row(&mut self, row: usize) {
+        if self.background == Color::BLACK {
+            let bytes = self.row_bytes(row);
+            self.framebuffer[bytes].fill(0);
+        } else {
+            for y in row * LINE_HEIGHT..(row + 1) * LINE_HEIGHT {
+                for x in 0..self.width() {
+                    self.write_color(x, y, self.background);
+                }
+            }
+        }
+        self.mark_dirty(0, row * LINE_HEIGHT, self.width(), LINE_HEIGHT);
+    }
+
+    /// Sets the colors used for newly drawn text and for cleared areas. Text already on screen
+    /// keeps its colors; use [`FrameBufferWriter::fill_screen`] to repaint everything.
+    pub fn set_colors(&mut self, foreground: Color, background: Color) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    /// Paints the whole screen, status bar included, in the background color and moves the
+    /// write position to the top of the text area.
+    pub fn fill_screen(&mut self) {
+        self.hide_pointer_for_drawing();
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                self.write_color(x, y, self.background);
+            }
+        }
+        self.rows = DirtyRows::new();
+        self.blink.clear_below(0);
+        self.dirty = Some(DirtyRect {
+            x: 0,
+            y: 0,
+            width: self.width(),
+            height: self.height(),
+        });
+        self.x_pos = BORDER_PADDING;
+        self.y_pos = STATUS_ROWS * LINE_HEIGHT + BORDER_PADDING;
+        self.restore_pointer_after_drawing();
+    }
+
+    fn copy_row(&mut self, src: usize, dst: usize) {
+        let bytes = self.row_bytes(src);
+        let dst_start = self.row_bytes(dst).start;
+        self.framebuffer.copy_within(bytes, dst_start);
+        self.mark_dirty(0, dst * LINE_HEIGHT, self.width(), LINE_HEIGHT);
     }
 
-    fn width(&self) -> usize {
+    /// Records that the given pixel rectangle was modified.
+    fn mark_dirty(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        for row in (y / LINE_HEIGHT)..=((y + height - 1) / LINE_HEIGHT) {
+            self.rows.mark(row);
+        }
+        let rect = DirtyRect {
+            x,
+            y,
+            width,
+            height,
+        };
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(rect),
+            None => rect,
+        });
+    }
+
+    /// Width of the framebuffer in pixels.
+    pub fn width(&self) -> usize {
         self.info.width
     }
 
-    fn height(&self) -> usize {
+    /// Height of the framebuffer in pixels.
+    pub fn height(&self) -> usize {
         self.info.height
     }
 
-    /// Sets the write position to the specified row and column.
+    /// Sets the write position to the specified row and column. Rows inside the status bar are
+    /// moved down to the first scrolling row.
     pub fn set_cursor(&mut self, row: usize, column: usize) {
+        let row = row.max(STATUS_ROWS);
         let max_row = self.height() / (font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING);
         let max_column = self.width() / (font_constants::CHAR_RASTER_WIDTH + LETTER_SPACING);
         self.y_pos = row * (font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING);
@@ -91,44 +518,251 @@ impl<'a> FrameBufferWriter<'a> {
         }
     }
 
+    /// Returns the current write position as `(row, column)`.
+    pub fn get_cursor(&self) -> (usize, usize) {
+        (self.y_pos / LINE_HEIGHT, self.x_pos / COLUMN_WIDTH)
+    }
+
+    /// Sets the write position to the specified row and column, or returns an error without
+    /// moving the cursor if the position is not on the scrolling text area.
+    pub fn set_cursor_checked(&mut self, row: usize, column: usize) -> Result<(), ConsoleError> {
+        let rows = self.text_rows();
+        if row < STATUS_ROWS || row >= rows {
+            return Err(ConsoleError::RowOutOfBounds { row, rows });
+        }
+        let columns = self.text_columns();
+        if column >= columns {
+            return Err(ConsoleError::ColumnOutOfBounds { column, columns });
+        }
+        self.y_pos = row * LINE_HEIGHT + BORDER_PADDING;
+        self.x_pos = column * COLUMN_WIDTH + BORDER_PADDING;
+        Ok(())
+    }
+
+    /// Writes `s` starting at the given row and column, then restores the previous write
+    /// position. If the text scrolled the screen, the position moves up with it, and stays on the
+    /// top row if its own row scrolled away.
+    pub fn write_at(&mut self, row: usize, column: usize, s: &str) -> Result<(), ConsoleError> {
+        self.write_fmt_at(row, column, format_args!("{}", s))
+    }
+
+    /// Like [`FrameBufferWriter::write_at`], but writes formatted text.
+    pub fn write_fmt_at(
+        &mut self,
+        row: usize,
+        column: usize,
+        args: fmt::Arguments,
+    ) -> Result<(), ConsoleError> {
+        let (x_pos, y_pos) = (self.x_pos, self.y_pos);
+        self.set_cursor_checked(row, column)?;
+        let scrolls = self.scrolls;
+        let _ = self.write_fmt(args);
+        let scrolled = self.scrolls.wrapping_sub(scrolls);
+        let top = STATUS_ROWS * LINE_HEIGHT + BORDER_PADDING;
+        self.x_pos = x_pos;
+        self.y_pos = y_pos
+            .saturating_sub(scrolled.saturating_mul(LINE_HEIGHT))
+            .max(top);
+        Ok(())
+    }
+
     /// Writes a single char to the framebuffer. Takes care of special control characters, such as
-    /// newlines and carriage returns.
+    /// newlines and carriage returns, and of ANSI escape sequences.
     fn write_char(&mut self, c: char) {
+        match self.ansi.advance(c) {
+            Action::Print(c) => self.write_plain_char(c),
+            Action::Csi(csi) => self.apply_csi(csi),
+            Action::None => {}
+        }
+    }
+
+    /// Applies a completed ANSI control sequence. Only SGR (`ESC [ ... m`) is supported.
+    fn apply_csi(&mut self, csi: Csi) {
+        if csi.final_byte != 'm' {
+            return;
+        }
+        if csi.params().is_empty() {
+            self.attributes = Attributes::NONE;
+        }
+        for param in csi.params() {
+            match param {
+                0 => self.attributes = Attributes::NONE,
+                1 => self.attributes.insert(Attributes::BOLD),
+                4 => self.attributes.insert(Attributes::UNDERLINE),
+                5 => self.attributes.insert(Attributes::BLINK),
+                7 => self.attributes.insert(Attributes::REVERSE),
+                22 => self.attributes.remove(Attributes::BOLD),
+                24 => self.attributes.remove(Attributes::UNDERLINE),
+                25 => self.attributes.remove(Attributes::BLINK),
+                27 => self.attributes.remove(Attributes::REVERSE),
+                _ => {}
+            }
+        }
+    }
+
+    fn write_plain_char(&mut self, c: char) {
         match c {
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
+            '\u{8}' => self.backspace(),
             c => {
+                while self.text_rows() > STATUS_ROWS
+                    && self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val() + BORDER_PADDING
+                        >= self.height()
+                {
+                    self.scroll();
+                }
                 let new_xpos = self.x_pos + font_constants::CHAR_RASTER_WIDTH;
                 if new_xpos >= self.width() {
-                    self.newline();
+                    match self.wrap_mode {
+                        WrapMode::Wrap => {
+                            self.newline();
+                            return self.write_plain_char(c);
+                        }
+                        WrapMode::Truncate => return self.truncate_line(),
+                        WrapMode::HorizontalScroll => {
+                            self.line.push(c, self.attributes);
+                            return self.scroll_line_left();
+                        }
+                    }
                 }
-                let new_ypos =
-                    self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val() + BORDER_PADDING;
-                if new_ypos >= self.height() {
-                    self.clear();
+                if self.wrap_mode == WrapMode::HorizontalScroll {
+                    self.line.push(c, self.attributes);
                 }
-                self.write_rendered_char(get_char_raster(c));
+                self.write_rendered_char(c);
             }
         }
     }
 
-    /// Prints a rendered char into the framebuffer.
+    /// Replaces the last visible character of the line with a truncation marker. Further
+    /// characters on this line are dropped.
+    fn truncate_line(&mut self) {
+        if self.line.truncated || self.x_pos < BORDER_PADDING + COLUMN_WIDTH {
+            return;
+        }
+        self.line.truncated = true;
+        let x_pos = self.x_pos - COLUMN_WIDTH;
+        self.draw_glyph(x_pos, self.y_pos, '>', Attributes::REVERSE);
+    }
+
+    /// Scrolls the current line left by half a screen and redraws it.
+    fn scroll_line_left(&mut self) {
+        let columns = self.text_columns();
+        let shift = (columns / 2).max(1);
+        self.line.scroll = (self.line.scroll + shift).min(self.line.len());
+
+        // Blank the line, then redraw the part that is still visible.
+        let y_pos = self.y_pos;
+        for column in 0..columns {
+            self.draw_glyph(
+                BORDER_PADDING + column * COLUMN_WIDTH,
+                y_pos,
+                ' ',
+                Attributes::NONE,
+            );
+            self.blink.remove(BORDER_PADDING + column * COLUMN_WIDTH, y_pos);
+        }
+        let attributes = self.attributes;
+        self.x_pos = BORDER_PADDING;
+        for i in 0..self.line.visible().len().min(columns) {
+            let (c, cell_attributes) = self.line.visible()[i];
+            self.attributes = cell_attributes;
+            self.write_rendered_char(c);
+        }
+        self.attributes = attributes;
+        self.draw_glyph(BORDER_PADDING, y_pos, '<', Attributes::REVERSE);
+    }
+
+    /// Prints a char into the framebuffer using the current attributes.
     /// Updates `self.x_pos`.
-    fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
-        for (y, row) in rendered_char.raster().iter().enumerate() {
-            for (x, byte) in row.iter().enumerate() {
-                self.write_pixel(self.x_pos + x, self.y_pos + y, *byte);
+    fn write_rendered_char(&mut self, c: char) {
+        let attributes = self.attributes;
+        let width = self.draw_glyph(self.x_pos, self.y_pos, c, attributes);
+        if attributes.contains(Attributes::BLINK) {
+            self.blink.insert(BlinkCell {
+                x: self.x_pos,
+                y: self.y_pos,
+                c,
+                attributes,
+            });
+        } else {
+            self.blink.remove(self.x_pos, self.y_pos);
+        }
+        self.x_pos += width + LETTER_SPACING;
+    }
+
+    /// Draws the glyph for `c` with its top-left corner at the given pixel position.
+    /// Returns the width of the glyph.
+    fn draw_glyph(&mut self, x_pos: usize, y_pos: usize, c: char, attributes: Attributes) -> usize {
+        let hidden = attributes.contains(Attributes::BLINK) && !self.blink.visible;
+        let c = if hidden { ' ' } else { c };
+        let shape = Shape::for_char(c);
+        let rendered_char: RasterizedChar = get_char_raster(if shape.is_some() { ' ' } else { c });
+        let (width, height) = match shape {
+            Some(_) => (font_constants::CHAR_RASTER_WIDTH, LINE_HEIGHT),
+            None => (rendered_char.width(), rendered_char.height()),
+        };
+        let height = height.min(self.height().saturating_sub(y_pos));
+        let underline_row = font_constants::CHAR_RASTER_HEIGHT.val() - 2;
+        for y in 0..height {
+            for x in 0..width {
+                let (mut intensity, left) = match shape {
+                    Some(shape) => (shape.intensity(x, y, width, height), 0),
+                    None => {
+                        let row = rendered_char.raster()[y];
+                        (row[x], if x > 0 { row[x - 1] } else { 0 })
+                    }
+                };
+                if attributes.contains(Attributes::BOLD) {
+                    intensity = intensity.max(left);
+                }
+                if attributes.contains(Attributes::UNDERLINE) && y == underline_row {
+                    intensity = u8::MAX;
+                }
+                if attributes.contains(Attributes::REVERSE) {
+                    intensity = u8::MAX - intensity;
+                }
+                self.write_pixel(x_pos + x, y_pos + y, intensity);
             }
         }
-        self.x_pos += rendered_char.width() + LETTER_SPACING;
+        self.mark_dirty(x_pos, y_pos, width, height);
+        width
     }
 
+    /// Byte offset of the pixel at `(x, y)`.
+    fn pixel_offset(&self, x: usize, y: usize) -> usize {
+        (y * self.info.stride + x) * self.info.bytes_per_pixel
+    }
+
+    /// Draws a pixel of a glyph: intensity 0 is the background color, 255 the foreground color.
     fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
+        let color = self.background.blend(self.foreground, intensity);
+        self.write_color(x, y, color);
+    }
+
+    fn write_color(&mut self, x: usize, y: usize, color: Color) {
         let pixel_offset = y * self.info.stride + x;
+        let Color { red, green, blue } = color;
         let color = match self.info.pixel_format {
-            PixelFormat::Rgb => [intensity, intensity, intensity / 2, 0],
-            PixelFormat::Bgr => [intensity / 2, intensity, intensity, 0],
-            PixelFormat::U8 => [if intensity > 200 { 0xf } else { 0 }, 0, 0, 0],
+            PixelFormat::Rgb => [red, green, blue, 0],
+            PixelFormat::Bgr => [blue, green, red, 0],
+            PixelFormat::U8 => [color.luma(), 0, 0, 0],
+            PixelFormat::Unknown {
+                red_position,
+                green_position,
+                blue_position,
+            } => {
+                // The positions are bit offsets into a little-endian pixel value.
+                let channel = |value: u8, position: u8| {
+                    u32::from(value)
+                        .checked_shl(u32::from(position))
+                        .unwrap_or(0)
+                };
+                (channel(red, red_position)
+                    | channel(green, green_position)
+                    | channel(blue, blue_position))
+                .to_le_bytes()
+            }
             other => {
                 // set a supported (but invalid) pixel format before panicking to avoid a double
                 // panic; it might not be readable though
@@ -143,14 +777,23 @@ impl<'a> FrameBufferWriter<'a> {
         let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
     }
     pub fn backspace(&mut self) {
+        self.hide_pointer_for_drawing();
         if self.x_pos >= (BORDER_PADDING + font_constants::CHAR_RASTER_WIDTH) {
             self.x_pos -= font_constants::CHAR_RASTER_WIDTH + LETTER_SPACING;
+            self.blink.remove(self.x_pos, self.y_pos);
             for y in self.y_pos..(self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val()) {
                 for x in (self.x_pos..(self.x_pos + font_constants::CHAR_RASTER_WIDTH)).rev() {
                     self.write_pixel(x, y, 0);
                 }
             }
+            self.mark_dirty(
+                self.x_pos,
+                self.y_pos,
+                font_constants::CHAR_RASTER_WIDTH,
+                font_constants::CHAR_RASTER_HEIGHT.val(),
+            );
         }
+        self.restore_pointer_after_drawing();
     }
 }
 
@@ -159,11 +802,25 @@ impl<'a> FrameBufferWriter<'a> {
 unsafe impl<'a> Send for FrameBufferWriter<'a> {}
 unsafe impl<'a> Sync for FrameBufferWriter<'a> {}
 
+/// Adapter that routes formatted output into the status bar.
+struct StatusLine<'w, 'a>(&'w mut FrameBufferWriter<'a>);
+
+impl<'w, 'a> fmt::Write for StatusLine<'w, 'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.0.write_status_char(c);
+        }
+        Ok(())
+    }
+}
+
 impl<'a> fmt::Write for FrameBufferWriter<'a> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.hide_pointer_for_drawing();
         for c in s.chars() {
             self.write_char(c);
         }
+        self.restore_pointer_after_drawing();
         Ok(())
     }
 }
@@ -0,0 +1,107 @@
+/// Maximum number of numeric parameters kept for a single control sequence.
+const MAX_PARAMS: usize = 8;
+
+const ESC: char = '\u{1b}';
+
+/// A complete `ESC [ ... <final>` control sequence.
+#[derive(Debug, Clone, Copy)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    pub final_byte: char,
+}
+
+impl Csi {
+    /// The numeric parameters. Omitted parameters are reported as `0`.
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+}
+
+/// What the writer should do with a character after it passed through the parser.
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    /// Draw the character (or handle it as a plain control character).
+    Print(char),
+    /// A control sequence has been completed.
+    Csi(Csi),
+    /// The character was consumed as part of an escape sequence.
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// Incremental parser for the subset of ANSI escape sequences the console understands.
+#[derive(Debug)]
+pub struct AnsiParser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+impl AnsiParser {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            len: 0,
+        }
+    }
+
+    /// Feeds a single character into the parser.
+    pub fn advance(&mut self, c: char) -> Action {
+        match self.state {
+            State::Ground if c == ESC => {
+                self.state = State::Escape;
+                Action::None
+            }
+            State::Ground => Action::Print(c),
+            State::Escape if c == '[' => {
+                self.state = State::Csi;
+                self.params = [0; MAX_PARAMS];
+                self.len = 0;
+                Action::None
+            }
+            // Unsupported escape sequences are dropped.
+            State::Escape => {
+                self.state = State::Ground;
+                Action::None
+            }
+            State::Csi => match c {
+                '0'..='9' => {
+                    if self.len == 0 {
+                        self.len = 1;
+                    }
+                    let digit = c as u16 - '0' as u16;
+                    let param = &mut self.params[self.len - 1];
+                    *param = param.saturating_mul(10).saturating_add(digit);
+                    Action::None
+                }
+                ';' => {
+                    if self.len == 0 {
+                        self.len = 1;
+                    }
+                    if self.len < MAX_PARAMS {
+                        self.len += 1;
+                    }
+                    Action::None
+                }
+                '\u{40}'..='\u{7e}' => {
+                    self.state = State::Ground;
+                    Action::Csi(Csi {
+                        params: self.params,
+                        len: self.len,
+                        final_byte: c,
+                    })
+                }
+                // Intermediate bytes and private markers are ignored.
+                _ => Action::None,
+            },
+        }
+    }
+}
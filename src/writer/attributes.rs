@@ -0,0 +1,125 @@
+use core::ops::{BitOr, BitOrAssign};
+
+/// Per-character rendering attributes, stored as a bitfield.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Attributes(u8);
+
+impl Attributes {
+    pub const NONE: Attributes = Attributes(0);
+    /// Thickens strokes by smearing each glyph one pixel to the right.
+    pub const BOLD: Attributes = Attributes(1 << 0);
+    /// Draws a line under the glyph.
+    pub const UNDERLINE: Attributes = Attributes(1 << 1);
+    /// Periodically hides and shows the glyph.
+    pub const BLINK: Attributes = Attributes(1 << 2);
+    /// Swaps foreground and background intensities.
+    pub const REVERSE: Attributes = Attributes(1 << 3);
+
+    /// Returns whether all attributes in `other` are set.
+    pub const fn contains(self, other: Attributes) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Attributes) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Attributes) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for Attributes {
+    type Output = Attributes;
+
+    fn bitor(self, rhs: Attributes) -> Attributes {
+        Attributes(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Attributes {
+    fn bitor_assign(&mut self, rhs: Attributes) {
+        self.insert(rhs);
+    }
+}
+
+/// Maximum number of blinking characters on screen at once. Further blinking characters are
+/// drawn but stay visible.
+const MAX_BLINK_CELLS: usize = 128;
+
+/// A character drawn with [`Attributes::BLINK`] that has to be redrawn on every blink phase.
+#[derive(Debug, Clone, Copy)]
+pub struct BlinkCell {
+    pub x: usize,
+    pub y: usize,
+    pub c: char,
+    pub attributes: Attributes,
+}
+
+/// The set of blinking characters currently on screen.
+#[derive(Debug, Clone)]
+pub struct BlinkCells {
+    cells: [Option<BlinkCell>; MAX_BLINK_CELLS],
+    /// Whether blinking characters are currently shown.
+    pub visible: bool,
+}
+
+impl BlinkCells {
+    /// Number of slots, each of which may hold a character.
+    pub const SLOTS: usize = MAX_BLINK_CELLS;
+
+    pub const fn new() -> Self {
+        Self {
+            cells: [None; MAX_BLINK_CELLS],
+            visible: true,
+        }
+    }
+
+    /// Records a blinking character, replacing any previous one at the same position.
+    pub fn insert(&mut self, cell: BlinkCell) {
+        self.remove(cell.x, cell.y);
+        if let Some(slot) = self.cells.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(cell);
+        }
+    }
+
+    /// Forgets the blinking character at the given pixel position, if any.
+    pub fn remove(&mut self, x: usize, y: usize) {
+        for slot in self.cells.iter_mut() {
+            if matches!(slot, Some(cell) if cell.x == x && cell.y == y) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Forgets all blinking characters at or below pixel line `top`.
+    pub fn clear_below(&mut self, top: usize) {
+        for slot in self.cells.iter_mut() {
+            if matches!(slot, Some(cell) if cell.y >= top) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Moves all characters at or below `top` up by `dy` pixels, dropping those that leave the
+    /// region.
+    pub fn scroll(&mut self, top: usize, dy: usize) {
+        for slot in self.cells.iter_mut() {
+            if let Some(cell) = slot {
+                if cell.y < top {
+                    continue;
+                }
+                if cell.y < top + dy {
+                    *slot = None;
+                } else {
+                    cell.y -= dy;
+                }
+            }
+        }
+    }
+
+    /// The character in `slot`, if it holds one.
+    pub fn get(&self, slot: usize) -> Option<BlinkCell> {
+        self.cells.get(slot).copied().flatten()
+    }
+}
@@ -0,0 +1,72 @@
+/// Box-drawing and block characters used by the TUI widgets.
+///
+/// The bundled font only covers Basic Latin, so these characters are rendered procedurally.
+/// They fill the whole text cell, including the line spacing, so that vertical lines and blocks
+/// in consecutive rows join up.
+#[derive(Debug, Clone, Copy)]
+pub enum Shape {
+    /// Light lines from the centre of the cell towards the given edges.
+    Lines {
+        up: bool,
+        down: bool,
+        left: bool,
+        right: bool,
+    },
+    /// `█`
+    FullBlock,
+    /// `░`
+    LightShade,
+}
+
+impl Shape {
+    pub fn for_char(c: char) -> Option<Shape> {
+        let lines = |up, down, left, right| {
+            Some(Shape::Lines {
+                up,
+                down,
+                left,
+                right,
+            })
+        };
+        match c {
+            '─' => lines(false, false, true, true),
+            '│' => lines(true, true, false, false),
+            '┌' => lines(false, true, false, true),
+            '┐' => lines(false, true, true, false),
+            '└' => lines(true, false, false, true),
+            '┘' => lines(true, false, true, false),
+            '├' => lines(true, true, false, true),
+            '┤' => lines(true, true, true, false),
+            '┬' => lines(false, true, true, true),
+            '┴' => lines(true, false, true, true),
+            '┼' => lines(true, true, true, true),
+            '█' => Some(Shape::FullBlock),
+            '░' => Some(Shape::LightShade),
+            _ => None,
+        }
+    }
+
+    /// Returns the intensity of pixel `(x, y)` in a cell of the given size.
+    pub fn intensity(self, x: usize, y: usize, width: usize, height: usize) -> u8 {
+        let lit = match self {
+            Shape::Lines {
+                up,
+                down,
+                left,
+                right,
+            } => {
+                let (cx, cy) = (width / 2, height / 2);
+                let horizontal = y == cy && ((left && x <= cx) || (right && x >= cx));
+                let vertical = x == cx && ((up && y <= cy) || (down && y >= cy));
+                horizontal || vertical
+            }
+            Shape::FullBlock => true,
+            Shape::LightShade => x.is_multiple_of(2) && y.is_multiple_of(2),
+        };
+        if lit {
+            u8::MAX
+        } else {
+            0
+        }
+    }
+}
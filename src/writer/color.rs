@@ -0,0 +1,39 @@
+/// A 24-bit RGB color, converted to the framebuffer's pixel format when drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::new(0, 0, 0);
+    pub const WHITE: Color = Color::new(0xff, 0xff, 0xff);
+    pub const RED: Color = Color::new(0xaa, 0, 0);
+    /// The pale yellow used for normal console text.
+    pub const DEFAULT_FOREGROUND: Color = Color::new(0xff, 0xff, 0x7f);
+
+    pub const fn new(red: u8, green: u8, blue: u8) -> Self {
+        Self { red, green, blue }
+    }
+
+    /// Mixes `self` (at intensity 0) with `other` (at intensity 255).
+    pub fn blend(self, other: Color, intensity: u8) -> Color {
+        let mix = |from: u8, to: u8| {
+            let (from, to, t) = (i32::from(from), i32::from(to), i32::from(intensity));
+            (from + (to - from) * t / 255) as u8
+        };
+        Color::new(
+            mix(self.red, other.red),
+            mix(self.green, other.green),
+            mix(self.blue, other.blue),
+        )
+    }
+
+    /// Perceived brightness, for grayscale framebuffers.
+    pub fn luma(self) -> u8 {
+        let luma =
+            77 * u32::from(self.red) + 150 * u32::from(self.green) + 29 * u32::from(self.blue);
+        (luma >> 8) as u8
+    }
+}
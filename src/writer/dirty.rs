@@ -0,0 +1,72 @@
+/// Number of text rows whose state can be tracked individually. Rows past this limit are always
+/// reported as dirty, which is slower but never wrong.
+const MAX_TRACKED_ROWS: usize = 256;
+
+/// A set of text rows that have been drawn into since they were last cleared.
+///
+/// Rows that are not dirty are known to contain only background pixels, so clearing and
+/// scrolling can skip them entirely.
+#[derive(Debug, Clone, Copy)]
+pub struct DirtyRows {
+    bits: [u64; MAX_TRACKED_ROWS / 64],
+}
+
+impl DirtyRows {
+    /// Creates a set with every row clean.
+    pub const fn new() -> Self {
+        Self {
+            bits: [0; MAX_TRACKED_ROWS / 64],
+        }
+    }
+
+    /// Marks `row` as containing drawn pixels.
+    pub fn mark(&mut self, row: usize) {
+        self.set(row, true);
+    }
+
+    /// Records whether `row` contains drawn pixels.
+    pub fn set(&mut self, row: usize, dirty: bool) {
+        if row >= MAX_TRACKED_ROWS {
+            return;
+        }
+        let mask = 1u64 << (row % 64);
+        if dirty {
+            self.bits[row / 64] |= mask;
+        } else {
+            self.bits[row / 64] &= !mask;
+        }
+    }
+
+    /// Returns whether `row` may contain drawn pixels.
+    pub fn is_dirty(&self, row: usize) -> bool {
+        if row >= MAX_TRACKED_ROWS {
+            return true;
+        }
+        self.bits[row / 64] & (1u64 << (row % 64)) != 0
+    }
+}
+
+/// A rectangle of pixels that changed since the last flush.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl DirtyRect {
+    /// Returns the smallest rectangle that contains both `self` and `other`.
+    pub fn union(self, other: DirtyRect) -> DirtyRect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        DirtyRect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
@@ -0,0 +1,69 @@
+/// Arrow sprite for the mouse pointer. `X` is drawn as outline, `o` as fill, and `.` is
+/// transparent.
+const ARROW: [&[u8; POINTER_WIDTH]; POINTER_HEIGHT] = [
+    b"X...........",
+    b"XX..........",
+    b"XoX.........",
+    b"XooX........",
+    b"XoooX.......",
+    b"XooooX......",
+    b"XoooooX.....",
+    b"XooooooX....",
+    b"XoooooooX...",
+    b"XooooooooX..",
+    b"XoooooooooX.",
+    b"XooooooXXXXX",
+    b"XoooXooX....",
+    b"XooXXooX....",
+    b"XoX..XooX...",
+    b"XX...XooX...",
+    b"X.....XooX..",
+    b"......XooX..",
+    b".......XX...",
+];
+
+pub const POINTER_WIDTH: usize = 12;
+pub const POINTER_HEIGHT: usize = 19;
+
+/// Largest pixel size of any supported pixel format.
+const MAX_BYTES_PER_PIXEL: usize = 4;
+
+/// Intensity of a sprite pixel, or `None` if the pixel is transparent.
+pub fn sprite_pixel(x: usize, y: usize) -> Option<u8> {
+    match ARROW[y][x] {
+        b'X' => Some(0),
+        b'o' => Some(u8::MAX),
+        _ => None,
+    }
+}
+
+/// State of the software-rendered mouse pointer, including a copy of the framebuffer bytes it
+/// currently covers.
+#[derive(Debug)]
+pub struct Pointer {
+    pub x: usize,
+    pub y: usize,
+    /// Whether the sprite is currently drawn into the framebuffer.
+    pub drawn: bool,
+    /// Whether the pointer should be shown at all.
+    pub enabled: bool,
+    saved: [u8; POINTER_WIDTH * POINTER_HEIGHT * MAX_BYTES_PER_PIXEL],
+}
+
+impl Pointer {
+    pub const fn new() -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            drawn: false,
+            enabled: false,
+            saved: [0; POINTER_WIDTH * POINTER_HEIGHT * MAX_BYTES_PER_PIXEL],
+        }
+    }
+
+    /// The saved bytes for sprite pixel `(x, y)`.
+    pub fn saved_mut(&mut self, x: usize, y: usize, bytes_per_pixel: usize) -> &mut [u8] {
+        let offset = (y * POINTER_WIDTH + x) * MAX_BYTES_PER_PIXEL;
+        &mut self.saved[offset..offset + bytes_per_pixel.min(MAX_BYTES_PER_PIXEL)]
+    }
+}
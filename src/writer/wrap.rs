@@ -0,0 +1,63 @@
+use super::Attributes;
+
+/// What happens to text that reaches the right edge of the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WrapMode {
+    /// Continue on the next line.
+    #[default]
+    Wrap,
+    /// Drop the rest of the line and mark the cut with `>`.
+    Truncate,
+    /// Shift the current line to the left so the newest text stays visible. Hidden text is
+    /// marked with `<`.
+    HorizontalScroll,
+}
+
+/// Longest line that can be horizontally scrolled. Characters beyond this are dropped.
+const MAX_LINE_CHARS: usize = 512;
+
+/// The characters of the line currently being written, kept so that it can be redrawn when it
+/// is truncated or scrolled.
+#[derive(Debug)]
+pub struct LineBuffer {
+    cells: [(char, Attributes); MAX_LINE_CHARS],
+    len: usize,
+    /// Number of characters scrolled out on the left.
+    pub scroll: usize,
+    /// Whether the truncation marker has been drawn for this line.
+    pub truncated: bool,
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        Self {
+            cells: [(' ', Attributes::NONE); MAX_LINE_CHARS],
+            len: 0,
+            scroll: 0,
+            truncated: false,
+        }
+    }
+
+    /// Starts a new, empty line.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.scroll = 0;
+        self.truncated = false;
+    }
+
+    pub fn push(&mut self, c: char, attributes: Attributes) {
+        if self.len < MAX_LINE_CHARS {
+            self.cells[self.len] = (c, attributes);
+            self.len += 1;
+        }
+    }
+
+    /// The characters that are not scrolled out.
+    pub fn visible(&self) -> &[(char, Attributes)] {
+        &self.cells[self.scroll.min(self.len)..self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}